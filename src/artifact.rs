@@ -0,0 +1,350 @@
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{SecondsFormat, Utc};
+use snafu::{OptionExt, ResultExt, ensure};
+use tokio::io::AsyncReadExt;
+
+use crate::error;
+use crate::image::Image;
+use crate::layer::Layer;
+use crate::models::MediaType;
+use crate::uri::{Reference, Uri};
+
+/// Annotation recording when an artifact was attached, the latest artifact of a kind wins
+pub const CREATED_ANNOTATION: &str = "org.opencontainers.image.created";
+
+/// Format of a software bill of materials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// SPDX document in JSON
+    Spdx,
+    /// CycloneDX document in JSON
+    CycloneDx,
+}
+
+impl SbomFormat {
+    /// Artifact type, and media type of the blob, of an SBOM in this format
+    pub fn artifact_type(&self) -> &'static str {
+        match self {
+            Self::Spdx => "application/spdx+json",
+            Self::CycloneDx => "application/vnd.cyclonedx+json",
+        }
+    }
+
+    /// Format of an SBOM from the fields only documents of that format have
+    pub fn detect(content: &[u8]) -> Option<Self> {
+        let document: serde_json::Value = serde_json::from_slice(content).ok()?;
+        if document.get("spdxVersion").is_some() {
+            Some(Self::Spdx)
+        } else if document.get("bomFormat").and_then(|x| x.as_str()) == Some("CycloneDX") {
+            Some(Self::CycloneDx)
+        } else {
+            None
+        }
+    }
+
+    /// Format of an SBOM from the artifact type it was attached with
+    fn from_artifact_type(artifact_type: &str) -> Option<Self> {
+        [Self::Spdx, Self::CycloneDx]
+            .into_iter()
+            .find(|x| x.artifact_type() == artifact_type)
+    }
+}
+
+/// Software bill of materials attached to an image as an artifact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sbom {
+    format: SbomFormat,
+    content: Vec<u8>,
+}
+
+impl Sbom {
+    /// Create an SBOM of the document, detecting its format when none is provided
+    pub fn new(content: Vec<u8>, format: Option<SbomFormat>) -> crate::Result<Self> {
+        let format = format
+            .or_else(|| SbomFormat::detect(&content))
+            .context(error::SbomFormatSnafu)?;
+        Ok(Self { format, content })
+    }
+
+    /// Format of the SBOM
+    pub fn format(&self) -> SbomFormat {
+        self.format
+    }
+
+    /// Document of the SBOM
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Attach the SBOM to the manifest at the uri, returning the descriptor of the artifact
+    pub async fn attach(&self, uri: &Uri) -> crate::Result<Layer> {
+        let artifact_type = self.format.artifact_type();
+        attach(
+            uri,
+            artifact_type,
+            &MediaType::Other(artifact_type.to_string()),
+            &self.content,
+            &[],
+        )
+        .await
+    }
+
+    /// Fetch the SBOM attached last to the manifest at the uri, if it has one
+    pub async fn fetch(uri: &Uri) -> crate::Result<Option<Self>> {
+        let types = [SbomFormat::Spdx, SbomFormat::CycloneDx].map(|x| x.artifact_type());
+        let Some(artifact) = list(uri, &types).await?.pop() else {
+            return Ok(None);
+        };
+        let (_, content) = fetch(uri, &artifact).await?;
+        Ok(artifact
+            .artifact_type()
+            .and_then(SbomFormat::from_artifact_type)
+            .map(|format| Self { format, content }))
+    }
+}
+
+/// Media type of an in-toto statement
+pub const IN_TOTO_MEDIA_TYPE: &str = "application/vnd.in-toto+json";
+/// Media type of a DSSE envelope, i.e. a signed in-toto statement
+pub const DSSE_MEDIA_TYPE: &str = "application/vnd.dsse.envelope.v1+json";
+/// Annotation holding the predicate type of an attestation, i.e. `https://slsa.dev/provenance/v1`
+pub const PREDICATE_TYPE_ANNOTATION: &str = "in-toto.io/predicate-type";
+
+/// In-toto attestation, such as build provenance, attached to an image as an artifact. The
+/// document is either a statement or a DSSE envelope signing one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    media_type: &'static str,
+    predicate_type: String,
+    content: Vec<u8>,
+}
+
+impl Attestation {
+    /// Create an attestation of an in-toto statement or a DSSE envelope of one
+    pub fn new(content: Vec<u8>) -> crate::Result<Self> {
+        let document = serde_json::from_slice::<serde_json::Value>(&content)
+            .ok()
+            .context(error::InvalidAttestationSnafu {
+                reason: "expected a JSON document",
+            })?;
+        let (media_type, statement) = match document.get("payload").and_then(|x| x.as_str()) {
+            Some(payload) => {
+                ensure!(
+                    document.get("payloadType").and_then(|x| x.as_str())
+                        == Some(IN_TOTO_MEDIA_TYPE),
+                    error::InvalidAttestationSnafu {
+                        reason: format!("expected an envelope of {IN_TOTO_MEDIA_TYPE}"),
+                    }
+                );
+                let statement = STANDARD
+                    .decode(payload)
+                    .ok()
+                    .and_then(|x| serde_json::from_slice(&x).ok())
+                    .context(error::InvalidAttestationSnafu {
+                        reason: "expected a base64 encoded JSON envelope payload",
+                    })?;
+                (DSSE_MEDIA_TYPE, statement)
+            }
+            None => (IN_TOTO_MEDIA_TYPE, document),
+        };
+        ensure!(
+            statement
+                .get("_type")
+                .and_then(|x| x.as_str())
+                .is_some_and(|x| x.starts_with("https://in-toto.io/Statement/")),
+            error::InvalidAttestationSnafu {
+                reason: "expected an in-toto statement",
+            }
+        );
+        let predicate_type = statement
+            .get("predicateType")
+            .and_then(|x| x.as_str())
+            .context(error::InvalidAttestationSnafu {
+                reason: "the statement has no predicate type",
+            })?
+            .to_string();
+        Ok(Self {
+            media_type,
+            predicate_type,
+            content,
+        })
+    }
+
+    /// Media type of the document, either [`IN_TOTO_MEDIA_TYPE`] or [`DSSE_MEDIA_TYPE`]
+    pub fn media_type(&self) -> &str {
+        self.media_type
+    }
+
+    /// Type of the predicate of the statement, i.e. `https://slsa.dev/provenance/v1`
+    pub fn predicate_type(&self) -> &str {
+        &self.predicate_type
+    }
+
+    /// Document of the attestation
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Attach the attestation to the manifest at the uri, returning the descriptor of the artifact
+    pub async fn attach(&self, uri: &Uri) -> crate::Result<Layer> {
+        attach(
+            uri,
+            self.media_type,
+            &MediaType::Other(self.media_type.to_string()),
+            &self.content,
+            &[(PREDICATE_TYPE_ANNOTATION, self.predicate_type.as_str())],
+        )
+        .await
+    }
+
+    /// List the attestations referring to the manifest at the uri, oldest first. The predicate
+    /// type of each is in its [`PREDICATE_TYPE_ANNOTATION`] annotation.
+    pub async fn list(uri: &Uri) -> crate::Result<Vec<Layer>> {
+        list(uri, &[IN_TOTO_MEDIA_TYPE, DSSE_MEDIA_TYPE]).await
+    }
+
+    /// Fetch the attestation of an artifact returned by [`Attestation::list`]
+    pub async fn fetch(uri: &Uri, artifact: &Layer) -> crate::Result<Self> {
+        let (_, content) = fetch(uri, artifact).await?;
+        Self::new(content)
+    }
+}
+
+/// Attach a document to the manifest at the uri as an artifact of the artifact type, stored in a
+/// single blob of the media type. The artifact refers to the manifest through its subject, so it
+/// is listed by the referrers api, along with the annotations. Returns the descriptor of the
+/// artifact manifest.
+pub async fn attach(
+    uri: &Uri,
+    artifact_type: &str,
+    media_type: &MediaType,
+    content: &[u8],
+    annotations: &[(&str, &str)],
+) -> crate::Result<Layer> {
+    let registry = uri.registry();
+    let (bytes, digest, subject_type) = registry
+        .fetch_manifest_raw(uri.repository(), uri.reference().to_string().as_str())
+        .await?;
+    let subject = Layer::builder()
+        .media_type(subject_type)
+        .digest(digest)
+        .size(bytes.len())
+        .build();
+    let blob = Layer::upload(uri, media_type, content).await?;
+    Image::create_empty_config(uri).await?;
+    let mut artifact = Image::artifact(artifact_type, &[blob], Some(subject));
+    artifact.annotate(
+        CREATED_ANNOTATION,
+        Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    for (key, value) in annotations {
+        artifact.annotate(*key, *value);
+    }
+    registry.push_referrer(uri.repository(), &artifact).await
+}
+
+/// List the artifacts of any of the artifact types referring to the manifest at the uri, oldest
+/// first by the time they were attached.
+pub async fn list(uri: &Uri, artifact_types: &[&str]) -> crate::Result<Vec<Layer>> {
+    let registry = uri.registry();
+    let digest = registry
+        .resolve_digest(uri.repository(), uri.reference().to_string().as_str())
+        .await?;
+    let filter = match artifact_types {
+        [artifact_type] => Some(*artifact_type),
+        _ => None,
+    };
+    let referrers = registry
+        .referrers(uri.repository(), &digest, filter)
+        .await?;
+    let mut artifacts = referrers
+        .manifests()
+        .iter()
+        .filter(|x| {
+            x.artifact_type()
+                .is_some_and(|x| artifact_types.contains(&x))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    // Timestamps in the same format sort in time order
+    artifacts.sort_by(|a, b| {
+        a.annotations()
+            .get(CREATED_ANNOTATION)
+            .cmp(&b.annotations().get(CREATED_ANNOTATION))
+    });
+    Ok(artifacts)
+}
+
+/// Read the document of an artifact attached with [`attach`], returning the descriptor of the
+/// blob it is stored in along with its content.
+pub async fn fetch(uri: &Uri, artifact: &Layer) -> crate::Result<(Layer, Vec<u8>)> {
+    let artifact_uri = Uri::builder()
+        .registry(uri.registry().clone())
+        .repository(uri.repository())
+        .reference(Reference::from_str(artifact.digest())?)
+        .build();
+    let image = Image::fetch(&artifact_uri, None).await?;
+    let blob = image
+        .layers()
+        .first()
+        .context(error::ImageNotFoundSnafu {
+            uri: artifact_uri.to_string(),
+        })?
+        .clone();
+    let mut content = Vec::with_capacity(blob.size());
+    blob.open(uri)
+        .await?
+        .read_to_end(&mut content)
+        .await
+        .context(error::LayerReadSnafu)?;
+    Ok((blob, content))
+}
+
+#[cfg(test)]
+mod test {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    use super::{Attestation, DSSE_MEDIA_TYPE, IN_TOTO_MEDIA_TYPE, SbomFormat};
+
+    #[test]
+    fn test_sbom_format_detect() {
+        assert_eq!(
+            SbomFormat::detect(br#"{"spdxVersion":"SPDX-2.3","packages":[]}"#),
+            Some(SbomFormat::Spdx)
+        );
+        assert_eq!(
+            SbomFormat::detect(br#"{"bomFormat":"CycloneDX","specVersion":"1.5"}"#),
+            Some(SbomFormat::CycloneDx)
+        );
+        assert_eq!(SbomFormat::detect(br#"{"name":"other"}"#), None);
+        assert_eq!(SbomFormat::detect(b"not json"), None);
+    }
+
+    #[test]
+    fn test_attestation_new() {
+        let statement = br#"{"_type":"https://in-toto.io/Statement/v1","subject":[],"predicateType":"https://slsa.dev/provenance/v1","predicate":{}}"#;
+        let attestation = Attestation::new(statement.to_vec()).unwrap();
+        assert_eq!(attestation.media_type(), IN_TOTO_MEDIA_TYPE);
+        assert_eq!(
+            attestation.predicate_type(),
+            "https://slsa.dev/provenance/v1"
+        );
+
+        let envelope = format!(
+            r#"{{"payloadType":"{IN_TOTO_MEDIA_TYPE}","payload":"{}","signatures":[]}}"#,
+            STANDARD.encode(statement)
+        );
+        let attestation = Attestation::new(envelope.into_bytes()).unwrap();
+        assert_eq!(attestation.media_type(), DSSE_MEDIA_TYPE);
+        assert_eq!(
+            attestation.predicate_type(),
+            "https://slsa.dev/provenance/v1"
+        );
+
+        assert!(Attestation::new(br#"{"spdxVersion":"SPDX-2.3"}"#.to_vec()).is_err());
+    }
+}
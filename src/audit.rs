@@ -0,0 +1,123 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use snafu::ResultExt;
+
+use crate::error;
+
+/// Mutating operations performed against a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    PutManifest,
+    DeleteManifest,
+    UploadBlob,
+    DeleteBlob,
+}
+
+/// Whether an audited operation succeeded, or was only planned by a dry run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Planned,
+}
+
+/// Record of a single mutating operation against a registry.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub operation: AuditOperation,
+    pub registry: String,
+    pub repository: String,
+    /// Tag or digest the operation targeted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// Digest of the content, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Size of the content in bytes, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    pub outcome: AuditOutcome,
+    /// Reason the operation failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Destination for audit events.
+///
+/// Sinks are called inline with the audited operation so they should not block for long.
+pub trait AuditSink: Send + Sync + fmt::Debug {
+    /// Record an event
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Audit sink appending each event as a line of JSON to a file.
+#[derive(Debug)]
+pub struct JsonLinesAudit {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAudit {
+    /// Open the file for appending, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(error::FileSnafu)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesAudit {
+    fn record(&self, event: &AuditEvent) {
+        let Ok(mut line) = serde_json::to_vec(event) else {
+            return;
+        };
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(&line) {
+            error!(target: "audit", "failed to write audit event: {e}");
+        }
+    }
+}
+
+/// Audit sink passing each event to a callback.
+pub struct AuditCallback<F> {
+    callback: F,
+}
+
+impl<F> AuditCallback<F>
+where
+    F: Fn(&AuditEvent) + Send + Sync,
+{
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> fmt::Debug for AuditCallback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditCallback")
+    }
+}
+
+impl<F> AuditSink for AuditCallback<F>
+where
+    F: Fn(&AuditEvent) + Send + Sync,
+{
+    fn record(&self, event: &AuditEvent) {
+        (self.callback)(event)
+    }
+}
@@ -0,0 +1,214 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Deserialize;
+use snafu::ResultExt;
+use tokio::process::Command;
+
+use crate::error;
+
+/// Resource, and scope with `/.default`, of tokens accepted by Azure Container Registry
+const RESOURCE: &str = "https://containerregistry.azure.net";
+/// Token endpoint of the instance metadata service, serving tokens of managed identities
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+/// Username ACR expects along with a refresh token as the password
+pub(crate) const USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+
+/// AAD access token along with the tenant it was issued by, when known.
+struct AadToken {
+    access_token: String,
+    tenant: Option<String>,
+}
+
+/// Output of `az account get-access-token`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliToken {
+    access_token: String,
+    tenant: Option<String>,
+}
+
+/// Response of an AAD or IMDS token endpoint.
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+/// Response of the ACR token exchange.
+#[derive(Deserialize)]
+struct RefreshToken {
+    refresh_token: String,
+}
+
+/// Whether the registry is Azure Container Registry
+pub(crate) fn is_azure(base: &str) -> bool {
+    base.ends_with(".azurecr.io")
+}
+
+/// Exchange an AAD token of the environment for an ACR refresh token of the registry. The AAD
+/// token is of the service principal in `AZURE_CLIENT_ID`, `AZURE_CLIENT_SECRET` and
+/// `AZURE_TENANT_ID`, the az CLI login or the managed identity of the instance, in that order.
+pub(crate) async fn refresh_token(registry: &str) -> crate::Result<Option<String>> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(2))
+        .build()
+        .context(error::RequestSnafu)?;
+    let aad = match service_principal_token(&client).await? {
+        Some(token) => Some(token),
+        None => match cli_token().await? {
+            Some(token) => Some(token),
+            None => managed_identity_token(&client).await?,
+        },
+    };
+    let Some(aad) = aad else {
+        return Ok(None);
+    };
+    let mut form = vec![
+        ("grant_type", "access_token"),
+        ("service", registry),
+        ("access_token", aad.access_token.as_str()),
+    ];
+    if let Some(tenant) = aad.tenant.as_deref() {
+        form.push(("tenant", tenant));
+    }
+    let response = post_form(
+        &client,
+        &format!("https://{registry}/oauth2/exchange"),
+        form,
+    )
+    .await?;
+    let token: RefreshToken = json(response).await?;
+    Ok(Some(token.refresh_token))
+}
+
+/// Token of the service principal configured in the environment, if there is one
+async fn service_principal_token(client: &reqwest::Client) -> crate::Result<Option<AadToken>> {
+    let var = |name| std::env::var(name).ok().filter(|x| !x.is_empty());
+    let (Some(client_id), Some(client_secret), Some(tenant)) = (
+        var("AZURE_CLIENT_ID"),
+        var("AZURE_CLIENT_SECRET"),
+        var("AZURE_TENANT_ID"),
+    ) else {
+        return Ok(None);
+    };
+    debug!(target: "registry", "using azure service principal {client_id}");
+    let scope = format!("{RESOURCE}/.default");
+    let response = post_form(
+        client,
+        &format!("https://login.microsoftonline.com/{tenant}/oauth2/v2.0/token"),
+        vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("scope", scope.as_str()),
+        ],
+    )
+    .await?;
+    let token: AccessToken = json(response).await?;
+    Ok(Some(AadToken {
+        access_token: token.access_token,
+        tenant: Some(tenant),
+    }))
+}
+
+/// Token of the az CLI login, if the CLI is installed and logged in
+async fn cli_token() -> crate::Result<Option<AadToken>> {
+    let output = Command::new("az")
+        .args([
+            "account",
+            "get-access-token",
+            "--output",
+            "json",
+            "--resource",
+        ])
+        .arg(RESOURCE)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            debug!(target: "registry", "az cli has no token: {}", String::from_utf8_lossy(&output.stderr).trim());
+            return Ok(None);
+        }
+        Err(e) => {
+            debug!(target: "registry", "az cli could not be run: {e}");
+            return Ok(None);
+        }
+    };
+    let token: CliToken = serde_json::from_slice(&output.stdout)
+        .context(error::CredentialHelperOutputSnafu { helper: "az" })?;
+    debug!(target: "registry", "using azure token of the az cli");
+    Ok(Some(AadToken {
+        access_token: token.access_token,
+        tenant: token.tenant,
+    }))
+}
+
+/// Token of the managed identity of the instance, if running on Azure
+async fn managed_identity_token(client: &reqwest::Client) -> crate::Result<Option<AadToken>> {
+    let mut url = reqwest::Url::parse(IMDS_TOKEN_URL).context(error::UrlSnafu)?;
+    url.query_pairs_mut()
+        .append_pair("api-version", "2018-02-01")
+        .append_pair("resource", RESOURCE);
+    if let Ok(client_id) = std::env::var("AZURE_CLIENT_ID") {
+        url.query_pairs_mut().append_pair("client_id", &client_id);
+    }
+    let response = match client.get(url).header("Metadata", "true").send().await {
+        Ok(response) if response.status().is_success() => response,
+        // Other clouds answer on the same address, and instances without an identity get an error
+        Ok(response) => {
+            debug!(target: "registry", "no azure managed identity, instance metadata service responded {}", response.status());
+            return Ok(None);
+        }
+        Err(e) => {
+            debug!(target: "registry", "no azure instance metadata service: {e}");
+            return Ok(None);
+        }
+    };
+    debug!(target: "registry", "using azure managed identity");
+    let token: AccessToken = json(response).await?;
+    Ok(Some(AadToken {
+        access_token: token.access_token,
+        tenant: None,
+    }))
+}
+
+/// Post a form to a token endpoint
+async fn post_form(
+    client: &reqwest::Client,
+    url: &str,
+    form: Vec<(&str, &str)>,
+) -> crate::Result<reqwest::Response> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    client
+        .post(url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .body(body)
+        .send()
+        .await
+        .context(error::RequestSnafu)
+}
+
+/// Read the body of a successful token endpoint response
+async fn json<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> crate::Result<T> {
+    if !response.status().is_success() {
+        return error::AuthorizationSnafu {
+            reason: format!(
+                "azure token endpoint {} responded {}",
+                response.url().path(),
+                response.status()
+            ),
+        }
+        .fail();
+    }
+    response
+        .json()
+        .await
+        .context(error::ResponseDeserializeSnafu)
+}
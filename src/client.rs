@@ -1,27 +1,59 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::Arc;
-
-use crate::models::Token;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::models::{Token, TokenResponse};
+use crate::options::{RegistryOptions, RetryPolicy};
+#[cfg(feature = "aws")]
+use crate::sigv4::SigV4Signer;
+use crate::uri::RegistryUri;
 use crate::{Result, error};
 use async_trait::async_trait;
 use bytes::Bytes;
-use reqwest::{RequestBuilder, Response};
-use snafu::ResultExt;
+use cfg_if::cfg_if;
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, IF_NONE_MATCH, RANGE, WWW_AUTHENTICATE};
+use reqwest::{Body, Method, Request, RequestBuilder, Response, StatusCode};
+use snafu::{OptionExt, ResultExt, ensure};
+use tokio::time::Instant;
 use url::Url;
 
 /// A trait for a client implementing requests to an OCI registry.
 ///
-/// This is primarily implemented to allow for ease of unit testing this crate.
+/// Implement it to send the requests of a [`Registry`](crate::registry::Registry) over a custom
+/// transport, i.e. to sign urls or add request middleware, and create the registry with
+/// [`RegistryClient::from_impl`] and [`Registry::with_client`](crate::registry::Registry::with_client).
+/// Upload urls are the `Location`s returned by the registry, resolve them against the registry url
+/// with [`upload_url`].
 #[async_trait]
-pub(crate) trait RegistryClientImpl: Send + Sync + Debug {
-    /// GET {uri}/v2/_catalog
-    async fn catalog(&self, uri: &Url) -> Result<Response>;
-    /// GET {uri}/v2/{repository}/tags/list
-    async fn get_tags(&self, uri: &Url, repository: &str) -> Result<Response>;
+pub trait RegistryClientImpl: Send + Sync + Debug {
+    /// GET {uri}/v2/
+    async fn ping(&self, uri: &Url) -> Result<Response>;
+    /// GET {uri}/v2/_catalog, not retried past the deadline
+    async fn catalog(&self, uri: &Url, deadline: Option<Instant>) -> Result<Response>;
+    /// GET {uri}/v2/{repository}/tags/list, not retried past the deadline
+    async fn get_tags(
+        &self,
+        uri: &Url,
+        repository: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response>;
+    /// GET {uri}{next} where next is the link to the next page of a list, not retried past the
+    /// deadline
+    async fn list_next(&self, uri: &Url, next: &str, deadline: Option<Instant>)
+    -> Result<Response>;
     /// HEAD {uri}/v2/{repository}/blobs/{digest}
     async fn head_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response>;
     /// GET {uri}/v2/{repository}/blobs/{digest}
-    async fn get_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response>;
+    async fn get_blob(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        range: Option<&str>,
+    ) -> Result<Response>;
     /// DELETE {uri}/v2/{repository}/blobs/{digest}
     async fn del_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response>;
     /// POST {url}/v2/{repository}/blobs/uploads/
@@ -34,21 +66,23 @@ pub(crate) trait RegistryClientImpl: Send + Sync + Debug {
     ) -> Result<Response>;
     /// POST {url}/v2/{repository}/blobs/uploads/ START chunked upload
     async fn start_upload(&self, uri: &Url, repository: &str) -> Result<Response>;
-    /// PATCH {url}/v2/{upload_url}
+    /// GET {upload_url}
+    async fn upload_status(&self, uri: &Url, upload: &str) -> Result<Response>;
+    /// PATCH {upload_url}
     async fn upload_part(
         &self,
         uri: &Url,
         upload: &str,
-        data: Bytes,
+        body: Body,
         start: usize,
         end: usize,
     ) -> Result<Response>;
-    /// PUT {url}/v2/{upload_url}
+    /// PUT {upload_url}?digest={digest}
     async fn finish_blob_upload(
         &self,
         uri: &Url,
         upload: &str,
-        data: Bytes,
+        body: Body,
         digest: &str,
         start: usize,
         end: usize,
@@ -56,18 +90,161 @@ pub(crate) trait RegistryClientImpl: Send + Sync + Debug {
     /// HEAD {uri}/v2/{repository}/manifests/{reference}
     async fn head_manifest(&self, uri: &Url, repository: &str, reference: &str)
     -> Result<Response>;
-    /// GET {uri}/v2/{repository}/manifests/{reference}
-    async fn get_manifest(&self, uri: &Url, repository: &str, reference: &str) -> Result<Response>;
-    /// PUT {uri}/v2/{repository}/manifests/{reference}
+    /// GET {uri}/v2/{repository}/manifests/{reference}, conditional on the etag when provided
+    async fn get_manifest(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+        etag: Option<&str>,
+    ) -> Result<Response>;
+    /// PUT {uri}/v2/{repository}/manifests/{reference}, with the media type of the manifest as
+    /// its content type
     async fn put_manifest(
         &self,
         uri: &Url,
         repository: &str,
         reference: &str,
+        media_type: &str,
         body: Bytes,
     ) -> Result<Response>;
     /// DELETE {uri}/v2/{repository}/manifests/{reference}
     async fn del_manifest(&self, uri: &Url, repository: &str, reference: &str) -> Result<Response>;
+    /// GET {uri}/v2/{repository}/referrers/{digest}, optionally filtered by artifact type
+    async fn get_referrers(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Response>;
+}
+
+/// Build the HTTP client used to connect to the registry with its TLS and proxy options
+pub(crate) fn http_client(uri: &RegistryUri, options: &RegistryOptions) -> Result<reqwest::Client> {
+    #[allow(unused_mut)]
+    let mut builder = options.client.apply(reqwest::Client::builder());
+    builder = options.apply_proxy(options.tls.apply(builder)?)?;
+    if let Some(socket) = uri.socket() {
+        cfg_if! {
+            if #[cfg(unix)] {
+                builder = builder.unix_socket(socket.clone());
+            } else {
+                return error::MalformedUriSnafu {
+                    reason: format!("unix socket '{}' is not supported on this platform", socket.display()),
+                }
+                .fail();
+            }
+        }
+    }
+    builder.build().context(error::RequestSnafu)
+}
+
+/// Media type of an OCI image index, which the referrers api responds with.
+const IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+/// Manifest media types requested when fetching manifests, without them registries may convert
+/// the manifest to a legacy format with a different digest.
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// Bearer challenge from a `WWW-Authenticate` header of a 401 response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Challenge {
+    /// Url of the token service
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+impl Challenge {
+    /// Parse a `Bearer realm="...",service="...",scope="..."` challenge, other schemes are ignored
+    pub(crate) fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let (mut realm, mut service, mut scope) = (None, None, None);
+        let mut rest = params.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest.split_once('=')?;
+            let value = value.trim_start();
+            // Values may be quoted and contain commas, i.e. a scope of "repository:x:pull,push"
+            let (value, remaining) = match value.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"')?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => value.split_once(',').map_or((value, ""), |(x, y)| (x, y)),
+            };
+            match key.trim().to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value.to_string()),
+                "service" => service = Some(value.to_string()),
+                "scope" => scope = Some(value.to_string()),
+                _ => {}
+            }
+            rest = remaining.trim_start_matches([',', ' ']);
+        }
+        Some(Self {
+            realm: realm?,
+            service,
+            scope,
+        })
+    }
+}
+
+/// Url of an upload session from the `Location` the registry returned for it. Locations are
+/// opaque: an absolute url is used as is, a relative one resolves against the registry, and the
+/// query is kept as registries may track the session in it.
+pub fn upload_url(uri: &Url, upload: &str) -> Result<Url> {
+    uri.join(upload).context(error::UrlSnafu)
+}
+
+/// Repository a registry api url addresses, used to key tokens obtained from challenges. Urls
+/// outside of a repository such as the catalog share the empty key.
+fn repository_of(url: &Url) -> String {
+    let path = url.path().strip_prefix("/v2/").unwrap_or_default();
+    ["/manifests/", "/blobs/", "/tags/", "/referrers/"]
+        .iter()
+        .filter_map(|x| path.find(x))
+        .min()
+        .map(|x| path[..x].to_string())
+        .unwrap_or_default()
+}
+
+/// Whether the request may be sent again after a transient failure. Chunks of an upload session
+/// are not, the layer writer resumes those from the offset the registry committed instead.
+fn retryable(request: &Request) -> bool {
+    !(matches!(*request.method(), Method::PATCH | Method::PUT)
+        && request.url().path().contains("/blobs/uploads/"))
+}
+
+/// Whether the status reports a throttled request or a transient failure of the registry
+pub(crate) fn transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::REQUEST_TIMEOUT
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Parse the Retry-After header as either a number of seconds or an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
 /// Implements a simple registry client using reqwest
@@ -75,13 +252,36 @@ pub(crate) trait RegistryClientImpl: Send + Sync + Debug {
 pub struct SimpleRegistryClient {
     client: reqwest::Client,
     auth: Option<Token>,
+    /// Tokens obtained by answering bearer challenges, keyed by repository
+    tokens: Mutex<HashMap<String, String>>,
+    /// Policy for retrying throttled and transiently failed requests
+    retry: RetryPolicy,
+    /// Signs every request with AWS SigV4 instead of using a token
+    #[cfg(feature = "aws")]
+    signer: Option<SigV4Signer>,
 }
 
 impl SimpleRegistryClient {
-    pub fn new(auth: Option<Token>) -> Self {
+    pub fn new(client: reqwest::Client, auth: Option<Token>, retry: RetryPolicy) -> Self {
         Self {
-            client: reqwest::Client::new(),
+            client,
             auth,
+            tokens: Mutex::default(),
+            retry,
+            #[cfg(feature = "aws")]
+            signer: None,
+        }
+    }
+
+    /// Create a client that signs every request with AWS SigV4
+    #[cfg(feature = "aws")]
+    pub fn sigv4(client: reqwest::Client, signer: SigV4Signer, retry: RetryPolicy) -> Self {
+        Self {
+            client,
+            auth: None,
+            tokens: Mutex::default(),
+            retry,
+            signer: Some(signer),
         }
     }
 
@@ -95,15 +295,195 @@ impl SimpleRegistryClient {
             request
         }
     }
+
+    /// Authorize and send the request, retrying it according to the retry policy when the
+    /// registry throttles it or fails transiently
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        self.send_until(request, None).await
+    }
+
+    /// Send the request like [`Self::send`], but return the last response instead of retrying
+    /// when waiting for the retry would exceed the deadline
+    async fn send_until(
+        &self,
+        request: RequestBuilder,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        let mut request = request.build().context(error::RequestSnafu)?;
+        let mut attempt = 0;
+        loop {
+            // Requests with a streamed body can't be sent again
+            let next = request.try_clone().filter(retryable);
+            let result = self.attempt(request).await;
+            let Some(next) = next.filter(|_| attempt < self.retry.max_attempts) else {
+                return result;
+            };
+            let wait = match result.as_ref() {
+                Ok(response) if transient(response.status()) => retry_after(response)
+                    .unwrap_or_else(|| self.retry.backoff(attempt))
+                    .min(self.retry.max_backoff()),
+                Err(error::Error::Request { source })
+                    if source.is_connect() || source.is_timeout() =>
+                {
+                    self.retry.backoff(attempt)
+                }
+                _ => return result,
+            };
+            if deadline.is_some_and(|x| Instant::now() + wait > x) {
+                debug!(target: "registry", "{} {} failed transiently and waiting {}ms would exceed the deadline", next.method(), next.url(), wait.as_millis());
+                return result;
+            }
+            attempt += 1;
+            debug!(target: "registry", "{} {} failed transiently, retrying in {}ms (attempt {attempt})", next.method(), next.url(), wait.as_millis());
+            tokio::time::sleep(wait).await;
+            request = next;
+        }
+    }
+
+    /// Authorize and send the request once, answering a bearer challenge if the registry
+    /// responds with one
+    async fn attempt(&self, request: Request) -> Result<Response> {
+        #[cfg(feature = "aws")]
+        if let Some(signer) = self.signer.as_ref() {
+            let request = RequestBuilder::from_parts(self.client.clone(), request);
+            let mut request = self.auth(request).build().context(error::RequestSnafu)?;
+            signer.sign(&mut request).await?;
+            return self
+                .client
+                .execute(request)
+                .await
+                .context(error::RequestSnafu);
+        }
+        // Requests with a streamed body can't be retried after a challenge
+        let retry = request
+            .try_clone()
+            .map(|x| RequestBuilder::from_parts(self.client.clone(), x));
+        let repository = repository_of(request.url());
+        let cached = self.tokens.lock().unwrap().get(&repository).cloned();
+        let request = RequestBuilder::from_parts(self.client.clone(), request);
+        // A token from an earlier challenge takes the place of the configured authorization
+        let request = match cached {
+            Some(token) => request.bearer_auth(token),
+            None => self.auth(request),
+        };
+        let response = request.send().await.context(error::RequestSnafu)?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let Some(challenge) = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(Challenge::parse)
+        else {
+            return Ok(response);
+        };
+        let Some(retry) = retry else {
+            return Ok(response);
+        };
+        debug!(target: "registry", "answering bearer challenge from {} for scope {:?}", challenge.realm, challenge.scope);
+        let token = self.token(&challenge).await?;
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(repository, token.clone());
+        retry
+            .bearer_auth(token)
+            .send()
+            .await
+            .context(error::RequestSnafu)
+    }
+
+    /// Fetch a token scoped by the challenge from its realm. Configured credentials are
+    /// exchanged for the token, otherwise an anonymous token is requested.
+    async fn token(&self, challenge: &Challenge) -> Result<String> {
+        let mut realm = Url::parse(&challenge.realm).context(error::UrlSnafu)?;
+        {
+            let mut query = realm.query_pairs_mut();
+            if let Some(service) = challenge.service.as_ref() {
+                query.append_pair("service", service);
+            }
+            if let Some(scope) = challenge.scope.as_ref() {
+                query.append_pair("scope", scope);
+            }
+        }
+        let request = match self.auth.as_ref() {
+            Some(Token::Basic { username, password }) => self
+                .client
+                .get(realm.clone())
+                .basic_auth(username, Some(password)),
+            // Identity tokens are refresh tokens for the oauth2 flow of the token service
+            Some(Token::Bearer(identity)) => {
+                let mut form = url::form_urlencoded::Serializer::new(String::new());
+                form.append_pair("grant_type", "refresh_token")
+                    .append_pair("refresh_token", identity)
+                    .append_pair("client_id", "ocilot");
+                if let Some(service) = challenge.service.as_ref() {
+                    form.append_pair("service", service);
+                }
+                if let Some(scope) = challenge.scope.as_ref() {
+                    form.append_pair("scope", scope);
+                }
+                let mut endpoint = realm.clone();
+                endpoint.set_query(None);
+                self.client
+                    .post(endpoint)
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(form.finish())
+            }
+            None => self.client.get(realm.clone()),
+        };
+        let mut response = request.send().await.context(error::RequestSnafu)?;
+        // Only pulls fall back, otherwise rejected credentials would look valid on public registries
+        let pull = challenge
+            .scope
+            .as_deref()
+            .is_some_and(|x| x.ends_with(":pull"));
+        if self.auth.is_some() && pull && response.status() == StatusCode::UNAUTHORIZED {
+            // Public repositories can still be pulled anonymously when the credentials are stale
+            debug!(target: "registry", "credentials rejected by {}, requesting an anonymous token", challenge.realm);
+            response = self
+                .client
+                .get(realm)
+                .send()
+                .await
+                .context(error::RequestSnafu)?;
+        }
+        ensure!(
+            response.status().is_success(),
+            error::AuthorizationSnafu {
+                reason: format!(
+                    "token service {} responded {}",
+                    challenge.realm,
+                    response.status()
+                )
+            }
+        );
+        let token: TokenResponse = response
+            .json()
+            .await
+            .context(error::ResponseDeserializeSnafu)?;
+        token
+            .token
+            .or(token.access_token)
+            .context(error::AuthorizationSnafu {
+                reason: format!("token service {} returned no token", challenge.realm),
+            })
+    }
 }
 
 #[async_trait]
 impl RegistryClientImpl for SimpleRegistryClient {
-    async fn catalog(&self, uri: &Url) -> Result<Response> {
+    async fn ping(&self, uri: &Url) -> Result<Response> {
+        let request = self.client.get(uri.join("/v2/").context(error::UrlSnafu)?);
+        self.send(request).await
+    }
+
+    async fn catalog(&self, uri: &Url, deadline: Option<Instant>) -> Result<Response> {
         let request = self
             .client
             .get(uri.join("/v2/_catalog").context(error::UrlSnafu)?);
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send_until(request, deadline).await
     }
 
     async fn head_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response> {
@@ -111,15 +491,24 @@ impl RegistryClientImpl for SimpleRegistryClient {
             uri.join(&format!("/v2/{}/blobs/{}", repository, digest))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send(request).await
     }
 
-    async fn get_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response> {
-        let request = self.client.get(
+    async fn get_blob(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        range: Option<&str>,
+    ) -> Result<Response> {
+        let mut request = self.client.get(
             uri.join(&format!("/v2/{}/blobs/{}", repository, digest))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        self.send(request).await
     }
 
     async fn del_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response> {
@@ -127,15 +516,30 @@ impl RegistryClientImpl for SimpleRegistryClient {
             uri.join(&format!("/v2/{}/blobs/{}", repository, digest))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send(request).await
     }
 
-    async fn get_tags(&self, uri: &Url, repository: &str) -> Result<Response> {
+    async fn get_tags(
+        &self,
+        uri: &Url,
+        repository: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
         let request = self.client.get(
             uri.join(&format!("/v2/{}/tags/list", repository))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send_until(request, deadline).await
+    }
+
+    async fn list_next(
+        &self,
+        uri: &Url,
+        next: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        let request = self.client.get(uri.join(next).context(error::UrlSnafu)?);
+        self.send_until(request, deadline).await
     }
 
     async fn post_blob(
@@ -150,13 +554,13 @@ impl RegistryClientImpl for SimpleRegistryClient {
             .context(error::UrlSnafu)?;
         uri.set_query(Some(format!("digest={digest}").as_str()));
         let request = self.client.post(uri);
-        self.auth(request)
-            .header("Content-Type", "application/octet-stream")
-            .header("Content-Length", data.len())
-            .body(data)
-            .send()
-            .await
-            .context(error::RequestSnafu)
+        self.send(
+            request
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Length", data.len())
+                .body(data),
+        )
+        .await
     }
 
     async fn start_upload(&self, uri: &Url, repository: &str) -> Result<Response> {
@@ -164,57 +568,58 @@ impl RegistryClientImpl for SimpleRegistryClient {
             uri.join(&format!("/v2/{}/blobs/uploads/", repository))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request)
-            .header("Content-Length", 0)
-            .send()
-            .await
-            .context(error::RequestSnafu)
+        self.send(request.header("Content-Length", 0)).await
+    }
+
+    async fn upload_status(&self, uri: &Url, upload: &str) -> Result<Response> {
+        let request = self.client.get(upload_url(uri, upload)?);
+        self.send(request).await
     }
 
     async fn upload_part(
         &self,
         uri: &Url,
         upload: &str,
-        data: Bytes,
+        body: Body,
         start: usize,
         end: usize,
     ) -> Result<Response> {
-        let request = self.client.patch(
-            uri.join(&format!("/v2/{}/blobs/uploads/{}", upload, upload))
-                .context(error::UrlSnafu)?,
-        );
-        self.auth(request)
-            .header("Content-Type", "application/octet-stream")
-            .header("Content-Length", data.len())
-            .header("Content-Range", format!("{}-{}", start, end))
-            .body(data)
-            .send()
-            .await
-            .context(error::RequestSnafu)
+        let request = self.client.patch(upload_url(uri, upload)?);
+        self.send(
+            request
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-Length", end - start)
+                .header("Content-Range", format!("{}-{}", start, end))
+                .body(body),
+        )
+        .await
     }
 
     async fn finish_blob_upload(
         &self,
         uri: &Url,
         upload: &str,
-        data: Bytes,
+        body: Body,
         digest: &str,
         start: usize,
         end: usize,
     ) -> Result<Response> {
-        let mut uri = uri
-            .join(&format!("/v2/{}/blobs/uploads/{}", upload, upload))
-            .context(error::UrlSnafu)?;
-        uri.set_query(Some(format!("digest={digest}").as_str()));
-        let request = self.client.put(uri);
-        self.auth(request)
+        let mut uri = upload_url(uri, upload)?;
+        // Registries may keep the state of the session in the query, the digest is added to it
+        let query = match uri.query().filter(|x| !x.is_empty()) {
+            Some(query) => format!("{query}&digest={digest}"),
+            None => format!("digest={digest}"),
+        };
+        uri.set_query(Some(query.as_str()));
+        let mut request = self
+            .client
+            .put(uri)
             .header("Content-Type", "application/octet-stream")
-            .header("Content-Length", data.len())
-            .header("Content-Range", format!("{}-{}", start, end))
-            .body(data)
-            .send()
-            .await
-            .context(error::RequestSnafu)
+            .header("Content-Length", end - start);
+        if end > start {
+            request = request.header("Content-Range", format!("{}-{}", start, end));
+        }
+        self.send(request.body(body)).await
     }
 
     async fn head_manifest(
@@ -227,15 +632,27 @@ impl RegistryClientImpl for SimpleRegistryClient {
             uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send(request.header(ACCEPT, MANIFEST_ACCEPT)).await
     }
 
-    async fn get_manifest(&self, uri: &Url, repository: &str, reference: &str) -> Result<Response> {
-        let request = self.client.get(
-            uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
-                .context(error::UrlSnafu)?,
-        );
-        self.auth(request).send().await.context(error::RequestSnafu)
+    async fn get_manifest(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+        etag: Option<&str>,
+    ) -> Result<Response> {
+        let mut request = self
+            .client
+            .get(
+                uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
+                    .context(error::UrlSnafu)?,
+            )
+            .header(ACCEPT, MANIFEST_ACCEPT);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        self.send(request).await
     }
 
     async fn put_manifest(
@@ -243,17 +660,17 @@ impl RegistryClientImpl for SimpleRegistryClient {
         uri: &Url,
         repository: &str,
         reference: &str,
+        media_type: &str,
         body: Bytes,
     ) -> Result<Response> {
-        let request = self.client.put(
-            uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
-                .context(error::UrlSnafu)?,
-        );
-        self.auth(request)
-            .body(body)
-            .send()
-            .await
-            .context(error::RequestSnafu)
+        let request = self
+            .client
+            .put(
+                uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
+                    .context(error::UrlSnafu)?,
+            )
+            .header(CONTENT_TYPE, media_type);
+        self.send(request.body(body)).await
     }
 
     async fn del_manifest(&self, uri: &Url, repository: &str, reference: &str) -> Result<Response> {
@@ -261,7 +678,25 @@ impl RegistryClientImpl for SimpleRegistryClient {
             uri.join(&format!("/v2/{}/manifests/{}", repository, reference))
                 .context(error::UrlSnafu)?,
         );
-        self.auth(request).send().await.context(error::RequestSnafu)
+        self.send(request).await
+    }
+
+    async fn get_referrers(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Response> {
+        let mut uri = uri
+            .join(&format!("/v2/{}/referrers/{}", repository, digest))
+            .context(error::UrlSnafu)?;
+        if let Some(artifact_type) = artifact_type {
+            uri.query_pairs_mut()
+                .append_pair("artifactType", artifact_type);
+        }
+        let request = self.client.get(uri);
+        self.send(request.header(ACCEPT, IMAGE_INDEX)).await
     }
 }
 
@@ -275,14 +710,27 @@ pub struct RegistryClient {
 }
 
 impl RegistryClient {
-    pub fn new(auth: Option<Token>) -> Self {
-        Self {
-            client: Arc::new(SimpleRegistryClient::new(auth)),
-        }
+    pub fn new(client: reqwest::Client, auth: Option<Token>, retry: RetryPolicy) -> Self {
+        Self::from_impl(Arc::new(SimpleRegistryClient::new(client, auth, retry)))
+    }
+
+    /// Create a client that signs every request with AWS SigV4
+    #[cfg(feature = "aws")]
+    pub fn sigv4(client: reqwest::Client, signer: SigV4Signer, retry: RetryPolicy) -> Self {
+        Self::from_impl(Arc::new(SimpleRegistryClient::sigv4(client, signer, retry)))
     }
 
-    pub async fn catalog(&self, uri: Url) -> Result<Response> {
-        self.client.catalog(&uri).await
+    /// Create a client sending requests through a custom implementation
+    pub fn from_impl(client: Arc<dyn RegistryClientImpl>) -> Self {
+        Self { client }
+    }
+
+    pub async fn ping(&self, uri: Url) -> Result<Response> {
+        self.client.ping(&uri).await
+    }
+
+    pub async fn catalog(&self, uri: Url, deadline: Option<Instant>) -> Result<Response> {
+        self.client.catalog(&uri, deadline).await
     }
 
     pub async fn head_blob(
@@ -296,9 +744,15 @@ impl RegistryClient {
             .await
     }
 
-    pub async fn get_blob(&self, uri: Url, repository: String, digest: String) -> Result<Response> {
+    pub async fn get_blob(
+        &self,
+        uri: Url,
+        repository: String,
+        digest: String,
+        range: Option<String>,
+    ) -> Result<Response> {
         self.client
-            .get_blob(&uri, repository.as_str(), digest.as_str())
+            .get_blob(&uri, repository.as_str(), digest.as_str(), range.as_deref())
             .await
     }
 
@@ -308,8 +762,22 @@ impl RegistryClient {
             .await
     }
 
-    pub async fn get_tags(&self, uri: &Url, repository: &str) -> Result<Response> {
-        self.client.get_tags(uri, repository).await
+    pub async fn get_tags(
+        &self,
+        uri: &Url,
+        repository: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        self.client.get_tags(uri, repository, deadline).await
+    }
+
+    pub async fn list_next(
+        &self,
+        uri: &Url,
+        next: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        self.client.list_next(uri, next, deadline).await
     }
 
     pub async fn post_blob(
@@ -332,17 +800,21 @@ impl RegistryClient {
             .await
     }
 
+    pub async fn upload_status(&self, uri: Url, upload: String) -> Result<Response> {
+        self.client.upload_status(&uri, upload.as_str()).await
+    }
+
     pub async fn upload_part(
         self,
         uri: Url,
         upload: String,
-        data: Bytes,
+        body: Body,
         start: usize,
         end: usize,
     ) -> Result<Response> {
         self.client
             .as_ref()
-            .upload_part(&uri, upload.as_str(), data, start, end)
+            .upload_part(&uri, upload.as_str(), body, start, end)
             .await
     }
 
@@ -350,14 +822,14 @@ impl RegistryClient {
         self,
         uri: Url,
         upload: String,
-        data: Bytes,
+        body: Body,
         digest: String,
         start: usize,
         end: usize,
     ) -> Result<Response> {
         self.client
             .as_ref()
-            .finish_blob_upload(&uri, upload.as_str(), data, digest.as_str(), start, end)
+            .finish_blob_upload(&uri, upload.as_str(), body, digest.as_str(), start, end)
             .await
     }
 
@@ -377,9 +849,15 @@ impl RegistryClient {
         uri: Url,
         repository: String,
         reference: String,
+        etag: Option<String>,
     ) -> Result<Response> {
         self.client
-            .get_manifest(&uri, repository.as_str(), reference.as_str())
+            .get_manifest(
+                &uri,
+                repository.as_str(),
+                reference.as_str(),
+                etag.as_deref(),
+            )
             .await
     }
 
@@ -388,10 +866,17 @@ impl RegistryClient {
         uri: Url,
         repository: String,
         reference: String,
+        media_type: String,
         body: Bytes,
     ) -> Result<Response> {
         self.client
-            .put_manifest(&uri, repository.as_str(), reference.as_str(), body)
+            .put_manifest(
+                &uri,
+                repository.as_str(),
+                reference.as_str(),
+                media_type.as_str(),
+                body,
+            )
             .await
     }
 
@@ -405,7 +890,127 @@ impl RegistryClient {
             .del_manifest(&uri, repository.as_str(), reference.as_str())
             .await
     }
+
+    pub async fn get_referrers(
+        &self,
+        uri: Url,
+        repository: String,
+        digest: String,
+        artifact_type: Option<String>,
+    ) -> Result<Response> {
+        self.client
+            .get_referrers(
+                &uri,
+                repository.as_str(),
+                digest.as_str(),
+                artifact_type.as_deref(),
+            )
+            .await
+    }
 }
 
-unsafe impl Send for RegistryClient {}
-unsafe impl Sync for RegistryClient {}
+#[cfg(test)]
+mod test {
+    use super::{Challenge, RegistryClient, repository_of, upload_url};
+    use crate::layer::Reader;
+    use crate::options::RetryPolicy;
+    use crate::registry::Registry;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+    use url::Url;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        // Registries and readers are moved into spawned tasks, this fails to compile when any of
+        // their fields stops being thread safe
+        assert_send_sync::<RegistryClient>();
+        assert_send_sync::<Registry>();
+        assert_send_sync::<Reader>();
+        #[cfg(feature = "compression")]
+        assert_send_sync::<crate::compression::Decompress>();
+    }
+
+    #[test]
+    fn test_challenge_parse() {
+        let challenge = Challenge::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "https://auth.docker.io/token");
+        assert_eq!(challenge.service.as_deref(), Some("registry.docker.io"));
+        assert_eq!(
+            challenge.scope.as_deref(),
+            Some("repository:library/alpine:pull,push")
+        );
+        let challenge = Challenge::parse(r#"bearer realm="https://ghcr.io/token""#).unwrap();
+        assert_eq!(challenge.realm, "https://ghcr.io/token");
+        assert!(challenge.service.is_none());
+        assert!(Challenge::parse(r#"Basic realm="registry""#).is_none());
+        assert!(Challenge::parse(r#"Bearer service="registry""#).is_none());
+    }
+
+    #[test]
+    fn test_upload_url() {
+        let registry = Url::parse("https://registry.io").unwrap();
+        assert_eq!(
+            upload_url(&registry, "/v2/org/app/blobs/uploads/abc?_state=xyz")
+                .unwrap()
+                .as_str(),
+            "https://registry.io/v2/org/app/blobs/uploads/abc?_state=xyz"
+        );
+        assert_eq!(
+            upload_url(&registry, "https://uploads.registry.io/upload/abc?sig=1")
+                .unwrap()
+                .as_str(),
+            "https://uploads.registry.io/upload/abc?sig=1"
+        );
+    }
+
+    #[test]
+    fn test_repository_of() {
+        let url = Url::parse("https://ghcr.io/v2/org/app/manifests/latest").unwrap();
+        assert_eq!(repository_of(&url), "org/app");
+        let url = Url::parse("https://ghcr.io/v2/org/blobs/uploads/").unwrap();
+        assert_eq!(repository_of(&url), "org");
+        let url = Url::parse("https://ghcr.io/v2/_catalog").unwrap();
+        assert_eq!(repository_of(&url), "");
+    }
+
+    #[tokio::test]
+    async fn test_retry_deadline() {
+        // A registry that always throttles, asking to wait longer than the deadline
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer).await;
+                served.fetch_add(1, Ordering::SeqCst);
+                let _ = stream
+                    .write_all(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 5\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+        let client = RegistryClient::new(reqwest::Client::new(), None, RetryPolicy::default());
+        let start = Instant::now();
+        let response = client
+            .list_next(
+                &url,
+                "/v2/app/tags/list",
+                Some(start + Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
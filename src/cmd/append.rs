@@ -0,0 +1,49 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
+use std::path::PathBuf;
+
+use super::context::{Ctx, LayerCompression, tag_target};
+
+/// Append local layer tarballs to an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Append local layer tarballs to an image and push the result", long_about = None)]
+pub struct Append {
+    /// Reference to the image to extend, i.e. reg.example/app:1.2.3
+    image: String,
+    /// Layer tarballs to append in order, gzip or zstd compressed or uncompressed
+    #[arg(required = true)]
+    layers: Vec<PathBuf>,
+    /// Compression of uncompressed layer tarballs
+    #[arg(short, long, default_value = "gzip")]
+    compression: LayerCompression,
+    /// Tag to push the new image to in the same repository, by default the tag of the image
+    #[arg(short, long)]
+    tag: Option<String>,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Append {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.image.as_str(), self.insecure).await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let target = tag_target(&uri, self.tag.as_deref())?;
+        let pushed = image
+            .append_layers(&target, &self.layers, (&self.compression).into())
+            .await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser};
+use ocilot::artifact::{Attestation, CREATED_ANNOTATION, PREDICATE_TYPE_ANNOTATION};
+use ocilot::error;
+use snafu::ResultExt;
+
+use super::context::Ctx;
+
+/// Attach in-toto attestations to images and list them.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Attach an in-toto attestation to an image, or list the attestations of an image",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Attest {
+    #[clap(subcommand)]
+    command: Option<AttestCommands>,
+    #[clap(flatten)]
+    attach: Option<AttachAttestation>,
+}
+
+/// Attestation subcommands.
+#[derive(Parser, Debug)]
+pub enum AttestCommands {
+    List(ListAttestations),
+}
+
+impl Attest {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        match (&self.command, &self.attach) {
+            (Some(AttestCommands::List(cmd)), _) => cmd.run(ctx).await,
+            (None, Some(cmd)) => cmd.run(ctx).await,
+            // Clap requires the attach arguments when no subcommand is given
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Attach an in-toto attestation to an image.
+#[derive(Args, Debug)]
+pub struct AttachAttestation {
+    url: String,
+    /// In-toto statement, or a DSSE envelope of one, i.e. build provenance
+    statement: PathBuf,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl AttachAttestation {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let content = tokio::fs::read(&self.statement)
+            .await
+            .context(error::FileSnafu)?;
+        let attestation = Attestation::new(content)?;
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
+        let artifact = attestation.attach(&uri).await?;
+        ctx.pushed(&uri, &artifact)
+    }
+}
+
+/// List the in-toto attestations of an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "List the in-toto attestations attached to an image, oldest first", long_about = None)]
+pub struct ListAttestations {
+    url: String,
+    /// Only list attestations with this predicate type, i.e. https://slsa.dev/provenance/v1
+    #[arg(short, long)]
+    predicate_type: Option<String>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl ListAttestations {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, false)
+            .await?;
+        let attestations = Attestation::list(&uri)
+            .await?
+            .into_iter()
+            .filter(|x| {
+                self.predicate_type.as_ref().is_none_or(|predicate_type| {
+                    x.annotations().get(PREDICATE_TYPE_ANNOTATION) == Some(predicate_type)
+                })
+            })
+            .collect::<Vec<_>>();
+        let text = attestations
+            .iter()
+            .map(|x| {
+                let annotation = |key| x.annotations().get(key).map_or("-", String::as_str);
+                format!(
+                    "{} {} {}",
+                    x.digest(),
+                    annotation(PREDICATE_TYPE_ANNOTATION),
+                    annotation(CREATED_ANNOTATION)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        ctx.print(&attestations, text)
+    }
+}
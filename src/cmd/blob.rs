@@ -1,33 +1,75 @@
 use std::path::PathBuf;
 
-use clap::Parser;
-use snafu::ResultExt;
+use clap::{Args, Parser};
+use snafu::{OptionExt, ResultExt, ensure};
 use tokio::fs::File;
 
 use ocilot::error;
 use ocilot::layer::Layer;
-use ocilot::uri::Uri;
+use ocilot::uri::Reference;
 
 use super::context::Ctx;
 
+/// Read blobs from the registry or check them without downloading them.
 #[derive(Parser, Debug)]
-#[command(version, about = "Read a blob from the registry", long_about = None)]
+#[command(
+    version,
+    about = "Read a blob from the registry",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Blob {
+    #[clap(subcommand)]
+    command: Option<BlobCommands>,
+    #[clap(flatten)]
+    read: Option<ReadBlob>,
+}
+
+/// Blob subcommands.
+#[derive(Parser, Debug)]
+pub enum BlobCommands {
+    Stat(StatBlob),
+}
+
+impl Blob {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        match (&self.command, &self.read) {
+            (Some(BlobCommands::Stat(cmd)), _) => cmd.run(ctx).await,
+            (None, Some(cmd)) => cmd.run(ctx).await,
+            // Clap requires the read arguments when no subcommand is given
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Read a blob from the registry.
+#[derive(Args, Debug)]
+pub struct ReadBlob {
     url: String,
-    #[arg(short, long)]
-    output: Option<PathBuf>,
+    /// Write the blob to this file instead of stdout (formerly `--output`, now the global format
+    /// option)
+    #[arg(short = 'o', long)]
+    file: Option<PathBuf>,
+    /// Only read these bytes of the blob, as START-END including the end byte or START- to read
+    /// to the end of the blob
+    #[arg(short, long, value_parser = parse_range)]
+    range: Option<(u64, Option<u64>)>,
     #[arg(short, long)]
     insecure: bool,
 }
 
-impl Blob {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
+impl ReadBlob {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let mut uri = ctx.uri(self.url.as_str()).await?;
         uri.set_secure(!self.insecure);
 
-        let mut reader = Layer::open_uri(&uri).await?;
-        if let Some(output) = self.output.as_ref() {
-            let mut file = File::create(output).await.context(error::FileSnafu)?;
+        let mut reader = match self.range {
+            Some((start, end)) => Layer::open_range(&uri, start, end).await?,
+            None => Layer::open_uri(&uri).await?,
+        };
+        if let Some(path) = self.file.as_ref() {
+            let mut file = File::create(path).await.context(error::FileSnafu)?;
             tokio::io::copy(&mut reader, &mut file)
                 .await
                 .context(error::LayerCopySnafu)?;
@@ -40,3 +82,56 @@ impl Blob {
         Ok(())
     }
 }
+
+/// Print the digest and size of a blob without downloading it.
+#[derive(Parser, Debug)]
+#[command(version, about = "Print the digest and size of a blob without downloading it, failing when the registry does not have it", long_about = None)]
+pub struct StatBlob {
+    /// Blob to check, i.e. reg.example/app@sha256:...
+    url: String,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl StatBlob {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let mut uri = ctx.uri(self.url.as_str()).await?;
+        uri.set_secure(!self.insecure);
+        ensure!(
+            matches!(uri.reference(), Reference::Digest { .. }),
+            error::DirectLoadBlobSnafu {
+                uri: uri.to_string()
+            }
+        );
+        let stat = uri
+            .registry()
+            .stat_blob(uri.repository(), uri.reference().to_string().as_str())
+            .await?
+            .context(error::BlobNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let text = format!("{} {}", stat.digest, stat.size);
+        ctx.print(&stat, text)
+    }
+}
+
+/// Parse a `START-END` byte range with an inclusive end, or `START-` for the rest of the blob,
+/// into a start and an exclusive end.
+fn parse_range(s: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END or START-, got '{s}'"))?;
+    let start = start
+        .parse::<u64>()
+        .map_err(|e| format!("invalid range start '{start}': {e}"))?;
+    if end.is_empty() {
+        return Ok((start, None));
+    }
+    let end = end
+        .parse::<u64>()
+        .map_err(|e| format!("invalid range end '{end}': {e}"))?;
+    if end < start {
+        return Err(format!("range end {end} is before its start {start}"));
+    }
+    Ok((start, Some(end + 1)))
+}
@@ -0,0 +1,59 @@
+use clap::Parser;
+use ocilot::error;
+use serde_json::json;
+use snafu::OptionExt;
+use std::time::Duration;
+
+use super::context::Ctx;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Commands to manage the local blob cache", long_about = None)]
+pub struct Cache {
+    #[clap(subcommand)]
+    command: CacheCommands,
+}
+
+#[derive(Parser, Debug)]
+pub enum CacheCommands {
+    Prune(CachePrune),
+}
+
+impl Cache {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        match &self.command {
+            CacheCommands::Prune(cmd) => cmd.run(ctx).await,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Remove cached blobs to free disk space", long_about = None)]
+pub struct CachePrune {
+    /// Only remove blobs that were not used in this many days, by default everything is removed
+    #[arg(long, value_name = "DAYS")]
+    older_than: Option<u64>,
+}
+
+impl CachePrune {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let store = ctx.store().context(error::StoreUnavailableSnafu)?;
+        let pruned = store.prune(
+            self.older_than
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        )?;
+        ctx.print(
+            &json!({
+                "blobs": pruned.blobs,
+                "bytes": pruned.bytes,
+                "root": store.root(),
+            }),
+            format!(
+                "pruned {} blobs ({} bytes) from {}",
+                pruned.blobs,
+                pruned.bytes,
+                store.root().display()
+            ),
+        )?;
+        Ok(())
+    }
+}
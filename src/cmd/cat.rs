@@ -0,0 +1,50 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::{OptionExt, ResultExt};
+use tokio::io::AsyncWriteExt;
+
+use super::context::Ctx;
+
+/// Print a file of an image filesystem.
+#[derive(Parser, Debug)]
+#[command(version, about = "Print a file of an image filesystem without exporting it, i.e. /etc/os-release", long_about = None)]
+pub struct Cat {
+    url: String,
+    /// Absolute path of the file in the image filesystem
+    path: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Cat {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let content =
+            image
+                .read_file(&uri, &self.path)
+                .await?
+                .context(error::FileNotFoundSnafu {
+                    path: self.path.as_str(),
+                })?;
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(&content).await.context(error::FileSnafu)?;
+        stdout.flush().await.context(error::FileSnafu)
+    }
+}
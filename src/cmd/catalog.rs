@@ -1,10 +1,11 @@
 use std::str::FromStr;
+use std::time::Duration;
 
 use clap::Parser;
 
 use ocilot::error;
-use ocilot::registry::Registry;
 use ocilot::uri::RegistryUri;
+use serde_json::json;
 
 use super::context::Ctx;
 
@@ -15,17 +16,41 @@ pub struct Catalog {
     url: String,
     #[arg(short, long)]
     insecure: bool,
+    /// Give up after this many seconds, including time spent waiting on registry rate limits
+    #[arg(long)]
+    deadline: Option<u64>,
+    /// Only fetch a single page of at most this many repositories
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Fetch all repositories in pages of this many, for registries whose default page is small
+    #[arg(long, conflicts_with = "limit")]
+    page_size: Option<usize>,
+    /// Only list repositories that sort after this one
+    #[arg(long)]
+    last: Option<String>,
 }
 
 impl Catalog {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
         let mut registry_uri = RegistryUri::from_str(self.url.as_str())?;
         if self.insecure {
             registry_uri.set_secure(false);
         }
-        let registry = Registry::new(&registry_uri).await?;
-        let repos = registry.catalog().await?;
-        println!("{}", repos.join("\n"));
+        let mut registry = ctx.registry(&registry_uri).await?;
+        registry.set_list_deadline(self.deadline.map(Duration::from_secs));
+        registry.set_page_size(self.page_size);
+        let page = registry
+            .catalog_page(self.limit, self.last.as_deref())
+            .await?;
+        ctx.print(
+            &json!({ "repositories": page.entries, "more": page.more }),
+            page.entries.join("\n"),
+        )?;
+        if page.more
+            && let Some(last) = page.entries.last()
+        {
+            eprintln!("more repositories are available, continue with --last {last}");
+        }
         Ok(())
     }
 }
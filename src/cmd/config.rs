@@ -1,34 +1,38 @@
+use super::context::Ctx;
 use clap::Parser;
-use snafu::{OptionExt, ResultExt};
 use ocilot::error;
-use ocilot::index::Index;
-use ocilot::uri::Uri;
-use super::context::Ctx;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Get the config of an image", long_about = None)]
 pub struct Config {
     url: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
     #[arg(short, long)]
-    platform: Option<String>,
+    platform: Option<Platform>,
     #[arg(short, long)]
     insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
 }
 
 impl Config {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
-        let index = Index::fetch(&uri).await?;
-        let image = index
-            .fetch_image(&uri, self.platform.clone().map(|x| x.into()))
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
             .await?
-            .context(error::ImageNotFoundSnafu { uri: uri.clone() })?;
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
         let config = image.fetch_config(&uri).await?;
-        println!(
-            "{}",
-            serde_json::to_string_pretty(&config).context(error::SerializeSnafu)?
-        );
+        ctx.print_document(&config)?;
         Ok(())
     }
 }
@@ -1,38 +1,558 @@
 use cfg_if::cfg_if;
-use indicatif::MultiProgress;
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use ocilot::audit::{
+    AuditCallback, AuditEvent, AuditOperation, AuditOutcome, AuditSink, JsonLinesAudit,
+};
+use ocilot::error;
+use ocilot::models::{Compression, Token};
+use ocilot::options::{ChunkPolicy, RegistryOptions, Settings};
+use ocilot::progress::{IndicatifProgress, ProgressEvent, ProgressSink};
+use ocilot::registry::Registry;
+use ocilot::repository::Repository;
+use ocilot::store::Store;
+use ocilot::uri::{Reference, RegistryUri, Uri};
+use serde::Serialize;
+use serde_json::json;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::{Global, LogFormat, OutputFormat, ProgressMode};
+
 /// Application context passed through command execution.
 pub struct Ctx {
-    multi: MultiProgress,
+    /// Per blob progress bars
+    bars: Arc<dyn ProgressSink>,
+    store: Option<Store>,
+    /// Neither serve blobs from the local store nor write pulled content to it
+    no_cache: bool,
+    /// Format of the results printed by commands
+    output: OutputFormat,
+    audit: Option<Arc<dyn AuditSink>>,
+    /// Stream of blob transfer progress driving the aggregate progress bar
+    progress: Arc<dyn ProgressSink>,
+    /// Limit on concurrent blob transfers shared by every registry of the command
+    limiter: Option<Arc<Semaphore>>,
+    /// Registry clients for reading keyed by registry base, insecure and offline
+    registries: Mutex<HashMap<(String, bool, bool), Registry>>,
+    /// Per registry options, i.e. mirrors, from the settings file
+    settings: Settings,
+    /// Size of upload chunks overriding the chunk policy of every registry
+    chunk_size: Option<usize>,
+    /// Chunks of a blob uploaded at once overriding the chunk policy of every registry
+    chunk_connections: Option<usize>,
+    /// Skip mutating operations of every registry, printing them as a plan instead
+    dry_run: bool,
 }
 
 impl Ctx {
-    pub fn init() -> ocilot::Result<Self> {
+    pub async fn init(global: &Global) -> ocilot::Result<Self> {
+        let file = match global.log_file.as_ref() {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(error::FileSnafu)?,
+            ),
+            None => None,
+        };
         cfg_if! {
             if #[cfg(feature = "progress")] {
                 let indicatif_layer = tracing_indicatif::IndicatifLayer::new();
+                let logs = match file {
+                    Some(file) => log_layer(global.log_format, global.log_spans, Mutex::new(file), false),
+                    None => log_layer(global.log_format, global.log_spans, indicatif_layer.get_stdout_writer(), true),
+                };
                 tracing_subscriber::registry()
-                    .with(tracing_subscriber::fmt::layer()
-                        .with_writer(indicatif_layer.get_stdout_writer())
-                        .with_filter(EnvFilter::from_default_env())
-                    )
+                    .with(logs)
                     .with(indicatif_layer.with_filter(EnvFilter::from_default_env()))
                     .try_init()
                     .unwrap();
             } else {
+                let logs = match file {
+                    Some(file) => log_layer(global.log_format, global.log_spans, Mutex::new(file), false),
+                    None => log_layer(global.log_format, global.log_spans, std::io::stdout, true),
+                };
                 tracing_subscriber::registry()
-                    .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+                    .with(logs)
                     .try_init()
-                    .unwrap();;
+                    .unwrap();
+            }
+        }
+        // Per blob bars are still tracked when only the aggregate is shown, they just aren't drawn
+        let (multi, aggregate) = match global.progress {
+            ProgressMode::All => {
+                let multi = MultiProgress::new();
+                (multi.clone(), multi)
+            }
+            ProgressMode::Aggregate => (
+                MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+                MultiProgress::new(),
+            ),
+        };
+        let (progress, events) = unbounded_channel::<ProgressEvent>();
+        tokio::spawn(aggregate_progress(events, aggregate));
+        let store = Store::open_default().ok();
+        let mut audit = match global.audit_log.as_ref() {
+            Some(path) => Some(Arc::new(JsonLinesAudit::open(path)?) as Arc<dyn AuditSink>),
+            None => None,
+        };
+        if global.dry_run {
+            // Planned operations are printed, and still recorded to the audit log when there is one
+            let output = global.output;
+            let log = audit.take();
+            audit = Some(Arc::new(AuditCallback::new(move |event: &AuditEvent| {
+                if let Some(log) = log.as_ref() {
+                    log.record(event);
+                }
+                print_plan(event, output);
+            })));
+        }
+        let settings = match global.config.as_ref() {
+            Some(path) => Settings::read(path).await?,
+            None => Settings::load().await?,
+        };
+        Ok(Self {
+            bars: Arc::new(IndicatifProgress::new(multi)),
+            store,
+            no_cache: global.no_cache,
+            output: global.output,
+            audit,
+            progress: Arc::new(progress),
+            limiter: global
+                .max_concurrent
+                .map(|x| Arc::new(Semaphore::new(x.max(1)))),
+            registries: Mutex::default(),
+            settings,
+            chunk_size: global.chunk_size,
+            chunk_connections: global.chunk_connections,
+            dry_run: global.dry_run,
+        })
+    }
+
+    /// Parse an object uri to read from. When offline all content is served from the local
+    /// store, otherwise pulled content is written through to it.
+    pub async fn read_uri(
+        &self,
+        input: &str,
+        insecure: bool,
+        offline: bool,
+    ) -> ocilot::Result<Uri> {
+        let (registry_uri, repository, reference) = Uri::split(input)?;
+        // Registry clients are shared between uris so authorization only happens once per registry
+        let key = (registry_uri.base().clone(), insecure, offline);
+        let cached = self.registries.lock().unwrap().get(&key).cloned();
+        let registry = match cached {
+            Some(registry) => registry,
+            None => {
+                let mut registry = if offline {
+                    Registry::offline(
+                        &registry_uri,
+                        self.store.as_ref().context(error::StoreUnavailableSnafu)?,
+                    )
+                } else {
+                    self.registry(&registry_uri).await?
+                };
+                registry.set_secure(!insecure);
+                if !offline {
+                    registry.set_store(self.cache());
+                }
+                registry.set_progress(Some(self.progress.clone()));
+                registry.set_limiter(self.limiter.clone());
+                self.registries
+                    .lock()
+                    .unwrap()
+                    .insert(key, registry.clone());
+                registry
+            }
+        };
+        Ok(Uri::builder()
+            .registry(registry)
+            .repository(repository)
+            .reference(reference)
+            .build())
+    }
+
+    /// Parse an object uri that will be written to. Mutating operations are recorded to the
+    /// audit log and the local store keeps checkpoints of upload sessions so they can be resumed.
+    pub async fn write_uri(&self, input: &str, insecure: bool) -> ocilot::Result<Uri> {
+        self.write_uri_with_creds(input, insecure, None).await
+    }
+
+    /// Parse an object uri that will be written to like [`Ctx::write_uri`], authorizing with the
+    /// credentials when provided instead of discovering them
+    pub async fn write_uri_with_creds(
+        &self,
+        input: &str,
+        insecure: bool,
+        creds: Option<&Token>,
+    ) -> ocilot::Result<Uri> {
+        let mut uri = self.uri_with_creds(input, creds).await?;
+        uri.set_secure(!insecure);
+        uri.set_store(self.store.clone());
+        uri.set_audit(self.audit.clone());
+        uri.set_progress(Some(self.progress.clone()));
+        uri.set_limiter(self.limiter.clone());
+        Ok(uri)
+    }
+
+    /// Parse a repository uri that will be written to, with the same registry settings as
+    /// [`Ctx::write_uri`]
+    pub async fn write_repository(
+        &self,
+        input: &str,
+        insecure: bool,
+    ) -> ocilot::Result<Repository> {
+        let (registry_uri, repository) = Uri::split_repository(input)?;
+        let mut registry = self.registry(&registry_uri).await?;
+        registry.set_secure(!insecure);
+        registry.set_store(self.store.clone());
+        registry.set_audit(self.audit.clone());
+        registry.set_progress(Some(self.progress.clone()));
+        registry.set_limiter(self.limiter.clone());
+        Ok(Repository::new(&registry, &repository))
+    }
+
+    /// Parse an object uri with a registry client using the options of the settings file
+    pub async fn uri(&self, input: &str) -> ocilot::Result<Uri> {
+        self.uri_with_creds(input, None).await
+    }
+
+    /// Parse an object uri like [`Ctx::uri`], authorizing with the credentials when provided
+    /// instead of discovering them
+    pub async fn uri_with_creds(&self, input: &str, creds: Option<&Token>) -> ocilot::Result<Uri> {
+        let (registry_uri, repository, reference) = Uri::split(input)?;
+        let registry = match creds {
+            Some(token) => {
+                let mut registry = Registry::with_token(
+                    &registry_uri,
+                    &self.registry_options(&registry_uri),
+                    token.clone(),
+                )
+                .await?;
+                self.apply_dry_run(&mut registry);
+                registry
+            }
+            None => self.registry(&registry_uri).await?,
+        };
+        Ok(Uri::builder()
+            .registry(registry)
+            .repository(repository)
+            .reference(reference)
+            .build())
+    }
+
+    /// Options of the settings file for the registry, with the chunk options of the command line
+    pub fn registry_options(&self, uri: &RegistryUri) -> RegistryOptions {
+        let mut options = self.settings.registry(uri.base());
+        if self.chunk_size.is_none() && self.chunk_connections.is_none() {
+            return options;
+        }
+        let mut chunk = options
+            .chunk
+            .take()
+            .unwrap_or_else(|| ChunkPolicy::for_registry(uri.base()));
+        if let Some(size) = self.chunk_size {
+            chunk.min_bytes = size;
+            chunk.max_bytes = size;
+        }
+        if let Some(connections) = self.chunk_connections {
+            chunk.connections = connections;
+        }
+        options.chunk = Some(chunk);
+        options
+    }
+
+    /// Create a registry client with the options of the settings file for the registry
+    pub async fn registry(&self, uri: &RegistryUri) -> ocilot::Result<Registry> {
+        let mut registry = Registry::new_with_options(uri, &self.registry_options(uri)).await?;
+        self.apply_dry_run(&mut registry);
+        Ok(registry)
+    }
+
+    /// Skip the mutating operations of the registry in a dry run, recording them to the plan
+    fn apply_dry_run(&self, registry: &mut Registry) {
+        if self.dry_run {
+            registry.set_dry_run(true);
+            registry.set_audit(self.audit.clone());
+        }
+    }
+
+    /// Limit on concurrent blob transfers, for uris not created through the context
+    pub fn limiter(&self) -> Option<Arc<Semaphore>> {
+        self.limiter.clone()
+    }
+
+    /// The local store to cache pulled content in, unless caching is disabled
+    pub fn cache(&self) -> Option<Store> {
+        self.store.clone().filter(|_| !self.no_cache)
+    }
+
+    /// The local store, if one is available
+    pub fn store(&self) -> Option<&Store> {
+        self.store.as_ref()
+    }
+
+    /// Format of the results printed by commands
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+
+    /// Whether mutating operations are only printed as a plan
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Print the result of a command, as a line of JSON when asked for it or the text otherwise
+    pub fn print(&self, value: &impl Serialize, text: impl fmt::Display) -> ocilot::Result<()> {
+        match self.output {
+            OutputFormat::Text => println!("{text}"),
+            OutputFormat::Json => {
+                println!(
+                    "{}",
+                    serde_json::to_string(value).context(error::SerializeSnafu)?
+                )
+            }
+        }
+        Ok(())
+    }
+
+    /// Print a document, pretty printed unless JSON output was asked for
+    pub fn print_document(&self, value: &impl Serialize) -> ocilot::Result<()> {
+        let text = serde_json::to_string_pretty(value).context(error::SerializeSnafu)?;
+        self.print(value, text)
+    }
+
+    /// Print the descriptor of a manifest that was pushed to the target
+    pub fn pushed(&self, target: &Uri, descriptor: &ocilot::layer::Layer) -> ocilot::Result<()> {
+        self.print(
+            &json!({
+                "reference": target.to_string(),
+                "mediaType": descriptor.media_type(),
+                "digest": descriptor.digest(),
+                "size": descriptor.size(),
+            }),
+            format!("{target} -> {}", descriptor.digest()),
+        )
+    }
+
+    /// Sink drawing a progress bar per blob transfer
+    pub fn bars(&self) -> Arc<dyn ProgressSink> {
+        self.bars.clone()
+    }
+}
+
+/// Tracing layer writing log lines in the requested format, filtered by `RUST_LOG`.
+///
+/// With `spans`, a line is also written as every registry operation completes, carrying its
+/// fields and duration, and operations are logged at info when `RUST_LOG` is unset.
+fn log_layer<S, W>(
+    format: LogFormat,
+    spans: bool,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let (events, filter) = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Err(_) if spans => (
+            FmtSpan::CLOSE,
+            EnvFilter::new("error,registry=info,layer=info"),
+        ),
+        _ if spans => (FmtSpan::CLOSE, EnvFilter::from_default_env()),
+        _ => (FmtSpan::NONE, EnvFilter::from_default_env()),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_span_events(events);
+    match format {
+        LogFormat::Text => layer.with_filter(filter).boxed(),
+        LogFormat::Json => layer.json().with_filter(filter).boxed(),
+    }
+}
+
+/// Print an operation skipped by a dry run as a line of the plan
+fn print_plan(event: &AuditEvent, output: OutputFormat) {
+    if event.outcome != AuditOutcome::Planned {
+        return;
+    }
+    if output == OutputFormat::Json {
+        if let Ok(line) = serde_json::to_string(event) {
+            println!("{line}");
+        }
+        return;
+    }
+    let repository = format!("{}/{}", event.registry, event.repository);
+    let digest = event.digest.as_deref().unwrap_or("unknown digest");
+    let target = match event.reference.as_deref() {
+        Some(reference) if reference.contains(':') => format!("{repository}@{reference}"),
+        Some(reference) => format!("{repository}:{reference}"),
+        None => repository.clone(),
+    };
+    match event.operation {
+        AuditOperation::UploadBlob => match event.size {
+            Some(size) => println!("would upload blob {digest} ({size} bytes) to {repository}"),
+            None => println!("would upload blob {digest} to {repository}"),
+        },
+        AuditOperation::PutManifest => println!("would put manifest {digest} to {target}"),
+        AuditOperation::DeleteManifest if event.digest.is_none() => {
+            println!("would delete tag {target}")
+        }
+        AuditOperation::DeleteManifest => println!("would delete manifest {target}"),
+        AuditOperation::DeleteBlob => println!("would delete blob {digest} from {repository}"),
+    }
+}
 
+/// Drive an overall progress bar with the total bytes across all blobs of the operation and the
+/// number of blobs done. The bar is only added once the first blob transfer is reported.
+async fn aggregate_progress(mut events: UnboundedReceiver<ProgressEvent>, multi: MultiProgress) {
+    let mut bar: Option<ProgressBar> = None;
+    let (mut blobs, mut done) = (0, 0);
+    while let Some(event) = events.recv().await {
+        let bar = bar.get_or_insert_with(|| {
+            let bar = multi.insert(0, ProgressBar::new(0));
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "== total: [{elapsed_precise}] {bar:40.green/blue} {msg} ({binary_bytes:>7}/{binary_total_bytes:7})",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            );
+            bar
+        });
+        match event {
+            ProgressEvent::Started { size, .. } => {
+                blobs += 1;
+                bar.inc_length(size);
+            }
+            ProgressEvent::Advanced { bytes } => bar.inc(bytes),
+            ProgressEvent::Finished => done += 1,
+            ProgressEvent::Skipped { size, .. } => {
+                blobs += 1;
+                done += 1;
+                bar.inc_length(size);
+                bar.inc(size);
             }
         }
-        let multi = MultiProgress::new();
-        Ok(Self { multi })
+        bar.set_message(format!("{done}/{blobs} blobs"));
+    }
+}
+
+/// Collect references from the command line and optionally a file with one reference per line,
+/// where '-' reads from stdin. Blank lines and lines starting with '#' are ignored.
+pub async fn read_refs(refs: &[String], file: Option<&Path>) -> ocilot::Result<Vec<String>> {
+    let mut refs = refs.to_vec();
+    if let Some(file) = file {
+        let mut contents = String::new();
+        if file.as_os_str() == "-" {
+            tokio::io::stdin()
+                .read_to_string(&mut contents)
+                .await
+                .context(error::FileSnafu)?;
+        } else {
+            contents = tokio::fs::read_to_string(file)
+                .await
+                .context(error::FileSnafu)?;
+        }
+        refs.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                .map(str::to_string),
+        );
     }
+    Ok(refs)
+}
+
+/// Uri to push a modified image to, the tag if one is given or else the tag of the image. Images
+/// referenced by digest need a tag as the modified image gets a new digest.
+pub fn tag_target(uri: &Uri, tag: Option<&str>) -> ocilot::Result<Uri> {
+    let reference = match (tag, uri.reference()) {
+        (Some(tag), _) => Reference::Tag(tag.to_string()),
+        (None, Reference::Tag(tag)) => Reference::Tag(tag.clone()),
+        (None, Reference::Digest { .. }) => {
+            return error::MalformedUriSnafu {
+                reason: "a --tag is required to modify an image referenced by digest",
+            }
+            .fail();
+        }
+    };
+    Ok(Uri::builder()
+        .registry(uri.registry().clone())
+        .repository(uri.repository())
+        .reference(reference)
+        .build())
+}
 
-    pub fn get(&mut self) -> &mut MultiProgress {
-        &mut self.multi
+/// Parse a `USERNAME:PASSWORD` command line argument, a value without a colon is a bearer token.
+pub fn parse_creds(s: &str) -> Result<Token, String> {
+    match s.split_once(':') {
+        Some((username, password)) => Ok(Token::basic(username, password)),
+        None if !s.is_empty() => Ok(Token::bearer(s)),
+        None => Err("expected USERNAME:PASSWORD or a bearer token".to_string()),
+    }
+}
+
+/// Parse a size command line argument in bytes, optionally with a binary unit (i.e. `512KiB` or
+/// `8MiB`).
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|x: char| !x.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let factor = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        _ => return Err(format!("unknown unit '{unit}', expected KiB, MiB or GiB")),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_mul(factor))
+        .filter(|x| *x > 0)
+        .ok_or_else(|| format!("expected a positive size, got '{s}'"))
+}
+
+/// Parse a `KEY=VALUE` command line argument.
+pub fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{s}'"))
+}
+
+/// Compression of layers built by a command.
+#[derive(Default, PartialEq, Eq, Debug, Clone, ValueEnum)]
+pub enum LayerCompression {
+    #[default]
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl From<&LayerCompression> for Compression {
+    fn from(compression: &LayerCompression) -> Self {
+        match compression {
+            LayerCompression::Gzip => Compression::Gzip,
+            LayerCompression::Zstd => Compression::Zstd,
+            LayerCompression::None => Compression::None,
+        }
     }
 }
@@ -1,101 +1,602 @@
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use super::context::Ctx;
+use super::context::{Ctx, LayerCompression, parse_creds};
+use super::report::{ImageReport, ImageStatus, Report, Transfers};
 use clap::Parser;
 use futures::future::join_all;
 use ocilot::{
-    Result,
+    Result, error,
     image::Image,
     index::Index,
     layer::Layer,
-    uri::{Reference, Uri},
+    manifest::Manifest,
+    models::{Compression, Platform, Token},
+    pack::{open_local, write_dir},
+    progress::ProgressSink,
+    uri::{Reference, Transport, Uri},
 };
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt, ensure};
+use tempfile::tempdir;
+use tokio::fs::File;
 use tokio::task::JoinHandle;
 
 #[derive(Parser, Debug)]
-#[command(version, about = "Efficiently copy a remote image from src to dst while retaining the digest value", long_about = None)]
+#[command(version, about = "Efficiently copy an image from src to dst while retaining the digest value", long_about = None)]
 pub struct Copy {
+    /// Image to copy, a registry reference optionally prefixed with `docker://`, or a local
+    /// `oci:/path`, `oci-archive:/path.tar`, `docker-archive:/path.tar` or `dir:/path`
     source: String,
+    /// Where to copy the image to, with the same transports as the source
     target: String,
     #[arg(short, long)]
     source_insecure: bool,
     #[arg(short, long)]
     target_insecure: bool,
+    /// Only copy the images for these platforms, the target index then only lists these and gets
+    /// a different digest than the source
+    #[arg(short, long)]
+    platform: Vec<Platform>,
+    /// Recompress the layers with this compression, i.e. to migrate images to zstd. The images and
+    /// the index then get different digests than the source
+    #[arg(short, long)]
+    compression: Option<LayerCompression>,
+    /// Write a json summary of the copy to this file ('-' writes to stdout)
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Credentials for the source as USERNAME:PASSWORD (or a bearer token) instead of the
+    /// discovered ones. Set OCILOT_SRC_CREDS instead to keep them out of the process list
+    #[arg(long, env = "OCILOT_SRC_CREDS", hide_env_values = true, value_parser = parse_creds)]
+    src_creds: Option<Token>,
+    /// Credentials for the target as USERNAME:PASSWORD (or a bearer token) instead of the
+    /// discovered ones. Set OCILOT_DST_CREDS instead to keep them out of the process list
+    #[arg(long, env = "OCILOT_DST_CREDS", hide_env_values = true, value_parser = parse_creds)]
+    dst_creds: Option<Token>,
+    /// Create the repository before pushing when the registry does not create it on push (ECR)
+    #[cfg(feature = "aws")]
+    #[arg(long)]
+    create_repo: bool,
 }
 
 impl Copy {
     pub async fn run(&self, ctx: &mut Ctx) -> Result<()> {
-        let mut source = Uri::new(self.source.as_str()).await?;
-        source.set_secure(!self.source_insecure);
-        let mut target = Uri::new(self.target.as_str()).await?;
-        target.set_secure(!self.target_insecure);
-        let index = Index::fetch(&source).await?;
-        let multi = ctx.get();
+        let source_transport = Transport::from_str(&self.source)?;
+        let target_transport = Transport::from_str(&self.target)?;
+        // Images on the local filesystem are staged into a store that is read like a registry
+        let staged = tempdir().context(error::TempSnafu)?;
+        let source = if source_transport.is_local() {
+            open_local(&source_transport, staged.path()).await?
+        } else {
+            let mut source = ctx
+                .uri_with_creds(self.source.as_str(), self.src_creds.as_ref())
+                .await?;
+            source.set_secure(!self.source_insecure);
+            source.set_limiter(ctx.limiter());
+            source.set_store(ctx.cache());
+            source
+        };
+        let source_name = if source_transport.is_local() {
+            source_transport.to_string()
+        } else {
+            source.to_string()
+        };
+        if target_transport.is_local() {
+            return self
+                .write_local(ctx, &source, source_name, &target_transport)
+                .await;
+        }
+        let target = ctx
+            .write_uri_with_creds(
+                self.target.as_str(),
+                self.target_insecure,
+                self.dst_creds.as_ref(),
+            )
+            .await?;
+        #[cfg(feature = "aws")]
+        if self.create_repo {
+            target
+                .registry()
+                .create_repository(target.repository())
+                .await?;
+        }
+        let (bytes, digest, media_type) = source
+            .registry()
+            .fetch_manifest_raw(source.repository(), source.reference().to_string().as_str())
+            .await?;
+        let progress = ctx.bars();
+        let mut report = Report::new(source_name, target.to_string());
+        let transfers = Arc::new(Transfers::default());
+        let mut index = match Manifest::from_slice(&bytes, &media_type)? {
+            Manifest::Index(index) => index,
+            Manifest::Image(image) => {
+                // The source is a single image, copy it and its manifest as is to keep the digest
+                if !self.platform.is_empty() {
+                    eprintln!("{source} is a single image, ignoring --platform");
+                }
+                let pushed = if let Some(compression) = self.compression.as_ref() {
+                    image
+                        .transcode(&source, &target, compression.into())
+                        .await?
+                        .push(&target)
+                        .await?
+                } else {
+                    copy_blobs(&image, &source, &target, &progress, &transfers).await?;
+                    target
+                        .registry()
+                        .push_manifest_raw(
+                            &media_type,
+                            target.repository(),
+                            target.reference().to_string().as_str(),
+                            bytes,
+                            None,
+                        )
+                        .await?
+                };
+                eprintln!("{digest}: copied");
+                report.image(ImageReport {
+                    source: source.to_string(),
+                    destination: target.to_string(),
+                    platform: None,
+                    status: ImageStatus::Copied,
+                });
+                report.finish(pushed.digest(), &transfers);
+                return report.write(self.report.as_deref(), ctx.output()).await;
+            }
+        };
+        if !self.platform.is_empty() {
+            index = index.filter_platforms(&self.platform)?;
+        }
+        let mut transcoded = Vec::new();
         for manifest in index.manifests().iter() {
-            let manifest_uri = Uri::builder()
-                .registry(source.registry().clone())
-                .repository(source.repository())
-                .reference(Reference::from_str(manifest.digest())?)
-                .build();
-            let image = Image::fetch(&manifest_uri, manifest.platform().clone()).await?;
-            // Copy the config over, note we do not use progress bars for the read
-            let config_uri = Uri::builder()
-                .registry(target.registry().clone())
-                .repository(target.repository())
-                .reference(Reference::from_str(image.config().digest())?)
-                .build();
-            let digest = &image.config().digest().strip_prefix("sha256:").unwrap()[0..9];
+            if let Some(compression) = self.compression.as_ref() {
+                let (pushed, image) =
+                    transcode_image(manifest, &source, &target, compression.into()).await?;
+                report.image(image);
+                transcoded.push(pushed);
+                continue;
+            }
+            report.image(copy_image(manifest, &source, &target, &progress, &transfers).await?);
+        }
+        // Now all images in index are copied push the index, as is unless it was filtered
+        let pushed = if self.compression.is_some() {
+            transcoded_index(&index, transcoded).push(&target).await?
+        } else if self.platform.is_empty() {
+            target
+                .registry()
+                .push_manifest_raw(
+                    &media_type,
+                    target.repository(),
+                    target.reference().to_string().as_str(),
+                    bytes,
+                    None,
+                )
+                .await?
+        } else {
+            index.push(&target).await?
+        };
+        report.finish(pushed.digest(), &transfers);
+        report.write(self.report.as_deref(), ctx.output()).await?;
+
+        Ok(())
+    }
+
+    /// Write the source image to a transport on the local filesystem
+    async fn write_local(
+        &self,
+        ctx: &Ctx,
+        source: &Uri,
+        source_name: String,
+        target: &Transport,
+    ) -> Result<()> {
+        if self.compression.is_some() {
+            eprintln!("{target} is on the local filesystem, ignoring --compression");
+        }
+        let (bytes, digest, media_type) = source
+            .registry()
+            .fetch_manifest_raw(source.repository(), source.reference().to_string().as_str())
+            .await?;
+        let index = match Manifest::from_slice(&bytes, &media_type)? {
+            Manifest::Index(index) if self.platform.is_empty() => index,
+            Manifest::Index(index) => index.filter_platforms(&self.platform)?,
+            Manifest::Image(_) => {
+                // A single image, wrap it in an index so all transports can handle it
+                if !self.platform.is_empty() {
+                    eprintln!("{source_name} is a single image, ignoring --platform");
+                }
+                let manifest = Layer::builder()
+                    .media_type(media_type)
+                    .size(bytes.len())
+                    .digest(digest.as_str())
+                    .build();
+                Index::new(&[manifest]).await
+            }
+        };
+        let progress = ctx.bars();
+        let not_found = || error::ImageNotFoundSnafu {
+            uri: source_name.as_str(),
+        };
+        match target {
+            Transport::Oci(path) => {
+                index
+                    .to_oci_dir_progress(source, None, path, &progress)
+                    .await?
+            }
+            Transport::OciArchive(path) => {
+                let output = File::create(path).await.context(error::FileSnafu)?;
+                index
+                    .to_oci_progress(source, None, output, &progress)
+                    .await?
+            }
+            // Docker archives and dir transports hold a single image
+            Transport::DockerArchive(path) => {
+                let image = index
+                    .fetch_image(source, None)
+                    .await?
+                    .context(not_found())?;
+                let output = File::create(path).await.context(error::FileSnafu)?;
+                image.to_tarball_progress(source, output, &progress).await?
+            }
+            Transport::Dir(path) => {
+                let manifest = index.find_image(source, None).await?.context(not_found())?;
+                let manifest_uri = Uri::builder()
+                    .registry(source.registry().clone())
+                    .repository(source.repository())
+                    .reference(Reference::from_str(manifest.digest())?)
+                    .build();
+                write_dir(&manifest_uri, path, &progress).await?
+            }
+            // Registry targets are copied by run
+            Transport::Registry(_) => {}
+        }
+        eprintln!("{digest}: copied");
+        let mut report = Report::new(source_name, target.to_string());
+        report.finish(digest, &Transfers::default());
+        report.write(self.report.as_deref(), ctx.output()).await
+    }
+}
+
+/// Copy the manifest the source refers to as is, along with the images of an index and their
+/// blobs, so the target gets the same digest. Returns the digest and the outcome of each image.
+pub(crate) async fn mirror(
+    source: &Uri,
+    target: &Uri,
+    progress: &Arc<dyn ProgressSink>,
+    transfers: &Arc<Transfers>,
+) -> Result<(String, Vec<ImageReport>)> {
+    let (bytes, digest, media_type) = source
+        .registry()
+        .fetch_manifest_raw(source.repository(), source.reference().to_string().as_str())
+        .await?;
+    let mut images = Vec::new();
+    match Manifest::from_slice(&bytes, &media_type)? {
+        Manifest::Index(index) => {
+            for manifest in index.manifests().iter() {
+                images.push(copy_image(manifest, source, target, progress, transfers).await?);
+            }
+        }
+        Manifest::Image(image) => copy_blobs(&image, source, target, progress, transfers).await?,
+    }
+    target
+        .registry()
+        .push_manifest_raw(
+            &media_type,
+            target.repository(),
+            target.reference().to_string().as_str(),
+            bytes,
+            None,
+        )
+        .await?;
+    Ok((digest, images))
+}
+
+/// Copy an image or artifact of an index by its digest, along with its blobs, skipping it when the
+/// target already has it
+async fn copy_image(
+    manifest: &Layer,
+    source: &Uri,
+    target: &Uri,
+    progress: &Arc<dyn ProgressSink>,
+    transfers: &Arc<Transfers>,
+) -> Result<ImageReport> {
+    let platform = manifest
+        .platform()
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let target_manifest_uri = Uri::builder()
+        .registry(target.registry().clone())
+        .repository(target.repository())
+        .reference(Reference::from_str(manifest.digest())?)
+        .build();
+    let manifest_uri = Uri::builder()
+        .registry(source.registry().clone())
+        .repository(source.repository())
+        .reference(Reference::from_str(manifest.digest())?)
+        .build();
+    // An image with the same digest already present has all of its blobs as well
+    if Image::check(&target_manifest_uri).await? {
+        eprintln!("{platform} {}: skipped, already present", manifest.digest());
+        return Ok(ImageReport {
+            source: manifest_uri.to_string(),
+            destination: target_manifest_uri.to_string(),
+            platform: manifest.platform().map(|x| x.to_string()),
+            status: ImageStatus::Skipped,
+        });
+    }
+    // Push the manifest as fetched, serializing it again could change its digest
+    let (manifest_bytes, manifest_digest, _) = source
+        .registry()
+        .fetch_manifest_raw(source.repository(), manifest.digest())
+        .await?;
+    ensure!(
+        manifest_digest == manifest.digest(),
+        error::DigestMismatchSnafu {
+            expected: manifest.digest(),
+            actual: manifest_digest,
+        }
+    );
+    let image: Image =
+        serde_json::from_slice(&manifest_bytes).context(error::BodyDeserializeSnafu)?;
+    copy_blobs(&image, source, target, progress, transfers).await?;
+    target
+        .registry()
+        .push_manifest_raw(
+            manifest.media_type(),
+            target.repository(),
+            manifest.digest(),
+            manifest_bytes,
+            manifest.platform(),
+        )
+        .await?;
+    eprintln!("{platform} {}: copied", manifest.digest());
+    Ok(ImageReport {
+        source: manifest_uri.to_string(),
+        destination: target_manifest_uri.to_string(),
+        platform: manifest.platform().map(|x| x.to_string()),
+        status: ImageStatus::Copied,
+    })
+}
+
+/// Recompress the layers of an image of an index with the compression and push the image to the
+/// target by its new digest, which the transcoded index refers to it by. Docker images become oci
+/// ones for compressions docker defines no media type for.
+async fn transcode_image(
+    manifest: &Layer,
+    source: &Uri,
+    target: &Uri,
+    compression: Compression,
+) -> Result<(Layer, ImageReport)> {
+    let platform = manifest
+        .platform()
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let manifest_uri = Uri::builder()
+        .registry(source.registry().clone())
+        .repository(source.repository())
+        .reference(Reference::from_str(manifest.digest())?)
+        .build();
+    let image = Image::fetch(&manifest_uri, manifest.platform()).await?;
+    let image = image.transcode(source, target, compression).await?;
+    let image_bytes = serde_json::to_vec(&image).context(error::SerializeSnafu)?;
+    let image_digest = format!(
+        "sha256:{}",
+        base16::encode_lower(Sha256::digest(&image_bytes).as_slice())
+    );
+    let pushed = target
+        .registry()
+        .push_manifest_raw(
+            image.media_type(),
+            target.repository(),
+            image_digest.as_str(),
+            image_bytes.into(),
+            manifest.platform(),
+        )
+        .await?;
+    eprintln!(
+        "{platform} {}: transcoded to {image_digest}",
+        manifest.digest()
+    );
+    let report = ImageReport {
+        source: manifest_uri.to_string(),
+        destination: Uri::builder()
+            .registry(target.registry().clone())
+            .repository(target.repository())
+            .reference(Reference::from_str(&image_digest)?)
+            .build()
+            .to_string(),
+        platform: manifest.platform().map(|x| x.to_string()),
+        status: ImageStatus::Copied,
+    };
+    Ok((pushed, report))
+}
+
+/// Index listing the transcoded images in place of those of the source index. A docker manifest
+/// list becomes an oci index once it lists images converted to oci ones.
+fn transcoded_index(index: &Index, manifests: Vec<Layer>) -> Index {
+    let converted = index
+        .manifests()
+        .iter()
+        .zip(manifests.iter())
+        .any(|(source, transcoded)| source.media_type() != transcoded.media_type());
+    let media_type = if converted {
+        index.media_type().to_oci()
+    } else {
+        index.media_type().clone()
+    };
+    Index::builder()
+        .schema_version(2_usize)
+        .media_type(media_type)
+        .manifests(manifests)
+        .annotations(index.annotations().clone())
+        .build()
+}
+
+/// Copy the config and layers of an image from the source to the target repository, skipping
+/// blobs the target already has
+async fn copy_blobs(
+    image: &Image,
+    source: &Uri,
+    target: &Uri,
+    progress: &Arc<dyn ProgressSink>,
+    transfers: &Arc<Transfers>,
+) -> Result<()> {
+    // Copy the config over, note we do not use progress bars for the read
+    let config_uri = Uri::builder()
+        .registry(target.registry().clone())
+        .repository(target.repository())
+        .reference(Reference::from_str(image.config().digest())?)
+        .build();
+    let mut writer = Layer::create_progress(
+        &config_uri,
+        image.config().media_type(),
+        image.config().size() as u64,
+        progress.clone(),
+        Some(image.config().digest().to_string()),
+    )
+    .await?;
+    if let Some(writer) = writer.as_mut() {
+        let mut reader = image.config().open(source).await?;
+        Layer::copy(&mut reader, writer, image.config().size()).await?;
+        writer.layer().await?;
+        transfers.uploaded(image.config().size() as u64);
+    } else {
+        transfers.skipped();
+    }
+    // Now we are ready to copy the layers for this image
+    let mut tasks: Vec<JoinHandle<Result<()>>> = Vec::new();
+    for layer in image.layers().iter() {
+        let source_uri = source.clone();
+        let target_uri = target.clone();
+        let layer = layer.clone();
+        let progress = progress.clone();
+        let transfers = transfers.clone();
+        tasks.push(tokio::spawn(async move {
+            // Both sides share the limiter, so a single permit covers the read and the write
+            let _permit = target_uri.registry().transfer_permit().await;
             let mut writer = Layer::create_progress(
-                &config_uri,
-                image.config().media_type(),
-                format!("blob {digest}").as_str(),
-                image.config().size() as u64,
-                multi,
-                Some(image.config().digest().to_string()),
+                &target_uri,
+                layer.media_type(),
+                layer.size() as u64,
+                progress,
+                Some(layer.digest().to_string()),
             )
             .await?;
             if let Some(writer) = writer.as_mut() {
-                let mut reader = image.config().open(&source).await?;
-                Layer::copy(&mut reader, writer, image.config().size()).await?;
+                let mut reader = layer.open(&source_uri).await?;
+                Layer::copy(&mut reader, writer, layer.size()).await?;
                 writer.layer().await?;
+                transfers.uploaded(layer.size() as u64);
+            } else {
+                transfers.skipped();
             }
-            // Now we are ready to copy the layers for this image
-            let mut tasks: Vec<JoinHandle<Result<()>>> = Vec::new();
-            for layer in image.layers().iter() {
-                let source_uri = source.clone();
-                let target_uri = target.clone();
-                let layer = layer.clone();
-                let mut multi = multi.clone();
-                tasks.push(tokio::spawn(async move {
-                    let digest = &layer.digest().strip_prefix("sha256:").unwrap()[0..9];
-                    let mut writer = Layer::create_progress(
-                        &target_uri,
-                        layer.media_type(),
-                        format!("blob {digest}").as_str(),
-                        layer.size() as u64,
-                        &mut multi,
-                        Some(layer.digest().to_string()),
-                    )
-                    .await?;
-                    if let Some(writer) = writer.as_mut() {
-                        let mut reader = layer.open(&source_uri).await?;
-                        Layer::copy(&mut reader, writer, layer.size()).await?;
-                        writer.layer().await?;
-                    }
-                    Ok(())
-                }));
-            }
-            join_all(tasks).await;
-            let target_manifest_uri = Uri::builder()
-                .registry(target.registry().clone())
-                .repository(target.repository())
-                .reference(Reference::from_str(manifest.digest())?)
-                .build();
-            image.push(&target_manifest_uri).await?;
-        }
-        // Now all images in index are copied push the index
-        index.push(&target).await?;
+            Ok(())
+        }));
+    }
+    for result in join_all(tasks).await {
+        result.context(error::LayerWaitSnafu)??;
+    }
+    Ok(())
+}
 
-        Ok(())
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ocilot::index::Index;
+    use ocilot::manifest::Manifest;
+    use ocilot::models::{Compression, MediaType};
+    use ocilot::testing::MockRegistryClient;
+    use ocilot::uri::{Algorithm, Reference, RegistryUri, Uri};
+    use serde_json::json;
+
+    use super::{transcode_image, transcoded_index};
+
+    fn uri(client: &MockRegistryClient, repository: &str) -> Uri {
+        Uri::builder()
+            .registry(client.registry(&RegistryUri::from_str("mock.local").unwrap()))
+            .repository(repository)
+            .reference(Reference::Tag("latest".to_string()))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_transcode_docker_list_to_zstd() {
+        let client = MockRegistryClient::new();
+        let layer = client.insert_blob("app", "layer content");
+        let config = client.insert_blob(
+            "app",
+            json!({
+                "architecture": "amd64",
+                "os": "linux",
+                "rootfs": { "type": "layers", "diff_ids": [layer] },
+            })
+            .to_string(),
+        );
+        let image = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {
+                "mediaType": "application/vnd.docker.container.image.v1+json",
+                "digest": config,
+                "size": client.blob("app", &config).unwrap().len(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.docker.image.rootfs.diff.tar",
+                "digest": layer,
+                "size": 13,
+            }],
+        })
+        .to_string();
+        let image_digest = Algorithm::Sha256.digest(image.as_bytes());
+        client.insert_manifest(
+            "app",
+            &image_digest,
+            &MediaType::DockerManifest,
+            image.clone(),
+        );
+        let list = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+            "manifests": [{
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "digest": image_digest,
+                "size": image.len(),
+                "platform": { "architecture": "amd64", "os": "linux" },
+            }],
+        });
+        let index: Index = serde_json::from_value(list).unwrap();
+        let (source, target) = (uri(&client, "app"), uri(&client, "copy"));
+
+        let mut transcoded = Vec::new();
+        for manifest in index.manifests() {
+            let (pushed, _) = transcode_image(manifest, &source, &target, Compression::Zstd)
+                .await
+                .unwrap();
+            transcoded.push(pushed);
+        }
+        // The registry rejects manifests that declare another media type than they are pushed as
+        let pushed = transcoded_index(&index, transcoded)
+            .push(&target)
+            .await
+            .unwrap();
+        assert_eq!(pushed.media_type(), &MediaType::ImageIndex);
+        let bytes = client.manifest("copy", "latest").unwrap();
+        let Manifest::Index(index) = Manifest::from_slice(&bytes, &MediaType::ImageIndex).unwrap()
+        else {
+            panic!("the target is not an index");
+        };
+        let manifest = &index.manifests()[0];
+        assert_eq!(manifest.media_type(), &MediaType::Manifest);
+        let bytes = client.manifest("copy", manifest.digest()).unwrap();
+        let Manifest::Image(image) = Manifest::from_slice(&bytes, &MediaType::Manifest).unwrap()
+        else {
+            panic!("the index does not list an image");
+        };
+        assert_eq!(image.media_type(), &MediaType::Manifest);
+        assert_eq!(
+            image.layers()[0].media_type(),
+            &MediaType::Layer(Compression::Zstd)
+        );
     }
 }
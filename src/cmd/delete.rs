@@ -3,9 +3,10 @@ use ocilot::{
     error,
     layer::Layer,
     models::MediaType,
-    repository::Repository,
-    uri::{Reference, Uri},
+    repository::{Repository, TagOptions},
+    uri::Reference,
 };
+use serde_json::json;
 use snafu::ensure;
 
 use super::context::Ctx;
@@ -21,13 +22,17 @@ pub struct Delete {
 pub enum DeleteCommands {
     Blob(DeleteBlob),
     Tag(DeleteTag),
+    Repo(DeleteRepo),
+    Manifest(DeleteManifest),
 }
 
 impl Delete {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
         match &self.command {
-            DeleteCommands::Blob(cmd) => cmd.run().await,
-            DeleteCommands::Tag(cmd) => cmd.run().await,
+            DeleteCommands::Blob(cmd) => cmd.run(ctx).await,
+            DeleteCommands::Tag(cmd) => cmd.run(ctx).await,
+            DeleteCommands::Repo(cmd) => cmd.run(ctx).await,
+            DeleteCommands::Manifest(cmd) => cmd.run(ctx).await,
         }
     }
 }
@@ -41,9 +46,8 @@ pub struct DeleteTag {
 }
 
 impl DeleteTag {
-    pub async fn run(&self) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
         let repository = Repository::new(uri.registry(), uri.repository());
         match uri.reference() {
             Reference::Digest { .. } => error::DeleteTagDigestSnafu {}.fail(),
@@ -52,6 +56,64 @@ impl DeleteTag {
     }
 }
 
+#[derive(Parser, Debug)]
+#[command(version, about = "Delete a manifest in a repo by its digest, along with its tags", long_about = None)]
+pub struct DeleteManifest {
+    url: String,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl DeleteManifest {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
+        ensure!(
+            matches!(uri.reference(), Reference::Digest { .. }),
+            error::DeleteManifestNoDigestSnafu
+        );
+        let repository = Repository::new(uri.registry(), uri.repository());
+        repository
+            .delete_manifest(uri.reference().to_string().as_str())
+            .await
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Delete every tag in a repo", long_about = None)]
+pub struct DeleteRepo {
+    url: String,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Maximum number of tags to delete concurrently
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+}
+
+impl DeleteRepo {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let repository = ctx
+            .write_repository(self.url.as_str(), self.insecure)
+            .await?;
+        let tags = if ctx.dry_run() {
+            repository.tags(&TagOptions::default()).await?
+        } else {
+            repository.delete_all_tags(self.jobs).await?
+        };
+        let verb = if ctx.dry_run() {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        ctx.print(
+            &json!({ "repository": repository.name(), "tags": tags, "dryRun": ctx.dry_run() }),
+            tags.iter()
+                .map(|x| format!("{verb} {}:{x}", repository.name()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about = "Delete a blob in a repo", long_about = None)]
 pub struct DeleteBlob {
@@ -61,9 +123,8 @@ pub struct DeleteBlob {
 }
 
 impl DeleteBlob {
-    pub async fn run(&self) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
         ensure!(
             matches!(uri.reference(), Reference::Digest { .. }),
             error::DeleteBlobNoDigestSnafu {}
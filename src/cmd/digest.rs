@@ -0,0 +1,34 @@
+use clap::Parser;
+use ocilot::error;
+use serde_json::json;
+
+use super::context::Ctx;
+
+/// Print the digest a reference resolves to.
+#[derive(Parser, Debug)]
+#[command(version, about = "Print the digest of the manifest a tag points to", long_about = None)]
+pub struct Digest {
+    url: String,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Digest {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let digest = uri
+            .registry()
+            .resolve_digest(uri.repository(), uri.reference().to_string().as_str())
+            .await?;
+        ctx.print(
+            &json!({ "reference": uri.to_string(), "digest": digest }),
+            &digest,
+        )?;
+        Ok(())
+    }
+}
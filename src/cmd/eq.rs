@@ -0,0 +1,55 @@
+use clap::Parser;
+use ocilot::error;
+use serde_json::json;
+
+use super::context::Ctx;
+
+/// Compare the digests two references resolve to.
+#[derive(Parser, Debug)]
+#[command(version, about = "Check two references point to the same manifest, exiting with 1 when their digests differ, i.e. to skip redundant copies in CI", long_about = None)]
+pub struct Eq {
+    first: String,
+    second: String,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Eq {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let first = ctx
+            .read_uri(self.first.as_str(), self.insecure, false)
+            .await?;
+        let second = ctx
+            .read_uri(self.second.as_str(), self.insecure, false)
+            .await?;
+        let (first_reference, second_reference) = (
+            first.reference().to_string(),
+            second.reference().to_string(),
+        );
+        let (first_digest, second_digest) = tokio::try_join!(
+            first
+                .registry()
+                .resolve_digest(first.repository(), &first_reference),
+            second
+                .registry()
+                .resolve_digest(second.repository(), &second_reference),
+        )?;
+        let equal = first_digest == second_digest;
+        ctx.print(
+            &json!({
+                "first": { "reference": first.to_string(), "digest": first_digest },
+                "second": { "reference": second.to_string(), "digest": second_digest },
+                "equal": equal,
+            }),
+            if equal {
+                format!("{first} and {second} are both {first_digest}")
+            } else {
+                format!("{first} is {first_digest} but {second} is {second_digest}")
+            },
+        )?;
+        if !equal {
+            std::process::exit(1);
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use clap::Parser;
 use ocilot::error;
-use ocilot::index::Index;
-use ocilot::uri::Uri;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
 use snafu::{OptionExt, ResultExt};
 use std::path::PathBuf;
 
@@ -9,31 +9,53 @@ use super::context::Ctx;
 
 /// Export filesystem of a container image as a tarball.
 #[derive(Parser, Debug)]
-#[command(version, about = "Export filesystem of a container image as a tarball", long_about = None)]
+#[command(version, about = "Export filesystem of a container image as a tarball, or unpack it to a directory", long_about = None)]
 pub struct Export {
     url: String,
-    output: PathBuf,
+    #[arg(value_name = "OUTPUT", required_unless_present = "unpack")]
+    tarball: Option<PathBuf>,
+    /// Unpack the filesystem to this directory instead of writing a tarball, applying whiteouts
+    #[arg(long, conflicts_with = "tarball")]
+    unpack: Option<PathBuf>,
     #[arg(short, long)]
     insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
     #[arg(short, long)]
-    platform: Option<String>,
+    platform: Option<Platform>,
 }
 
 impl Export {
     pub async fn run(&self, ctx: &mut Ctx) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
-        let index = Index::fetch(&uri).await?;
-        let image = index
-            .fetch_image(&uri, self.platform.clone().map(|x| x.into()))
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let image = Manifest::fetch(&uri)
             .await?
-            .context(error::ImageNotFoundSnafu { uri: uri.clone() })?;
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
 
-        let file = tokio::fs::File::create(&self.output)
-            .await
-            .context(error::FileSnafu)?;
-        let multi = ctx.get();
-        image.filesystem_progress(&uri, file, multi).await?;
+        match (&self.unpack, &self.tarball) {
+            (Some(directory), _) => {
+                tokio::fs::create_dir_all(directory)
+                    .await
+                    .context(error::FileSnafu)?;
+                image.unpack_progress(&uri, directory, &ctx.bars()).await?;
+            }
+            (None, Some(tarball)) => {
+                let file = tokio::fs::File::create(tarball)
+                    .await
+                    .context(error::FileSnafu)?;
+                image.filesystem_progress(&uri, file, &ctx.bars()).await?;
+            }
+            // Clap requires the tarball unless unpacking
+            (None, None) => {}
+        }
         Ok(())
     }
 }
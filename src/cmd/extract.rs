@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use serde_json::json;
+use snafu::{OptionExt, ensure};
+
+use super::context::Ctx;
+
+/// Extract a file or directory of an image filesystem.
+#[derive(Parser, Debug)]
+#[command(version, about = "Extract a file or directory of an image filesystem to a local directory without exporting the whole filesystem", long_about = None)]
+pub struct Extract {
+    url: String,
+    /// Absolute path of the file or directory in the image filesystem
+    path: String,
+    /// Directory to extract to, keeping the path relative to the filesystem root
+    #[arg(short, long, default_value = ".")]
+    out: PathBuf,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Extract {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let entries = image.extract(&uri, &self.path, &self.out).await?;
+        ensure!(
+            entries > 0,
+            error::FileNotFoundSnafu {
+                path: self.path.as_str(),
+            }
+        );
+        ctx.print(
+            &json!({ "path": self.path, "output": self.out, "entries": entries }),
+            format!(
+                "{} -> {}: {entries} entries extracted",
+                self.path,
+                self.out.display()
+            ),
+        )
+    }
+}
@@ -0,0 +1,78 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
+
+use super::context::Ctx;
+
+/// List the filesystem of an image without extracting it.
+#[derive(Parser, Debug)]
+#[command(version, about = "List the files of an image filesystem with their size, mode and layer, honoring whiteouts", long_about = None)]
+pub struct Files {
+    url: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Files {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let files = image.list_files(&uri).await?.collect::<Vec<_>>();
+        let width = files
+            .iter()
+            .map(|x| x.size.to_string().len())
+            .max()
+            .unwrap_or_default();
+        let text = files
+            .iter()
+            .map(|x| {
+                let layer = x.layer.split_once(':').map_or(x.layer.as_str(), |x| x.1);
+                format!(
+                    "{} {:>width$} {} {}",
+                    mode(x.mode),
+                    x.size,
+                    &layer[..layer.len().min(12)],
+                    x.path
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        ctx.print(&files, text)
+    }
+}
+
+/// Mode as printed by `ls -l`, i.e. drwxr-xr-x
+fn mode(mode: u32) -> String {
+    let kind = match mode & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o010000 => 'p',
+        _ => '-',
+    };
+    let permissions = (0..9).rev().map(|bit| {
+        if mode & (1 << bit) == 0 {
+            '-'
+        } else {
+            ['x', 'w', 'r'][bit % 3]
+        }
+    });
+    std::iter::once(kind).chain(permissions).collect()
+}
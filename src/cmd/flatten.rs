@@ -0,0 +1,46 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
+
+use super::context::{Ctx, LayerCompression};
+
+/// Squash the layers of an image into one.
+#[derive(Parser, Debug)]
+#[command(version, about = "Squash the layers of an image into a single layer and push the result", long_about = None)]
+pub struct Flatten {
+    /// Reference to the image to flatten, i.e. reg.example/app:1.2.3
+    source: String,
+    /// Reference to push the flattened image to, i.e. reg.example/app:1.2.3-flat
+    target: String,
+    /// Compression of the flattened layer
+    #[arg(short, long, default_value = "gzip")]
+    compression: LayerCompression,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Flatten {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let source = ctx
+            .read_uri(self.source.as_str(), self.insecure, false)
+            .await?;
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let image = Manifest::fetch(&source)
+            .await?
+            .fetch_image(&source, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: source.to_string(),
+            })?;
+        let pushed = image
+            .flatten(&source, &target, (&self.compression).into())
+            .await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
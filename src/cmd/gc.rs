@@ -0,0 +1,46 @@
+use clap::Parser;
+use futures::{StreamExt, TryStreamExt};
+use ocilot::error;
+use serde_json::json;
+
+use super::context::Ctx;
+
+/// Delete the manifests no tag references.
+#[derive(Parser, Debug)]
+#[command(version, about = "Delete the manifests of a repo that no tag references", long_about = None)]
+pub struct Gc {
+    url: String,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Maximum number of manifests to delete concurrently
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+}
+
+impl Gc {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let repository = ctx
+            .write_repository(self.url.as_str(), self.insecure)
+            .await?;
+        let orphaned = repository.orphaned_manifests().await?;
+        if !ctx.dry_run() {
+            futures::stream::iter(orphaned.iter().map(|x| repository.delete_manifest(x)))
+                .buffer_unordered(self.jobs.max(1))
+                .try_collect::<Vec<_>>()
+                .await?;
+        }
+        let verb = if ctx.dry_run() {
+            "would delete"
+        } else {
+            "deleted"
+        };
+        ctx.print(
+            &json!({ "repository": repository.name(), "manifests": orphaned, "dryRun": ctx.dry_run() }),
+            orphaned
+                .iter()
+                .map(|x| format!("{verb} {}@{x}", repository.name()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    }
+}
@@ -1,15 +1,19 @@
 use clap::Parser;
 use ocilot::error;
 use ocilot::layer::Layer;
+use ocilot::manifest::Manifest;
 use ocilot::models::Platform;
 use ocilot::uri::Reference;
 use ocilot::uri::Uri;
 use ocilot::{image::Image, index::Index};
-use sha2::{Digest, Sha256};
 use snafu::OptionExt;
-use snafu::ResultExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use super::context::Ctx;
+use super::push::{Layout, push_image};
+use super::report::Transfers;
 
 /// Manage image indexes.
 #[derive(Parser, Debug)]
@@ -24,6 +28,9 @@ pub struct IndexCmd {
 pub enum IndexCommands {
     Get(GetIndex),
     Add(AddIndex),
+    Remove(RemoveIndex),
+    Replace(ReplaceIndex),
+    Create(CreateIndex),
 }
 
 impl IndexCmd {
@@ -31,6 +38,9 @@ impl IndexCmd {
         match &self.command {
             IndexCommands::Get(cmd) => cmd.run(ctx).await,
             IndexCommands::Add(cmd) => cmd.run(ctx).await,
+            IndexCommands::Remove(cmd) => cmd.run(ctx).await,
+            IndexCommands::Replace(cmd) => cmd.run(ctx).await,
+            IndexCommands::Create(cmd) => cmd.run(ctx).await,
         }
     }
 }
@@ -45,14 +55,11 @@ pub struct GetIndex {
 }
 
 impl GetIndex {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), ocilot::error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), ocilot::error::Error> {
+        let mut uri = ctx.uri(self.url.as_str()).await?;
         uri.set_secure(!self.insecure);
         let index = Index::fetch(&uri).await?;
-        println!(
-            "{}",
-            serde_json::to_string_pretty(&index).context(ocilot::error::SerializeSnafu)?
-        );
+        ctx.print_document(&index)?;
         Ok(())
     }
 }
@@ -63,60 +70,189 @@ impl GetIndex {
 pub struct AddIndex {
     target: String,
     source: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
     #[arg(short, long)]
-    platform: Option<String>,
+    platform: Option<Platform>,
     #[arg(short, long)]
     insecure: bool,
 }
 
 impl AddIndex {
-    pub async fn run(&self, _ctx: &mut Ctx) -> Result<(), ocilot::error::Error> {
-        let mut target = Uri::new(self.target.as_str()).await?;
-        target.set_secure(!self.insecure);
-        let mut source = Uri::new(self.source.as_str()).await?;
+    pub async fn run(&self, ctx: &mut Ctx) -> Result<(), ocilot::error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let mut source = ctx.uri(self.source.as_str()).await?;
         source.set_secure(!self.insecure);
-        let index = if Index::check(&target).await? {
+        let mut index = if Index::check(&target).await? {
             Index::fetch(&target).await?
         } else {
             Index::new(&[]).await
         };
+        let (image, digest, size) = source_image(&source, self.platform.as_ref()).await?;
+        index.add_image(&source, &image, digest, size).await?;
+        let pushed = index.push(&target).await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
 
-        // Now load the manifest we want to add
-        let platform: Option<Platform> = self.platform.clone().map(|x| x.into());
-        // If a platform is set and reference is a tag we can use an index to find the right
-        // image
-        let image = if let Some(platform) = platform.as_ref() {
-            if matches!(source.reference(), Reference::Tag(..)) {
-                let source_index = Index::fetch(&source).await?;
-                source_index
-                    .fetch_image(&source, Some(platform.clone()))
-                    .await?
-                    .context(error::IndexNoPlatformSnafu {
-                        platform: platform.clone(),
-                    })?
-            } else {
-                Image::fetch(&source, Some(platform.clone())).await?
-            }
-        } else {
-            Image::fetch(&source, None).await?
-        };
-        let image_bytes = serde_json::to_vec(&image).context(error::SerializeSnafu)?;
-        let hash = Sha256::digest(image_bytes.as_slice());
-        let digest = format!("sha256:{}", base16::encode_lower(hash.as_slice()));
+/// Remove the manifests of a platform from an image index.
+#[derive(Parser, Debug)]
+#[command(version, about = "Remove the images of a platform from an image index in oci registry", long_about = None)]
+pub struct RemoveIndex {
+    target: String,
+    /// Platform to remove as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Platform,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl RemoveIndex {
+    pub async fn run(&self, ctx: &mut Ctx) -> Result<(), ocilot::error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let mut index = Index::fetch(&target).await?;
+        index.remove_manifest(&self.platform)?;
+        let pushed = index.push(&target).await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
+
+/// Replace the manifest of a platform in an image index.
+#[derive(Parser, Debug)]
+#[command(version, about = "Replace the image of a platform in an image index in oci registry", long_about = None)]
+pub struct ReplaceIndex {
+    target: String,
+    source: String,
+    /// Platform to replace as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Platform,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl ReplaceIndex {
+    pub async fn run(&self, ctx: &mut Ctx) -> Result<(), ocilot::error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let mut source = ctx.uri(self.source.as_str()).await?;
+        source.set_secure(!self.insecure);
+        let mut index = Index::fetch(&target).await?;
+        // Keep the full platform of the replaced entry, the requested one may omit the variant
+        let platform = index
+            .manifests()
+            .iter()
+            .filter_map(Layer::platform)
+            .find(|x| self.platform.matches(x))
+            .unwrap_or_else(|| self.platform.clone());
+        let (image, digest, size) = source_image(&source, Some(&platform)).await?;
         let layer = Layer::builder()
             .media_type(image.media_type().clone())
-            .digest(digest.clone())
-            .size(image_bytes.len())
+            .digest(digest)
+            .size(size)
+            .maybe_platform(image.platform())
             .build();
-        let mut manifests = index.manifests().to_vec();
-        manifests.push(layer);
-        let index = Index::builder()
-            .schema_version(2_usize)
-            .media_type(index.media_type().clone())
-            .manifests(manifests)
-            .build();
-        index.push(&target).await?;
+        index.replace_manifest(&self.platform, layer)?;
+        let pushed = index.push(&target).await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
 
+/// Create a multi-platform image index from the images of several archives.
+#[derive(Parser, Debug)]
+#[command(version, about = "Push the images of oci archives and create an image index of them in oci registry", long_about = None)]
+pub struct CreateIndex {
+    target: String,
+    /// Image of a platform as PLATFORM=PATH, where the path is an oci archive or oci layout
+    /// directory, i.e. linux/arm64=arm64.tar
+    #[arg(long, required = true, value_parser = parse_from)]
+    from: Vec<(Platform, PathBuf)>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl CreateIndex {
+    pub async fn run(&self, ctx: &mut Ctx) -> Result<(), ocilot::error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let transfers = Arc::new(Transfers::default());
+        let mut manifests = Vec::with_capacity(self.from.len());
+        for (platform, path) in self.from.iter() {
+            let (layout, index) = Layout::read(path).await?;
+            // An archive of a single image may not record its platform
+            let entry = match index.manifests() {
+                [entry] if entry.platform().is_none() => entry,
+                entries => entries
+                    .iter()
+                    .find(|x| x.platform().is_some_and(|x| platform.matches(&x)))
+                    .context(error::IndexNoPlatformSnafu {
+                        platform: platform.clone(),
+                    })?,
+            };
+            let manifest = Layer::builder()
+                .media_type(entry.media_type().clone())
+                .digest(entry.digest())
+                .size(entry.size())
+                .platform(entry.platform().unwrap_or_else(|| platform.clone()))
+                .build();
+            push_image(
+                layout,
+                target.clone(),
+                manifest.clone(),
+                ctx.bars(),
+                transfers.clone(),
+            )
+            .await?;
+            manifests.push(manifest);
+        }
+        let pushed = Index::new(&manifests).await.push(&target).await?;
+        ctx.pushed(&target, &pushed)?;
         Ok(())
     }
 }
+
+/// Parse a `PLATFORM=PATH` command line argument.
+fn parse_from(s: &str) -> Result<(Platform, PathBuf), String> {
+    let (platform, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PLATFORM=PATH, got '{s}'"))?;
+    let platform = Platform::from_str(platform).map_err(|e| e.to_string())?;
+    Ok((platform, PathBuf::from(path)))
+}
+
+/// Load the source image with the digest and size of its manifest. When the source is an index the
+/// image of the platform is selected, or the one of the current environment without a platform.
+/// The platform hint of the image is taken from the index entry or the image configuration.
+async fn source_image(
+    source: &Uri,
+    platform: Option<&Platform>,
+) -> Result<(Image, String, usize), ocilot::error::Error> {
+    let (bytes, digest, media_type) = source
+        .registry()
+        .fetch_manifest_raw(source.repository(), source.reference().to_string().as_str())
+        .await?;
+    match Manifest::from_slice(&bytes, &media_type)? {
+        Manifest::Index(index) => {
+            let platform = platform.cloned().unwrap_or_default();
+            let entry = index
+                .manifests()
+                .iter()
+                .find(|x| x.platform().is_some_and(|x| platform.matches(&x)))
+                .context(error::IndexNoPlatformSnafu { platform })?;
+            let uri = Uri::builder()
+                .registry(source.registry().clone())
+                .repository(source.repository())
+                .reference(Reference::from_str(entry.digest())?)
+                .build();
+            let image = Image::fetch(&uri, entry.platform()).await?;
+            Ok((image, entry.digest().to_string(), entry.size()))
+        }
+        Manifest::Image(mut image) => {
+            let platform = match platform {
+                Some(platform) => platform.clone(),
+                None => image.fetch_platform(source).await?,
+            };
+            image.set_platform(Some(platform));
+            Ok((*image, digest, bytes.len()))
+        }
+    }
+}
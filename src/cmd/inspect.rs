@@ -0,0 +1,102 @@
+use std::fmt::Write;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::inspect::Inspection;
+use ocilot::models::Platform;
+
+use super::context::Ctx;
+
+/// Summarize an image from its manifest, index and configuration.
+#[derive(Parser, Debug)]
+#[command(version, about = "Print the digest, platforms, configuration and layers of an image", long_about = None)]
+pub struct Inspect {
+    url: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Inspect {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let inspection = Inspection::fetch(&uri, self.platform.clone()).await?;
+        let text = summary(&inspection);
+        ctx.print(&inspection, text.trim_end())
+    }
+}
+
+/// Human readable summary of an inspection, one field per line with lists continuing on the
+/// following lines
+fn summary(inspection: &Inspection) -> String {
+    let mut text = String::new();
+    let mut field = |name: &str, values: &[String]| {
+        let mut values = values.iter();
+        let first = values.next().map_or("-", String::as_str);
+        let _ = writeln!(text, "{:<12}{first}", format!("{name}:"));
+        for value in values {
+            let _ = writeln!(text, "{:<12}{value}", "");
+        }
+    };
+    field("Reference", std::slice::from_ref(&inspection.reference));
+    field("Digest", std::slice::from_ref(&inspection.digest));
+    field(
+        "Media type",
+        &[serde_json::to_value(&inspection.media_type)
+            .ok()
+            .and_then(|x| x.as_str().map(str::to_string))
+            .unwrap_or_default()],
+    );
+    field(
+        "Platforms",
+        &inspection
+            .platforms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+    );
+    field("Platform", &[inspection.platform.to_string()]);
+    field(
+        "Created",
+        &inspection
+            .created
+            .iter()
+            .map(|x| x.to_rfc3339())
+            .collect::<Vec<_>>(),
+    );
+    field(
+        "Labels",
+        &inspection
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>(),
+    );
+    field("Env", &inspection.env);
+    field("Entrypoint", &command(&inspection.entrypoint));
+    field("Cmd", &command(&inspection.cmd));
+    field(
+        "Layers",
+        &[format!(
+            "{} ({} bytes)",
+            inspection.layers.len(),
+            inspection.size
+        )],
+    );
+    text
+}
+
+/// Command line of an entrypoint or cmd as a JSON array, nothing when it is empty
+fn command(args: &[String]) -> Vec<String> {
+    if args.is_empty() {
+        return Vec::new();
+    }
+    vec![serde_json::to_string(args).unwrap_or_default()]
+}
@@ -1,11 +1,12 @@
 use std::str::FromStr;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 use ocilot::error;
-use ocilot::registry::Registry;
-use ocilot::repository::Repository;
+use ocilot::repository::{Repository, TagOptions, TagSort};
 use ocilot::uri::RegistryUri;
+use serde_json::json;
 
 use super::context::Ctx;
 
@@ -16,10 +17,51 @@ pub struct List {
     url: String,
     #[arg(short, long)]
     insecure: bool,
+    /// Give up after this many seconds, including time spent waiting on registry rate limits
+    #[arg(long)]
+    deadline: Option<u64>,
+    /// Only fetch a single page of at most this many tags, or list at most this many tags after
+    /// filtering and sorting them
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Fetch all tags in pages of this many, for registries whose default page is small
+    #[arg(long)]
+    page_size: Option<usize>,
+    /// Only list tags that sort after this one
+    #[arg(long)]
+    last: Option<String>,
+    /// Only list tags matching the glob, i.e. 'v1.*'
+    #[arg(long, conflicts_with = "last")]
+    filter: Option<String>,
+    /// Only list tags matching the regular expression
+    #[arg(long, conflicts_with = "last")]
+    regex: Option<String>,
+    /// Order to list the tags in, which lists all tags before limiting them
+    #[arg(long, value_enum, conflicts_with = "last")]
+    sort: Option<SortOrder>,
+    /// List the tags in descending order
+    #[arg(long, conflicts_with = "last")]
+    reverse: bool,
+}
+
+/// Order to list tags in.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SortOrder {
+    Lexical,
+    Semver,
+}
+
+impl From<SortOrder> for TagSort {
+    fn from(value: SortOrder) -> Self {
+        match value {
+            SortOrder::Lexical => TagSort::Lexical,
+            SortOrder::Semver => TagSort::Semver,
+        }
+    }
 }
 
 impl List {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
         let mut segments: Vec<_> = self.url.split("/").collect();
         let object = segments.pop().unwrap();
         let registry = segments.join("/");
@@ -27,10 +69,36 @@ impl List {
         if self.insecure {
             registry_uri.set_secure(false);
         }
-        let registry = Registry::new(&registry_uri).await?;
+        let mut registry = ctx.registry(&registry_uri).await?;
+        registry.set_list_deadline(self.deadline.map(Duration::from_secs));
+        registry.set_page_size(self.page_size);
         let repository = Repository::new(&registry, object);
-        let tags = repository.tags().await?;
-        println!("{}", tags.join("\n"));
+        if self.filter.is_some() || self.regex.is_some() || self.sort.is_some() || self.reverse {
+            let options = TagOptions::builder()
+                .maybe_filter(self.filter.clone())
+                .maybe_regex(self.regex.clone())
+                .sort(self.sort.map(TagSort::from).unwrap_or_default())
+                .reverse(self.reverse)
+                .maybe_limit(self.limit)
+                .build();
+            let tags = repository.tags(&options).await?;
+            return ctx.print(
+                &json!({ "repository": object, "tags": tags, "more": false }),
+                tags.join("\n"),
+            );
+        }
+        let page = repository
+            .tags_page(self.limit, self.last.as_deref())
+            .await?;
+        ctx.print(
+            &json!({ "repository": object, "tags": page.entries, "more": page.more }),
+            page.entries.join("\n"),
+        )?;
+        if page.more
+            && let Some(last) = page.entries.last()
+        {
+            eprintln!("more tags are available, continue with --last {last}");
+        }
         Ok(())
     }
 }
@@ -0,0 +1,74 @@
+use std::str::FromStr;
+
+use clap::Parser;
+use console::Term;
+use ocilot::uri::RegistryUri;
+use ocilot::{Result, credentials, error};
+use serde_json::json;
+use snafu::ResultExt;
+use tokio::io::AsyncReadExt;
+
+use super::context::Ctx;
+
+/// Log in to a registry.
+#[derive(Parser, Debug)]
+#[clap(version, about = "Log in to a registry", long_about = None)]
+pub struct Login {
+    registry: String,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Username, prompted for when not given
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Password, prompted for when not given
+    #[arg(short, long)]
+    password: Option<String>,
+    /// Read the password from stdin
+    #[arg(long, conflicts_with = "password")]
+    password_stdin: bool,
+}
+
+impl Login {
+    pub async fn run(&self, ctx: &Ctx) -> Result<()> {
+        let mut registry_uri = RegistryUri::from_str(self.registry.as_str())?;
+        if self.insecure {
+            registry_uri.set_secure(false);
+        }
+        let term = Term::stderr();
+        let username = match self.username.as_ref() {
+            Some(username) => username.clone(),
+            None => {
+                term.write_str("Username: ").context(error::FileSnafu)?;
+                term.read_line().context(error::FileSnafu)?
+            }
+        };
+        let password = if self.password_stdin {
+            let mut password = String::new();
+            tokio::io::stdin()
+                .read_to_string(&mut password)
+                .await
+                .context(error::FileSnafu)?;
+            password.trim_end_matches(['\n', '\r']).to_string()
+        } else {
+            match self.password.as_ref() {
+                Some(password) => password.clone(),
+                None => {
+                    term.write_str("Password: ").context(error::FileSnafu)?;
+                    term.read_secure_line().context(error::FileSnafu)?
+                }
+            }
+        };
+        credentials::login_with_options(
+            &registry_uri,
+            &ctx.registry_options(&registry_uri),
+            username.trim(),
+            &password,
+        )
+        .await?;
+        ctx.print(
+            &json!({ "registry": registry_uri.base(), "loggedIn": true }),
+            "Login succeeded",
+        )?;
+        Ok(())
+    }
+}
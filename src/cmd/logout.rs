@@ -0,0 +1,31 @@
+use std::str::FromStr;
+
+use clap::Parser;
+use ocilot::uri::RegistryUri;
+use ocilot::{Result, credentials};
+use serde_json::json;
+
+use super::context::Ctx;
+
+/// Log out of a registry.
+#[derive(Parser, Debug)]
+#[clap(version, about = "Remove the stored credentials of a registry", long_about = None)]
+pub struct Logout {
+    registry: String,
+}
+
+impl Logout {
+    pub async fn run(&self, ctx: &Ctx) -> Result<()> {
+        let registry_uri = RegistryUri::from_str(self.registry.as_str())?;
+        let removed = credentials::logout(&registry_uri).await?;
+        let text = if removed {
+            format!("Removed credentials for {}", registry_uri.base())
+        } else {
+            format!("Not logged in to {}", registry_uri.base())
+        };
+        ctx.print(
+            &json!({ "registry": registry_uri.base(), "removed": removed }),
+            text,
+        )
+    }
+}
@@ -1,9 +1,7 @@
 use clap::Parser;
 use ocilot::error;
-use ocilot::index::Index;
+use ocilot::manifest;
 use ocilot::models::Platform;
-use ocilot::uri::Uri;
-use snafu::ResultExt;
 
 use super::context::Ctx;
 
@@ -12,23 +10,27 @@ use super::context::Ctx;
 #[command(version, about = "Get the manifest of an image", long_about = None)]
 pub struct Manifest {
     url: String,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
     #[arg(short, long)]
-    platform: Option<String>,
+    platform: Option<Platform>,
     #[arg(short, long)]
     insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
 }
 
 impl Manifest {
-    pub async fn run(&self, _ctx: &Ctx) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
-        let platform: Option<Platform> = self.platform.clone().map(|x| x.into());
-        let index = Index::fetch(&uri).await?;
-        let image = index.fetch_image(&uri, platform).await?;
-        println!(
-            "{}",
-            serde_json::to_string_pretty(&image).context(error::SerializeSnafu)?
-        );
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        let platform = self.platform.clone();
+        let image = manifest::Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, platform)
+            .await?;
+        ctx.print_document(&image)?;
         Ok(())
     }
 }
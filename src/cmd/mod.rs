@@ -1,5 +1,13 @@
+/// Layer append subcommand.
+pub mod append;
+/// In-toto attestation subcommand.
+pub mod attest;
 /// Blob operations subcommand.
 pub mod blob;
+/// Local blob cache subcommand.
+pub mod cache;
+/// Image file read subcommand.
+pub mod cat;
 /// Catalog listing subcommand.
 pub mod catalog;
 /// Config inspection subcommand.
@@ -10,15 +18,57 @@ pub mod context;
 pub mod copy;
 /// Delete operations subcommand.
 pub mod delete;
+/// Manifest digest subcommand.
+pub mod digest;
+/// Digest equality subcommand.
+pub mod eq;
 /// Filesystem export subcommand.
 pub mod export;
+/// Image file extraction subcommand.
+pub mod extract;
+/// Image filesystem listing subcommand.
+pub mod files;
+/// Image flatten subcommand.
+pub mod flatten;
+/// Repository garbage collection subcommand.
+pub mod gc;
 /// Image index management subcommand.
 pub mod index;
+/// Image inspection subcommand.
+pub mod inspect;
 /// Tag listing subcommand.
 pub mod list;
+/// Registry login subcommand.
+pub mod login;
+/// Registry logout subcommand.
+pub mod logout;
 /// Manifest inspection subcommand.
 pub mod manifest;
+/// Config mutation subcommand.
+pub mod mutate;
+/// Directory packing subcommand.
+pub mod pack;
+/// Local store prefetch subcommand.
+pub mod prefetch;
 /// Image pull subcommand.
 pub mod pull;
 /// Image push subcommand.
 pub mod push;
+/// Base image rebase subcommand.
+pub mod rebase;
+/// Referrers listing subcommand.
+pub mod referrers;
+/// Copy and push summary reports.
+pub mod report;
+/// SBOM attachment subcommand.
+pub mod sbom;
+/// Image signing subcommand.
+pub mod sign;
+/// Repository mirroring subcommand.
+pub mod sync;
+/// Manifest tagging subcommand.
+pub mod tag;
+/// Signature verification subcommand.
+pub mod verify;
+/// Blob digest verification subcommand.
+pub mod verify_digests;
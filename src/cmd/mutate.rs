@@ -0,0 +1,74 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
+
+use super::context::{Ctx, parse_key_value, tag_target};
+
+/// Edit the config of an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Edit the config of an image and push the result", long_about = None)]
+pub struct Mutate {
+    /// Reference to the image to edit, i.e. reg.example/app:1.2.3
+    image: String,
+    /// Tag to push the new image to in the same repository, by default the tag of the image
+    #[arg(short, long)]
+    tag: Option<String>,
+    /// Environment variable to set as KEY=VALUE, replacing any existing value of the variable
+    #[arg(short, long, value_parser = parse_key_value)]
+    env: Vec<(String, String)>,
+    /// Label to set as KEY=VALUE
+    #[arg(short, long, value_parser = parse_key_value)]
+    label: Vec<(String, String)>,
+    /// Entrypoint to replace the current one with, repeat for each argument
+    #[arg(long, allow_hyphen_values = true)]
+    entrypoint: Option<Vec<String>>,
+    /// Command to replace the current one with, repeat for each argument
+    #[arg(long, allow_hyphen_values = true)]
+    cmd: Option<Vec<String>>,
+    /// User to run the image as
+    #[arg(short, long)]
+    user: Option<String>,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Mutate {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.image.as_str(), self.insecure).await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let target = tag_target(&uri, self.tag.as_deref())?;
+        let pushed = image
+            .mutate_config(&target, |config| {
+                let config = &mut config.config;
+                for (key, value) in self.env.iter() {
+                    let prefix = format!("{key}=");
+                    config.env.retain(|x| !x.starts_with(&prefix));
+                    config.env.push(format!("{key}={value}"));
+                }
+                config.labels.extend(self.label.iter().cloned());
+                if let Some(entrypoint) = self.entrypoint.as_ref() {
+                    config.entrypoint = entrypoint.clone();
+                }
+                if let Some(cmd) = self.cmd.as_ref() {
+                    config.cmd = cmd.clone();
+                }
+                if let Some(user) = self.user.as_ref() {
+                    config.user = Some(user.clone());
+                }
+            })
+            .await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::models::{Config, Platform};
+use ocilot::pack::pack;
+use std::path::PathBuf;
+
+use super::context::{Ctx, LayerCompression, parse_key_value};
+
+/// Build a single layer image from a local directory.
+#[derive(Parser, Debug)]
+#[command(version, about = "Build a single layer image from a local directory and push it", long_about = None)]
+pub struct Pack {
+    /// Directory whose content becomes the root of the image filesystem
+    path: PathBuf,
+    /// Reference to push the image to, i.e. reg.example/app:1.2.3
+    target: String,
+    /// Compression of the layer
+    #[arg(short, long, default_value = "gzip")]
+    compression: LayerCompression,
+    /// Platform of the image as os[(os.version)]/architecture[/variant], by default the current one
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    /// Entrypoint of the image, repeat for each argument
+    #[arg(long, allow_hyphen_values = true)]
+    entrypoint: Vec<String>,
+    /// Command of the image, repeat for each argument
+    #[arg(long, allow_hyphen_values = true)]
+    cmd: Vec<String>,
+    /// Environment variable to set as KEY=VALUE
+    #[arg(short, long, value_parser = parse_key_value)]
+    env: Vec<(String, String)>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Create the repository before pushing when the registry does not create it on push (ECR)
+    #[cfg(feature = "aws")]
+    #[arg(long)]
+    create_repo: bool,
+}
+
+impl Pack {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        #[cfg(feature = "aws")]
+        if self.create_repo {
+            target
+                .registry()
+                .create_repository(target.repository())
+                .await?;
+        }
+        let config = Config {
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect(),
+            entrypoint: self.entrypoint.clone(),
+            cmd: self.cmd.clone(),
+            ..Default::default()
+        };
+        let pushed = pack(
+            &target,
+            &self.path,
+            (&self.compression).into(),
+            self.platform.clone().unwrap_or_default(),
+            config,
+        )
+        .await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
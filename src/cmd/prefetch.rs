@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::models::Platform;
+use ocilot::store::Store;
+use serde_json::json;
+use snafu::OptionExt;
+
+use super::context::{Ctx, read_refs};
+
+/// Populate the local store ahead of time.
+#[derive(Parser, Debug)]
+#[command(version, about = "Populate the local store with images so they can later be used offline", long_about = None)]
+pub struct Prefetch {
+    /// References to prefetch
+    refs: Vec<String>,
+    /// Read additional references from a file, one per line ('-' reads from stdin)
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+    /// Only prefetch images for these platforms, by default all images of an index are fetched
+    #[arg(short, long)]
+    platform: Vec<Platform>,
+    /// Maximum number of blobs to fetch concurrently
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Prefetch {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let refs = read_refs(&self.refs, self.file.as_deref()).await?;
+
+        let mut uris = Vec::new();
+        for reference in refs.iter() {
+            uris.push(ctx.read_uri(reference, self.insecure, false).await?);
+        }
+        let store: &Store = ctx.store().context(error::StoreUnavailableSnafu)?;
+        let count = store.prefetch(&uris, &self.platform, self.jobs).await?;
+        ctx.print(
+            &json!({
+                "blobs": count,
+                "references": uris.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+                "root": store.root(),
+            }),
+            format!(
+                "prefetched {count} blobs for {} references into {}",
+                uris.len(),
+                store.root().display()
+            ),
+        )?;
+        Ok(())
+    }
+}
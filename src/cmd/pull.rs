@@ -1,24 +1,55 @@
 use clap::{Parser, ValueEnum};
+use ocilot::daemon::Daemon;
 use ocilot::index::Index;
+use ocilot::layer::Layer;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
 use ocilot::uri::Uri;
 use ocilot::{Result, error};
-use snafu::{OptionExt, ResultExt};
+use serde_json::json;
+use snafu::{OptionExt, ResultExt, ensure};
 use std::path::PathBuf;
+use tokio::fs::File;
 
-use super::context::Ctx;
+use super::context::{Ctx, read_refs};
+
+/// Placeholders expanded in the output path for each reference.
+const PLACEHOLDERS: &[&str] = &["{registry}", "{repository}", "{reference}"];
 
 /// Pull remote images and store locally as an archive.
 #[derive(Parser, Debug)]
 #[command(version, about = "Pull remote images by reference and store their contents locally as an archive", long_about = None)]
 pub struct Pull {
-    url: String,
-    output: PathBuf,
+    /// References to pull followed by the archive to write. When pulling multiple references the
+    /// archive is a template expanded with the {registry}, {repository} and {reference} of each.
+    /// There is no archive when loading into a daemon
+    #[arg(value_name = "REF", required = true)]
+    args: Vec<String>,
+    /// Read additional references from a file, one per line ('-' reads from stdin)
+    #[arg(long)]
+    file: Option<PathBuf>,
     #[arg(short, long)]
     insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
     #[arg(short, long)]
-    platform: Option<String>,
-    #[arg(short, long)]
-    format: Format,
+    platform: Option<Platform>,
+    #[arg(
+        short,
+        long,
+        required_unless_present = "daemon",
+        conflicts_with = "daemon"
+    )]
+    format: Option<Format>,
+    /// Load the images into a docker or containerd daemon instead of writing archives
+    #[arg(long)]
+    daemon: Option<Daemon>,
+    /// Socket of the daemon, defaults to DOCKER_HOST or CONTAINERD_ADDRESS and then the default
+    /// socket of the daemon
+    #[arg(long, requires = "daemon")]
+    daemon_socket: Option<PathBuf>,
 }
 
 /// Output archive format.
@@ -27,30 +58,127 @@ enum Format {
     #[default]
     Tarball,
     Oci,
+    /// Unpacked OCI layout written to a directory
+    OciDir,
 }
 
 impl Pull {
     pub async fn run(&self, ctx: &mut Ctx) -> Result<()> {
-        let mut uri = Uri::new(self.url.as_str()).await?;
-        uri.set_secure(!self.insecure);
-        let index = Index::fetch(&uri).await?;
-        let platform = self.platform.clone().map(|x| x.into());
-
-        let output = tokio::fs::File::create(&self.output)
-            .await
-            .context(error::FileSnafu)?;
-        let multi = ctx.get();
-        match self.format {
+        let (template, refs) = match self.daemon {
+            Some(_) => ("", self.args.as_slice()),
+            None => {
+                let (output, refs) = self.args.split_last().unwrap();
+                (output.as_str(), refs)
+            }
+        };
+        let refs = read_refs(refs, self.file.as_deref()).await?;
+        ensure!(
+            !refs.is_empty(),
+            error::MalformedUriSnafu {
+                reason: "no references were provided to pull",
+            }
+        );
+        ensure!(
+            refs.len() <= 1
+                || self.daemon.is_some()
+                || PLACEHOLDERS.iter().any(|x| template.contains(x)),
+            error::OutputTemplateSnafu {
+                output: template.to_string(),
+            }
+        );
+        let mut failure = None;
+        for reference in refs.iter() {
+            if let Err(e) = self.pull(ctx, reference, template).await {
+                if refs.len() == 1 {
+                    return Err(e);
+                }
+                eprintln!("failed to pull {reference}: {e}");
+                failure = Some(e);
+            }
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Pull a single reference into its archive, or load it into the daemon
+    async fn pull(&self, ctx: &mut Ctx, reference: &str, template: &str) -> Result<()> {
+        let uri = ctx.read_uri(reference, self.insecure, self.offline).await?;
+        let (bytes, digest, media_type) = uri
+            .registry()
+            .fetch_manifest_raw(uri.repository(), uri.reference().to_string().as_str())
+            .await?;
+        let mut platform = self.platform.clone();
+        let (index, image) = match Manifest::from_slice(&bytes, &media_type)? {
+            Manifest::Index(index) => (index, None),
+            Manifest::Image(image) => {
+                // A tag of a single image, wrap it in an index so all formats can handle it
+                if platform.take().is_some() {
+                    eprintln!("{uri} is a single image, ignoring --platform");
+                }
+                let manifest = Layer::builder()
+                    .media_type(media_type)
+                    .size(bytes.len())
+                    .digest(digest)
+                    .build();
+                (Index::new(&[manifest]).await, Some(image))
+            }
+        };
+
+        let path = Self::output_path(template, &uri);
+        let progress = ctx.bars();
+        let image = async || match image {
+            Some(image) => Ok(*image),
+            None => index.fetch_image(&uri, platform.clone()).await?.context(
+                error::ImageNotFoundSnafu {
+                    uri: uri.to_string(),
+                },
+            ),
+        };
+        if let Some(daemon) = self.daemon {
+            let socket = self
+                .daemon_socket
+                .clone()
+                .unwrap_or_else(|| daemon.socket());
+            let messages = daemon
+                .load_progress(&socket, &uri, &image().await?, &progress)
+                .await?;
+            return ctx.print(
+                &json!({ "reference": uri.to_string(), "daemon": daemon.to_string(), "messages": messages }),
+                messages.join("\n"),
+            );
+        }
+        match self.format.clone().unwrap_or_default() {
             Format::Tarball => {
-                let image = index
-                    .fetch_image(&uri, platform.clone())
+                let image = image().await?;
+                let output = File::create(path).await.context(error::FileSnafu)?;
+                image.to_tarball_progress(&uri, output, &progress).await?
+            }
+            Format::Oci => {
+                let output = File::create(path).await.context(error::FileSnafu)?;
+                index
+                    .to_oci_progress(&uri, platform, output, &progress)
+                    .await?
+            }
+            Format::OciDir => {
+                index
+                    .to_oci_dir_progress(&uri, platform, &path, &progress)
                     .await?
-                    .context(error::ImageNotFoundSnafu { uri: uri.clone() })?;
-                image.to_tarball_progress(&uri, output, multi).await?
             }
-            Format::Oci => index.to_oci_progress(&uri, platform, output, multi).await?,
         }
 
         Ok(())
     }
+
+    /// Expand the output template for the uri, separators in the values are replaced with '_'
+    fn output_path(template: &str, uri: &Uri) -> PathBuf {
+        let sanitize = |x: &str| x.replace(['/', ':'], "_");
+        PathBuf::from(
+            template
+                .replace("{registry}", &sanitize(uri.registry().uri().base()))
+                .replace("{repository}", &sanitize(uri.repository()))
+                .replace("{reference}", &sanitize(&uri.reference().to_string())),
+        )
+    }
 }
@@ -1,7 +1,9 @@
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use async_recursion::async_recursion;
+use bytes::Bytes;
 use clap::Parser;
 use futures::StreamExt;
 use futures::future::join_all;
@@ -10,140 +12,266 @@ use ocilot::image::Image;
 use ocilot::index::Index;
 use ocilot::layer::Layer;
 use ocilot::models::MediaType;
+use ocilot::pack::docker_to_oci_layout;
+use ocilot::progress::ProgressSink;
 use ocilot::uri::{Reference, Uri};
 use snafu::{OptionExt, ResultExt};
 use std::io::SeekFrom;
-use tokio::io::AsyncSeekExt;
+use tempfile::{TempDir, tempdir};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 use tokio::task::JoinHandle;
 use tokio::{fs::File, io::AsyncReadExt};
 use tokio_tar::{Archive, Entry};
 
 use super::context::Ctx;
+use super::report::{ImageReport, ImageStatus, Report, Transfers};
 
-/// Push an OCI archive to a registry.
+/// Push an OCI archive, OCI layout directory or docker-save tarball to a registry.
 #[derive(Parser, Debug)]
-#[command(version, about = "Push an oci archive to repo", long_about = None)]
+#[command(version, about = "Push an oci archive, oci layout directory or docker-save tarball to repo", long_about = None)]
 pub struct Push {
+    /// OCI archive, a directory holding an unpacked OCI layout (`index.json` and `blobs/`), or a
+    /// tarball written by `docker save` (`manifest.json`)
     archive: PathBuf,
     uri: String,
     #[arg(short, long)]
     insecure: bool,
+    /// Maximum number of images to push concurrently
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// Write a json summary of the push to this file ('-' writes to stdout)
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Create the repository before pushing when the registry does not create it on push (ECR)
+    #[cfg(feature = "aws")]
+    #[arg(long)]
+    create_repo: bool,
 }
 
 impl Push {
     pub async fn run(&self, ctx: &mut Ctx) -> Result<(), error::Error> {
-        let mut uri = Uri::new(self.uri.as_str()).await?;
-        uri.set_secure(!self.insecure);
-        let multi = ctx.get();
-        let mut archive = File::open(&self.archive).await.context(error::FileSnafu)?;
-        // We need to find the index first
-        let mut index_entry = afind(&mut archive, |x| x.ends_with("index.json"))
-            .await?
-            .context(error::ImageNotValidSnafu {})?;
-        let mut buffer = Vec::new();
-        index_entry
-            .read_to_end(&mut buffer)
-            .await
-            .context(error::ArchiveSnafu)?;
-        let mut index: Index =
-            serde_json::from_slice(buffer.as_slice()).context(error::ImageInvalidIndexSnafu)?;
-        index = find_index(&mut archive, &index).await?;
-        for manifest in index.manifests().iter() {
-            let digest = manifest.digest().split_once(':').unwrap().1;
-            let mut blob_entry = afind(&mut archive, |x| x.ends_with(digest))
-                .await?
-                .context(error::BlobMissingSnafu {
-                    digest: manifest.digest(),
-                })?;
-            let mut buffer = Vec::new();
-            blob_entry
-                .read_to_end(&mut buffer)
-                .await
-                .context(error::ArchiveSnafu)?;
-            let image: Image = serde_json::from_slice(buffer.as_slice())
-                .context(error::ImageInvalidManifestSnafu)?;
-            // First lets copy the config blob
-            let cdigest = image.config().digest().split_once(':').unwrap().1;
-            let mut config_entry = afind(&mut archive, |x| x.ends_with(cdigest))
-                .await?
-                .context(error::BlobMissingSnafu {
-                    digest: image.config().digest(),
-                })?;
-            let config_size = config_entry
-                .header()
-                .entry_size()
-                .context(error::ArchiveSnafu)?;
+        let uri = ctx.write_uri(self.uri.as_str(), self.insecure).await?;
+        #[cfg(feature = "aws")]
+        if self.create_repo {
+            uri.registry().create_repository(uri.repository()).await?;
+        }
+        let progress = ctx.bars();
+        let (layout, index) = Layout::read(&self.archive).await?;
+        let mut report = Report::new(self.archive.display().to_string(), uri.to_string());
+        let transfers = Arc::new(Transfers::default());
+        // Images are pushed concurrently, blobs shared between them are only uploaded once
+        let mut images = futures::stream::iter(index.manifests().iter().cloned().map(|manifest| {
+            let layout = layout.clone();
+            let uri = uri.clone();
+            let progress = progress.clone();
+            let transfers = transfers.clone();
+            tokio::spawn(push_image(layout, uri, manifest, progress, transfers))
+        }))
+        .buffer_unordered(self.jobs.max(1));
+        while let Some(result) = images.next().await {
+            report.image(result.context(error::LayerWaitSnafu)??);
+        }
+        // Now that all the layers are uploaded we can push the image
+        let pushed = index.push(&uri).await?;
+        report.finish(pushed.digest(), &transfers);
+        report.write(self.report.as_deref(), ctx.output()).await?;
 
+        Ok(())
+    }
+}
+
+/// Push the config, layers and manifest of a single image from the archive or directory.
+pub(crate) async fn push_image(
+    layout: Layout,
+    uri: Uri,
+    manifest: Layer,
+    progress: Arc<dyn ProgressSink>,
+    transfers: Arc<Transfers>,
+) -> Result<ImageReport, error::Error> {
+    let mut buffer = Vec::new();
+    layout
+        .blob(manifest.digest())
+        .await?
+        .0
+        .read_to_end(&mut buffer)
+        .await
+        .context(error::ArchiveSnafu)?;
+    let image: Image =
+        serde_json::from_slice(buffer.as_slice()).context(error::ImageInvalidManifestSnafu)?;
+    // First lets copy the config blob
+    let (mut config_entry, config_size) = layout.blob(image.config().digest()).await?;
+
+    let mut writer = Layer::create_progress(
+        &uri,
+        image.config().media_type(),
+        config_size,
+        progress.clone(),
+        Some(image.config().digest().to_string()),
+    )
+    .await?;
+    if let Some(writer) = writer.as_mut() {
+        Layer::copy(&mut config_entry, writer, config_size as usize).await?;
+        writer.layer().await?;
+        transfers.uploaded(config_size);
+    } else {
+        transfers.skipped();
+    }
+    let mut tasks: Vec<JoinHandle<Result<(), error::Error>>> = Vec::new();
+    // Copy all the blobs
+    for layer in image.layers().iter() {
+        let layout = layout.clone();
+        let layer = layer.clone();
+        let uri = uri.clone();
+        let progress = progress.clone();
+        let transfers = transfers.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = uri.registry().transfer_permit().await;
+            let (mut layer_entry, layer_size) = layout.blob(layer.digest()).await?;
             let mut writer = Layer::create_progress(
                 &uri,
-                image.config().media_type(),
-                format!("blob {}", &cdigest[0..9]).as_str(),
-                config_size,
-                multi,
-                Some(image.config().digest().to_string()),
+                layer.media_type(),
+                layer_size,
+                progress,
+                Some(layer.digest().to_string()),
             )
             .await?;
             if let Some(writer) = writer.as_mut() {
-                Layer::copy(&mut config_entry, writer, config_size as usize).await?;
+                Layer::copy(&mut layer_entry, writer, layer_size as usize).await?;
                 writer.layer().await?;
+                transfers.uploaded(layer_size);
+            } else {
+                transfers.skipped();
             }
-            let mut tasks: Vec<JoinHandle<Result<(), error::Error>>> = Vec::new();
-            // Copy all the blobs
-            for layer in image.layers().iter() {
-                let mut larchive = File::open(&self.archive).await.context(error::FileSnafu)?;
-                let layer = layer.clone();
-                let uri = uri.clone();
-                let mut multi = multi.clone();
-                tasks.push(tokio::spawn(async move {
-                    let ldigest = layer.digest().split_once(":").unwrap().1;
-                    let mut layer_entry = afind(&mut larchive, |x| x.ends_with(ldigest))
-                        .await?
-                        .context(error::BlobMissingSnafu {
-                            digest: layer.digest(),
-                        })?;
-                    let layer_size = layer_entry
-                        .header()
-                        .entry_size()
-                        .context(error::ArchiveSnafu)?;
-                    let mut writer = Layer::create_progress(
-                        &uri,
-                        layer.media_type(),
-                        format!("blob {}", &ldigest[0..9]).as_str(),
-                        layer_size,
-                        &mut multi,
-                        Some(layer.digest().to_string()),
-                    )
-                    .await?;
-                    if let Some(writer) = writer.as_mut() {
-                        Layer::copy(&mut layer_entry, writer, layer_size as usize).await?;
-                        writer.layer().await?;
+            Ok(())
+        }));
+    }
+    for result in join_all(tasks).await {
+        let result = result.expect("failed to join");
+        result?;
+    }
+    let manifest_uri = Uri::builder()
+        .registry(uri.registry().clone())
+        .repository(uri.repository())
+        .reference(Reference::from_str(manifest.digest())?)
+        .build();
+    // Push the manifest as read so it keeps the digest the index refers to it by
+    uri.registry()
+        .push_manifest_raw(
+            image.media_type(),
+            uri.repository(),
+            manifest.digest(),
+            Bytes::from(buffer),
+            manifest.platform(),
+        )
+        .await?;
+    Ok(ImageReport {
+        source: format!("{}@{}", layout.path().display(), manifest.digest()),
+        destination: manifest_uri.to_string(),
+        platform: manifest.platform().map(|x| x.to_string()),
+        status: ImageStatus::Copied,
+    })
+}
+
+/// Location of the images being pushed, either an OCI archive or an unpacked OCI layout directory.
+#[derive(Debug, Clone)]
+pub(crate) enum Layout {
+    Archive(PathBuf),
+    Directory(PathBuf),
+    /// Docker-save tarball, converted to an OCI layout in a temporary directory
+    Docker {
+        archive: PathBuf,
+        layout: Arc<TempDir>,
+    },
+}
+
+impl Layout {
+    /// Open an OCI archive or layout directory and read its root index
+    pub(crate) async fn read(path: &Path) -> Result<(Self, Index), error::Error> {
+        let mut layout = if path.is_dir() {
+            Self::Directory(path.to_path_buf())
+        } else {
+            Self::Archive(path.to_path_buf())
+        };
+        if layout.open("index.json").await?.is_none()
+            && layout.open("manifest.json").await?.is_some()
+        {
+            let directory = tempdir().context(error::TempSnafu)?;
+            docker_to_oci_layout(path, directory.path()).await?;
+            layout = Self::Docker {
+                archive: path.to_path_buf(),
+                layout: Arc::new(directory),
+            };
+        }
+        let mut buffer = Vec::new();
+        layout
+            .open("index.json")
+            .await?
+            .context(error::ImageNotValidSnafu {})?
+            .0
+            .read_to_end(&mut buffer)
+            .await
+            .context(error::ArchiveSnafu)?;
+        let index: Index =
+            serde_json::from_slice(buffer.as_slice()).context(error::ImageInvalidIndexSnafu)?;
+        let index = find_index(&layout, &index).await?;
+        Ok((layout, index))
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::Archive(path) | Self::Directory(path) => path,
+            Self::Docker { archive, .. } => archive,
+        }
+    }
+
+    /// Open a file of the layout by its path relative to the root of the layout, along with its size
+    async fn open(&self, name: &str) -> Result<Option<(Blob, u64)>, error::Error> {
+        match self {
+            Self::Archive(path) => {
+                let file = File::open(path).await.context(error::FileSnafu)?;
+                match afind(file, |x| x.ends_with(name)).await? {
+                    Some(entry) => {
+                        let size = entry.header().entry_size().context(error::ArchiveSnafu)?;
+                        Ok(Some((Box::new(entry), size)))
                     }
-                    Ok(())
-                }));
-            }
-            for result in join_all(tasks).await {
-                let result = result.expect("failed to join");
-                result?;
+                    None => Ok(None),
+                }
             }
-            let manifest_uri = Uri::builder()
-                .registry(uri.registry().clone())
-                .repository(uri.repository())
-                .reference(Reference::from_str(manifest.digest())?)
-                .build();
-            image.push(&manifest_uri).await?;
+            Self::Directory(path) => open_file(&path.join(name)).await,
+            Self::Docker { layout, .. } => open_file(&layout.path().join(name)).await,
         }
-        // Now that all the layers are uploaded we can push the image
-        index.push(&uri).await?;
+    }
 
-        Ok(())
+    /// Open a blob of the layout by its digest, along with its size
+    async fn blob(&self, digest: &str) -> Result<(Blob, u64), error::Error> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .context(error::BlobMissingSnafu { digest })?;
+        self.open(&format!("blobs/{algorithm}/{hex}"))
+            .await?
+            .context(error::BlobMissingSnafu { digest })
     }
 }
 
-async fn afind<F>(
-    archive: &mut File,
+/// Content of a blob read from an archive entry or a file.
+type Blob = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Open a file of a layout directory along with its size, if it exists
+async fn open_file(path: &Path) -> Result<Option<(Blob, u64)>, error::Error> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let file = File::open(path).await.context(error::FileSnafu)?;
+    let size = file.metadata().await.context(error::FileSnafu)?.len();
+    Ok(Some((Box::new(file), size)))
+}
+
+async fn afind<R, F>(
+    mut archive: R,
     predicate: F,
-) -> Result<Option<Entry<Archive<&mut File>>>, error::Error>
+) -> Result<Option<Entry<Archive<R>>>, error::Error>
 where
+    R: AsyncRead + AsyncSeek + Unpin + Send,
     F: Fn(&Path) -> bool,
 {
     archive
@@ -162,19 +290,15 @@ where
     Ok(None)
 }
 
-/// Find the root index from an OCI archive.
+/// Find the root index from an OCI archive or layout directory.
 #[async_recursion]
-async fn find_index<'a>(archive: &'a mut File, index: &Index) -> Result<Index, error::Error> {
+async fn find_index(layout: &Layout, index: &Index) -> Result<Index, error::Error> {
     for manifest in index.manifests().iter() {
-        let digest = manifest.digest().split_once(':').unwrap().1;
-        let mut blob_entry =
-            afind(archive, |x| x.ends_with(digest))
-                .await?
-                .context(error::BlobMissingSnafu {
-                    digest: manifest.digest(),
-                })?;
         let mut buffer = Vec::new();
-        blob_entry
+        layout
+            .blob(manifest.digest())
+            .await?
+            .0
             .read_to_end(&mut buffer)
             .await
             .context(error::ArchiveSnafu)?;
@@ -187,7 +311,7 @@ async fn find_index<'a>(archive: &'a mut File, index: &Index) -> Result<Index, e
                 // Nested image index so recurse
                 let next: Index =
                     serde_json::from_value(value.clone()).context(error::ImageInvalidIndexSnafu)?;
-                return find_index(archive, &next).await;
+                return find_index(layout, &next).await;
             } else {
                 // Non-index this is our root
                 return Ok(index.clone());
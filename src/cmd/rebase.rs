@@ -0,0 +1,52 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use snafu::OptionExt;
+
+use super::context::{Ctx, tag_target};
+
+/// Swap the base image of an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Replace the base image layers of an image with those of a new base", long_about = None)]
+pub struct Rebase {
+    /// Reference to the image to rebase, i.e. reg.example/app:1.2.3
+    image: String,
+    /// Base image the image was built on, i.e. reg.example/base:1.0
+    #[arg(long)]
+    old_base: String,
+    /// Base image to rebase onto, i.e. reg.example/base:1.1
+    #[arg(long)]
+    new_base: String,
+    /// Tag to push the new image to in the same repository, by default the tag of the image
+    #[arg(short, long)]
+    tag: Option<String>,
+    /// Platform to select as os[(os.version)]/architecture[/variant], i.e. linux/arm/v7
+    #[arg(short, long)]
+    platform: Option<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Rebase {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx.write_uri(self.image.as_str(), self.insecure).await?;
+        let old_base = ctx
+            .read_uri(self.old_base.as_str(), self.insecure, false)
+            .await?;
+        let new_base = ctx
+            .read_uri(self.new_base.as_str(), self.insecure, false)
+            .await?;
+        let image = Manifest::fetch(&uri)
+            .await?
+            .fetch_image(&uri, self.platform.clone())
+            .await?
+            .context(error::ImageNotFoundSnafu {
+                uri: uri.to_string(),
+            })?;
+        let target = tag_target(&uri, self.tag.as_deref())?;
+        let pushed = image.rebase(&uri, &old_base, &new_base, &target).await?;
+        ctx.pushed(&target, &pushed)?;
+        Ok(())
+    }
+}
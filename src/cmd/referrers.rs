@@ -0,0 +1,46 @@
+use clap::Parser;
+use ocilot::uri::Reference;
+use ocilot::{Result, error};
+use snafu::ensure;
+
+use super::context::Ctx;
+
+/// List the artifacts referring to an image.
+#[derive(Parser, Debug)]
+#[clap(version, about = "List the artifacts referring to a manifest, i.e. signatures and SBOMs", long_about = None)]
+pub struct Referrers {
+    /// Uri of the manifest by digest, i.e. registry/repository@sha256:...
+    url: String,
+    /// Only list referrers of this artifact type
+    #[arg(short, long)]
+    artifact_type: Option<String>,
+    #[arg(short, long)]
+    insecure: bool,
+    /// Serve all content from the local store without any network calls
+    #[arg(long)]
+    offline: bool,
+}
+
+impl Referrers {
+    pub async fn run(&self, ctx: &Ctx) -> Result<()> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, self.offline)
+            .await?;
+        ensure!(
+            matches!(uri.reference(), Reference::Digest { .. }),
+            error::DirectLoadImageSnafu {
+                uri: uri.to_string()
+            }
+        );
+        let index = uri
+            .registry()
+            .referrers(
+                uri.repository(),
+                uri.reference().to_string().as_str(),
+                self.artifact_type.as_deref(),
+            )
+            .await?;
+        ctx.print_document(&index)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use ocilot::error;
+use serde::Serialize;
+use snafu::ResultExt;
+
+use crate::OutputFormat;
+
+/// Summary of a copy, push or sync for pipeline steps that need the pinned digests downstream.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Reference that was copied, archive that was pushed or repository that was synced
+    source: String,
+    /// Reference that was written to
+    destination: String,
+    /// Digest of the index at the destination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    images: Vec<ImageReport>,
+    bytes_transferred: u64,
+    blobs_uploaded: usize,
+    blobs_skipped: usize,
+}
+
+/// Outcome of copying or pushing a single image.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageReport {
+    pub source: String,
+    pub destination: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    pub status: ImageStatus,
+}
+
+/// Whether an image was transferred, was already present at the destination or could not be
+/// copied.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageStatus {
+    Copied,
+    Skipped,
+    Failed,
+}
+
+/// Blob transfer counters shared between the tasks of a copy or push.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    bytes: AtomicU64,
+    uploaded: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl Transfers {
+    /// Count a blob that was uploaded
+    pub fn uploaded(&self, size: u64) {
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.uploaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a blob the destination already had
+    pub fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Report {
+    pub fn new(source: impl Into<String>, destination: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            destination: destination.into(),
+            digest: None,
+            images: Vec::new(),
+            bytes_transferred: 0,
+            blobs_uploaded: 0,
+            blobs_skipped: 0,
+        }
+    }
+
+    /// Add the outcome of an image
+    pub fn image(&mut self, image: ImageReport) {
+        self.images.push(image);
+    }
+
+    /// Record the digest of the destination index and the totals of the blob transfers
+    pub fn finish(&mut self, digest: impl Into<String>, transfers: &Transfers) {
+        self.digest = Some(digest.into());
+        self.totals(transfers);
+    }
+
+    /// Record the totals of the blob transfers when there is no single digest at the destination,
+    /// i.e. when syncing every tag of a repository
+    pub fn totals(&mut self, transfers: &Transfers) {
+        self.bytes_transferred = transfers.bytes.load(Ordering::Relaxed);
+        self.blobs_uploaded = transfers.uploaded.load(Ordering::Relaxed);
+        self.blobs_skipped = transfers.skipped.load(Ordering::Relaxed);
+    }
+
+    /// Write the report as json to the file ('-' writes to stdout), or print it when no file is
+    /// provided: as a line of json with JSON output or a short summary otherwise
+    pub async fn write(
+        &self,
+        path: Option<&Path>,
+        output: OutputFormat,
+    ) -> Result<(), error::Error> {
+        match path {
+            Some(path) => {
+                let json = serde_json::to_string_pretty(self).context(error::SerializeSnafu)?;
+                if path.as_os_str() == "-" {
+                    println!("{json}");
+                } else {
+                    tokio::fs::write(path, json)
+                        .await
+                        .context(error::FileSnafu)?;
+                }
+            }
+            None if output == OutputFormat::Json => println!(
+                "{}",
+                serde_json::to_string(self).context(error::SerializeSnafu)?
+            ),
+            None => println!(
+                "{} -> {}: {} bytes in {} blobs transferred, {} blobs skipped",
+                self.source,
+                self.digest.as_deref().unwrap_or(&self.destination),
+                self.bytes_transferred,
+                self.blobs_uploaded,
+                self.blobs_skipped
+            ),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use ocilot::artifact::{Sbom, SbomFormat};
+use ocilot::error;
+use snafu::{OptionExt, ResultExt};
+use tokio::io::AsyncWriteExt;
+
+use super::context::Ctx;
+
+/// Manage the SBOMs attached to images.
+#[derive(Parser, Debug)]
+#[command(version, about = "Commands to attach and get the SBOM of an image", long_about = None)]
+pub struct SbomCmd {
+    #[clap(subcommand)]
+    command: SbomCommands,
+}
+
+/// SBOM subcommands.
+#[derive(Parser, Debug)]
+pub enum SbomCommands {
+    Attach(AttachSbom),
+    Get(GetSbom),
+}
+
+impl SbomCmd {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        match &self.command {
+            SbomCommands::Attach(cmd) => cmd.run(ctx).await,
+            SbomCommands::Get(cmd) => cmd.run(ctx).await,
+        }
+    }
+}
+
+/// Format of an SBOM document.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Spdx,
+    Cyclonedx,
+}
+
+impl From<Format> for SbomFormat {
+    fn from(value: Format) -> Self {
+        match value {
+            Format::Spdx => Self::Spdx,
+            Format::Cyclonedx => Self::CycloneDx,
+        }
+    }
+}
+
+/// Attach an SBOM to an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Attach an SBOM document to an image as a referrer artifact", long_about = None)]
+pub struct AttachSbom {
+    url: String,
+    /// SPDX or CycloneDX JSON document
+    sbom: PathBuf,
+    /// Format of the document, detected from its content when omitted
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl AttachSbom {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let content = tokio::fs::read(&self.sbom)
+            .await
+            .context(error::FileSnafu)?;
+        let sbom = Sbom::new(content, self.format.map(SbomFormat::from))?;
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
+        let artifact = sbom.attach(&uri).await?;
+        ctx.pushed(&uri, &artifact)
+    }
+}
+
+/// Get the SBOM of an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Get the SBOM attached last to an image", long_about = None)]
+pub struct GetSbom {
+    url: String,
+    /// Write the document to this file instead of stdout
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl GetSbom {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, false)
+            .await?;
+        let sbom = Sbom::fetch(&uri).await?.context(error::NoSbomSnafu {
+            uri: uri.to_string(),
+        })?;
+        match self.file.as_ref() {
+            Some(path) => tokio::fs::write(path, sbom.content())
+                .await
+                .context(error::FileSnafu),
+            None => {
+                let mut stdout = tokio::io::stdout();
+                stdout
+                    .write_all(sbom.content())
+                    .await
+                    .context(error::FileSnafu)?;
+                stdout.flush().await.context(error::FileSnafu)
+            }
+        }
+    }
+}
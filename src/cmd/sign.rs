@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::signing::{self, SignatureMode, SigningKey};
+use snafu::ResultExt;
+
+use super::context::Ctx;
+
+/// Sign an image with a key pair.
+#[derive(Parser, Debug)]
+#[command(version, about = "Sign an image and attach the signature in the cosign format", long_about = None)]
+pub struct Sign {
+    url: String,
+    /// PEM encoded PKCS#8 ECDSA P-256 private key to sign with
+    #[arg(short, long)]
+    key: PathBuf,
+    /// Attach the signature as a referrer of the image instead of in the `sha256-<hex>.sig` tag
+    #[arg(long)]
+    referrers: bool,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Sign {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let pem = tokio::fs::read_to_string(&self.key)
+            .await
+            .context(error::FileSnafu)?;
+        let key = SigningKey::from_pem(&pem)?;
+        let uri = ctx.write_uri(self.url.as_str(), self.insecure).await?;
+        let mode = if self.referrers {
+            SignatureMode::Referrers
+        } else {
+            SignatureMode::Tag
+        };
+        let signature = signing::sign(&uri, &key, mode).await?;
+        ctx.pushed(&uri, &signature)
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::context::Ctx;
+use super::copy::mirror;
+use super::report::{ImageReport, ImageStatus, Report, Transfers};
+use clap::Parser;
+use futures::StreamExt;
+use ocilot::{
+    Result, error,
+    progress::ProgressSink,
+    repository::{Repository, TagOptions},
+    uri::{Reference, Uri},
+};
+use snafu::ResultExt;
+
+/// Mirror the tags of a repository to another repository.
+#[derive(Parser, Debug)]
+#[command(version, about = "Mirror every tag of a repo to another repo, only copying the tags whose digest differs at the destination", long_about = None)]
+pub struct Sync {
+    /// Repository to mirror, i.e. source.io/mysource
+    source: String,
+    /// Repository to mirror the tags to
+    target: String,
+    #[arg(short, long)]
+    source_insecure: bool,
+    #[arg(short, long)]
+    target_insecure: bool,
+    /// Only sync tags matching the glob, i.e. 'v1.*'
+    #[arg(long)]
+    filter: Option<String>,
+    /// Maximum number of tags to copy concurrently
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    /// Write a json summary of the sync to this file ('-' writes to stdout)
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Create the repository before pushing when the registry does not create it on push (ECR)
+    #[cfg(feature = "aws")]
+    #[arg(long)]
+    create_repo: bool,
+}
+
+impl Sync {
+    pub async fn run(&self, ctx: &Ctx) -> Result<()> {
+        let (registry_uri, repository) = Uri::split_repository(&self.source)?;
+        let mut registry = ctx.registry(&registry_uri).await?;
+        registry.set_secure(!self.source_insecure);
+        registry.set_limiter(ctx.limiter());
+        registry.set_store(ctx.cache());
+        let source = Repository::new(&registry, &repository);
+        let target = ctx
+            .write_repository(&self.target, self.target_insecure)
+            .await?;
+        #[cfg(feature = "aws")]
+        if self.create_repo {
+            target.registry().create_repository(target.name()).await?;
+        }
+        let tags = source
+            .tags(
+                &TagOptions::builder()
+                    .maybe_filter(self.filter.clone())
+                    .build(),
+            )
+            .await?;
+        let progress = ctx.bars();
+        let transfers = Arc::new(Transfers::default());
+        let mut report = Report::new(self.source.as_str(), self.target.as_str());
+        // Tags are copied concurrently but reported in order, blobs shared between them are only
+        // uploaded once
+        let mut tasks = futures::stream::iter(tags.into_iter().map(|tag| {
+            let source = tag_uri(&source, &tag);
+            let target = tag_uri(&target, &tag);
+            let progress = progress.clone();
+            let transfers = transfers.clone();
+            tokio::spawn(async move {
+                let status = sync_tag(&source, &target, &progress, &transfers).await;
+                (source, target, status)
+            })
+        }))
+        .buffered(self.jobs.max(1));
+        let mut failure = None;
+        while let Some(result) = tasks.next().await {
+            let (source, target, status) = result.context(error::LayerWaitSnafu)?;
+            let status = status.unwrap_or_else(|e| {
+                eprintln!("failed to sync {source}: {e}");
+                failure = Some(e);
+                ImageStatus::Failed
+            });
+            report.image(ImageReport {
+                source: source.to_string(),
+                destination: target.to_string(),
+                platform: None,
+                status,
+            });
+        }
+        report.totals(&transfers);
+        report.write(self.report.as_deref(), ctx.output()).await?;
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Uri of a tag in the repository
+fn tag_uri(repository: &Repository, tag: &str) -> Uri {
+    Uri::builder()
+        .registry(repository.registry().clone())
+        .repository(repository.name())
+        .reference(Reference::Tag(tag.to_string()))
+        .build()
+}
+
+/// Copy a tag unless the target tag already points to a manifest with the same digest
+async fn sync_tag(
+    source: &Uri,
+    target: &Uri,
+    progress: &Arc<dyn ProgressSink>,
+    transfers: &Arc<Transfers>,
+) -> Result<ImageStatus> {
+    let tag = source.reference().to_string();
+    let digest = source
+        .registry()
+        .resolve_digest(source.repository(), &tag)
+        .await?;
+    // A tag missing at the destination fails to resolve and is copied
+    let current = target
+        .registry()
+        .resolve_digest(target.repository(), &tag)
+        .await
+        .ok();
+    if current.as_deref() == Some(digest.as_str()) {
+        eprintln!("{tag} {digest}: skipped, up to date");
+        return Ok(ImageStatus::Skipped);
+    }
+    let (digest, _) = mirror(source, target, progress, transfers).await?;
+    eprintln!("{tag} {digest}: copied");
+    Ok(ImageStatus::Copied)
+}
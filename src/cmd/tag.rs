@@ -0,0 +1,46 @@
+use clap::Parser;
+use ocilot::error;
+use ocilot::repository::Repository;
+use ocilot::uri::Reference;
+use snafu::ensure;
+
+use super::context::Ctx;
+
+/// Tag an existing manifest without transferring any blobs.
+#[derive(Parser, Debug)]
+#[command(version, about = "Add a tag to an existing manifest in the same repository", long_about = None)]
+pub struct Tag {
+    /// Reference to the existing manifest, i.e. reg.example/app:1.2.3
+    source: String,
+    /// Reference with the new tag, i.e. reg.example/app:latest
+    target: String,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Tag {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let target = ctx.write_uri(self.target.as_str(), self.insecure).await?;
+        let source = ctx
+            .read_uri(self.source.as_str(), self.insecure, false)
+            .await?;
+        let mismatch = || error::TagRepositorySnafu {
+            from: self.source.clone(),
+            to: self.target.clone(),
+        };
+        ensure!(
+            source.registry().uri().base() == target.registry().uri().base()
+                && source.repository() == target.repository(),
+            mismatch()
+        );
+        let Reference::Tag(tag) = target.reference() else {
+            return mismatch().fail();
+        };
+        let repository = Repository::new(target.registry(), target.repository());
+        let tagged = repository
+            .tag(source.reference().to_string().as_str(), tag)
+            .await?;
+        ctx.pushed(&target, &tagged)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::signing::{self, VerifyingKey};
+use serde_json::json;
+use snafu::ResultExt;
+
+use super::context::Ctx;
+
+/// Verify the signatures of an image.
+#[derive(Parser, Debug)]
+#[command(version, about = "Verify the cosign signatures of an image with a public key", long_about = None)]
+pub struct Verify {
+    url: String,
+    /// PEM encoded ECDSA P-256 public key, i.e. cosign.pub
+    #[arg(short, long)]
+    key: PathBuf,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+impl Verify {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        let pem = tokio::fs::read_to_string(&self.key)
+            .await
+            .context(error::FileSnafu)?;
+        let key = VerifyingKey::from_pem(&pem)?;
+        let uri = ctx
+            .read_uri(self.url.as_str(), self.insecure, false)
+            .await?;
+        let signatures = signing::verify(&uri, &key).await?;
+        ctx.print(
+            &json!({ "reference": uri.to_string(), "signatures": signatures }),
+            signatures
+                .iter()
+                .map(|x| format!("verified signature {} of {}", x.payload, x.identity))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    }
+}
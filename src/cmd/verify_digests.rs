@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use clap::Parser;
+use ocilot::error;
+use ocilot::image::{BlobProblem, Image};
+use ocilot::manifest::Manifest;
+use ocilot::models::Platform;
+use ocilot::uri::{Reference, Uri};
+use serde::Serialize;
+use serde_json::json;
+use snafu::ensure;
+
+use super::context::Ctx;
+
+/// Check the blobs of an image against their digests.
+#[derive(Parser, Debug)]
+#[command(version, about = "Download every blob of an image, checking their digests, sizes and the diff ids of the config, i.e. after a registry migration", long_about = None)]
+pub struct VerifyDigests {
+    url: String,
+    /// Only verify the images of an index for these platforms
+    #[arg(short, long)]
+    platform: Vec<Platform>,
+    #[arg(short, long)]
+    insecure: bool,
+}
+
+/// Outcome of verifying an image.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageVerification {
+    reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    platform: Option<String>,
+    blobs: usize,
+    problems: Vec<BlobProblem>,
+}
+
+impl VerifyDigests {
+    pub async fn run(&self, ctx: &Ctx) -> Result<(), error::Error> {
+        // Blobs are always downloaded from the registry, never served from the local store
+        let mut uri = ctx.uri(self.url.as_str()).await?;
+        uri.set_secure(!self.insecure);
+        uri.set_progress(Some(ctx.bars()));
+        uri.set_limiter(ctx.limiter());
+        let images = match Manifest::fetch(&uri).await? {
+            Manifest::Image(image) => vec![(uri.clone(), *image)],
+            Manifest::Index(index) => {
+                let index = if self.platform.is_empty() {
+                    index
+                } else {
+                    index.filter_platforms(&self.platform)?
+                };
+                let mut images = Vec::new();
+                for manifest in index.images().chain(index.artifacts()) {
+                    let image_uri = Uri::builder()
+                        .registry(uri.registry().clone())
+                        .repository(uri.repository())
+                        .reference(Reference::from_str(manifest.digest())?)
+                        .build();
+                    let image = Image::fetch(&image_uri, manifest.platform()).await?;
+                    images.push((image_uri, image));
+                }
+                images
+            }
+        };
+        let mut verifications = Vec::with_capacity(images.len());
+        for (image_uri, image) in images.iter() {
+            verifications.push(ImageVerification {
+                reference: image_uri.to_string(),
+                platform: image.platform().map(|x| x.to_string()),
+                blobs: image.layers().len() + 1,
+                problems: image.verify_digests(image_uri).await?,
+            });
+        }
+        let problems = verifications
+            .iter()
+            .map(|x| x.problems.len())
+            .sum::<usize>();
+        ctx.print(
+            &json!({ "reference": uri.to_string(), "images": verifications }),
+            verifications
+                .iter()
+                .flat_map(|x| match x.problems.is_empty() {
+                    true => vec![format!("{}: {} blobs verified", x.reference, x.blobs)],
+                    false => x
+                        .problems
+                        .iter()
+                        .map(|problem| {
+                            format!("{} {}: {}", x.reference, problem.digest, problem.reason)
+                        })
+                        .collect(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )?;
+        ensure!(
+            problems == 0,
+            error::CorruptImageSnafu {
+                uri: uri.to_string(),
+                problems,
+            }
+        );
+        Ok(())
+    }
+}
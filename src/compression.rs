@@ -1,44 +1,40 @@
 use std::pin::Pin;
 
+use std::path::Path;
+
 use async_compression::tokio::bufread::{
     BzDecoder, GzipDecoder, LzmaDecoder, XzDecoder, ZstdDecoder,
 };
-use tokio::io::AsyncRead;
+use async_compression::tokio::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
+use snafu::{ResultExt, ensure};
+use tokio::fs::File;
 use tokio::io::BufReader;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::{
+    error,
     layer::Reader,
     models::{Compression, MediaType},
 };
 
 pub struct Decompress {
-    inner: Pin<Box<dyn AsyncRead>>,
+    inner: Pin<Box<dyn AsyncRead + Send + Sync>>,
 }
 
-unsafe impl Send for Decompress {}
-unsafe impl Sync for Decompress {}
-
 impl Decompress {
     pub fn new(media: &MediaType, reader: Reader) -> Self {
         Self {
             inner: match media {
-                MediaType::DockerImageRootfs(compression) => match compression {
-                    Compression::Gzip | Compression::None => {
-                        Box::pin(GzipDecoder::new(BufReader::new(reader)))
+                MediaType::DockerImageRootfs(compression) | MediaType::Layer(compression) => {
+                    match compression {
+                        Compression::Gzip => Box::pin(GzipDecoder::new(BufReader::new(reader))),
+                        Compression::Bzip2 => Box::pin(BzDecoder::new(BufReader::new(reader))),
+                        Compression::Lz4 => Box::pin(LzmaDecoder::new(BufReader::new(reader))),
+                        Compression::Xz => Box::pin(XzDecoder::new(BufReader::new(reader))),
+                        Compression::Zstd => Box::pin(ZstdDecoder::new(BufReader::new(reader))),
+                        Compression::None => Box::pin(BufReader::new(reader)),
                     }
-                    Compression::Bzip2 => Box::pin(BzDecoder::new(BufReader::new(reader))),
-                    Compression::Lz4 => Box::pin(LzmaDecoder::new(BufReader::new(reader))),
-                    Compression::Xz => Box::pin(XzDecoder::new(BufReader::new(reader))),
-                    Compression::Zstd => Box::pin(ZstdDecoder::new(BufReader::new(reader))),
-                },
-                MediaType::Layer(compression) => match compression {
-                    Compression::Gzip => Box::pin(GzipDecoder::new(BufReader::new(reader))),
-                    Compression::Bzip2 => Box::pin(BzDecoder::new(BufReader::new(reader))),
-                    Compression::Lz4 => Box::pin(LzmaDecoder::new(BufReader::new(reader))),
-                    Compression::Xz => Box::pin(XzDecoder::new(BufReader::new(reader))),
-                    Compression::Zstd => Box::pin(ZstdDecoder::new(BufReader::new(reader))),
-                    Compression::None => Box::pin(BufReader::new(reader)),
-                },
+                }
                 _ => Box::pin(BufReader::new(reader)),
             },
         }
@@ -55,3 +51,79 @@ impl AsyncRead for Decompress {
         this.inner.as_mut().poll_read(cx, buf)
     }
 }
+
+/// Compresses everything written to it with the compression of a layer, the writer has to be shut
+/// down to write out the end of the compressed stream.
+pub struct Compress {
+    inner: Pin<Box<dyn AsyncWrite + Send>>,
+}
+
+impl Compress {
+    /// Wrap the writer, fails for compressions layers can not be written with
+    pub fn new(
+        compression: &Compression,
+        writer: impl AsyncWrite + Send + 'static,
+    ) -> crate::Result<Self> {
+        Ok(Self {
+            inner: match compression {
+                Compression::Gzip => Box::pin(GzipEncoder::new(writer)),
+                Compression::Zstd => Box::pin(ZstdEncoder::new(writer)),
+                Compression::Bzip2 => Box::pin(BzEncoder::new(writer)),
+                Compression::Xz => Box::pin(XzEncoder::new(writer)),
+                Compression::None => Box::pin(writer),
+                Compression::Lz4 => {
+                    return error::UnsupportedCompressionSnafu {
+                        compression: compression.clone(),
+                    }
+                    .fail();
+                }
+            },
+        })
+    }
+}
+
+impl AsyncWrite for Compress {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.get_mut().inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Compress a local file into another file, only gzip and zstd are accepted as those are the
+/// compressions of oci layers
+pub(crate) async fn compress_file(
+    input: &Path,
+    output: &Path,
+    compression: &Compression,
+) -> crate::Result<()> {
+    ensure!(
+        matches!(compression, Compression::Gzip | Compression::Zstd),
+        error::UnsupportedCompressionSnafu {
+            compression: compression.clone(),
+        }
+    );
+    let mut input = File::open(input).await.context(error::FileSnafu)?;
+    let output = File::create(output).await.context(error::FileSnafu)?;
+    let mut encoder = Compress::new(compression, output)?;
+    tokio::io::copy(&mut input, &mut encoder)
+        .await
+        .context(error::LayerCopySnafu)?;
+    encoder.shutdown().await.context(error::FileSnafu)
+}
@@ -0,0 +1,282 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use base64::Engine;
+use home::home_dir;
+use keyring::Entry;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt, ensure};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::client::{RegistryClient, http_client};
+use crate::error;
+use crate::models::{DockerConfig, Token};
+use crate::options::{RegistryOptions, RetryPolicy, Settings};
+use crate::uri::RegistryUri;
+
+/// Key Docker Hub credentials are stored under in the docker config.
+pub(crate) const DOCKER_HUB_AUTH: &str = "https://index.docker.io/v1/";
+/// Docker config credentials are persisted to, relative to the home directory.
+const DOCKER_CONFIG: &str = ".docker/config.json";
+/// Keychain service docker credential helpers store credentials under.
+pub(crate) const KEYRING_SERVICE: &str = "docker-credential-helpers";
+/// Username credential helpers report when the secret is an identity token.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// Credentials exchanged with a docker credential helper.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    #[serde(
+        rename = "ServerURL",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    server_url: String,
+    username: String,
+    secret: String,
+}
+
+impl From<HelperCredentials> for Token {
+    fn from(value: HelperCredentials) -> Self {
+        if value.username == IDENTITY_TOKEN_USERNAME {
+            Token::Bearer(value.secret)
+        } else {
+            Token::Basic {
+                username: value.username,
+                password: value.secret,
+            }
+        }
+    }
+}
+
+/// Get the credentials for a server from the docker credential helper `docker-credential-{helper}`.
+///
+/// Helpers that are not installed or have no credentials for the server resolve to `None` so
+/// discovery can carry on, only output that can't be understood is an error.
+pub async fn helper_get(helper: &str, server: &str) -> crate::Result<Option<Token>> {
+    let program = format!("docker-credential-{helper}");
+    let child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            debug!(target: "registry", "credential helper {program} could not be run: {e}");
+            return Ok(None);
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(server.as_bytes())
+            .await
+            .context(error::CredentialHelperIoSnafu { helper: &program })?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .context(error::CredentialHelperIoSnafu { helper: &program })?;
+    if !output.status.success() {
+        debug!(target: "registry", "credential helper {program} has no credentials for {server}: {}", String::from_utf8_lossy(&output.stdout).trim());
+        return Ok(None);
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .context(error::CredentialHelperOutputSnafu { helper: &program })?;
+    Ok(Some(credentials.into()))
+}
+
+/// Store credentials for a server with the docker credential helper `docker-credential-{helper}`.
+pub async fn helper_store(
+    helper: &str,
+    server: &str,
+    username: &str,
+    secret: &str,
+) -> crate::Result<()> {
+    let input = serde_json::to_vec(&HelperCredentials {
+        server_url: server.to_string(),
+        username: username.to_string(),
+        secret: secret.to_string(),
+    })
+    .context(error::SerializeSnafu)?;
+    run_helper(helper, "store", &input).await
+}
+
+/// Erase the credentials for a server from the docker credential helper `docker-credential-{helper}`.
+pub async fn helper_erase(helper: &str, server: &str) -> crate::Result<()> {
+    run_helper(helper, "erase", server.as_bytes()).await
+}
+
+/// Run a credential helper action that only reports failures
+async fn run_helper(helper: &str, action: &str, input: &[u8]) -> crate::Result<()> {
+    let program = format!("docker-credential-{helper}");
+    let mut child = Command::new(&program)
+        .arg(action)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(error::CredentialHelperIoSnafu { helper: &program })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input)
+            .await
+            .context(error::CredentialHelperIoSnafu { helper: &program })?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .context(error::CredentialHelperIoSnafu { helper: &program })?;
+    ensure!(
+        output.status.success(),
+        error::CredentialHelperSnafu {
+            helper: program,
+            reason: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        }
+    );
+    Ok(())
+}
+
+/// Server name credentials for a registry are stored under
+pub(crate) fn server(uri: &RegistryUri) -> &str {
+    if uri.is_docker_hub() {
+        DOCKER_HUB_AUTH
+    } else {
+        uri.base().as_str()
+    }
+}
+
+/// Path of the docker config credentials are persisted to
+fn config_path() -> crate::Result<PathBuf> {
+    Ok(home_dir().context(error::HomeDirSnafu)?.join(DOCKER_CONFIG))
+}
+
+/// Read the docker config as json so fields this crate doesn't model are kept when it is written
+async fn read_config() -> crate::Result<serde_json::Value> {
+    match tokio::fs::read_to_string(config_path()?).await {
+        Ok(contents) => serde_json::from_str(&contents).context(error::ConfigDeserializeSnafu),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(serde_json::json!({})),
+        Err(e) => Err(e).context(error::FileSnafu),
+    }
+}
+
+/// Write the docker config back
+async fn write_config(config: &serde_json::Value) -> crate::Result<()> {
+    let path = config_path()?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context(error::FileSnafu)?;
+    }
+    let contents = serde_json::to_vec_pretty(config).context(error::SerializeSnafu)?;
+    tokio::fs::write(path, contents)
+        .await
+        .context(error::FileSnafu)
+}
+
+/// Store the credentials in the system keychain, returning false when no keychain is available
+fn keychain_store(server: &str, encoded: &str) -> bool {
+    let stored = Entry::new(KEYRING_SERVICE, server).and_then(|x| x.set_password(encoded));
+    // Without a platform keychain the store does not persist, so read the password back
+    stored.is_ok()
+        && Entry::new(KEYRING_SERVICE, server)
+            .and_then(|x| x.get_password())
+            .is_ok_and(|x| x == encoded)
+}
+
+/// Validate credentials against the registry and persist them to the docker config, using its
+/// credential store or the system keychain when available. Options for the registry are read from
+/// the settings file.
+pub async fn login(uri: &RegistryUri, username: &str, password: &str) -> crate::Result<()> {
+    let settings = Settings::load().await?;
+    login_with_options(uri, &settings.registry(uri.base()), username, password).await
+}
+
+/// Validate credentials against the registry, connecting with the provided options, and persist
+/// them like [`login`].
+pub async fn login_with_options(
+    uri: &RegistryUri,
+    options: &RegistryOptions,
+    username: &str,
+    password: &str,
+) -> crate::Result<()> {
+    let token = Token::Basic {
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+    let client = RegistryClient::new(
+        http_client(uri, options)?,
+        Some(token),
+        RetryPolicy::default(),
+    );
+    let response = client.ping(uri.clone().try_into()?).await?;
+    ensure!(
+        response.status().is_success(),
+        error::LoginSnafu {
+            registry: uri.base(),
+            reason: format!("registry responded {}", response.status()),
+        }
+    );
+
+    let server = server(uri);
+    let mut config = read_config().await?;
+    let modeled: DockerConfig =
+        serde_json::from_value(config.clone()).context(error::ConfigDeserializeSnafu)?;
+    let encoded =
+        base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+    // An empty entry tells readers of the config the credentials live elsewhere
+    let entry = if let Some(helper) = modeled
+        .cred_helpers
+        .get(uri.base())
+        .or(modeled.creds_store.as_ref())
+    {
+        helper_store(helper, server, username, password).await?;
+        serde_json::json!({})
+    } else if keychain_store(server, &encoded) {
+        serde_json::json!({})
+    } else {
+        serde_json::json!({ "auth": encoded })
+    };
+    let auths = config
+        .as_object_mut()
+        .context(error::LoginSnafu {
+            registry: uri.base(),
+            reason: "docker config is not an object",
+        })?
+        .entry("auths")
+        .or_insert_with(|| serde_json::json!({}));
+    if let Some(auths) = auths.as_object_mut() {
+        auths.insert(server.to_string(), entry);
+    }
+    write_config(&config).await
+}
+
+/// Remove the credentials of a registry from the docker config, its credential store and the
+/// system keychain. Returns whether there were any credentials stored.
+pub async fn logout(uri: &RegistryUri) -> crate::Result<bool> {
+    let server = server(uri);
+    let mut config = read_config().await?;
+    let modeled: DockerConfig =
+        serde_json::from_value(config.clone()).context(error::ConfigDeserializeSnafu)?;
+    let mut removed = false;
+    if let Some(helper) = modeled
+        .cred_helpers
+        .get(uri.base())
+        .or(modeled.creds_store.as_ref())
+    {
+        removed |= helper_erase(helper, server).await.is_ok();
+    }
+    if let Ok(entry) = Entry::new(KEYRING_SERVICE, server) {
+        removed |= entry.delete_credential().is_ok();
+    }
+    if let Some(auths) = config.get_mut("auths").and_then(|x| x.as_object_mut())
+        && auths.remove(server).is_some()
+    {
+        removed = true;
+        write_config(&config).await?;
+    }
+    Ok(removed)
+}
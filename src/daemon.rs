@@ -0,0 +1,218 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use cfg_if::cfg_if;
+use serde::Deserialize;
+use snafu::{ResultExt, ensure};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::process::Command;
+use tokio_util::io::ReaderStream;
+
+use crate::error;
+use crate::image::Image;
+use crate::progress::ProgressSink;
+use crate::uri::Uri;
+
+/// Socket of the docker daemon when `DOCKER_HOST` doesn't point to another one
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+/// Socket of containerd when `CONTAINERD_ADDRESS` doesn't point to another one
+const CONTAINERD_SOCKET: &str = "/run/containerd/containerd.sock";
+/// Size of the pipe between the tarball being written and the daemon reading it
+const PIPE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Container daemon images can be loaded into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Daemon {
+    /// Docker engine, through `POST /images/load` of its API
+    Docker,
+    /// Containerd, through `ctr images import` as its API is gRPC
+    Containerd,
+}
+
+/// Message of the stream docker responds to a load with.
+#[derive(Deserialize)]
+struct LoadMessage {
+    stream: Option<String>,
+    error: Option<String>,
+}
+
+impl FromStr for Daemon {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Self::Docker),
+            "containerd" => Ok(Self::Containerd),
+            _ => error::DaemonLoadSnafu {
+                daemon: s,
+                reason: "unsupported daemon, expected docker or containerd",
+            }
+            .fail(),
+        }
+    }
+}
+
+impl fmt::Display for Daemon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Docker => write!(f, "docker"),
+            Self::Containerd => write!(f, "containerd"),
+        }
+    }
+}
+
+impl Daemon {
+    /// Socket of the daemon from `DOCKER_HOST` or `CONTAINERD_ADDRESS`, or its default location
+    pub fn socket(&self) -> PathBuf {
+        match self {
+            Self::Docker => std::env::var("DOCKER_HOST")
+                .ok()
+                .and_then(|x| x.strip_prefix("unix://").map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from(DOCKER_SOCKET)),
+            Self::Containerd => std::env::var_os("CONTAINERD_ADDRESS")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(CONTAINERD_SOCKET)),
+        }
+    }
+
+    /// Load an image into the daemon listening on the socket, streaming its docker loadable
+    /// tarball to the daemon as it is written. Returns the messages of the daemon, i.e. the
+    /// images it loaded
+    pub async fn load(
+        &self,
+        socket: &Path,
+        uri: &Uri,
+        image: &Image,
+    ) -> crate::Result<Vec<String>> {
+        let (reader, writer) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let ((), messages) =
+            tokio::try_join!(image.to_tarball(uri, writer), self.send(socket, reader))?;
+        Ok(messages)
+    }
+
+    /// Load an image into the daemon listening on the socket, streaming its docker loadable
+    /// tarball to the daemon as it is written. This version will report as it fetches the image
+    /// to the progress sink.
+    pub async fn load_progress(
+        &self,
+        socket: &Path,
+        uri: &Uri,
+        image: &Image,
+        progress: &Arc<dyn ProgressSink>,
+    ) -> crate::Result<Vec<String>> {
+        let (reader, writer) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let ((), messages) = tokio::try_join!(
+            image.to_tarball_progress(uri, writer, progress),
+            self.send(socket, reader)
+        )?;
+        Ok(messages)
+    }
+
+    /// Send a tarball to the daemon
+    async fn send(&self, socket: &Path, tarball: DuplexStream) -> crate::Result<Vec<String>> {
+        match self {
+            Self::Docker => docker_load(socket, tarball).await,
+            Self::Containerd => containerd_import(socket, tarball).await,
+        }
+    }
+}
+
+/// Post a tarball to the images/load endpoint of the docker API
+async fn docker_load(socket: &Path, tarball: DuplexStream) -> crate::Result<Vec<String>> {
+    cfg_if! {
+        if #[cfg(unix)] {
+            let client = reqwest::Client::builder()
+                .unix_socket(socket)
+                .build()
+                .context(error::RequestSnafu)?;
+        } else {
+            return error::DaemonLoadSnafu {
+                daemon: "docker",
+                reason: format!("unix socket '{}' is not supported on this platform", socket.display()),
+            }
+            .fail();
+        }
+    }
+    let response = client
+        .post("http://localhost/images/load?quiet=1")
+        .header(reqwest::header::CONTENT_TYPE, "application/x-tar")
+        .body(reqwest::Body::wrap_stream(ReaderStream::new(tarball)))
+        .send()
+        .await
+        .map_err(|e| error::Error::DaemonLoad {
+            daemon: "docker".to_string(),
+            reason: format!("{} is unreachable: {e}", socket.display()),
+        })?;
+    let status = response.status();
+    let body = response.text().await.context(error::RequestSnafu)?;
+    ensure!(
+        status.is_success(),
+        error::DaemonLoadSnafu {
+            daemon: "docker",
+            reason: format!("{status}: {}", body.trim()),
+        }
+    );
+    let mut messages = Vec::new();
+    for message in serde_json::Deserializer::from_str(&body).into_iter::<LoadMessage>() {
+        let message = message.context(error::BodyDeserializeSnafu)?;
+        if let Some(reason) = message.error {
+            return error::DaemonLoadSnafu {
+                daemon: "docker",
+                reason,
+            }
+            .fail();
+        }
+        messages.extend(
+            message
+                .stream
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty()),
+        );
+    }
+    Ok(messages)
+}
+
+/// Import a tarball into the content store of containerd with its ctr CLI
+async fn containerd_import(socket: &Path, mut tarball: DuplexStream) -> crate::Result<Vec<String>> {
+    let mut child = Command::new("ctr")
+        .arg("--address")
+        .arg(socket)
+        .args(["images", "import", "--all-platforms", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(error::DaemonIoSnafu {
+            daemon: "containerd",
+        })?;
+    let mut stdin = child.stdin.take().expect("stdin of ctr is piped");
+    let written = async move {
+        let copied = tokio::io::copy(&mut tarball, &mut stdin).await;
+        // Closing stdin ends the import
+        copied.and(stdin.shutdown().await)
+    };
+    // The output of ctr is read while the tarball is written, so neither blocks on a full pipe
+    let (written, output) = tokio::join!(written, child.wait_with_output());
+    let output = output.context(error::DaemonIoSnafu {
+        daemon: "containerd",
+    })?;
+    ensure!(
+        output.status.success(),
+        error::DaemonLoadSnafu {
+            daemon: "containerd",
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+    );
+    written.context(error::DaemonIoSnafu {
+        daemon: "containerd",
+    })?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect())
+}
@@ -1,12 +1,12 @@
 use std::num::ParseIntError;
+use std::path::PathBuf;
 
 use reqwest::header::ToStrError;
 use snafu::Snafu;
 use tokio::task::JoinError;
 use url::Url;
 
-use crate::models::{ErrorResponse, Platform};
-use crate::uri::Uri;
+use crate::models::{Compression, ErrorResponse, Platform};
 
 #[derive(Snafu, Debug)]
 #[snafu(visibility(pub))]
@@ -17,6 +17,42 @@ pub enum Error {
     Authorization { reason: String },
     #[snafu(display("blob with digest {digest} is missing from oci archive"))]
     BlobMissing { digest: String },
+    #[snafu(display("no blob was found in oci registry matching: {uri}"))]
+    BlobNotFound { uri: String },
+    #[snafu(display("invalid chunk policy: {reason}"))]
+    ChunkPolicy { reason: String },
+    #[snafu(display("failed to load image into the {daemon} daemon: {reason}"))]
+    DaemonLoad { daemon: String, reason: String },
+    #[snafu(display("failed to talk to the {daemon} daemon: {source}"))]
+    DaemonIo {
+        daemon: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("invalid docker archive: {reason}"))]
+    DockerArchive { reason: String },
+    #[snafu(display("could not determine the home directory"))]
+    HomeDir,
+    #[snafu(display("login to {registry} failed: {reason}"))]
+    Login { registry: String, reason: String },
+    #[snafu(display("credential helper {helper} failed: {reason}"))]
+    CredentialHelper { helper: String, reason: String },
+    #[snafu(display("failed to run credential helper {helper}: {source}"))]
+    CredentialHelperIo {
+        helper: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("credential helper {helper} returned malformed credentials: {source}"))]
+    CredentialHelperOutput {
+        helper: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display(
+        "credentials in the system keychain for {server} are not valid base64: {source}"
+    ))]
+    KeychainCredentials {
+        server: String,
+        source: base64::DecodeError,
+    },
     #[snafu(display("failed to deserialize image configuration received from registry: {source}"))]
     ConfigDeserialize { source: serde_json::Error },
     #[snafu(display("oci registry did not return the content length"))]
@@ -27,6 +63,9 @@ pub enum Error {
     ImproperHeader { source: ToStrError },
     #[snafu(display("failed to deserialize response body: {source}"))]
     BodyDeserialize { source: serde_json::Error },
+    #[cfg(feature = "aws")]
+    #[snafu(display("failed to create repository {repository}: {reason}"))]
+    CreateRepository { repository: String, reason: String },
     #[snafu(display("failed to delete blob '{digest}': {reason}"))]
     DeleteBlob {
         digest: String,
@@ -38,18 +77,37 @@ pub enum Error {
     DeleteTag { tag: String, reason: ErrorResponse },
     #[snafu(display("cannot delete a tag via a sha256 digest"))]
     DeleteTagDigest,
+    #[snafu(display("failed to delete manifest '{digest}': {reason}"))]
+    DeleteManifest {
+        digest: String,
+        reason: ErrorResponse,
+    },
+    #[snafu(display("cannot delete a manifest without a specific digest"))]
+    DeleteManifestNoDigest,
+    #[snafu(display("cannot tag '{from}' as '{to}', tags can only be added within a repository"))]
+    TagRepository { from: String, to: String },
+    #[snafu(display(
+        "cannot rebase '{image}', its layers do not start with the layers of '{base}'"
+    ))]
+    RebaseBase { image: String, base: String },
+    #[snafu(display("layers can not be written with {compression:?} compression"))]
+    UnsupportedCompression { compression: Compression },
     #[snafu(display("failed to perform operation with directory: {source}"))]
     Directory { source: std::io::Error },
     #[snafu(display("cannot read a blob without a specific digest uri (uri: {uri})"))]
-    DirectLoadBlob { uri: Uri },
+    DirectLoadBlob { uri: String },
     #[snafu(display("cannot direct load an image without a specific digest uri (uri: {uri})"))]
-    DirectLoadImage { uri: Uri },
+    DirectLoadImage { uri: String },
     #[snafu(display("failed to deserialize error response from oci registry: {source}"))]
     ErrorDeserialize { source: reqwest::Error },
     #[snafu(display("failed to fetch blob: {reason}"))]
     FetchBlob { reason: ErrorResponse },
+    #[snafu(display("failed to stat blob {digest}: {reason}"))]
+    StatBlob { digest: String, reason: String },
     #[snafu(display("failed to fetch index: {reason}"))]
     FetchIndex { reason: ErrorResponse },
+    #[snafu(display("failed to list referrers: {reason}"))]
+    Referrers { reason: ErrorResponse },
     #[snafu(display("failed to fetch manifest: {reason}"))]
     FetchManifest { reason: ErrorResponse },
     #[cfg(feature = "aws")]
@@ -57,8 +115,16 @@ pub enum Error {
     FetchTagsAws { reason: String },
     #[snafu(display("failed to interact with local file: {source}"))]
     File { source: std::io::Error },
+    #[snafu(display("no file {path} exists in the image filesystem"))]
+    FileNotFound { path: String },
     #[snafu(display("failed to finish blob upload: {reason}"))]
     FinishBlob { reason: ErrorResponse },
+    #[snafu(display("content of {expected} does not match its digest, it hashes to {actual}"))]
+    DigestMismatch { expected: String, actual: String },
+    #[snafu(display("failed to resume blob upload: {reason}"))]
+    UploadResume { reason: String },
+    #[snafu(display("upload of blob '{digest}' was not finalized: {reason}"))]
+    FinalizeBlob { digest: String, reason: String },
     #[snafu(display("oci image archive has invalid index: {source}"))]
     ImageInvalidIndex { source: serde_json::Error },
     #[snafu(display("oci image archive does not have a valid manifest: {source}"))]
@@ -66,11 +132,30 @@ pub enum Error {
     #[snafu(display("index does not contain an image for the platform: {platform}"))]
     IndexNoPlatform { platform: Platform },
     #[snafu(display("no image was found in oci registry matching: {uri}"))]
-    ImageNotFound { uri: Uri },
+    ImageNotFound { uri: String },
     #[snafu(display("file is not a valid oci archive as it is missing index.json"))]
     ImageNotValid,
     #[snafu(display("invalid algorithm in digest: {algorithm}"))]
     InvalidAlgorithm { algorithm: String },
+    #[snafu(display(
+        "invalid platform '{platform}', expected os[(os.version)]/architecture[/variant]"
+    ))]
+    InvalidPlatform { platform: String },
+    #[snafu(display("invalid byte range {start}-{}: {reason}", end.map(|x| x.to_string()).unwrap_or_default()))]
+    InvalidRange {
+        start: u64,
+        end: Option<u64>,
+        reason: String,
+    },
+    #[snafu(display("invalid in-toto attestation: {reason}"))]
+    InvalidAttestation { reason: String },
+    #[snafu(display("invalid key: {reason}"))]
+    InvalidKey { reason: String },
+    #[snafu(display("invalid tag pattern {pattern}: {source}"))]
+    InvalidTagPattern {
+        pattern: String,
+        source: regex_lite::Error,
+    },
     #[snafu(display("failed to unpack archive from layer: {source}"))]
     LayerArchive { source: std::io::Error },
     #[snafu(display("failed to copy from layer: {source}"))]
@@ -87,8 +172,24 @@ pub enum Error {
     ListTags { reason: ErrorResponse },
     #[snafu(display("malformed object uri provided: {reason}"))]
     MalformedUri { reason: String },
+    #[snafu(display("no sbom is attached to {uri}"))]
+    NoSbom { uri: String },
+    #[snafu(display("{path} is a directory in the image filesystem, not a file"))]
+    NotAFile { path: String },
+    #[snafu(display("no valid signature of {digest} was found for the key"))]
+    NoValidSignature { digest: String },
+    #[snafu(display("found {problems} problems verifying the blobs of {uri}"))]
+    CorruptImage { uri: String, problems: usize },
     #[snafu(display("no image index found at uri: {uri}"))]
-    NoIndex { uri: Uri },
+    NoIndex { uri: String },
+    #[snafu(display("'{reference}' is not available in the local store while offline"))]
+    OfflineMissing { reference: String },
+    #[snafu(display(
+        "output '{output}' must contain {{registry}}, {{repository}} or {{reference}} when pulling multiple references"
+    ))]
+    OutputTemplate { output: String },
+    #[snafu(display("invalid proxy '{url}': {source}"))]
+    Proxy { url: String, source: reqwest::Error },
     #[snafu(display("failed to push image to '{uri}': {reason}"))]
     PushImage { uri: Url, reason: ErrorResponse },
     #[snafu(display("failed to make request to oci registry: {source}"))]
@@ -97,14 +198,42 @@ pub enum Error {
     ResponseDeserialize { source: reqwest::Error },
     #[snafu(display("failed to serialize to json: {source}"))]
     Serialize { source: serde_json::Error },
+    #[snafu(display("failed to parse settings file '{}': {source}", path.display()))]
+    Settings {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("unknown sbom format, expected an SPDX or CycloneDX JSON document"))]
+    SbomFormat,
+    #[snafu(display("failed to sign payload"))]
+    Sign,
+    #[snafu(display("failed to sign request: {reason}"))]
+    Signing { reason: String },
     #[snafu(display("failed to start a blob upload: {reason}"))]
     StartBlobUpload { reason: ErrorResponse },
     #[snafu(display("registry did not provide an upload_url for blob upload"))]
     StartBlobNoLocation,
+    #[snafu(display("failed to interact with the local store: {source}"))]
+    Store { source: std::io::Error },
+    #[snafu(display("no local store is available, unable to determine the home directory"))]
+    StoreUnavailable,
+    #[snafu(display("too many levels of symbolic links resolving {path}"))]
+    SymlinkLoop { path: String },
     #[snafu(display("failed to create temporary directory: {source}"))]
     Temp { source: std::io::Error },
+    #[snafu(display("failed to load TLS certificate or key '{}': {source}", path.display()))]
+    Tls {
+        path: PathBuf,
+        source: reqwest::Error,
+    },
+    #[snafu(display("invalid TLS options: {reason}"))]
+    TlsConfig { reason: String },
     #[snafu(display("upload of chunk for blob failed: {reason}"))]
     Upload { reason: ErrorResponse },
+    #[snafu(display(
+        "registry computed digest '{actual}' for the uploaded blob, expected '{expected}'"
+    ))]
+    UploadDigestMismatch { expected: String, actual: String },
     #[snafu(display("invalid url detected: {source}"))]
     Url { source: url::ParseError },
 }
@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use home::home_dir;
+use ring::rand::SystemRandom;
+use ring::signature::{RSA_PKCS1_SHA256, RsaKeyPair};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::error;
+use crate::signing::pem_decode;
+
+/// Environment variable pointing to the application default credentials file
+const CREDENTIALS_ENV: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+/// Location of the credentials written by `gcloud auth application-default login`, relative to
+/// the home directory
+const CREDENTIALS_FILE: &str = ".config/gcloud/application_default_credentials.json";
+/// Token endpoint that refresh tokens and service account assertions are exchanged at
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+/// Token endpoint of the metadata server of GCE, GKE and Cloud Run
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+/// Scope requested for service account tokens
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// Username Artifact Registry expects along with an access token as the password
+pub(crate) const USERNAME: &str = "oauth2accesstoken";
+
+/// Application default credentials file.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Credentials {
+    /// Credentials of a user, from `gcloud auth application-default login`
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    /// Key of a service account
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        token_uri: Option<String>,
+    },
+}
+
+/// Response of a token endpoint.
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+}
+
+/// Whether the registry is Google Artifact Registry (`*-docker.pkg.dev`) or Container Registry
+pub(crate) fn is_google(base: &str) -> bool {
+    base.ends_with("-docker.pkg.dev") || base == "gcr.io" || base.ends_with(".gcr.io")
+}
+
+/// Access token of the application default credentials, or of the service account of the
+/// instance when running on Google Cloud without a credentials file
+pub(crate) async fn access_token() -> crate::Result<Option<String>> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(2))
+        .build()
+        .context(error::RequestSnafu)?;
+    let path = std::env::var_os(CREDENTIALS_ENV)
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|x| x.join(CREDENTIALS_FILE)));
+    let Some(path) = path.filter(|x| x.exists()) else {
+        return metadata_token(&client).await;
+    };
+    let contents = tokio::fs::read(&path).await.context(error::FileSnafu)?;
+    let credentials: Credentials =
+        serde_json::from_slice(&contents).map_err(|e| error::Error::Authorization {
+            reason: format!("unsupported credentials in {}: {e}", path.display()),
+        })?;
+    let (url, form) = match credentials {
+        Credentials::AuthorizedUser {
+            client_id,
+            client_secret,
+            refresh_token,
+        } => (
+            TOKEN_URL.to_string(),
+            vec![
+                ("grant_type", "refresh_token".to_string()),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("refresh_token", refresh_token),
+            ],
+        ),
+        Credentials::ServiceAccount {
+            client_email,
+            private_key,
+            token_uri,
+        } => {
+            let url = token_uri.unwrap_or_else(|| TOKEN_URL.to_string());
+            let assertion = assertion(&client_email, &private_key, &url)?;
+            (
+                url,
+                vec![
+                    (
+                        "grant_type",
+                        "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string(),
+                    ),
+                    ("assertion", assertion),
+                ],
+            )
+        }
+    };
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    let response = client
+        .post(url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded",
+        )
+        .body(body)
+        .send()
+        .await
+        .context(error::RequestSnafu)?;
+    token(response).await.map(Some)
+}
+
+/// Access token of the service account of the instance from the metadata server, if there is one
+async fn metadata_token(client: &reqwest::Client) -> crate::Result<Option<String>> {
+    let response = match client
+        .get(METADATA_TOKEN_URL)
+        .header("Metadata-Flavor", "Google")
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => response,
+        // Instances without a service account get an error
+        Ok(response) => {
+            debug!(target: "registry", "no gcp service account, metadata server responded {}", response.status());
+            return Ok(None);
+        }
+        Err(e) => {
+            debug!(target: "registry", "no gcp metadata server: {e}");
+            return Ok(None);
+        }
+    };
+    token(response).await.map(Some)
+}
+
+/// Read the access token of a token endpoint response
+async fn token(response: reqwest::Response) -> crate::Result<String> {
+    if !response.status().is_success() {
+        return error::AuthorizationSnafu {
+            reason: format!("gcp token endpoint responded {}", response.status()),
+        }
+        .fail();
+    }
+    let token: AccessToken = response
+        .json()
+        .await
+        .context(error::ResponseDeserializeSnafu)?;
+    Ok(token.access_token)
+}
+
+/// Signed JWT asserting the identity of a service account, exchanged for an access token
+fn assertion(client_email: &str, private_key: &str, audience: &str) -> crate::Result<String> {
+    let key = RsaKeyPair::from_pkcs8(&pem_decode(private_key, "PRIVATE KEY")?).map_err(|e| {
+        error::Error::InvalidKey {
+            reason: e.to_string(),
+        }
+    })?;
+    let now = chrono::Utc::now().timestamp();
+    let header = json!({ "alg": "RS256", "typ": "JWT" });
+    let claims = json!({
+        "iss": client_email,
+        "scope": SCOPE,
+        "aud": audience,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let message = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(header.to_string()),
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    );
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(
+        &RSA_PKCS1_SHA256,
+        &SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|_| error::Error::Sign)?;
+    Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
@@ -1,17 +1,31 @@
 #[cfg(feature = "compression")]
-use crate::compression::Decompress;
+use crate::compression::{Decompress, compress_file};
 use crate::error;
-use crate::layer::Layer;
-use crate::models::{Config, ImageConfig, MediaType, Platform, TarballManifest};
+#[cfg(feature = "compression")]
+use crate::layer::Hasher;
+use crate::layer::{Layer, Reader};
+use crate::manifest::Manifest;
+use crate::models::{
+    Compression, History, ImageConfig, MediaType, Platform, RootFs, TarballManifest,
+};
+#[cfg(feature = "compression")]
+use crate::progress::ProgressSink;
+#[cfg(feature = "compression")]
+use crate::uri::Algorithm;
 use crate::uri::{Reference, Uri};
 use bon::Builder;
+#[cfg(feature = "compression")]
+use cfg_if::cfg_if;
+use chrono::{SecondsFormat, Utc};
 use futures::StreamExt;
 use futures::future::join_all;
-#[cfg(feature = "progress")]
-use indicatif::MultiProgress;
 use serde::{Deserialize, Serialize};
-use snafu::{ResultExt, ensure};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt, ensure};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+#[cfg(feature = "compression")]
+use std::sync::Arc;
 use tempfile::tempdir;
 use tokio::fs::File;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -19,6 +33,439 @@ use tokio::task::JoinHandle;
 use tokio_tar::{Archive, Builder as ArchiveBuilder};
 
 const WHITEOUT: &str = ".wh.";
+/// Size of the pipe between a blob being downloaded and its decompression when verifying it.
+#[cfg(feature = "compression")]
+const VERIFY_PIPE_SIZE: usize = 1024 * 1024;
+/// Name of the whiteout hiding the content of lower layers in the directory it is in.
+#[cfg(feature = "compression")]
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Leading bytes of gzip compressed content.
+#[cfg(feature = "compression")]
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Leading bytes of zstd compressed content.
+#[cfg(feature = "compression")]
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Content of the empty descriptor artifact manifests use as their config.
+const EMPTY_CONFIG: &[u8] = b"{}";
+/// Digest of the empty descriptor content.
+const EMPTY_CONFIG_DIGEST: &str =
+    "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+
+/// Hash the content of a reader, returning its digest and size
+#[cfg(feature = "compression")]
+pub(crate) async fn hash<R>(mut reader: R) -> crate::Result<(String, usize)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .await
+            .context(error::LayerReadSnafu)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read;
+    }
+    Ok((
+        format!(
+            "sha256:{}",
+            base16::encode_lower(hasher.finalize().as_slice())
+        ),
+        size,
+    ))
+}
+
+/// Upload a local file as a blob of the media type, unless the registry already has it. Returns
+/// the descriptor of the blob and its digest.
+#[cfg(feature = "compression")]
+pub(crate) async fn upload_file(
+    uri: &Uri,
+    path: &Path,
+    media_type: &MediaType,
+) -> crate::Result<(Layer, String)> {
+    let (digest, size) = hash(File::open(path).await.context(error::FileSnafu)?).await?;
+    let layer = match Layer::create(uri, media_type, size, Some(digest.clone())).await? {
+        Some(mut writer) => {
+            let mut file = File::open(path).await.context(error::FileSnafu)?;
+            Layer::copy(&mut file, &mut writer, size).await?;
+            writer.flush().await.context(error::LayerWriteSnafu)?;
+            writer.layer().await?
+        }
+        None => Layer::builder()
+            .media_type(media_type.clone())
+            .size(size)
+            .digest(digest.clone())
+            .build(),
+    };
+    Ok((layer, digest))
+}
+
+/// Copy a blob from one repository to another, unless the target already has it
+async fn transfer(layer: &Layer, from: &Uri, to: &Uri) -> crate::Result<()> {
+    let Some(mut writer) = Layer::create(
+        to,
+        layer.media_type(),
+        layer.size(),
+        Some(layer.digest().to_string()),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+    let mut reader = layer.open(from).await?;
+    Layer::copy(&mut reader, &mut writer, layer.size()).await?;
+    writer.flush().await.context(error::LayerWriteSnafu)?;
+    writer.layer().await?;
+    Ok(())
+}
+
+/// Merges the layers of an image into a single filesystem archive. Layers have to be added from
+/// the top most layer down, so the files of upper layers shadow the ones below them and their
+/// whiteouts delete files and the content of opaque directories of the layers below them.
+#[cfg(feature = "compression")]
+struct Merger<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    archive: ArchiveBuilder<W>,
+    overlay: Overlay,
+}
+
+#[cfg(feature = "compression")]
+impl<W> Merger<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn new(output: W) -> Self {
+        Self {
+            archive: ArchiveBuilder::new(output),
+            overlay: Overlay::default(),
+        }
+    }
+
+    /// Add the entries of an uncompressed layer that are not shadowed by the layers above it
+    async fn add<R>(&mut self, reader: R) -> crate::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut layer = Archive::new(reader);
+        // Make sure to use the raw entry stream to avoid truncation of long links and long paths.
+        // The extension entries carrying them are held back until the entry they describe is
+        // either kept or skipped.
+        let mut entries = layer.entries_raw().context(error::LayerArchiveSnafu)?;
+        let mut extensions = Vec::new();
+        let mut long_path = None;
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.context(error::LayerArchiveSnafu)?;
+            let header = entry.header().clone();
+            let kind = header.entry_type();
+            if kind.is_gnu_longname() || kind.is_gnu_longlink() || kind.is_pax_local_extensions() {
+                let mut data = Vec::new();
+                entry
+                    .read_to_end(&mut data)
+                    .await
+                    .context(error::LayerReadSnafu)?;
+                if kind.is_gnu_longname() {
+                    long_path = Some(
+                        String::from_utf8_lossy(&data)
+                            .trim_end_matches('\0')
+                            .to_string(),
+                    );
+                } else if kind.is_pax_local_extensions() {
+                    long_path = pax_path(&data).or(long_path);
+                }
+                extensions.push((header, data));
+                continue;
+            }
+            let extensions = std::mem::take(&mut extensions);
+            let path = match long_path.take() {
+                Some(path) => path,
+                None => header
+                    .path()
+                    .context(error::LayerArchiveSnafu)?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            if !kind.is_pax_global_extensions() && !self.overlay.visible(&path, kind.is_dir()) {
+                continue;
+            }
+
+            for (header, data) in extensions {
+                self.archive
+                    .append(&header, data.as_slice())
+                    .await
+                    .context(error::LayerCopySnafu)?;
+            }
+            self.archive
+                .append(&header, &mut entry)
+                .await
+                .context(error::LayerCopySnafu)?;
+        }
+        self.overlay.next_layer();
+        Ok(())
+    }
+
+    /// Finish the archive and return the output
+    async fn finish(self) -> crate::Result<W> {
+        self.archive.into_inner().await.context(error::ArchiveSnafu)
+    }
+}
+
+/// Path of a pax extended header, the records of which are `<length> <key>=<value>\n`
+#[cfg(feature = "compression")]
+fn pax_path(mut data: &[u8]) -> Option<String> {
+    let mut path = None;
+    while !data.is_empty() {
+        let space = data.iter().position(|x| *x == b' ')?;
+        let length: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        let record = data.get(space + 1..length)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).to_string());
+        }
+        data = &data[length..];
+    }
+    path
+}
+
+/// Path of a layer entry relative to the filesystem root, without a trailing slash
+#[cfg(feature = "compression")]
+fn relative_path(path: &str) -> &str {
+    path.trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+}
+
+/// Resolve the target of a symbolic link in the directory it is in, relative to the filesystem
+/// root. Like the kernel, `..` stops at the root.
+#[cfg(feature = "compression")]
+fn resolve_link(parent: &str, target: &str) -> String {
+    let base = if target.starts_with('/') { "" } else { parent };
+    let mut components = Vec::new();
+    for component in base.split('/').chain(target.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Set the permission bits of an extracted directory, which only exist on unix
+#[cfg(feature = "compression")]
+async fn set_mode(path: &Path, mode: u32) -> crate::Result<()> {
+    cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+                .await
+                .context(error::FileSnafu)
+        } else {
+            let _ = (path, mode);
+            Ok(())
+        }
+    }
+}
+
+/// Outcome of looking up a path in the layers of an image.
+#[cfg(feature = "compression")]
+enum Lookup {
+    /// Content of the file
+    Content(Vec<u8>),
+    /// Path the file, or a directory on its way, links to
+    Link(String),
+    /// No visible file exists at the path
+    Missing,
+}
+
+/// Hasher for the algorithm of the digest of a blob, sha256 when it is not supported
+#[cfg(feature = "compression")]
+fn blob_hasher(layer: &Layer) -> Hasher {
+    let algorithm = layer
+        .digest()
+        .split_once(':')
+        .and_then(|(x, _)| x.parse::<Algorithm>().ok())
+        .unwrap_or_default();
+    Hasher::new(&algorithm)
+}
+
+/// Record a problem when the content of a blob doesn't hash to its digest or has another size
+#[cfg(feature = "compression")]
+fn check_blob(layer: &Layer, hasher: &Hasher, content: &[u8], problems: &mut Vec<BlobProblem>) {
+    let mut hasher = hasher.clone();
+    hasher.update(content);
+    check_size(layer, &hasher.digest(), content.len(), problems);
+}
+
+/// Record a problem when the digest or size of the content of a blob doesn't match its descriptor
+#[cfg(feature = "compression")]
+fn check_size(layer: &Layer, digest: &str, size: usize, problems: &mut Vec<BlobProblem>) {
+    if digest != layer.digest() {
+        problems.push(BlobProblem {
+            digest: layer.digest().to_string(),
+            reason: format!("content hashes to {digest}"),
+        });
+    }
+    if size != layer.size() {
+        problems.push(BlobProblem {
+            digest: layer.digest().to_string(),
+            reason: format!(
+                "content is {size} bytes, the manifest lists {}",
+                layer.size()
+            ),
+        });
+    }
+}
+
+/// Download a blob once, hashing its content along with its decompressed content. Returns the
+/// hash and size of the content, and the digest of the decompressed content or why it could not
+/// be decompressed.
+#[cfg(feature = "compression")]
+async fn digest_blob(
+    layer: &Layer,
+    uri: &Uri,
+) -> crate::Result<(Hasher, usize, crate::Result<String>)> {
+    let mut reader = layer.open_unverified(uri).await?;
+    let (writer, pipe) = tokio::io::duplex(VERIFY_PIPE_SIZE);
+    let mut hasher = blob_hasher(layer);
+    let compressed = async {
+        let mut writer = Some(writer);
+        let mut size = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .await
+                .context(error::LayerReadSnafu)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read;
+            // The decompression stops reading the pipe when it fails or ignores trailing data, the
+            // rest of the blob is still hashed
+            if let Some(pipe) = writer.as_mut()
+                && pipe.write_all(&buffer[..read]).await.is_err()
+            {
+                writer = None;
+            }
+        }
+        Ok::<_, error::Error>(size)
+    };
+    let decompressed = async {
+        hash(Decompress::new(layer.media_type(), Reader::new(pipe)))
+            .await
+            .map(|(digest, _)| digest)
+    };
+    let (size, diff_id) = tokio::join!(compressed, decompressed);
+    Ok((hasher, size?, diff_id))
+}
+
+/// Tracks the paths of the layers read so far, from the top most layer down, to decide which
+/// entries of the next layer are still visible in the image filesystem.
+#[cfg(feature = "compression")]
+#[derive(Default)]
+struct Overlay {
+    /// Paths of upper layers, and whether they are directories
+    present: HashMap<String, bool>,
+    /// Paths deleted by whiteouts of upper layers, along with everything below them
+    removed: HashSet<String>,
+    /// Directories of upper layers hiding the content of lower layers
+    opaque: HashSet<String>,
+    /// Whiteouts of the current layer, which only apply to the layers below it
+    pending: Vec<(String, bool)>,
+}
+
+#[cfg(feature = "compression")]
+impl Overlay {
+    /// Whether an entry of the current layer is visible, recording it when it is. Whiteouts are
+    /// never visible themselves.
+    fn visible(&mut self, path: &str, is_dir: bool) -> bool {
+        let path = relative_path(path);
+        if path.is_empty() || path == "." {
+            return false;
+        }
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name == OPAQUE_WHITEOUT {
+            self.pending.push((parent.to_string(), true));
+            return false;
+        }
+        if let Some(name) = name.strip_prefix(WHITEOUT) {
+            let removed = if parent.is_empty() {
+                name.to_string()
+            } else {
+                format!("{parent}/{name}")
+            };
+            self.pending.push((removed, false));
+            return false;
+        }
+        let hidden = self.present.contains_key(path) || self.hidden(path);
+        if !hidden {
+            self.present.insert(path.to_string(), is_dir);
+        }
+        !hidden
+    }
+
+    /// Whether a path of the lower layers is deleted by the whiteouts of the upper layers, or
+    /// replaced by a file of theirs further up the path
+    fn hidden(&self, path: &str) -> bool {
+        let mut parents =
+            std::iter::once("").chain(path.match_indices('/').map(|(i, _)| &path[..i]));
+        self.removed.contains(path)
+            || parents.any(|x| {
+                self.removed.contains(x)
+                    || self.opaque.contains(x)
+                    || self.present.get(x).is_some_and(|is_dir| !is_dir)
+            })
+    }
+
+    /// Apply the whiteouts of the current layer before moving on to the one below it
+    fn next_layer(&mut self) {
+        for (path, opaque) in self.pending.drain(..) {
+            if opaque {
+                self.opaque.insert(path);
+            } else {
+                self.removed.insert(path);
+            }
+        }
+    }
+}
+
+/// Symbolic links followed when reading a file before giving up, as `MAXSYMLINKS` of Linux.
+#[cfg(feature = "compression")]
+const MAX_SYMLINKS: usize = 40;
+
+/// File of an image filesystem, as listed by [`Image::list_files`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEntry {
+    /// Absolute path of the file in the image filesystem
+    pub path: String,
+    /// Size of the content in bytes, zero for directories and links
+    pub size: u64,
+    /// Unix mode, including the file type bits
+    pub mode: u32,
+    /// Digest of the layer the file comes from
+    pub layer: String,
+}
+
+/// Corruption found in a blob of an image, as listed by [`Image::verify_digests`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobProblem {
+    /// Digest of the blob in the manifest
+    pub digest: String,
+    /// What the content of the blob does not match
+    pub reason: String,
+}
 
 /// Represents a single Image or Manifest object in an OCI registry + repository.
 ///
@@ -34,6 +481,17 @@ pub struct Image {
     config: Layer,
     #[builder(into)]
     layers: Vec<Layer>,
+    /// Type of artifact this manifest describes, when it is not a container image
+    #[builder(into)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+    /// Manifest this artifact refers to, i.e. the image a signature is for
+    #[builder(into)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<Layer>,
+    #[builder(into, default)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
     #[builder(into)]
     #[serde(skip)]
     platform: Option<Platform>,
@@ -60,23 +518,89 @@ impl Image {
     pub async fn create(config: &Layer, layers: &[Layer], platform: Option<Platform>) -> Self {
         Self {
             schema_version: 2,
-            media_type: MediaType::Config,
+            media_type: MediaType::Manifest,
             config: config.clone(),
             layers: layers.to_vec(),
+            artifact_type: None,
+            subject: None,
+            annotations: BTreeMap::new(),
             platform,
         }
     }
 
+    /// Create a new Image manifest for layers already uploaded to the uri. The rootfs of the config
+    /// is set to the diff ids of the layers, which requires reading any compressed layers, before
+    /// the config is uploaded.
+    #[cfg(feature = "compression")]
+    pub async fn from_layers(
+        uri: &Uri,
+        mut config: ImageConfig,
+        layers: &[Layer],
+        platform: Option<Platform>,
+    ) -> crate::Result<Self> {
+        let image = Self::create(&Self::empty_config(), layers, None).await;
+        config.rootfs = RootFs::builder()
+            .diff_ids(image.diff_ids(uri).await?)
+            .build();
+        let config = Self::create_config(uri, &config).await?;
+        Ok(Self::create(&config, layers, platform).await)
+    }
+
+    /// Create an OCI artifact manifest of the artifact type for the blobs in layers, referring to
+    /// the subject manifest when one is provided. The config is the empty descriptor, which has to
+    /// be uploaded with [`Image::create_empty_config`].
+    pub fn artifact(artifact_type: &str, layers: &[Layer], subject: Option<Layer>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: MediaType::Manifest,
+            config: Self::empty_config(),
+            layers: layers.to_vec(),
+            artifact_type: Some(artifact_type.to_string()),
+            subject,
+            annotations: BTreeMap::new(),
+            platform: None,
+        }
+    }
+
+    /// Descriptor of the empty config used by artifact manifests
+    fn empty_config() -> Layer {
+        Layer::builder()
+            .media_type(MediaType::Empty)
+            .size(EMPTY_CONFIG.len())
+            .digest(EMPTY_CONFIG_DIGEST)
+            .build()
+    }
+
+    /// Check if there is a manifest at the provided URI.
+    ///
+    /// Note: This only checks that a manifest exists, it does not verify that it is an image.
+    pub async fn check(uri: &Uri) -> crate::Result<bool> {
+        uri.registry()
+            .manifest_exists(uri.repository(), uri.reference().to_string().as_str())
+            .await
+    }
+
     /// Fetch an image manigest from an oci registry
     pub async fn fetch(uri: &Uri, platform: Option<Platform>) -> crate::Result<Self> {
         ensure!(
             matches!(uri.reference(), Reference::Digest { .. }),
-            error::DirectLoadImageSnafu { uri: uri.clone() }
+            error::DirectLoadImageSnafu {
+                uri: uri.to_string()
+            }
         );
-        let mut me: Self = uri
+        let reference = uri.reference().to_string();
+        let (bytes, digest, _) = uri
             .registry()
-            .fetch_manifest(uri.repository(), uri.reference().to_string().as_str())
+            .fetch_manifest_raw(uri.repository(), reference.as_str())
             .await?;
+        ensure!(
+            digest == reference,
+            error::DigestMismatchSnafu {
+                expected: reference,
+                actual: digest,
+            }
+        );
+        let mut me: Self = serde_json::from_slice(&bytes).context(error::BodyDeserializeSnafu)?;
         me.platform = platform.clone();
         Ok(me)
     }
@@ -101,11 +625,44 @@ impl Image {
         &self.layers
     }
 
+    /// Type of artifact this manifest describes, if it is not a container image
+    pub fn artifact_type(&self) -> Option<&str> {
+        self.artifact_type.as_deref()
+    }
+
+    /// Manifest this artifact refers to, if any
+    pub fn subject(&self) -> Option<&Layer> {
+        self.subject.as_ref()
+    }
+
+    /// Annotations of the manifest
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.annotations
+    }
+
+    /// Set an annotation on the manifest
+    pub fn annotate(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.annotations.insert(key.into(), value.into());
+    }
+
     /// Stored platform hint, primarily used for construction of an index
     pub fn platform(&self) -> Option<Platform> {
         self.platform.clone()
     }
 
+    /// Set the platform hint of a manifest that was not fetched through [`Image::fetch`]
+    pub fn set_platform(&mut self, platform: Option<Platform>) {
+        self.platform = platform;
+    }
+
+    /// Platform of this image, taken from the platform hint or else from the image configuration
+    pub async fn fetch_platform(&self, uri: &Uri) -> crate::Result<Platform> {
+        if let Some(platform) = self.platform() {
+            return Ok(platform);
+        }
+        Ok(self.fetch_config(uri).await?.platform())
+    }
+
     /// Fetch and deserialize the image configuration from the registry
     pub async fn fetch_config(&self, uri: &Uri) -> crate::Result<ImageConfig> {
         let mut layer = self.config.open(uri).await?;
@@ -117,6 +674,112 @@ impl Image {
         serde_json::from_str(config.as_str()).context(error::ConfigDeserializeSnafu)
     }
 
+    /// Compute the diff ids of the layers, the digests of their uncompressed content. Compressed
+    /// layers are read and decompressed to hash them.
+    #[cfg(feature = "compression")]
+    pub async fn diff_ids(&self, uri: &Uri) -> crate::Result<Vec<String>> {
+        let mut diff_ids = Vec::with_capacity(self.layers.len());
+        for layer in self.layers.iter() {
+            if layer.media_type().compression() == Compression::None {
+                diff_ids.push(layer.digest().to_string());
+                continue;
+            }
+            let reader = Decompress::new(layer.media_type(), layer.open(uri).await?);
+            diff_ids.push(hash(reader).await?.0);
+        }
+        Ok(diff_ids)
+    }
+
+    /// Download the config and every layer of the image, checking their content against the
+    /// digest and size of their descriptor and the diff ids of the config against the digests of
+    /// the decompressed layers. Returns the problems found, none for an intact image.
+    #[cfg(feature = "compression")]
+    pub async fn verify_digests(&self, uri: &Uri) -> crate::Result<Vec<BlobProblem>> {
+        let mut problems = Vec::new();
+        let config = match self.config.open_unverified(uri).await {
+            Ok(mut reader) => {
+                let mut bytes = Vec::with_capacity(self.config.size());
+                reader
+                    .read_to_end(&mut bytes)
+                    .await
+                    .context(error::LayerReadSnafu)?;
+                check_blob(
+                    &self.config,
+                    &blob_hasher(&self.config),
+                    &bytes,
+                    &mut problems,
+                );
+                serde_json::from_slice::<ImageConfig>(&bytes)
+                    .map_err(|e| {
+                        problems.push(BlobProblem {
+                            digest: self.config.digest().to_string(),
+                            reason: format!("config is not a valid image configuration: {e}"),
+                        })
+                    })
+                    .ok()
+            }
+            Err(e) => {
+                problems.push(BlobProblem {
+                    digest: self.config.digest().to_string(),
+                    reason: format!("config can not be read: {e}"),
+                });
+                None
+            }
+        };
+        let mut diff_ids = Vec::with_capacity(self.layers.len());
+        for layer in self.layers.iter() {
+            match digest_blob(layer, uri).await {
+                Ok((hasher, size, diff_id)) => {
+                    check_size(layer, &hasher.digest(), size, &mut problems);
+                    match diff_id {
+                        Ok(diff_id) => diff_ids.push(Some(diff_id)),
+                        Err(e) => {
+                            problems.push(BlobProblem {
+                                digest: layer.digest().to_string(),
+                                reason: format!("layer can not be decompressed: {e}"),
+                            });
+                            diff_ids.push(None);
+                        }
+                    }
+                }
+                Err(e) => {
+                    problems.push(BlobProblem {
+                        digest: layer.digest().to_string(),
+                        reason: format!("layer can not be read: {e}"),
+                    });
+                    diff_ids.push(None);
+                }
+            }
+        }
+        // Artifacts have no rootfs to check the layers against
+        if let Some(config) = config.filter(|_| self.artifact_type.is_none()) {
+            let expected = &config.rootfs.diff_ids;
+            if expected.len() != self.layers.len() {
+                problems.push(BlobProblem {
+                    digest: self.config.digest().to_string(),
+                    reason: format!(
+                        "config lists {} diff ids for {} layers",
+                        expected.len(),
+                        self.layers.len()
+                    ),
+                });
+            }
+            for ((layer, actual), expected) in self.layers.iter().zip(diff_ids).zip(expected) {
+                if let Some(actual) = actual
+                    && &actual != expected
+                {
+                    problems.push(BlobProblem {
+                        digest: layer.digest().to_string(),
+                        reason: format!(
+                            "decompressed content hashes to {actual}, the config lists diff id {expected}"
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(problems)
+    }
+
     /// Extract the content of this image to filesystem. This method assumes that the layers are a series
     /// of tar archives that can be extracted. It requires the compression feature in order to automatically
     /// decompress the layers
@@ -125,80 +788,515 @@ impl Image {
     where
         W: AsyncWrite + Unpin + Send + 'static,
     {
-        let mut archive = ArchiveBuilder::new(output);
-        let mut filemap: HashSet<String> = HashSet::new();
+        let mut merger = Merger::new(output);
+        for layer in self.layers.iter().rev() {
+            merger
+                .add(Decompress::new(layer.media_type(), layer.open(uri).await?))
+                .await?;
+        }
+        merger.finish().await?;
+        Ok(())
+    }
 
+    /// Extract the content of this image to filesystem. This method assumes that the layers are a series
+    /// of tar archives that can be extracted. It requires the compression feature in order to automatically
+    /// decompress the layers. It also reports the layer downloads to the progress sink.
+    #[cfg(feature = "compression")]
+    pub async fn filesystem_progress<W>(
+        &self,
+        uri: &Uri,
+        output: W,
+        progress: &Arc<dyn ProgressSink>,
+    ) -> crate::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut merger = Merger::new(output);
         for layer in self.layers.iter().rev() {
-            let reader = Decompress::new(layer.media_type(), layer.open(uri).await?);
-            let mut layer = Archive::new(reader);
-            // Make sure to use the raw entry stream to avoid truncation of long links and long paths
-            let mut entries = layer.entries_raw().context(error::LayerArchiveSnafu)?;
+            merger
+                .add(Decompress::new(
+                    layer.media_type(),
+                    layer.open_progress(uri, progress.clone()).await?,
+                ))
+                .await?;
+        }
+        merger.finish().await?;
+        Ok(())
+    }
+
+    /// List the files of the image filesystem without extracting it, sorted by path. The layers
+    /// are streamed from the top most one down, leaving out the files shadowed by upper layers or
+    /// deleted by their whiteouts. It requires the compression feature to decompress the layers
+    #[cfg(feature = "compression")]
+    pub async fn list_files(&self, uri: &Uri) -> crate::Result<impl Iterator<Item = FileEntry>> {
+        let mut overlay = Overlay::default();
+        let mut files = Vec::new();
+        for layer in self.layers.iter().rev() {
+            let mut archive =
+                Archive::new(Decompress::new(layer.media_type(), layer.open(uri).await?));
+            let mut entries = archive.entries().context(error::LayerArchiveSnafu)?;
             while let Some(entry) = entries.next().await {
-                let mut entry = entry.context(error::LayerArchiveSnafu)?;
-                let header = entry.header().clone();
-                let path = header.path().context(error::LayerArchiveSnafu)?;
+                let entry = entry.context(error::LayerArchiveSnafu)?;
+                let path = entry.path().context(error::LayerArchiveSnafu)?;
                 let path = path.to_string_lossy();
-                if path.contains(WHITEOUT)
-                    || (header.entry_type().is_file() && filemap.contains(path.as_ref()))
-                {
+                let header = entry.header();
+                let kind = header.entry_type();
+                if !overlay.visible(&path, kind.is_dir()) {
                     continue;
                 }
+                let file_type = if kind.is_dir() {
+                    0o040000
+                } else if kind.is_symlink() {
+                    0o120000
+                } else if kind.is_character_special() {
+                    0o020000
+                } else if kind.is_block_special() {
+                    0o060000
+                } else if kind.is_fifo() {
+                    0o010000
+                } else {
+                    0o100000
+                };
+                let mode = header.mode().context(error::LayerArchiveSnafu)? & 0o7777;
+                files.push(FileEntry {
+                    path: format!("/{}", relative_path(&path)),
+                    size: header.size().context(error::LayerArchiveSnafu)?,
+                    mode: file_type | mode,
+                    layer: layer.digest().to_string(),
+                });
+            }
+            overlay.next_layer();
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files.into_iter())
+    }
 
-                filemap.insert(path.to_string());
-                archive
-                    .append(&header, &mut entry)
+    /// Read a file of the image filesystem without extracting it. The layers are scanned from the
+    /// top most one down, honoring whiteouts, and the scan stops at the first match. Symbolic links
+    /// to the file or to directories on its path are followed. Returns `None` when there is no
+    /// such file
+    #[cfg(feature = "compression")]
+    pub async fn read_file(&self, uri: &Uri, path: &str) -> crate::Result<Option<Vec<u8>>> {
+        let mut target = relative_path(path).to_string();
+        for _ in 0..=MAX_SYMLINKS {
+            match self.lookup(uri, &target).await? {
+                Lookup::Content(content) => return Ok(Some(content)),
+                Lookup::Link(link) => target = link,
+                Lookup::Missing => return Ok(None),
+            }
+        }
+        error::SymlinkLoopSnafu { path }.fail()
+    }
+
+    /// Look up a path relative to the filesystem root in the layers, from the top most one down
+    #[cfg(feature = "compression")]
+    async fn lookup(&self, uri: &Uri, path: &str) -> crate::Result<Lookup> {
+        let mut overlay = Overlay::default();
+        for layer in self.layers.iter().rev() {
+            let mut archive =
+                Archive::new(Decompress::new(layer.media_type(), layer.open(uri).await?));
+            let mut entries = archive.entries().context(error::LayerArchiveSnafu)?;
+            while let Some(entry) = entries.next().await {
+                let mut entry = entry.context(error::LayerArchiveSnafu)?;
+                let name = entry.path().context(error::LayerArchiveSnafu)?;
+                let name = relative_path(&name.to_string_lossy()).to_string();
+                let kind = entry.header().entry_type();
+                if !overlay.visible(&name, kind.is_dir()) {
+                    continue;
+                }
+                let rest = if name == path {
+                    ""
+                } else if let Some(rest) = path.strip_prefix(&format!("{name}/")) {
+                    rest
+                } else {
+                    continue;
+                };
+                if kind.is_symlink() || (kind.is_hard_link() && rest.is_empty()) {
+                    let link = entry
+                        .link_name()
+                        .context(error::LayerArchiveSnafu)?
+                        .unwrap_or_default();
+                    let link = link.to_string_lossy();
+                    // Hard links are relative to the root, symbolic links to their directory
+                    let parent = match name.rsplit_once('/') {
+                        Some((parent, _)) if kind.is_symlink() => parent,
+                        _ => "",
+                    };
+                    let resolved = resolve_link(parent, &link);
+                    return Ok(Lookup::Link(if rest.is_empty() {
+                        resolved
+                    } else {
+                        format!("{resolved}/{rest}")
+                    }));
+                }
+                if !rest.is_empty() {
+                    continue;
+                }
+                ensure!(
+                    !kind.is_dir(),
+                    error::NotAFileSnafu {
+                        path: format!("/{path}"),
+                    }
+                );
+                let mut content = Vec::new();
+                entry
+                    .read_to_end(&mut content)
                     .await
-                    .context(error::LayerCopySnafu)?;
+                    .context(error::LayerReadSnafu)?;
+                return Ok(Lookup::Content(content));
+            }
+            overlay.next_layer();
+            if overlay.hidden(path) {
+                break;
             }
         }
-        archive.finish().await.context(error::ArchiveSnafu)?;
+        Ok(Lookup::Missing)
+    }
+
+    /// Extract a file or directory of the image filesystem to a local directory, keeping its path
+    /// relative to the filesystem root, without exporting the whole filesystem. The layers are
+    /// scanned from the top most one down, honoring whiteouts, and the scan stops at the first
+    /// match unless it is a directory. Returns the number of entries written, zero when there is
+    /// no such path
+    #[cfg(feature = "compression")]
+    pub async fn extract(&self, uri: &Uri, path: &str, output: &Path) -> crate::Result<usize> {
+        self.extract_layers(uri, path, output, None).await
+    }
 
+    /// Unpack the filesystem of this image to a local directory, as the rootfs of a container
+    /// would be, with the modes, symbolic links and hard links of the layers and their whiteouts
+    /// applied. It requires the compression feature in order to automatically decompress the
+    /// layers
+    #[cfg(feature = "compression")]
+    pub async fn unpack(&self, uri: &Uri, output: &Path) -> crate::Result<()> {
+        self.extract_layers(uri, "/", output, None).await?;
         Ok(())
     }
 
-    /// Extract the content of this image to filesystem. This method assumes that the layers are a series
-    /// of tar archives that can be extracted. It requires the compression feature in order to automatically
-    /// decompress the layers. It also reports to indicatif progress bars.
-    #[cfg(all(feature = "progress", feature = "compression"))]
-    pub async fn filesystem_progress<W>(
+    /// Unpack the filesystem of this image to a local directory, as the rootfs of a container
+    /// would be, with the modes, symbolic links and hard links of the layers and their whiteouts
+    /// applied. It requires the compression feature in order to automatically decompress the
+    /// layers. It also reports the layer downloads to the progress sink.
+    #[cfg(feature = "compression")]
+    pub async fn unpack_progress(
         &self,
         uri: &Uri,
-        output: W,
-        multi: &mut MultiProgress,
-    ) -> crate::Result<()>
-    where
-        W: AsyncWrite + Unpin + Send + 'static,
-    {
-        let mut archive = ArchiveBuilder::new(output);
-        let mut filemap: HashSet<String> = HashSet::new();
+        output: &Path,
+        progress: &Arc<dyn ProgressSink>,
+    ) -> crate::Result<()> {
+        self.extract_layers(uri, "/", output, Some(progress))
+            .await?;
+        Ok(())
+    }
 
+    /// Write the visible entries at or below a path to a local directory, reading the layers from
+    /// the top most one down
+    #[cfg(feature = "compression")]
+    async fn extract_layers(
+        &self,
+        uri: &Uri,
+        path: &str,
+        output: &Path,
+        progress: Option<&Arc<dyn ProgressSink>>,
+    ) -> crate::Result<usize> {
+        let path = relative_path(path);
+        let mut overlay = Overlay::default();
+        // Directories are created as they come and get their modes once their content is written,
+        // hard links once the file they link to, possibly from a lower layer, is written
+        let mut directories = Vec::new();
+        let mut links = Vec::new();
+        let mut count = 0;
         for layer in self.layers.iter().rev() {
-            let reader =
-                Decompress::new(layer.media_type(), layer.open_progress(uri, multi).await?);
-            let mut layer = Archive::new(reader);
-            // Make sure to use the raw entry stream to avoid truncation of long links and long paths
-            let mut entries = layer.entries_raw().context(error::LayerArchiveSnafu)?;
+            let reader = match progress {
+                Some(progress) => layer.open_progress(uri, progress.clone()).await?,
+                None => layer.open(uri).await?,
+            };
+            let mut archive = Archive::new(Decompress::new(layer.media_type(), reader));
+            let mut entries = archive.entries().context(error::LayerArchiveSnafu)?;
             while let Some(entry) = entries.next().await {
                 let mut entry = entry.context(error::LayerArchiveSnafu)?;
+                let name = entry.path().context(error::LayerArchiveSnafu)?;
+                let name = relative_path(&name.to_string_lossy()).to_string();
                 let header = entry.header().clone();
-                let path = header.path().context(error::LayerArchiveSnafu)?;
-                let path = path.to_string_lossy();
-                if path.contains(WHITEOUT)
-                    || (header.entry_type().is_file() && filemap.contains(path.as_ref()))
+                let kind = header.entry_type();
+                if !overlay.visible(&name, kind.is_dir())
+                    || !(path.is_empty() || name == path || name.starts_with(&format!("{path}/")))
                 {
                     continue;
                 }
+                if name.split('/').any(|x| x == "..") {
+                    warn!(target: "image", "skipping {name} which is outside of the filesystem root");
+                    continue;
+                }
+                let destination = output.join(&name);
+                if let Some(parent) = destination.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .context(error::FileSnafu)?;
+                }
+                count += 1;
+                if kind.is_dir() {
+                    tokio::fs::create_dir_all(&destination)
+                        .await
+                        .context(error::FileSnafu)?;
+                    directories.push((destination, header.mode().unwrap_or(0o755)));
+                } else if kind.is_hard_link() {
+                    let link = entry
+                        .link_name()
+                        .context(error::LayerArchiveSnafu)?
+                        .unwrap_or_default();
+                    links.push((
+                        destination,
+                        output.join(resolve_link("", &link.to_string_lossy())),
+                    ));
+                } else {
+                    entry.set_preserve_permissions(true);
+                    entry
+                        .unpack(&destination)
+                        .await
+                        .context(error::LayerArchiveSnafu)?;
+                }
+                if name == path && !kind.is_dir() {
+                    break;
+                }
+            }
+            overlay.next_layer();
+            if overlay.hidden(path) || overlay.present.get(path).is_some_and(|is_dir| !is_dir) {
+                break;
+            }
+        }
+        for (destination, target) in links {
+            if let Err(e) = tokio::fs::hard_link(&target, &destination).await {
+                warn!(
+                    target: "image",
+                    "could not link {} to {}: {e}",
+                    destination.display(),
+                    target.display()
+                );
+            }
+        }
+        // Deepest first, so read only directories don't prevent setting the modes below them
+        for (destination, mode) in directories.into_iter().rev() {
+            set_mode(&destination, mode).await?;
+        }
+        Ok(count)
+    }
 
-                filemap.insert(path.to_string());
-                archive
-                    .append(&header, &mut entry)
-                    .await
-                    .context(error::LayerCopySnafu)?;
+    /// Squash the layers of this image into a single layer with the compression and push the
+    /// resulting single layer image to the target, which can be in another repository. The config is
+    /// kept apart from the rootfs and history, which describe the single layer.
+    #[cfg(feature = "compression")]
+    pub async fn flatten(
+        &self,
+        uri: &Uri,
+        target: &Uri,
+        compression: Compression,
+    ) -> crate::Result<Layer> {
+        let tmp_dir = tempdir().context(error::TempSnafu)?;
+        let tar_path = tmp_dir.path().join("layer.tar");
+        let mut merger = Merger::new(File::create(&tar_path).await.context(error::FileSnafu)?);
+        for layer in self.layers.iter().rev() {
+            merger
+                .add(Decompress::new(layer.media_type(), layer.open(uri).await?))
+                .await?;
+        }
+        merger
+            .finish()
+            .await?
+            .flush()
+            .await
+            .context(error::FileSnafu)?;
+        let (diff_id, _) = hash(File::open(&tar_path).await.context(error::FileSnafu)?).await?;
+
+        let layer_path = match compression {
+            Compression::None => tar_path,
+            _ => {
+                let layer_path = tmp_dir.path().join("layer");
+                compress_file(&tar_path, &layer_path, &compression).await?;
+                layer_path
+            }
+        };
+        let mut image = self.clone();
+        image.convert_for(&compression);
+        let media_type = image.layer_media_type(&compression);
+        let layer = upload_file(target, &layer_path, &media_type).await?.0;
+
+        let mut config = self.fetch_config(uri).await?;
+        config.rootfs = RootFs::builder().diff_ids(vec![diff_id]).build();
+        config.history = vec![
+            History::builder()
+                .created(Utc::now())
+                .created_by("ocilot flatten")
+                .comment(format!("flattened {} layers", self.layers.len()))
+                .empty_layer(false)
+                .build(),
+        ];
+        let bytes = serde_json::to_vec(&config).context(error::SerializeSnafu)?;
+        image.config = Self::upload_config(target, image.config.media_type(), &bytes).await?;
+        image.layers = vec![layer];
+        image.push(target).await
+    }
+
+    /// Recompress the layers of this image with the compression and upload them with the config to
+    /// the target, which can be in another repository. Layers already using the compression are
+    /// copied as they are, the diff ids in the config are updated for the others. Docker images
+    /// are converted to the oci media types for compressions docker has none for, i.e. zstd.
+    /// Returns the transcoded image, which still has to be pushed.
+    #[cfg(feature = "compression")]
+    pub async fn transcode(
+        &self,
+        uri: &Uri,
+        target: &Uri,
+        compression: Compression,
+    ) -> crate::Result<Self> {
+        let tmp_dir = tempdir().context(error::TempSnafu)?;
+        let mut config = self.fetch_config(uri).await?;
+        let mut changed = false;
+        let mut image = self.clone();
+        image.convert_for(&compression);
+        for (index, layer) in self.layers.iter().enumerate() {
+            let media_type = match layer.media_type() {
+                MediaType::DockerImageRootfs(current) | MediaType::Layer(current)
+                    if *current != compression =>
+                {
+                    image.layer_media_type(&compression)
+                }
+                _ => {
+                    transfer(layer, uri, target).await?;
+                    continue;
+                }
+            };
+            let tar_path = tmp_dir.path().join(format!("{index}.tar"));
+            let mut file = File::create(&tar_path).await.context(error::FileSnafu)?;
+            let mut reader = Decompress::new(layer.media_type(), layer.open(uri).await?);
+            tokio::io::copy(&mut reader, &mut file)
+                .await
+                .context(error::LayerCopySnafu)?;
+            file.flush().await.context(error::FileSnafu)?;
+            let (diff_id, _) = hash(File::open(&tar_path).await.context(error::FileSnafu)?).await?;
+            let layer_path = match compression {
+                Compression::None => tar_path,
+                _ => {
+                    let layer_path = tmp_dir.path().join(index.to_string());
+                    compress_file(&tar_path, &layer_path, &compression).await?;
+                    layer_path
+                }
+            };
+            image.layers[index] = upload_file(target, &layer_path, &media_type).await?.0;
+            debug!(target: "image", "transcoded {} to {}", layer.digest(), image.layers[index].digest());
+            if let Some(id) = config.rootfs.diff_ids.get_mut(index)
+                && *id != diff_id
+            {
+                *id = diff_id;
+                changed = true;
             }
         }
-        archive.finish().await.context(error::ArchiveSnafu)?;
+        if changed {
+            let bytes = serde_json::to_vec(&config).context(error::SerializeSnafu)?;
+            image.config = Self::upload_config(target, image.config.media_type(), &bytes).await?;
+        } else {
+            transfer(&self.config, uri, target).await?;
+        }
+        Ok(image)
+    }
 
-        Ok(())
+    /// Switch a docker manifest to the oci media types when it is to hold a layer with a
+    /// compression docker defines no media type for. The blobs stay the same, the docker config is
+    /// a valid oci config.
+    fn convert_for(&mut self, compression: &Compression) {
+        if self.media_type != MediaType::DockerManifest || compression.has_docker_media_type() {
+            return;
+        }
+        debug!(target: "image", "converting to oci media types for {} layers", compression.to_ext());
+        self.media_type = self.media_type.to_oci();
+        self.config
+            .set_media_type(self.config.media_type().to_oci());
+        for layer in self.layers.iter_mut() {
+            layer.set_media_type(layer.media_type().to_oci());
+        }
+    }
+
+    /// Media type of a layer with the compression in this kind of manifest
+    fn layer_media_type(&self, compression: &Compression) -> MediaType {
+        match self.media_type {
+            MediaType::DockerManifest => MediaType::DockerImageRootfs(compression.clone()),
+            _ => MediaType::Layer(compression.clone()),
+        }
+    }
+
+    /// Replace the layers of the old base image this image was built on with the layers of the new
+    /// base image and push the result to the target. The bases are selected by the platform of this
+    /// image when they are indexes. The diff ids and history of the base are swapped as well, while
+    /// the rest of the config is kept.
+    pub async fn rebase(
+        &self,
+        uri: &Uri,
+        old_base: &Uri,
+        new_base: &Uri,
+        target: &Uri,
+    ) -> crate::Result<Layer> {
+        let mut config = self.fetch_config(uri).await?;
+        let platform = self.platform().unwrap_or_else(|| config.platform());
+        let mut bases = Vec::with_capacity(2);
+        for base in [old_base, new_base] {
+            let image = Manifest::fetch(base)
+                .await?
+                .fetch_image(base, Some(platform.clone()))
+                .await?
+                .context(error::ImageNotFoundSnafu {
+                    uri: base.to_string(),
+                })?;
+            let config = image.fetch_config(base).await?;
+            bases.push((image, config));
+        }
+        let (new_image, new_config) = bases.pop().unwrap();
+        let (old_image, old_config) = bases.pop().unwrap();
+        let base_layers = old_image.layers.len();
+        ensure!(
+            self.layers.len() >= base_layers
+                && self
+                    .layers
+                    .iter()
+                    .zip(old_image.layers.iter())
+                    .all(|(x, y)| x.digest() == y.digest()),
+            error::RebaseBaseSnafu {
+                image: uri.to_string(),
+                base: old_base.to_string(),
+            }
+        );
+
+        let own_layers = &self.layers[base_layers..];
+        for layer in new_image.layers.iter() {
+            transfer(layer, new_base, target).await?;
+        }
+        for layer in own_layers.iter() {
+            transfer(layer, uri, target).await?;
+        }
+        let diff_ids = config
+            .rootfs
+            .diff_ids
+            .split_off(base_layers.min(config.rootfs.diff_ids.len()));
+        config.rootfs.diff_ids = new_config
+            .rootfs
+            .diff_ids
+            .into_iter()
+            .chain(diff_ids)
+            .collect();
+        let history = config
+            .history
+            .split_off(old_config.history.len().min(config.history.len()));
+        config.history = new_config.history.into_iter().chain(history).collect();
+
+        let bytes = serde_json::to_vec(&config).context(error::SerializeSnafu)?;
+        let mut image = self.clone();
+        image.config = Self::upload_config(target, self.config.media_type(), &bytes).await?;
+        image.layers = new_image
+            .layers
+            .iter()
+            .chain(own_layers.iter())
+            .cloned()
+            .collect();
+        image.push(target).await
     }
 
     /// Write this image out as a docker loadable tarball. This is NOT an oci archive and is primarily to be used with
@@ -227,6 +1325,7 @@ impl Image {
             let uri = uri.clone();
             let tmp_path = tmp_path.clone();
             tasks.push(tokio::spawn(async move {
+                let _permit = uri.registry().transfer_permit().await;
                 let mut reader = layer.open(&uri).await?;
                 let blob_layer = format!(
                     "{}.tar{}",
@@ -260,13 +1359,13 @@ impl Image {
     }
 
     /// Write this image out as a docker loadable tarball. This is NOT an oci archive and is primarily to be used with
-    /// docker/finch/podman/nerdctl load. This version will report as it fetches the image to indicatif progress bars.
-    #[cfg(all(feature = "compression", feature = "progress"))]
+    /// docker/finch/podman/nerdctl load. This version will report as it fetches the image to the progress sink.
+    #[cfg(feature = "compression")]
     pub async fn to_tarball_progress<W>(
         &self,
         uri: &Uri,
         output: W,
-        progress: &mut MultiProgress,
+        progress: &Arc<dyn ProgressSink>,
     ) -> crate::Result<()>
     where
         W: AsyncWrite + Unpin + Send + 'static,
@@ -277,7 +1376,7 @@ impl Image {
             .layers(vec![])
             .build();
         let tmp_dir = tempdir().context(error::TempSnafu)?;
-        let mut config_reader = self.config.open_progress(uri, progress).await?;
+        let mut config_reader = self.config.open_progress(uri, progress.clone()).await?;
         let mut config_file = File::create(tmp_dir.path().join(self.config.digest()))
             .await
             .context(error::FileSnafu)?;
@@ -289,9 +1388,10 @@ impl Image {
             let layer = layer.clone();
             let uri = uri.clone();
             let tmp_path = tmp_path.clone();
-            let mut multi = progress.clone();
+            let progress = progress.clone();
             tasks.push(tokio::spawn(async move {
-                let mut reader = layer.open_progress(&uri, &mut multi).await?;
+                let _permit = uri.registry().transfer_permit().await;
+                let mut reader = layer.open_progress(&uri, progress).await?;
                 let blob_layer = format!(
                     "{}.tar{}",
                     layer.digest().split_once(":").unwrap().1,
@@ -336,17 +1436,369 @@ impl Image {
             .await
     }
 
+    /// Upload the empty config blob of artifact manifests, unless the registry already has it
+    pub async fn create_empty_config(uri: &Uri) -> crate::Result<Layer> {
+        if let Some(mut writer) = Layer::create(
+            uri,
+            &MediaType::Empty,
+            EMPTY_CONFIG.len(),
+            Some(EMPTY_CONFIG_DIGEST.to_string()),
+        )
+        .await?
+        {
+            writer
+                .write_all(EMPTY_CONFIG)
+                .await
+                .context(error::LayerWriteSnafu)?;
+            writer.flush().await.context(error::LayerWriteSnafu)?;
+            writer.layer().await?;
+        }
+        Ok(Self::empty_config())
+    }
+
+    /// Append local layer tarballs to this image and push the updated manifest to the uri, which
+    /// has to be in the repository of this image. Gzip and zstd compressed tarballs are uploaded as
+    /// they are, uncompressed ones are compressed with the compression unless it is none. The config
+    /// records the diff ids and a history entry for each new layer, all other fields of the config
+    /// are kept as they are.
+    #[cfg(feature = "compression")]
+    pub async fn append_layers<P>(
+        &self,
+        uri: &Uri,
+        new_layers: &[P],
+        compression: Compression,
+    ) -> crate::Result<Layer>
+    where
+        P: AsRef<Path>,
+    {
+        let tmp_dir = tempdir().context(error::TempSnafu)?;
+        let mut image = self.clone();
+        let mut diff_ids = Vec::new();
+        for (index, path) in new_layers.iter().map(AsRef::as_ref).enumerate() {
+            let mut file = File::open(path).await.context(error::FileSnafu)?;
+            let mut magic = [0u8; 4];
+            let detected = match file.read_exact(&mut magic).await {
+                Ok(_) if magic[..2] == GZIP_MAGIC => Compression::Gzip,
+                Ok(_) if magic == ZSTD_MAGIC => Compression::Zstd,
+                _ => Compression::None,
+            };
+            let (diff_id, layer) = match detected {
+                Compression::None => {
+                    let (diff_id, _) =
+                        hash(File::open(path).await.context(error::FileSnafu)?).await?;
+                    if compression == Compression::None {
+                        let layer = upload_file(uri, path, &image.layer_media_type(&compression))
+                            .await?
+                            .0;
+                        (diff_id, layer)
+                    } else {
+                        let compressed = tmp_dir.path().join(index.to_string());
+                        compress_file(path, &compressed, &compression).await?;
+                        image.convert_for(&compression);
+                        let media_type = image.layer_media_type(&compression);
+                        (diff_id, upload_file(uri, &compressed, &media_type).await?.0)
+                    }
+                }
+                detected => {
+                    image.convert_for(&detected);
+                    let media_type = image.layer_media_type(&detected);
+                    let reader = Reader::new(File::open(path).await.context(error::FileSnafu)?);
+                    let (diff_id, _) = hash(Decompress::new(&media_type, reader)).await?;
+                    (diff_id, upload_file(uri, path, &media_type).await?.0)
+                }
+            };
+            debug!(target: "image", "appended {} as {}", path.display(), layer.digest());
+            image.layers.push(layer);
+            diff_ids.push(serde_json::Value::from(diff_id));
+        }
+
+        // Edit the config as json so fields the models do not know about are kept
+        let mut reader = self.config.open(uri).await?;
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .context(error::LayerReadSnafu)?;
+        let mut config: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&bytes).context(error::ConfigDeserializeSnafu)?;
+        if let serde_json::Value::Object(rootfs) = config
+            .entry("rootfs")
+            .or_insert_with(|| serde_json::json!({ "type": "layers" }))
+            && let serde_json::Value::Array(ids) = rootfs
+                .entry("diff_ids")
+                .or_insert_with(|| serde_json::json!([]))
+        {
+            ids.extend(diff_ids);
+        }
+        if let serde_json::Value::Array(history) = config
+            .entry("history")
+            .or_insert_with(|| serde_json::json!([]))
+        {
+            let created = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+            history.extend(new_layers.iter().map(|path| {
+                serde_json::json!({
+                    "created": created,
+                    "created_by": "ocilot append",
+                    "comment": path.as_ref().display().to_string(),
+                })
+            }));
+        }
+        let bytes = serde_json::to_vec(&config).context(error::SerializeSnafu)?;
+        image.config = Self::upload_config(uri, image.config.media_type(), &bytes).await?;
+        image.push(uri).await
+    }
+
+    /// Edit the config of this image and push the image with the new config to the uri, which has
+    /// to be in the repository of this image. The layers are left as they are.
+    pub async fn mutate_config<F>(&self, uri: &Uri, mutate: F) -> crate::Result<Layer>
+    where
+        F: FnOnce(&mut ImageConfig),
+    {
+        let mut config = self.fetch_config(uri).await?;
+        mutate(&mut config);
+        let bytes = serde_json::to_vec(&config).context(error::SerializeSnafu)?;
+        let mut image = self.clone();
+        image.config = Self::upload_config(uri, self.config.media_type(), &bytes).await?;
+        image.push(uri).await
+    }
+
     /// Create a new config layer blob for an image
-    pub async fn create_config(uri: &Uri, config: &Config) -> crate::Result<Layer> {
+    pub async fn create_config(uri: &Uri, config: &ImageConfig) -> crate::Result<Layer> {
         let config_bytes = serde_json::to_vec(config).context(error::SerializeSnafu)?;
-        let mut writer = Layer::create(uri, &MediaType::Config, config_bytes.len(), None)
-            .await?
-            .unwrap();
-        writer
-            .write_all(config_bytes.as_slice())
+        Self::upload_config(uri, &MediaType::Config, &config_bytes).await
+    }
+
+    /// Upload a config blob of the media type, unless the registry already has it
+    async fn upload_config(
+        uri: &Uri,
+        media_type: &MediaType,
+        bytes: &[u8],
+    ) -> crate::Result<Layer> {
+        Layer::upload(uri, media_type, bytes).await
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod test {
+    use super::{Image, Overlay, blob_hasher, check_blob, pax_path, resolve_link};
+    use crate::layer::Layer;
+    use crate::models::{Compression, MediaType};
+
+    #[test]
+    fn test_overlay_whiteouts() {
+        let mut overlay = Overlay::default();
+        // Top most layer
+        assert!(overlay.visible("etc/", true));
+        assert!(!overlay.visible("etc/.wh.passwd", false));
+        assert!(overlay.visible("etc/hosts", false));
+        assert!(!overlay.visible("opt/.wh..wh..opq", false));
+        assert!(!overlay.visible(".wh.tmp", false));
+        overlay.next_layer();
+        // Lower layer
+        assert!(!overlay.visible("./etc/", true));
+        assert!(!overlay.visible("etc/passwd", false));
+        assert!(!overlay.visible("etc/hosts", false));
+        assert!(overlay.visible("etc/group", false));
+        assert!(overlay.visible("opt", true));
+        assert!(!overlay.visible("opt/app", false));
+        assert!(!overlay.visible("tmp/file", false));
+        assert!(overlay.visible("usr/bin/sh", false));
+    }
+
+    #[test]
+    fn test_pax_path() {
+        assert_eq!(
+            pax_path(b"19 path=etc/passwd\n30 mtime=1700000000.123456789\n").as_deref(),
+            Some("etc/passwd")
+        );
+        assert_eq!(pax_path(b"30 mtime=1700000000.123456789\n"), None);
+        assert_eq!(pax_path(b"99 path=truncated\n"), None);
+    }
+
+    #[test]
+    fn test_resolve_link() {
+        assert_eq!(
+            resolve_link("etc", "../usr/lib/os-release"),
+            "usr/lib/os-release"
+        );
+        assert_eq!(resolve_link("usr/bin", "python3"), "usr/bin/python3");
+        assert_eq!(resolve_link("usr/bin", "/bin/./sh"), "bin/sh");
+        assert_eq!(resolve_link("", "../../etc"), "etc");
+    }
+
+    #[test]
+    fn test_check_blob() {
+        let layer = Layer::builder()
+            .media_type(MediaType::Config)
+            .digest("sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a")
+            .size(2usize)
+            .build();
+        let hasher = blob_hasher(&layer);
+        let mut problems = Vec::new();
+        check_blob(&layer, &hasher, b"{}", &mut problems);
+        assert!(problems.is_empty());
+        check_blob(&layer, &hasher, b"{ }", &mut problems);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].reason.starts_with("content hashes to sha256:"));
+        assert_eq!(
+            problems[1].reason,
+            "content is 3 bytes, the manifest lists 2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_annotations_order() {
+        let config = Layer::builder()
+            .media_type(MediaType::Config)
+            .digest("sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a")
+            .size(2usize)
+            .build();
+        let mut first = Image::create(&config, &[], None).await;
+        let mut second = first.clone();
+        for key in ["org.example.b", "org.example.a", "org.example.c"] {
+            first.annotate(key, "value");
+        }
+        for key in ["org.example.c", "org.example.a", "org.example.b"] {
+            second.annotate(key, "value");
+        }
+        // The same annotations serialize the same way, so the manifests have the same digest
+        assert_eq!(
+            serde_json::to_vec(&first).unwrap(),
+            serde_json::to_vec(&second).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_docker_for_zstd() {
+        let mut image: Image = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {
+                "mediaType": "application/vnd.docker.container.image.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2,
+            }],
+        }))
+        .unwrap();
+        image.convert_for(&Compression::Gzip);
+        assert_eq!(
+            image.layer_media_type(&Compression::Gzip),
+            MediaType::DockerImageRootfs(Compression::Gzip)
+        );
+        // Docker defines no zstd layers, so the image becomes an oci one
+        image.convert_for(&Compression::Zstd);
+        assert_eq!(image.media_type, MediaType::Manifest);
+        assert_eq!(image.config.media_type(), &MediaType::Config);
+        assert_eq!(
+            image.layers[0].media_type(),
+            &MediaType::Layer(Compression::Gzip)
+        );
+        assert_eq!(
+            image.layer_media_type(&Compression::Zstd),
+            MediaType::Layer(Compression::Zstd)
+        );
+    }
+
+    /// Tar archive of the entries, given as path, type, mode and content or link target
+    #[cfg(feature = "compression")]
+    async fn tar(entries: &[(&str, tokio_tar::EntryType, u32, &str)]) -> Vec<u8> {
+        let mut archive = super::ArchiveBuilder::new(Vec::new());
+        for (path, kind, mode, content) in entries {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_mode(*mode);
+            if kind.is_file() {
+                header.set_size(content.len() as u64);
+                archive
+                    .append_data(&mut header, path, content.as_bytes())
+                    .await
+                    .unwrap();
+            } else {
+                header.set_size(0);
+                if !kind.is_dir() {
+                    header.set_link_name(content).unwrap();
+                }
+                archive
+                    .append_data(&mut header, path, tokio::io::empty())
+                    .await
+                    .unwrap();
+            }
+        }
+        archive.into_inner().await.unwrap()
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_unpack() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::path::Path;
+        use std::str::FromStr;
+        use tokio_tar::EntryType;
+
+        use crate::testing::MockRegistryClient;
+        use crate::uri::{Reference, RegistryUri, Uri};
+
+        let client = MockRegistryClient::new();
+        let uri = Uri::builder()
+            .registry(client.registry(&RegistryUri::from_str("mock.local").unwrap()))
+            .repository("app")
+            .reference(Reference::Tag("latest".to_string()))
+            .build();
+        let lower = tar(&[
+            ("etc/", EntryType::Directory, 0o750, ""),
+            ("etc/passwd", EntryType::Regular, 0o644, "root"),
+            ("etc/hosts", EntryType::Regular, 0o644, "localhost"),
+            ("opt/app/run", EntryType::Regular, 0o755, "old"),
+        ])
+        .await;
+        let upper = tar(&[
+            ("etc/.wh.hosts", EntryType::Regular, 0o644, ""),
+            ("etc/passwd", EntryType::Regular, 0o600, "admin"),
+            ("etc/shadow", EntryType::Link, 0o600, "etc/passwd"),
+            ("opt/.wh..wh..opq", EntryType::Regular, 0o644, ""),
+            ("opt/run", EntryType::Symlink, 0o777, "/etc/passwd"),
+        ])
+        .await;
+        let media_type = MediaType::Layer(Compression::None);
+        let mut layers = Vec::new();
+        for content in [lower, upper] {
+            layers.push(Layer::upload(&uri, &media_type, &content).await.unwrap());
+        }
+        let config = Layer::upload(&uri, &MediaType::Config, b"{}")
             .await
-            .context(error::LayerWriteSnafu)?;
-        writer.flush().await.context(error::LayerWriteSnafu)?;
-        writer.layer().await
+            .unwrap();
+        let image = Image::create(&config, &layers, None).await;
+        let output = tempfile::tempdir().unwrap();
+        image.unpack(&uri, output.path()).await.unwrap();
+
+        let path = output.path();
+        assert_eq!(
+            std::fs::read_to_string(path.join("etc/passwd")).unwrap(),
+            "admin"
+        );
+        assert_eq!(
+            std::fs::read_to_string(path.join("etc/shadow")).unwrap(),
+            "admin"
+        );
+        assert!(!path.join("etc/hosts").exists());
+        assert!(!path.join("opt/app").exists());
+        assert_eq!(
+            std::fs::read_link(path.join("opt/run")).unwrap(),
+            Path::new("/etc/passwd")
+        );
+        let mode = |name: &str| {
+            std::fs::symlink_metadata(path.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("etc"), 0o750);
+        assert_eq!(mode("etc/passwd"), 0o600);
     }
 }
@@ -1,17 +1,20 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::error;
 use crate::image::Image;
 use crate::layer::Layer;
 use crate::models::MediaType;
 use crate::models::Platform;
+use crate::progress::ProgressSink;
 use crate::uri::{Reference, Uri};
+use async_recursion::async_recursion;
 use bon::Builder;
 use futures::future::join_all;
-#[cfg(feature = "progress")]
-use indicatif::MultiProgress;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt};
+use snafu::{OptionExt, ResultExt, ensure};
 use tempfile::tempdir;
 use tokio::fs::{File, create_dir_all};
 use tokio::io::AsyncWrite;
@@ -28,6 +31,9 @@ pub struct Index {
     media_type: MediaType,
     #[builder(into)]
     manifests: Vec<Layer>,
+    #[builder(into, default)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
 }
 
 impl Index {
@@ -37,6 +43,7 @@ impl Index {
             schema_version: 2,
             media_type: MediaType::ImageIndex,
             manifests: manifests.to_vec(),
+            annotations: BTreeMap::new(),
         }
     }
 
@@ -47,15 +54,27 @@ impl Index {
     /// it is an index.
     pub async fn check(uri: &Uri) -> crate::Result<bool> {
         uri.registry()
-            .check_manifest(uri.repository(), uri.reference().to_string().as_str())
+            .manifest_exists(uri.repository(), uri.reference().to_string().as_str())
             .await
     }
 
     /// Fetch an image index from a registry
     pub async fn fetch(uri: &Uri) -> crate::Result<Self> {
-        uri.registry()
-            .fetch_manifest(uri.repository(), uri.reference().to_string().as_str())
-            .await
+        let reference = uri.reference().to_string();
+        let (bytes, digest, _) = uri
+            .registry()
+            .fetch_manifest_raw(uri.repository(), reference.as_str())
+            .await?;
+        if let Reference::Digest { .. } = uri.reference() {
+            ensure!(
+                digest == reference,
+                error::DigestMismatchSnafu {
+                    expected: reference,
+                    actual: digest,
+                }
+            );
+        }
+        serde_json::from_slice(&bytes).context(error::BodyDeserializeSnafu)
     }
 
     /// Schema version
@@ -73,64 +92,179 @@ impl Index {
         self.manifests.as_slice()
     }
 
+    /// Annotations of the index
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.annotations
+    }
+
+    /// Set an annotation on the index
+    pub fn annotate(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.annotations.insert(key.into(), value.into());
+    }
+
+    /// Create a copy of this index with only the manifests matching any of the platforms, fails if
+    /// a platform does not match any manifest. Manifests without a platform are dropped.
+    pub fn filter_platforms(&self, platforms: &[Platform]) -> crate::Result<Self> {
+        if let Some(platform) = platforms.iter().find(|platform| {
+            !self
+                .manifests
+                .iter()
+                .any(|x| x.platform().is_some_and(|x| platform.matches(&x)))
+        }) {
+            return error::IndexNoPlatformSnafu {
+                platform: platform.clone(),
+            }
+            .fail();
+        }
+        let mut index = self.clone();
+        index.manifests.retain(|x| {
+            x.platform()
+                .is_some_and(|x| platforms.iter().any(|platform| platform.matches(&x)))
+        });
+        Ok(index)
+    }
+
+    /// Image manifests of this index, leaving out nested indexes and artifacts such as the
+    /// attestation manifests buildx adds
+    pub fn images(&self) -> impl Iterator<Item = &Layer> {
+        self.manifests.iter().filter(|x| is_image(x))
+    }
+
+    /// Entries of this index that are neither images nor nested indexes
+    pub fn artifacts(&self) -> impl Iterator<Item = &Layer> {
+        self.manifests
+            .iter()
+            .filter(|x| !is_image(x) && !is_index(x))
+    }
+
+    /// Create a copy of this index with the entries of nested indexes in place of the nested
+    /// indexes, recursively. Entries of a nested index without a platform take the platform of the
+    /// nested index.
+    #[async_recursion]
+    pub async fn resolve(&self, uri: &Uri) -> crate::Result<Self> {
+        let mut index = self.clone();
+        index.manifests = Vec::with_capacity(self.manifests.len());
+        for manifest in self.manifests.iter() {
+            if !is_index(manifest) {
+                index.manifests.push(manifest.clone());
+                continue;
+            }
+            let nested = Self::fetch(&digest_uri(uri, manifest.digest())?)
+                .await?
+                .resolve(uri)
+                .await?;
+            for mut entry in nested.manifests {
+                if entry.platform().is_none() {
+                    entry.set_platform(manifest.platform());
+                }
+                index.manifests.push(entry);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Add an image in the repository of the uri to this index with the digest and size of its raw
+    /// manifest, returning the added entry. The entry records the platform hint of the image, or
+    /// else the platform of its configuration.
+    pub async fn add_image(
+        &mut self,
+        uri: &Uri,
+        image: &Image,
+        digest: impl Into<String>,
+        size: usize,
+    ) -> crate::Result<Layer> {
+        let manifest = Layer::builder()
+            .media_type(image.media_type().clone())
+            .digest(digest.into())
+            .size(size)
+            .platform(image.fetch_platform(uri).await?)
+            .build();
+        self.manifests.push(manifest.clone());
+        Ok(manifest)
+    }
+
+    /// Remove all manifests matching the platform from this index, returning the removed ones.
+    /// Fails if no manifest matches the platform.
+    pub fn remove_manifest(&mut self, platform: &Platform) -> crate::Result<Vec<Layer>> {
+        let (removed, kept) = std::mem::take(&mut self.manifests)
+            .into_iter()
+            .partition::<Vec<_>, _>(|x| x.platform().is_some_and(|x| platform.matches(&x)));
+        self.manifests = kept;
+        ensure!(
+            !removed.is_empty(),
+            error::IndexNoPlatformSnafu {
+                platform: platform.clone(),
+            }
+        );
+        Ok(removed)
+    }
+
+    /// Replace the first manifest matching the platform in this index, keeping its position, and
+    /// return the replaced one. Fails if no manifest matches the platform.
+    pub fn replace_manifest(
+        &mut self,
+        platform: &Platform,
+        manifest: Layer,
+    ) -> crate::Result<Layer> {
+        let existing = self
+            .manifests
+            .iter_mut()
+            .find(|x| x.platform().is_some_and(|x| platform.matches(&x)))
+            .context(error::IndexNoPlatformSnafu {
+                platform: platform.clone(),
+            })?;
+        Ok(std::mem::replace(existing, manifest))
+    }
+
     /// Fetch an image from this index.
     ///
     /// If a platform is provided, looks for the first matching image. If not
     /// provided, loads an image matching the current running environment or
-    /// the first image in the index.
+    /// the first image in the index. Nested indexes are searched as well, while
+    /// artifact entries are never selected.
     pub async fn fetch_image(
         &self,
         uri: &Uri,
         platform: Option<Platform>,
     ) -> crate::Result<Option<Image>> {
+        match self.find_image(uri, platform).await? {
+            // Keep the full platform of the index, the requested one may omit the variant
+            Some(oci) => Ok(Some(
+                Image::fetch(&digest_uri(uri, oci.digest())?, oci.platform()).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the entry of the image [`Index::fetch_image`] would fetch without fetching it
+    pub async fn find_image(
+        &self,
+        uri: &Uri,
+        platform: Option<Platform>,
+    ) -> crate::Result<Option<Layer>> {
+        let index = self.resolve(uri).await?;
         if let Some(platform) = platform {
-            let oci = self
-                .manifests
-                .iter()
-                .find(|x| x.platform() == Some(platform.clone()))
-                .context(error::IndexNoPlatformSnafu {
-                    platform: platform.clone(),
-                })?;
-            // Use the digest
-            let new_uri = Uri::builder()
-                .registry(uri.registry().clone())
-                .repository(uri.repository())
-                .reference(Reference::from_str(oci.digest())?)
-                .build();
-            Ok(Some(Image::fetch(&new_uri, Some(platform)).await?))
-        } else {
-            // See if we can match by architecture
-            let current = Platform::default();
-            if let Some(oci) = self
-                .manifests
-                .iter()
-                .find(|x| x.platform() == Some(current.clone()))
-            {
-                // Use the digest
-                let new_uri = Uri::builder()
-                    .registry(uri.registry().clone())
-                    .repository(uri.repository())
-                    .reference(Reference::from_str(oci.digest())?)
-                    .build();
-                return Ok(Some(Image::fetch(&new_uri, Some(current.clone())).await?));
-            }
-            // Otherwise we return the first image
-            if let Some(oci) = self.manifests.first() {
-                // Use the digest
-                let new_uri = Uri::builder()
-                    .registry(uri.registry().clone())
-                    .repository(uri.repository())
-                    .reference(Reference::from_str(oci.digest())?)
-                    .build();
-                Ok(Some(Image::fetch(&new_uri, oci.platform().clone()).await?))
-            } else {
-                Ok(None)
-            }
+            return Ok(Some(
+                index
+                    .images()
+                    .find(|x| x.platform().is_some_and(|x| platform.matches(&x)))
+                    .context(error::IndexNoPlatformSnafu {
+                        platform: platform.clone(),
+                    })?
+                    .clone(),
+            ));
         }
+        // See if we can match by architecture, otherwise we return the first image
+        let current = Platform::default();
+        Ok(index
+            .images()
+            .find(|x| x.platform().is_some_and(|x| current.matches(&x)))
+            .or_else(|| index.images().next())
+            .cloned())
     }
 
     /// Push this image index to a registry
-    pub async fn push(&self, uri: &Uri) -> crate::Result<()> {
+    pub async fn push(&self, uri: &Uri) -> crate::Result<Layer> {
         uri.registry()
             .push_manifest(
                 &self.media_type,
@@ -139,8 +273,7 @@ impl Index {
                 self,
                 None,
             )
-            .await?;
-        Ok(())
+            .await
     }
 
     /// Create an OCI tar archive that contains either all of the index images (if no platform provided)
@@ -155,60 +288,44 @@ impl Index {
         W: AsyncWrite + Unpin + Send + 'static,
     {
         let tmp_dir = tempdir().context(error::TempSnafu)?;
-        tokio::fs::write(
-            tmp_dir.path().join("oci-layout"),
-            r#"{ "imageLayoutVersion": "1.0.0" }"#,
-        )
-        .await
-        .context(error::FileSnafu)?;
-
-        let blob_dir = tmp_dir.path().join("blobs/sha256");
-        create_dir_all(&blob_dir)
-            .await
-            .context(error::DirectorySnafu)?;
+        self.to_oci_dir(uri, platform, tmp_dir.path()).await?;
+        archive_dir(tmp_dir.path(), output).await
+    }
 
-        // Start with ourselves for the index
-        let mut index = self.clone();
-        if let Some(platform) = platform {
-            // If we are selecting only a single platform then filter the manifests down
-            index.manifests = index
-                .manifests
-                .iter()
-                .filter(|x| x.platform() == Some(platform.clone()))
-                .cloned()
-                .collect::<Vec<Layer>>();
-            if index.manifests.is_empty() {
-                return error::IndexNoPlatformSnafu { platform }.fail();
-            }
-        }
-        let index_content = serde_json::to_string(&index).context(error::SerializeSnafu)?;
-        tokio::fs::write(tmp_dir.path().join("index.json"), &index_content)
-            .await
-            .context(error::FileSnafu)?;
+    /// Create an OCI tar archive that contains either all of the index images (if no platform provided)
+    /// or only the platforms specified, reporting progress to the sink as blobs are fetched.
+    pub async fn to_oci_progress<W>(
+        &self,
+        uri: &Uri,
+        platform: Option<Platform>,
+        output: W,
+        progress: &Arc<dyn ProgressSink>,
+    ) -> crate::Result<()>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let tmp_dir = tempdir().context(error::TempSnafu)?;
+        self.to_oci_dir_progress(uri, platform, tmp_dir.path(), progress)
+            .await?;
+        archive_dir(tmp_dir.path(), output).await
+    }
 
-        // Now for every manifest we are working with we need to store it out
+    /// Write an OCI layout directory that contains either all of the index images (if no platform
+    /// provided) or only the platforms specified. The directory is created if it does not exist.
+    pub async fn to_oci_dir(
+        &self,
+        uri: &Uri,
+        platform: Option<Platform>,
+        path: &Path,
+    ) -> crate::Result<()> {
+        let (index, blob_dir) = self.oci_layout(uri, platform, path).await?;
         for manifest in index.manifests.iter() {
-            let image_uri = Uri::builder()
-                .registry(uri.registry().clone())
-                .repository(uri.repository())
-                .reference(Reference::from_str(manifest.digest())?)
-                .build();
-            let image = Image::fetch(&image_uri, manifest.platform().clone()).await?;
-            // Write the image manifest as a blob
-            let manifest_bytes = serde_json::to_string(&image).context(error::SerializeSnafu)?;
-            tokio::fs::write(
-                blob_dir.join(manifest.digest().strip_prefix("sha256:").unwrap()),
-                &manifest_bytes,
-            )
-            .await
-            .context(error::FileSnafu)?;
+            let image = write_manifest(uri, manifest, &blob_dir).await?;
             // Copy the image config
             let mut config_reader = image.config().open(uri).await?;
-            let mut config_file = File::create(
-                blob_dir.join(image.config().digest().strip_prefix("sha256:").unwrap()),
-            )
-            .await
-            .context(error::FileSnafu)?;
+            let mut config_file = File::create(blob_path(&blob_dir, image.config().digest()))
+                .await
+                .context(error::FileSnafu)?;
             Layer::copy(&mut config_reader, &mut config_file, image.config().size()).await?;
 
             let mut tasks: Vec<JoinHandle<crate::Result<()>>> = Vec::new();
@@ -217,12 +334,11 @@ impl Index {
                 let uri = uri.clone();
                 let blob_dir = blob_dir.clone();
                 tasks.push(tokio::spawn(async move {
+                    let _permit = uri.registry().transfer_permit().await;
                     let mut reader = layer.open(&uri).await?;
-                    let mut blob_file = File::create(
-                        blob_dir.join(layer.digest().strip_prefix("sha256:").unwrap()),
-                    )
-                    .await
-                    .context(error::FileSnafu)?;
+                    let mut blob_file = File::create(blob_path(&blob_dir, layer.digest()))
+                        .await
+                        .context(error::FileSnafu)?;
                     Layer::copy(&mut reader, &mut blob_file, layer.size()).await?;
                     Ok(())
                 }));
@@ -232,100 +348,40 @@ impl Index {
                 result?;
             }
         }
-
-        let mut archive = ArchiveBuilder::new(output);
-        archive
-            .append_dir_all(".", tmp_dir.path().to_path_buf())
-            .await
-            .context(error::ArchiveSnafu)?;
-        archive.finish().await.context(error::ArchiveSnafu)?;
-
         Ok(())
     }
 
-    /// Create an OCI tar archive that contains either all of the index images (if no platform provided)
-    /// or only the platforms specified
-    #[cfg(feature = "progress")]
-    pub async fn to_oci_progress<W>(
+    /// Write an OCI layout directory that contains either all of the index images (if no platform
+    /// provided) or only the platforms specified, reporting progress to the sink as blobs are fetched.
+    pub async fn to_oci_dir_progress(
         &self,
         uri: &Uri,
         platform: Option<Platform>,
-        output: W,
-        multi: &mut MultiProgress,
-    ) -> crate::Result<()>
-    where
-        W: AsyncWrite + Unpin + Send + 'static,
-    {
-        let tmp_dir = tempdir().context(error::TempSnafu)?;
-        tokio::fs::write(
-            tmp_dir.path().join("oci-layout"),
-            r#"{ "imageLayoutVersion": "1.0.0" }"#,
-        )
-        .await
-        .context(error::FileSnafu)?;
-
-        let blob_dir = tmp_dir.path().join("blobs/sha256");
-        create_dir_all(&blob_dir)
-            .await
-            .context(error::DirectorySnafu)?;
-
-        // Start with ourselves for the index
-        let mut index = self.clone();
-        if let Some(platform) = platform {
-            // If we are selecting only a single platform then filter the manifests down
-            index.manifests = index
-                .manifests
-                .iter()
-                .filter(|x| x.platform() == Some(platform.clone()))
-                .cloned()
-                .collect::<Vec<Layer>>();
-            if index.manifests.is_empty() {
-                return error::IndexNoPlatformSnafu { platform }.fail();
-            }
-        }
-        let index_content = serde_json::to_string(&index).context(error::SerializeSnafu)?;
-        tokio::fs::write(tmp_dir.path().join("index.json"), &index_content)
-            .await
-            .context(error::FileSnafu)?;
-
-        // Now for every manifest we are working with we need to store it out
+        path: &Path,
+        progress: &Arc<dyn ProgressSink>,
+    ) -> crate::Result<()> {
+        let (index, blob_dir) = self.oci_layout(uri, platform, path).await?;
         for manifest in index.manifests.iter() {
-            let image_uri = Uri::builder()
-                .registry(uri.registry().clone())
-                .repository(uri.repository())
-                .reference(Reference::from_str(manifest.digest())?)
-                .build();
-            let image = Image::fetch(&image_uri, manifest.platform().clone()).await?;
-            // Write the image manifest as a blob
-            let manifest_bytes = serde_json::to_string(&image).context(error::SerializeSnafu)?;
-            tokio::fs::write(
-                blob_dir.join(manifest.digest().strip_prefix("sha256:").unwrap()),
-                &manifest_bytes,
-            )
-            .await
-            .context(error::FileSnafu)?;
+            let image = write_manifest(uri, manifest, &blob_dir).await?;
             // Copy the image config
-            let mut config_reader = image.config().open_progress(uri, multi).await?;
-            let mut config_file = File::create(
-                blob_dir.join(image.config().digest().strip_prefix("sha256:").unwrap()),
-            )
-            .await
-            .context(error::FileSnafu)?;
+            let mut config_reader = image.config().open_progress(uri, progress.clone()).await?;
+            let mut config_file = File::create(blob_path(&blob_dir, image.config().digest()))
+                .await
+                .context(error::FileSnafu)?;
             Layer::copy(&mut config_reader, &mut config_file, image.config().size()).await?;
 
             let mut tasks: Vec<JoinHandle<crate::Result<()>>> = Vec::new();
             for layer in image.layers().iter() {
                 let layer = layer.clone();
                 let uri = uri.clone();
-                let mut multi = multi.clone();
+                let progress = progress.clone();
                 let blob_dir = blob_dir.clone();
                 tasks.push(tokio::spawn(async move {
-                    let mut reader = layer.open_progress(&uri, &mut multi).await?;
-                    let mut blob_file = File::create(
-                        blob_dir.join(layer.digest().strip_prefix("sha256:").unwrap()),
-                    )
-                    .await
-                    .context(error::FileSnafu)?;
+                    let _permit = uri.registry().transfer_permit().await;
+                    let mut reader = layer.open_progress(&uri, progress).await?;
+                    let mut blob_file = File::create(blob_path(&blob_dir, layer.digest()))
+                        .await
+                        .context(error::FileSnafu)?;
                     Layer::copy(&mut reader, &mut blob_file, layer.size()).await?;
                     Ok(())
                 }));
@@ -335,14 +391,149 @@ impl Index {
                 result?;
             }
         }
+        Ok(())
+    }
+
+    /// Lay out the `oci-layout` marker, the blob directory and the `index.json` of the manifests
+    /// matching the platform. Returns the written index and the blob directory.
+    async fn oci_layout(
+        &self,
+        uri: &Uri,
+        platform: Option<Platform>,
+        path: &Path,
+    ) -> crate::Result<(Index, PathBuf)> {
+        let blob_dir = path.join("blobs/sha256");
+        create_dir_all(&blob_dir)
+            .await
+            .context(error::DirectorySnafu)?;
+        tokio::fs::write(
+            path.join("oci-layout"),
+            r#"{ "imageLayoutVersion": "1.0.0" }"#,
+        )
+        .await
+        .context(error::FileSnafu)?;
 
-        let mut archive = ArchiveBuilder::new(output);
-        archive
-            .append_dir_all(".", tmp_dir.path().to_path_buf())
+        // Start with ourselves for the index, flattening nested indexes
+        let mut index = self.resolve(uri).await?;
+        // Only images are written, artifacts such as attestations are left out
+        index.manifests.retain(is_image);
+        if let Some(platform) = platform {
+            // If we are selecting only a single platform then filter the manifests down
+            index = index.filter_platforms(&[platform])?;
+        }
+        let index_content = serde_json::to_string(&index).context(error::SerializeSnafu)?;
+        tokio::fs::write(path.join("index.json"), &index_content)
             .await
-            .context(error::ArchiveSnafu)?;
-        archive.finish().await.context(error::ArchiveSnafu)?;
+            .context(error::FileSnafu)?;
+        Ok((index, blob_dir))
+    }
+}
 
-        Ok(())
+/// Annotation buildx sets on the manifests it adds to an index that are not images
+const REFERENCE_TYPE: &str = "vnd.docker.reference.type";
+
+/// Check if an index entry is an image manifest
+fn is_image(manifest: &Layer) -> bool {
+    matches!(
+        manifest.media_type(),
+        MediaType::Manifest | MediaType::DockerManifest
+    ) && manifest.artifact_type().is_none()
+        && !manifest.annotations().contains_key(REFERENCE_TYPE)
+}
+
+/// Check if an index entry is a nested index
+fn is_index(manifest: &Layer) -> bool {
+    matches!(
+        manifest.media_type(),
+        MediaType::ImageIndex | MediaType::DockerManifestList
+    )
+}
+
+/// Uri of a manifest in the repository of the uri by its digest
+fn digest_uri(uri: &Uri, digest: &str) -> crate::Result<Uri> {
+    Ok(Uri::builder()
+        .registry(uri.registry().clone())
+        .repository(uri.repository())
+        .reference(Reference::from_str(digest)?)
+        .build())
+}
+
+/// Path of a blob within the blob directory of an OCI layout
+fn blob_path(blob_dir: &Path, digest: &str) -> PathBuf {
+    blob_dir.join(digest.split_once(':').map(|x| x.1).unwrap_or(digest))
+}
+
+/// Fetch an image manifest of the index and store its canonical bytes as a blob, so the blob
+/// still hashes to the digest the index refers to it by
+async fn write_manifest(uri: &Uri, manifest: &Layer, blob_dir: &Path) -> crate::Result<Image> {
+    let (bytes, digest, _) = uri
+        .registry()
+        .fetch_manifest_raw(uri.repository(), manifest.digest())
+        .await?;
+    ensure!(
+        digest == manifest.digest(),
+        error::DigestMismatchSnafu {
+            expected: manifest.digest(),
+            actual: digest,
+        }
+    );
+    tokio::fs::write(blob_path(blob_dir, manifest.digest()), &bytes)
+        .await
+        .context(error::FileSnafu)?;
+    let mut image: Image = serde_json::from_slice(&bytes).context(error::BodyDeserializeSnafu)?;
+    image.set_platform(manifest.platform());
+    Ok(image)
+}
+
+/// Archive the content of a directory as a tar written to the output
+async fn archive_dir<W>(path: &Path, output: W) -> crate::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut archive = ArchiveBuilder::new(output);
+    archive
+        .append_dir_all(".", path)
+        .await
+        .context(error::ArchiveSnafu)?;
+    archive.finish().await.context(error::ArchiveSnafu)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::Index;
+    use crate::image::Image;
+    use crate::layer::Layer;
+    use crate::models::{MediaType, Platform};
+    use crate::testing::MockRegistryClient;
+    use crate::uri::{Reference, RegistryUri, Uri};
+
+    #[tokio::test]
+    async fn test_add_image_platform() {
+        let client = MockRegistryClient::new();
+        let uri = Uri::builder()
+            .registry(client.registry(&RegistryUri::from_str("mock.local").unwrap()))
+            .repository("app")
+            .reference(Reference::Tag("v1".to_string()))
+            .build();
+        let config =
+            br#"{"architecture":"arm64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+        let config = Layer::upload(&uri, &MediaType::Config, config)
+            .await
+            .unwrap();
+        // Created images have no platform hint, the entry takes the platform of the config
+        let image = Image::create(&config, &[], None).await;
+        let mut index = Index::new(&[]).await;
+        let entry = index
+            .add_image(&uri, &image, "sha256:abc", 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            entry.platform(),
+            Some(Platform::from_str("linux/arm64").unwrap())
+        );
+        assert_eq!(index.manifests().len(), 1);
     }
 }
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use snafu::{OptionExt, ensure};
+
+use crate::error;
+use crate::layer::Layer;
+use crate::manifest::Manifest;
+use crate::models::{MediaType, Platform};
+use crate::uri::{Reference, Uri};
+
+/// Summary of an image drawn from its manifest, the index it is part of and its configuration,
+/// much like `skopeo inspect`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Inspection {
+    /// Reference that was inspected
+    pub reference: String,
+    /// Digest of the manifest the reference resolves to, the index for multi platform images
+    pub digest: String,
+    /// Media type of the manifest the reference resolves to
+    pub media_type: MediaType,
+    /// Platforms of the images of the index, or the platform of a single image
+    pub platforms: Vec<Platform>,
+    /// Platform of the image the remaining fields describe
+    pub platform: Platform,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    pub labels: BTreeMap<String, String>,
+    pub env: Vec<String>,
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    pub layers: Vec<Layer>,
+    /// Total compressed size of the layers in bytes
+    pub size: usize,
+}
+
+impl Inspection {
+    /// Inspect the image the uri points to. For an index the image is selected by platform as in
+    /// [`crate::index::Index::fetch_image`], while the digest and platforms are of the index.
+    pub async fn fetch(uri: &Uri, platform: Option<Platform>) -> crate::Result<Self> {
+        let reference = uri.reference().to_string();
+        let (bytes, digest, media_type) = uri
+            .registry()
+            .fetch_manifest_raw(uri.repository(), reference.as_str())
+            .await?;
+        if let Reference::Digest { .. } = uri.reference() {
+            ensure!(
+                digest == reference,
+                error::DigestMismatchSnafu {
+                    expected: reference,
+                    actual: digest,
+                }
+            );
+        }
+        let manifest = Manifest::from_slice(&bytes, &media_type)?;
+        let platforms = match &manifest {
+            Manifest::Index(index) => index
+                .resolve(uri)
+                .await?
+                .images()
+                .filter_map(Layer::platform)
+                .collect(),
+            Manifest::Image(_) => Vec::new(),
+        };
+        let image =
+            manifest
+                .fetch_image(uri, platform)
+                .await?
+                .context(error::ImageNotFoundSnafu {
+                    uri: uri.to_string(),
+                })?;
+        let config = image.fetch_config(uri).await?;
+        Ok(Self {
+            reference: uri.to_string(),
+            digest,
+            media_type,
+            platforms: if platforms.is_empty() {
+                vec![config.platform()]
+            } else {
+                platforms
+            },
+            platform: config.platform(),
+            created: config.created,
+            labels: config.config.labels.into_iter().collect(),
+            env: config.config.env,
+            entrypoint: config.config.entrypoint,
+            cmd: config.config.cmd,
+            layers: image.layers().to_vec(),
+            size: image.layers().iter().map(Layer::size).sum(),
+        })
+    }
+}
@@ -1,28 +1,41 @@
+use crate::audit::AuditOperation;
 use crate::error;
 use crate::models::MediaType;
 use crate::models::Platform;
-use crate::uri::{Reference, Uri};
+use crate::progress::{Direction, ProgressSink, Reporter, Transfer};
+use crate::store::UploadCheckpoint;
+use crate::uri::{Algorithm, Reference, Uri};
 use bon::Builder;
-use bytes::Bytes;
-use cfg_if::cfg_if;
-use futures::FutureExt;
+use bytes::{Bytes, BytesMut};
 use futures::future::BoxFuture;
-#[cfg(feature = "progress")]
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::Response;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use reqwest::header::LOCATION;
+use reqwest::{Body, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use snafu::{ResultExt, ensure};
+use sha2::{Digest, Sha256, Sha512};
+use snafu::{OptionExt, ResultExt, ensure};
 use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{OwnedMutexGuard, mpsc};
 use tokio_util::io::StreamReader;
+use tokio_util::sync::PollSender;
+use tracing::field::Empty;
+use tracing::{Span, instrument};
 
-/// Minimum chunk size for layer operations (5 MiB).
-const MIN_CHUNK_SIZE: usize = 5 * 1024 * 1024;
-/// Maximum chunk size for layer operations (100 MiB).
-const MAX_CHUNK_SIZE: usize = 100 * 1024 * 1024;
+/// Size of the buffer layers are copied through, blobs that fit are uploaded in a single request
+/// (5 MiB).
+const COPY_BUFFER_SIZE: usize = 5 * 1024 * 1024;
+/// Pieces of a chunk queued for its streamed request body before writes wait on the upload.
+const STREAM_QUEUE: usize = 4;
+
+/// Header the registry reports the digest of stored content with.
+pub(crate) const DOCKER_CONTENT_DIGEST: &str = "Docker-Content-Digest";
 
 /// A layer represents a blob or sub-object associated with an image.
 ///
@@ -39,15 +52,22 @@ pub struct Layer {
     #[builder(into)]
     #[serde(skip_serializing_if = "Option::is_none")]
     platform: Option<Platform>,
+    /// Type of the artifact a manifest descriptor refers to, i.e. in a referrers list
+    #[builder(into)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+    #[builder(into, default)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
 }
 
 impl Layer {
-    /// Perform a chunked copy of a layer from one reader to another.
+    /// Copy a layer from one reader to another through a buffer of bounded size.
     ///
     /// Any time you want to interact with a layer in a registry, it is recommended
-    /// to use this method. While most OCI registry implementations do not need special
-    /// handling to make the chunks of data sent uniform, certain implementations
-    /// (i.e. ECR) work better when using more uniform chunked operations.
+    /// to use this method. Blobs that fit in the buffer are written at once, which lets a
+    /// [`Writer`] upload them in a single request, larger ones are streamed to the registry in
+    /// uniform chunks by the writer.
     pub async fn copy<'a, R, W>(
         reader: &'a mut R,
         writer: &'a mut W,
@@ -58,23 +78,18 @@ impl Layer {
         W: AsyncWrite + Unpin + ?Sized,
     {
         let mut index = 0;
-        // To determine the chunk size we do some math:
-        // 1. The chunk size should always be >= MIN_CHUNK_SIZE
-        // 2. The chunk size should always be <= MAX_CHUNK_SIZE
-        // 3. Ideally the chunk size should be 1/40th of the size of the layer (this lines up with how we print progress bar updates)
-        let chunk_size = (size / 40).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        let mut buffer = vec![0; min(size, COPY_BUFFER_SIZE)];
         while index < size {
-            let read_size = min(chunk_size, size - index);
-            let mut buffer = vec![0; read_size];
+            let read_size = min(buffer.len(), size - index);
             reader
-                .read_exact(&mut buffer)
+                .read_exact(&mut buffer[..read_size])
                 .await
                 .context(error::LayerReadSnafu)?;
             writer
-                .write_all(buffer.as_slice())
+                .write_all(&buffer[..read_size])
                 .await
                 .context(error::LayerWriteSnafu)?;
-            index += chunk_size;
+            index += read_size;
         }
         Ok(())
     }
@@ -86,136 +101,255 @@ impl Layer {
         size: usize,
         digest: Option<String>,
     ) -> crate::Result<Option<Writer>> {
-        if let Some(digest) = digest.as_ref() {
-            // Check if the registry already has this layer
-            trace!(target: "layer", "checking if a blob already exists with the digest: {digest}");
-            if uri
-                .registry()
-                .check_blob(uri.repository(), digest.as_str())
-                .await?
-            {
-                debug!(target: "layer", "blob already exists with the digest: {digest}");
-                return Ok(None);
-            }
-        }
-
-        cfg_if! {
-            if #[cfg(feature = "progress")] {
-                Ok(Some(Writer {
-                    uri: uri.clone(),
-                    index: 0,
-                    size,
-                    media_type: media_type.clone(),
-                    upload_url: None,
-                    active: None,
-                    digest: Sha256::new(),
-                    progress: None,
-                }))
-            } else {
-                Ok(Some(Writer {
-                    uri: uri.clone(),
-                    index: 0,
-                    size,
-                    media_type: media_type.clone(),
-                    upload_url: None,
-                    active: None,
-                    digest: Sha256::new(),
-                }))
+        Self::create_reported(uri, media_type, size, digest, None).await
+    }
+
+    /// Upload a blob held in memory, i.e. a config or an artifact document, unless the registry
+    /// already has it
+    pub async fn upload(uri: &Uri, media_type: &MediaType, bytes: &[u8]) -> crate::Result<Self> {
+        let digest = Algorithm::Sha256.digest(bytes);
+        match Self::create(uri, media_type, bytes.len(), Some(digest.clone())).await? {
+            Some(mut writer) => {
+                writer
+                    .write_all(bytes)
+                    .await
+                    .context(error::LayerWriteSnafu)?;
+                writer.flush().await.context(error::LayerWriteSnafu)?;
+                writer.layer().await
             }
+            None => Ok(Self::builder()
+                .media_type(media_type.clone())
+                .size(bytes.len())
+                .digest(digest)
+                .build()),
         }
     }
 
-    /// Create a new layer and report upload progress via an indicatif progress bar
-    #[cfg(feature = "progress")]
+    /// Create a new layer and report upload progress to the sink, along with the progress sink of
+    /// the registry if it has one
     pub async fn create_progress(
         uri: &Uri,
         media_type: &MediaType,
-        prefix: &str,
         size: u64,
-        multi: &mut MultiProgress,
+        progress: Arc<dyn ProgressSink>,
         digest: Option<String>,
     ) -> crate::Result<Option<Writer>> {
-        let bar = multi.add(ProgressBar::new(size));
-        bar.set_style(
-            ProgressStyle::with_template(
-                "-> {prefix}: [{elapsed_precise}] {bar:40.cyan/blue} {msg} ({binary_bytes:>7}/{binary_total_bytes:7})",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-        );
-        bar.set_prefix(prefix.to_string());
-        if let Some(digest) = digest.as_ref() {
-            // Check if the registry already has this layer
-            trace!(target: "layer", "checking if a blob already exists with the digest: {digest}");
-            if uri
-                .registry()
-                .check_blob(uri.repository(), digest.as_str())
-                .await?
-            {
-                debug!(target: "layer", "blob already exists with the digest: {digest}");
-                bar.finish_with_message("already exists");
-                return Ok(None);
-            }
-        }
+        Self::create_reported(uri, media_type, size as usize, digest, Some(progress)).await
+    }
 
+    #[instrument(
+        target = "layer",
+        name = "upload_blob",
+        skip_all,
+        fields(
+            registry = %uri.registry().uri().base(), repository = %uri.repository(),
+            digest = digest.as_deref().unwrap_or_default(), bytes = size, status = Empty,
+        )
+    )]
+    async fn create_reported(
+        uri: &Uri,
+        media_type: &MediaType,
+        size: usize,
+        digest: Option<String>,
+        progress: Option<Arc<dyn ProgressSink>>,
+    ) -> crate::Result<Option<Writer>> {
+        let algorithm = Self::algorithm(digest.as_deref())?;
+        let reporter = Reporter::new(
+            Transfer::new(Direction::Upload, digest.clone(), size as u64),
+            uri.registry().progress().into_iter().chain(progress),
+        );
+        let Claim::Upload(flight) = Self::claim(uri, digest.as_deref()).await? else {
+            reporter.skipped();
+            return Ok(None);
+        };
+        let dry_run = uri.registry().is_dry_run();
+        let (upload_url, committed) = if dry_run {
+            (None, 0)
+        } else {
+            Self::restore(uri, digest.as_deref(), size).await
+        };
+        let chunk_size = uri.registry().chunk_policy().size(size);
+        let monolithic = (!dry_run
+            && upload_url.is_none()
+            && size <= uri.registry().chunk_policy().monolithic_bytes)
+            .then(|| BytesMut::with_capacity(size));
+        // Resumed uploads continue one chunk at a time from where the session left off
+        let connections = uri.registry().chunk_connections();
+        let parallel = (!dry_run
+            && connections > 1
+            && size > chunk_size
+            && committed == 0
+            && monolithic.is_none())
+        .then(|| Parallel {
+            connections,
+            start: 0,
+            buffer: BytesMut::with_capacity(chunk_size),
+            requests: FuturesUnordered::new(),
+            sent: VecDeque::new(),
+            furthest: 0,
+            failed: false,
+        });
+        reporter.started();
         Ok(Some(Writer {
             uri: uri.clone(),
             index: 0,
-            size: size as usize,
+            size,
             media_type: media_type.clone(),
-            upload_url: None,
+            upload_url,
+            committed,
+            expected: digest,
+            flight,
+            registry_digest: None,
+            chunk: None,
+            chunk_size,
+            monolithic,
+            parallel,
+            stream: None,
+            replay: Vec::new(),
+            backlog: VecDeque::new(),
+            retries: 0,
             active: None,
-            digest: Sha256::new(),
-            progress: Some(bar),
+            digest: Hasher::new(&algorithm),
+            reporter,
+            dry_run,
+            span: Span::current(),
         }))
     }
 
+    /// Algorithm to hash the blob with, the one of the expected digest or sha256 without one
+    fn algorithm(digest: Option<&str>) -> crate::Result<Algorithm> {
+        match digest.and_then(|x| x.split_once(':')) {
+            Some((algorithm, _)) => Algorithm::from_str(algorithm),
+            None => Ok(Algorithm::default()),
+        }
+    }
+
+    /// Check if the registry already has the blob, otherwise claim its upload so that concurrent
+    /// uploads of the same digest await this one instead of sending the blob again.
+    async fn claim(uri: &Uri, digest: Option<&str>) -> crate::Result<Claim> {
+        let Some(digest) = digest else {
+            return Ok(Claim::Upload(None));
+        };
+        // Check if the registry already has this layer
+        trace!(target: "layer", "checking if a blob already exists with the digest: {digest}");
+        if uri.registry().blob_exists(uri.repository(), digest).await? {
+            debug!(target: "layer", "blob already exists with the digest: {digest}");
+            return Ok(Claim::Exists);
+        }
+        let (flight, waited) = uri.registry().claim_upload(digest).await;
+        // The upload we waited on may have failed, in which case this one carries on with it
+        if waited && uri.registry().blob_exists(uri.repository(), digest).await? {
+            debug!(target: "layer", "blob was uploaded concurrently with the digest: {digest}");
+            return Ok(Claim::Exists);
+        }
+        Ok(Claim::Upload(Some(flight)))
+    }
+
+    /// Restore an interrupted upload session of a blob from the checkpoints in the registry's
+    /// store, returning the upload url and the number of bytes already committed.
+    async fn restore(uri: &Uri, digest: Option<&str>, size: usize) -> (Option<String>, usize) {
+        let (Some(store), Some(digest)) = (uri.registry().store(), digest) else {
+            return (None, 0);
+        };
+        let registry = uri.registry().uri().base();
+        let Some(checkpoint) = store.checkpoint(registry, uri.repository(), digest) else {
+            return (None, 0);
+        };
+        match uri.registry().upload_status(&checkpoint.upload_url).await {
+            Ok(Some(committed)) if committed > 0 && committed < size => {
+                debug!(target: "layer", "resuming upload of {digest} from byte {committed}");
+                (Some(checkpoint.upload_url), committed)
+            }
+            _ => {
+                debug!(target: "layer", "upload session for {digest} can not be resumed");
+                store.clear_checkpoint(registry, uri.repository(), digest);
+                (None, 0)
+            }
+        }
+    }
+
     /// Open a layer blob for reading
     pub async fn open(&self, uri: &Uri) -> crate::Result<Reader> {
-        let (reader, _) = uri
-            .registry()
-            .fetch_blob(uri.repository(), self.digest.as_str())
-            .await?;
-        let reader = StreamReader::new(reader);
-        Ok(Reader::new(reader))
+        Ok(self
+            .open_unverified(uri)
+            .await?
+            .verified(&self.digest, Some(self.size as u64)))
     }
 
-    /// Open a layer blob for reading and report progress to an indicatif progress bar
-    #[cfg(feature = "progress")]
+    /// Open a layer blob for reading without verifying the content matches its digest
+    pub async fn open_unverified(&self, uri: &Uri) -> crate::Result<Reader> {
+        self.open_reported(uri, None).await
+    }
+
+    /// Open a layer blob for reading and report download progress to the sink, along with the
+    /// progress sink of the registry if it has one
     pub async fn open_progress(
         &self,
         uri: &Uri,
-        multi: &mut MultiProgress,
+        progress: Arc<dyn ProgressSink>,
+    ) -> crate::Result<Reader> {
+        Ok(self
+            .open_reported(uri, Some(progress))
+            .await?
+            .verified(&self.digest, Some(self.size as u64)))
+    }
+
+    async fn open_reported(
+        &self,
+        uri: &Uri,
+        progress: Option<Arc<dyn ProgressSink>>,
     ) -> crate::Result<Reader> {
-        let prefix = &self.digest.strip_prefix("sha256:").unwrap()[0..9];
         let (reader, _) = uri
             .registry()
             .fetch_blob(uri.repository(), self.digest.as_str())
             .await?;
-        let bar = multi.add(ProgressBar::new(self.size as u64));
-        bar.set_style(
-            ProgressStyle::with_template(
-                "<- {prefix}: [{elapsed_precise}] {bar:40.cyan/blue} {msg} ({binary_bytes:>7}/{binary_total_bytes:7})",
-            )
-            .unwrap()
-            .progress_chars("##-"),
+        let reporter = Reporter::new(
+            Transfer::new(
+                Direction::Download,
+                Some(self.digest.clone()),
+                self.size as u64,
+            ),
+            uri.registry().progress().into_iter().chain(progress),
         );
-        bar.set_prefix(format!("blob {prefix}"));
-        let reader = StreamReader::new(reader);
-        Ok(Reader::new_progress(reader, bar))
+        Ok(Reader::new(StreamReader::new(reader)).reported(reporter))
     }
 
     /// Open a layer for reading at the specified uri
     pub async fn open_uri(uri: &Uri) -> crate::Result<Reader> {
         ensure!(
             matches!(uri.reference(), Reference::Digest { .. }),
-            error::DirectLoadBlobSnafu { uri: uri.clone() }
+            error::DirectLoadBlobSnafu {
+                uri: uri.to_string()
+            }
         );
         let digest = uri.reference().to_string();
-        let (reader, _) = uri
+        let (reader, size) = uri
             .registry()
             .fetch_blob(uri.repository(), digest.as_str())
             .await?;
+        // A length of zero means the registry did not report one, so verify at the end instead
+        Ok(Reader::new(StreamReader::new(reader)).verified(&digest, Some(size).filter(|x| *x > 0)))
+    }
+
+    /// Open part of a blob for reading at the specified uri, from the start byte up to the end byte
+    /// (exclusive) or the end of the blob without one. The content is not verified against the
+    /// digest, as only part of the blob is read.
+    pub async fn open_range(uri: &Uri, start: u64, end: Option<u64>) -> crate::Result<Reader> {
+        ensure!(
+            matches!(uri.reference(), Reference::Digest { .. }),
+            error::DirectLoadBlobSnafu {
+                uri: uri.to_string()
+            }
+        );
+        let reader = uri
+            .registry()
+            .fetch_blob_range(
+                uri.repository(),
+                uri.reference().to_string().as_str(),
+                start,
+                end,
+            )
+            .await?;
         Ok(Reader::new(StreamReader::new(reader)))
     }
 
@@ -224,6 +358,11 @@ impl Layer {
         &self.media_type
     }
 
+    /// Describe the same blob with another media type
+    pub(crate) fn set_media_type(&mut self, media_type: MediaType) {
+        self.media_type = media_type;
+    }
+
     /// Digest string for the layer
     pub fn digest(&self) -> &str {
         &self.digest
@@ -234,11 +373,31 @@ impl Layer {
         self.size
     }
 
+    /// Artifact type of the manifest this descriptor refers to, if any
+    pub fn artifact_type(&self) -> Option<&str> {
+        self.artifact_type.as_deref()
+    }
+
+    /// Annotations of the descriptor
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.annotations
+    }
+
+    /// Set an annotation on the descriptor
+    pub fn annotate(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.annotations.insert(key.into(), value.into());
+    }
+
     /// Platform this layer is specific to, this is primarily only used in an image index
     pub fn platform(&self) -> Option<Platform> {
         self.platform.clone()
     }
 
+    /// Set the platform of the descriptor, i.e. when lifting it out of a nested index
+    pub(crate) fn set_platform(&mut self, platform: Option<Platform>) {
+        self.platform = platform;
+    }
+
     /// Delete this layer from the registry and repository provided by a uri
     pub async fn delete(&self, uri: &Uri) -> crate::Result<()> {
         uri.registry()
@@ -247,53 +406,167 @@ impl Layer {
     }
 }
 
+/// Url of the upload session from the `Location` of a response. Relative locations resolve against
+/// the url of the request they answer, the query of the location is kept as is.
+fn upload_location(response: &Response) -> Option<String> {
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    Some(
+        response
+            .url()
+            .join(location)
+            .map(|x| x.to_string())
+            .unwrap_or_else(|_| location.to_string()),
+    )
+}
+
+/// Running hash of blob content.
+#[derive(Clone)]
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: &Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Self::Sha512(Sha512::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Digest of the content hashed so far, in the `algorithm:hex` form
+    pub(crate) fn digest(&self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!(
+                "sha256:{}",
+                base16::encode_lower(hasher.clone().finalize().as_slice())
+            ),
+            Self::Sha512(hasher) => format!(
+                "sha512:{}",
+                base16::encode_lower(hasher.clone().finalize().as_slice())
+            ),
+        }
+    }
+}
+
+/// Checks content read from a blob hashes to the digest of the blob.
+struct Verifier {
+    expected: String,
+    /// Size of the blob, if known
+    size: Option<u64>,
+    read: u64,
+    hasher: Hasher,
+    done: bool,
+}
+
+impl Verifier {
+    fn new(digest: &str, size: Option<u64>) -> Option<Self> {
+        let (algorithm, _) = digest.split_once(':')?;
+        let hasher = match Algorithm::from_str(algorithm) {
+            Ok(algorithm) => Hasher::new(&algorithm),
+            Err(_) => {
+                debug!(target: "layer", "not verifying {digest}, its algorithm is not supported");
+                return None;
+            }
+        };
+        Some(Self {
+            expected: digest.to_string(),
+            size,
+            read: 0,
+            hasher,
+            done: false,
+        })
+    }
+
+    /// Hash content read, checking the digest once the whole blob was read
+    fn update(&mut self, data: &[u8]) -> crate::Result<()> {
+        self.hasher.update(data);
+        self.read += data.len() as u64;
+        match self.size {
+            Some(size) if self.read >= size => self.finish(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the digest of the content read so far, this only happens once
+    fn finish(&mut self) -> crate::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        let actual = self.hasher.digest();
+        ensure!(
+            actual == self.expected,
+            error::DigestMismatchSnafu {
+                expected: self.expected.clone(),
+                actual,
+            }
+        );
+        Ok(())
+    }
+}
+
 /// Layer `AsyncRead` implementation with optional progress reporting.
 ///
-/// Automatically reports to a progress bar if provided and the progress
-/// feature is enabled. It can also decompress the contents of the reader.
+/// Automatically reports to progress sinks if provided. It can also decompress the contents of
+/// the reader.
 pub struct Reader {
-    inner: Pin<Box<dyn AsyncRead>>,
-    #[cfg(feature = "progress")]
-    progress: Option<ProgressBar>,
+    inner: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    /// Reports the blob transfer to the progress sinks watching it
+    reporter: Option<Reporter>,
+    /// Verifies the content matches the digest of the blob
+    verifier: Option<Verifier>,
 }
 
-#[cfg(feature = "progress")]
 impl Drop for Reader {
     fn drop(&mut self) {
-        if let Some(progress) = self.progress.as_mut() {
-            progress.finish_with_message("done");
+        if let Some(reporter) = self.reporter.as_ref() {
+            reporter.finished();
         }
     }
 }
 
-unsafe impl Send for Reader {}
-unsafe impl Sync for Reader {}
-
 impl Reader {
     /// Create a base reader
-    pub fn new(inner: impl AsyncRead + 'static) -> Self {
-        cfg_if! {
-            if #[cfg(feature = "progress")] {
-                Self {
-                    inner: Box::pin(inner),
-                    progress: None,
-                }
-            } else {
-                Self {
-                    inner: Box::pin(inner),
-                }
-            }
-        }
-    }
-
-    /// Create a reader that will report progress to an indicatif progress bar
-    #[cfg(feature = "progress")]
-    pub fn new_progress(inner: impl AsyncRead + 'static, progress: ProgressBar) -> Self {
+    pub fn new(inner: impl AsyncRead + Send + Sync + 'static) -> Self {
         Self {
             inner: Box::pin(inner),
-            progress: Some(progress),
+            reporter: None,
+            verifier: None,
         }
     }
+
+    /// Create a reader that will report the download of a blob to a progress sink
+    pub fn new_progress(
+        inner: impl AsyncRead + Send + Sync + 'static,
+        progress: Arc<dyn ProgressSink>,
+        digest: Option<String>,
+        size: u64,
+    ) -> Self {
+        let transfer = Transfer::new(Direction::Download, digest, size);
+        Self::new(inner).reported(Reporter::new(transfer, [progress]))
+    }
+
+    /// Verify the content read hashes to the digest, once the size is read or at the end of the
+    /// content when the size is not known. Unsupported digest algorithms are not verified.
+    fn verified(mut self, digest: &str, size: Option<u64>) -> Self {
+        self.verifier = Verifier::new(digest, size);
+        self
+    }
+
+    /// Report the transfer of a blob as it is read
+    fn reported(mut self, reporter: Reporter) -> Self {
+        reporter.started();
+        self.reporter = Some(reporter);
+        self
+    }
 }
 
 impl AsyncRead for Reader {
@@ -303,15 +576,26 @@ impl AsyncRead for Reader {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         let this = self.get_mut();
+        let filled = buf.filled().len();
+        let eof = buf.remaining() > 0;
         match this.inner.as_mut().poll_read(cx, buf) {
             Poll::Ready(Ok(())) => {
-                cfg_if! {
-                    if #[cfg(feature = "progress")] {
-                        if let Some(bar) = this.progress.as_mut() && buf.remaining() == 0 {
-                            bar.inc(buf.filled().len() as u64);
-                        }
+                let read = (buf.filled().len() - filled) as u64;
+                if let Some(verifier) = this.verifier.as_mut() {
+                    let result = if eof && read == 0 {
+                        verifier.finish()
+                    } else {
+                        verifier.update(&buf.filled()[filled..])
+                    };
+                    if let Err(e) = result {
+                        return Poll::Ready(Err(std::io::Error::other(e)));
                     }
                 }
+                if let Some(reporter) = this.reporter.as_ref()
+                    && read > 0
+                {
+                    reporter.advanced(read);
+                }
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
@@ -323,18 +607,60 @@ impl AsyncRead for Reader {
 /// `AsyncWrite` implementation that writes a blob to a registry.
 ///
 /// Automatically handles chunked upload versus single upload based on the
-/// size of the blob. Construction of this type is done by the Layer create methods.
+/// size of the blob, blobs up to the monolithic size of the registry's chunk policy are buffered
+/// until all of them were written and uploaded in a single request. Chunks are streamed to the
+/// registry as they are written, so memory use is bounded by the chunk size rather than the size
+/// of the blob.
+/// Construction of this type is done by the Layer create methods.
 pub struct Writer {
     uri: Uri,
 
     media_type: MediaType,
     upload_url: Option<String>,
+    /// Bytes committed by a resumed upload session, these are hashed but not sent again
+    committed: usize,
+    /// Digest the blob is expected to have, used to checkpoint the upload session
+    expected: Option<String>,
+    /// Claim on the upload of the expected digest, released once the layer is finished
+    flight: Option<OwnedMutexGuard<()>>,
+    /// Digest the registry reported for the finished upload
+    registry_digest: Option<String>,
+    /// Chunk upload in flight, kept to resume it when it fails
+    chunk: Option<Chunk>,
+    /// Size of the chunks larger blobs are uploaded in
+    chunk_size: usize,
+    /// Content of a blob small enough to be uploaded in a single request once all of it is written
+    monolithic: Option<BytesMut>,
+    /// Chunks uploaded concurrently, until the registry rejects one
+    parallel: Option<Parallel>,
+    /// Chunk whose request body is being fed by the writes
+    stream: Option<Stream>,
+    /// Content of the chunk in flight, kept to send it again when resuming the chunk
+    replay: Vec<Bytes>,
+    /// Content written but not sent yet, i.e. after the registry rejected a chunk sent
+    /// concurrently, it is sent ahead of the content written from here on
+    backlog: VecDeque<Bytes>,
+    /// Attempts made to resume the chunk upload in flight
+    retries: usize,
     index: usize,
     size: usize,
-    digest: Sha256,
-    #[cfg(feature = "progress")]
-    progress: Option<ProgressBar>,
+    /// Hash of the content written, with the algorithm of the expected digest
+    digest: Hasher,
+    /// Reports the blob transfer to the progress sinks watching it
+    reporter: Reporter,
     active: Option<Operation>,
+    /// Only hash the content as the registry is in a dry run, the upload is recorded as planned
+    dry_run: bool,
+    /// Span of the upload, open until the writer is dropped so it lasts as long as the upload
+    span: Span,
+}
+
+/// Outcome of claiming the upload of a blob.
+enum Claim {
+    /// The registry already has the blob
+    Exists,
+    /// The blob should be uploaded, holding the claim when the digest is known
+    Upload(Option<OwnedMutexGuard<()>>),
 }
 
 /// Represents the current state of an async write operation.
@@ -342,186 +668,895 @@ enum Operation {
     Error(BoxFuture<'static, Result<Bytes, reqwest::Error>>),
     Start(BoxFuture<'static, crate::Result<Response>>),
     Upload(BoxFuture<'static, crate::Result<Response>>),
+    /// Querying how much of the upload session the registry committed after a failed chunk
+    Recover(BoxFuture<'static, crate::Result<Option<usize>>>),
+}
+
+/// Chunk upload whose request body is streamed from the writes as they come in.
+struct Stream {
+    sender: PollSender<Bytes>,
+    request: BoxFuture<'static, crate::Result<Response>>,
+}
+
+/// Chunk uploads of a blob sent concurrently over separate connections.
+struct Parallel {
+    /// Most chunk requests in flight at once
+    connections: usize,
+    /// Offset of the content in the buffer
+    start: usize,
+    /// Content written for the next chunk, sent once it holds all of it
+    buffer: BytesMut,
+    /// Chunk requests in flight
+    requests: FuturesUnordered<BoxFuture<'static, (Chunk, crate::Result<Response>)>>,
+    /// Chunks sent from the first one the registry did not acknowledge yet, along with their
+    /// content and whether they were acknowledged, kept to send them again one at a time when the
+    /// registry rejects a chunk
+    sent: VecDeque<(Chunk, Bytes, bool)>,
+    /// End of the furthest chunk the registry acknowledged
+    furthest: usize,
+    /// Whether the registry rejected a chunk or a request failed
+    failed: bool,
+}
+
+/// Range of the blob sent by the chunk upload in flight.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    start: usize,
+    end: usize,
+    /// Whether the chunk finishes the upload
+    last: bool,
+}
+
+impl Parallel {
+    /// Record the registry acknowledged the chunk, dropping the content kept of the chunks up to
+    /// the first one that is not acknowledged yet
+    fn acknowledge(&mut self, chunk: Chunk) {
+        if let Some(sent) = self.sent.iter_mut().find(|x| x.0.start == chunk.start) {
+            sent.2 = true;
+        }
+        while self.sent.front().is_some_and(|x| x.2) {
+            self.sent.pop_front();
+        }
+    }
 }
 
 impl Writer {
     /// Construct a layer object out of this writer, this also will signal a finish to the progress
-    /// bar in this writer if the feature is being used.
+    /// sinks watching the upload.
     pub async fn layer(&mut self) -> crate::Result<Layer> {
-        let digest_bytes = self.digest.clone().finalize();
-        let digest = base16::encode_lower(&digest_bytes);
-        let digest = format!("sha256:{}", digest.clone());
-
-        cfg_if! {
-            if #[cfg(feature = "progress")] {
-                if let Some(bar) = self.progress.as_mut() {
-                    bar.finish_with_message("done");
-                }
-            }
-
+        // The last request of the upload is awaited when flushing
+        self.flush().await.context(error::LayerWriteSnafu)?;
+        let digest = self.digest.digest();
+        self.span.record("digest", digest.as_str());
+        if self.dry_run {
+            self.uri.registry().plan(
+                AuditOperation::UploadBlob,
+                self.uri.repository(),
+                None,
+                Some(&digest),
+                Some(self.index),
+            );
         }
+        self.reporter.finished();
+        // Release the claim so concurrent uploads of this blob can see it now exists
+        self.flight = None;
         Ok(Layer {
             media_type: self.media_type.clone(),
             digest: digest.clone(),
             size: self.index,
             platform: None,
+            artifact_type: None,
+            annotations: BTreeMap::new(),
         })
     }
 }
 
-impl AsyncWrite for Writer {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, std::io::Error>> {
-        let this = self.get_mut();
-        if let Some(operation) = this.active.as_mut() {
+impl Writer {
+    /// Persist the progress of the upload session to the registry's store so it can be resumed,
+    /// or remove it once the upload is complete
+    fn checkpoint(&self) {
+        let (Some(store), Some(digest)) = (self.uri.registry().store(), self.expected.as_ref())
+        else {
+            return;
+        };
+        let registry = self.uri.registry().uri().base();
+        match self.upload_url.as_ref() {
+            Some(upload_url) if self.index < self.size => {
+                let checkpoint = UploadCheckpoint {
+                    upload_url: upload_url.clone(),
+                    offset: self.index,
+                };
+                if let Err(e) =
+                    store.save_checkpoint(registry, self.uri.repository(), digest, &checkpoint)
+                {
+                    debug!(target: "layer", "failed to checkpoint upload of {digest}: {e}");
+                }
+            }
+            _ => store.clear_checkpoint(registry, self.uri.repository(), digest),
+        }
+    }
+}
+
+impl Writer {
+    /// Request sending a chunk of the blob to the upload session. The upload is finished by an
+    /// empty last chunk, as the digest of the blob is only known once all of it was sent.
+    fn chunk_request(
+        &self,
+        body: Body,
+        start: usize,
+        end: usize,
+        last: bool,
+    ) -> std::io::Result<BoxFuture<'static, crate::Result<Response>>> {
+        let upload_url = self
+            .upload_url
+            .clone()
+            .ok_or_else(|| std::io::Error::other("no upload session was started"))?;
+        let url = self.uri.registry().url().map_err(std::io::Error::other)?;
+        let client = self.uri.registry().client.clone();
+        Ok(if last && start == end {
+            Box::pin(client.finish_blob_upload(
+                url,
+                upload_url,
+                body,
+                self.digest.digest(),
+                start,
+                end,
+            ))
+        } else {
+            Box::pin(client.upload_part(url, upload_url, body, start, end))
+        })
+    }
+
+    /// Stream the range of the chunk in flight from the start to the registry, sending the pieces
+    /// given first and then the ones written from here on. The request is awaited once all of the
+    /// chunk was written.
+    fn stream_chunk(&mut self, start: usize, pieces: Vec<Bytes>) -> std::io::Result<()> {
+        let chunk = self
+            .chunk
+            .ok_or_else(|| std::io::Error::other("there is no chunk in flight"))?;
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(STREAM_QUEUE);
+        let body = Body::wrap_stream(
+            futures::stream::iter(pieces)
+                .chain(futures::stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+                .map(Ok::<_, std::io::Error>),
+        );
+        let request = self.chunk_request(body, start, chunk.end, chunk.last)?;
+        if self.position() >= chunk.end {
+            self.active = Some(Operation::Upload(request));
+        } else {
+            self.stream = Some(Stream {
+                sender: PollSender::new(sender),
+                request,
+            });
+        }
+        Ok(())
+    }
+
+    /// Upload the buffered blob in a single request once all of it was written
+    fn post_monolithic(&mut self) -> std::io::Result<()> {
+        if self.monolithic.as_ref().is_none_or(|x| x.len() < self.size) {
+            return Ok(());
+        }
+        let Some(content) = self.monolithic.take() else {
+            return Ok(());
+        };
+        let url = self.uri.registry().url().map_err(std::io::Error::other)?;
+        self.active = Some(Operation::Upload(Box::pin(
+            self.uri.registry().client.clone().post_blob(
+                url,
+                self.uri.repository().clone(),
+                content.freeze(),
+                self.digest.digest(),
+            ),
+        )));
+        Ok(())
+    }
+
+    /// Start uploading the next chunk of the blob, beginning with the content in the backlog
+    fn start_chunk(&mut self) -> std::io::Result<()> {
+        let start = self.position();
+        let end = min(start + self.chunk_size, self.size);
+        self.chunk = Some(Chunk {
+            start,
+            end,
+            last: end >= self.size,
+        });
+        self.replay.clear();
+        let mut pieces = Vec::new();
+        let mut length = 0;
+        while length < end - start
+            && let Some(mut piece) = self.backlog.pop_front()
+        {
+            if length + piece.len() > end - start {
+                self.backlog
+                    .push_front(piece.split_off(end - start - length));
+            }
+            length += piece.len();
+            pieces.push(piece);
+        }
+        if self.uri.registry().retry_policy().max_attempts > 0 {
+            self.replay.extend(pieces.iter().cloned());
+        }
+        self.stream_chunk(start, pieces)
+    }
+
+    /// Offset up to which the written content was handed to chunk uploads, the rest of it is in
+    /// the backlog
+    fn position(&self) -> usize {
+        self.index - self.backlog.iter().map(Bytes::len).sum::<usize>()
+    }
+
+    /// Queue a piece of the chunk being streamed for its request body, returning how many bytes of
+    /// the buffer were taken. Once the whole chunk is queued its request is awaited.
+    fn poll_stream(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Poll::Ready(Ok(0));
+        };
+        let reserved = match stream.sender.poll_reserve(cx) {
+            Poll::Ready(reserved) => reserved.is_ok(),
+            Poll::Pending => {
+                // The request only ends early when it failed, it then needs handling
+                return match stream.request.poll_unpin(cx) {
+                    Poll::Ready(result) => {
+                        self.stream = None;
+                        self.active =
+                            Some(Operation::Upload(Box::pin(futures::future::ready(result))));
+                        Poll::Ready(Ok(0))
+                    }
+                    Poll::Pending => Poll::Pending,
+                };
+            }
+        };
+        let chunk = self
+            .chunk
+            .ok_or_else(|| std::io::Error::other("there is no chunk in flight"))?;
+        let piece = Bytes::copy_from_slice(&buf[..min(buf.len(), chunk.end - self.index)]);
+        if !reserved || stream.sender.send_item(piece.clone()).is_err() {
+            // The request body was dropped, so the request is done and tells what went wrong
+            let Some(stream) = self.stream.take() else {
+                return Poll::Ready(Ok(0));
+            };
+            self.active = Some(Operation::Upload(stream.request));
+            return Poll::Ready(Ok(0));
+        }
+        self.digest.update(&piece);
+        self.index += piece.len();
+        self.advance(piece.len());
+        if self.uri.registry().retry_policy().max_attempts > 0 {
+            self.replay.push(piece.clone());
+        }
+        if self.index >= chunk.end
+            && let Some(stream) = self.stream.take()
+        {
+            self.active = Some(Operation::Upload(stream.request));
+        }
+        Poll::Ready(Ok(piece.len()))
+    }
+
+    /// Buffer the written content for the next chunk sent concurrently, returning how many bytes
+    /// of the buffer were taken. Writes wait while all connections are busy and the chunk is full.
+    fn poll_parallel(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        loop {
+            self.poll_finished(cx);
+            let Some(parallel) = self.parallel.as_mut() else {
+                return Poll::Ready(Ok(0));
+            };
+            if parallel.failed {
+                return self.poll_fall_back(cx).map_ok(|()| 0);
+            }
+            if self.index >= self.size && parallel.buffer.is_empty() {
+                return Poll::Ready(Err(std::io::Error::other(
+                    "more content was written than the size of the blob",
+                )));
+            }
+            let end = min(parallel.start + self.chunk_size, self.size);
+            if self.index >= end {
+                if parallel.requests.len() >= parallel.connections {
+                    return Poll::Pending;
+                }
+                self.send_parallel()?;
+                continue;
+            }
+            let piece = &buf[..min(buf.len(), end - self.index)];
+            parallel.buffer.extend_from_slice(piece);
+            self.digest.update(piece);
+            self.index += piece.len();
+            self.advance(piece.len());
+            return Poll::Ready(Ok(piece.len()));
+        }
+    }
+
+    /// Send the buffered chunk concurrently with the chunks in flight
+    fn send_parallel(&mut self) -> std::io::Result<()> {
+        let Some(parallel) = self.parallel.as_mut() else {
+            return Ok(());
+        };
+        let content = parallel.buffer.split().freeze();
+        let chunk = Chunk {
+            start: parallel.start,
+            end: parallel.start + content.len(),
+            last: parallel.start + content.len() >= self.size,
+        };
+        parallel.start = chunk.end;
+        parallel.sent.push_back((chunk, content.clone(), false));
+        trace!(target: "layer", "sending chunk {}-{} concurrently", chunk.start, chunk.end);
+        let request = self.chunk_request(Body::from(content), chunk.start, chunk.end, false)?;
+        if let Some(parallel) = self.parallel.as_mut() {
+            parallel
+                .requests
+                .push(Box::pin(request.map(move |x| (chunk, x))));
+        }
+        Ok(())
+    }
+
+    /// Handle the chunk requests sent concurrently that finished
+    fn poll_finished(&mut self, cx: &mut Context<'_>) {
+        while let Some(parallel) = self.parallel.as_mut()
+            && let Poll::Ready(Some((chunk, result))) = parallel.requests.poll_next_unpin(cx)
+        {
+            let response = match result {
+                Ok(response) if response.status().is_success() => response,
+                Ok(response) => {
+                    debug!(target: "layer", "registry rejected chunk {}-{} sent concurrently with {}, sending the rest one at a time", chunk.start, chunk.end, response.status());
+                    if !crate::client::transient(response.status()) {
+                        self.uri.registry().reject_concurrent_chunks();
+                    }
+                    parallel.failed = true;
+                    continue;
+                }
+                Err(e) => {
+                    debug!(target: "layer", "chunk {}-{} sent concurrently failed, sending the rest one at a time: {e}", chunk.start, chunk.end);
+                    parallel.failed = true;
+                    continue;
+                }
+            };
+            trace!(target: "layer", "RESPONSE {:?}", response);
+            parallel.acknowledge(chunk);
+            if chunk.end >= parallel.furthest {
+                parallel.furthest = chunk.end;
+                if let Some(location) = upload_location(&response) {
+                    self.upload_url = Some(location);
+                }
+            }
+        }
+    }
+
+    /// Once no chunk requests are in flight, carry on one chunk at a time from what the registry
+    /// committed, sending the content it did not acknowledge again
+    fn poll_fall_back(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let Some(parallel) = self.parallel.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        while let Some((chunk, result)) = ready!(parallel.requests.poll_next_unpin(cx)) {
+            if result.is_ok_and(|x| x.status().is_success()) {
+                parallel.acknowledge(chunk);
+            }
+        }
+        let Some(mut parallel) = self.parallel.take() else {
+            return Poll::Ready(Ok(()));
+        };
+        // The first chunk not acknowledged is resumed, the content after it is sent again in
+        // chunks of the same size
+        let first = parallel.sent.pop_front();
+        self.backlog = parallel.sent.drain(..).map(|x| x.1).collect();
+        self.backlog.push_back(parallel.buffer.split().freeze());
+        self.backlog.retain(|x| !x.is_empty());
+        if let Some((chunk, content, _)) = first {
+            self.chunk = Some(chunk);
+            self.replay = vec![content];
+            self.recover();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send what is left of the blob once all of it was written, then finish the upload once the
+    /// registry acknowledged every chunk sent concurrently
+    fn poll_parallel_flush(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.upload_url.is_none() {
+                // Nothing was sent yet
+                return Poll::Ready(Ok(()));
+            }
+            self.poll_finished(cx);
+            let Some(parallel) = self.parallel.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+            if parallel.failed {
+                return self.poll_fall_back(cx);
+            }
+            if self.index < self.size {
+                // Midway the chunk being filled is only sent once it is complete
+                return if parallel.requests.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                };
+            }
+            if !parallel.buffer.is_empty() {
+                if parallel.requests.len() >= parallel.connections {
+                    return Poll::Pending;
+                }
+                self.send_parallel()?;
+                continue;
+            }
+            if !parallel.requests.is_empty() {
+                return Poll::Pending;
+            }
+            // All of the blob was acknowledged, finish the upload with its digest
+            self.parallel = None;
+            let chunk = Chunk {
+                start: self.size,
+                end: self.size,
+                last: true,
+            };
+            self.chunk = Some(chunk);
+            self.active = Some(Operation::Upload(self.chunk_request(
+                Body::from(Bytes::new()),
+                chunk.start,
+                chunk.end,
+                true,
+            )?));
+            return Poll::Ready(Ok(()));
+        }
+    }
+
+    /// Whether a failed chunk upload can be resumed, either because the request failed or
+    /// because the registry responded with a transient error status
+    fn recoverable(&self, status: Option<StatusCode>) -> bool {
+        let transient = status.is_none_or(|x| {
+            x.is_server_error()
+                || x == StatusCode::TOO_MANY_REQUESTS
+                || x == StatusCode::REQUEST_TIMEOUT
+        });
+        transient
+            && self.chunk.is_some()
+            && self.upload_url.is_some()
+            && self.retries < self.uri.registry().retry_policy().max_attempts
+    }
+
+    /// Wait with an exponential backoff, then ask the registry how much of the upload session it
+    /// committed so the failed chunk can be resumed from there
+    fn recover(&mut self) {
+        let delay = self.uri.registry().retry_policy().backoff(self.retries);
+        self.retries += 1;
+        let registry = self.uri.registry().clone();
+        let upload_url = self.upload_url.clone().unwrap_or_default();
+        self.active = Some(Operation::Recover(Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            registry.upload_status(&upload_url).await
+        })));
+    }
+
+    /// Resend the part of the failed chunk the registry did not commit from the content kept of
+    /// it, returning false when it had committed all of it. Writing the rest of the chunk then
+    /// continues where it left off.
+    fn resume(&mut self, committed: usize) -> crate::Result<bool> {
+        let chunk = self.chunk.context(error::UploadResumeSnafu {
+            reason: "there is no chunk in flight",
+        })?;
+        let position = self.position();
+        ensure!(
+            committed >= chunk.start && committed <= position,
+            error::UploadResumeSnafu {
+                reason: format!(
+                    "the registry committed {committed} bytes but {}-{position} of the chunk were sent",
+                    chunk.start
+                ),
+            }
+        );
+        debug!(target: "layer", "resuming chunk {}-{} from byte {committed} (attempt {})", chunk.start, chunk.end, self.retries);
+        if committed == chunk.end && !chunk.last {
+            // The registry committed the whole chunk, only the response was lost
+            return Ok(false);
+        }
+        let kept: usize = self.replay.iter().map(Bytes::len).sum();
+        ensure!(
+            kept == position - chunk.start,
+            error::UploadResumeSnafu {
+                reason: "the content of the chunk was not kept",
+            }
+        );
+        let mut skip = committed - chunk.start;
+        let mut pieces = Vec::with_capacity(self.replay.len());
+        for piece in self.replay.iter() {
+            if skip >= piece.len() {
+                skip -= piece.len();
+            } else {
+                pieces.push(piece.slice(skip..));
+                skip = 0;
+            }
+        }
+        self.stream_chunk(committed, pieces).map_err(|e| {
+            error::UploadResumeSnafu {
+                reason: e.to_string(),
+            }
+            .build()
+        })?;
+        Ok(true)
+    }
+
+    /// Drive the operation in flight to completion, writes and flushes wait on it
+    fn poll_active(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            let Some(operation) = self.active.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
             match operation {
-                Operation::Start(poll) => match poll.poll_unpin(cx) {
-                    Poll::Ready(Ok(response)) => {
+                Operation::Start(poll) => match ready!(poll.poll_unpin(cx)) {
+                    Ok(response) => {
+                        self.span.record("status", response.status().as_u16());
                         trace!(target: "layer", "RESPONSE {:?}", response);
-                        this.active = None;
+                        self.active = None;
                         if !response.status().is_success() {
-                            this.active = Some(Operation::Error(Box::pin(response.bytes())));
-                            cx.waker().wake_by_ref();
-                            return Poll::Pending;
+                            self.active = Some(Operation::Error(Box::pin(response.bytes())));
+                            continue;
                         }
-                        this.upload_url = response
-                            .headers()
-                            .get("Location")
-                            .and_then(|x| x.to_str().ok())
-                            .map(|x| x.to_string());
-                        trace!(target: "layer", "registry provided upload_url = {:?}", this.upload_url);
-                        // We return pending here with a wake to ensure we write the first buf
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                        self.upload_url = upload_location(&response);
+                        trace!(target: "layer", "registry provided upload_url = {:?}", self.upload_url);
                     }
-                    Poll::Ready(Err(e)) => Poll::Ready(Err(std::io::Error::other(e))),
-                    Poll::Pending => {
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                    Err(e) => {
+                        self.active = None;
+                        return Poll::Ready(Err(std::io::Error::other(e)));
                     }
                 },
-                Operation::Upload(poll) => match poll.poll_unpin(cx) {
-                    Poll::Ready(Ok(response)) => {
+                Operation::Upload(poll) => match ready!(poll.poll_unpin(cx)) {
+                    Ok(response) if self.recoverable(Some(response.status())) => {
+                        debug!(target: "layer", "chunk upload failed with {}, resuming", response.status());
+                        self.recover();
+                    }
+                    Err(e) if self.recoverable(None) => {
+                        debug!(target: "layer", "chunk upload failed, resuming: {e}");
+                        self.recover();
+                    }
+                    Ok(response) => {
+                        self.span.record("status", response.status().as_u16());
                         trace!(target: "layer", "RESPONSE {:?}", response);
-                        this.active = None;
-                        if response.status().is_success() {
-                            cfg_if! {
-                                if #[cfg(feature = "progress")] {
-                                    if let Some(bar) = this.progress.as_mut() {
-                                        bar.inc(buf.len() as u64);
-                                    }
-                                }
+                        self.active = None;
+                        if !response.status().is_success() {
+                            self.active = Some(Operation::Error(Box::pin(response.bytes())));
+                            continue;
+                        }
+                        let chunk = self.chunk.take();
+                        self.replay.clear();
+                        self.retries = 0;
+                        if let Some(location) = upload_location(&response)
+                            && self.upload_url.is_some()
+                        {
+                            self.upload_url = Some(location);
+                        }
+                        if let Some(chunk) = chunk.filter(|x| x.last && x.start < x.end) {
+                            // All of the blob was sent, finish the upload with its digest
+                            let chunk = Chunk {
+                                start: chunk.end,
+                                ..chunk
+                            };
+                            self.chunk = Some(chunk);
+                            self.active = Some(Operation::Upload(self.chunk_request(
+                                Body::from(Bytes::new()),
+                                chunk.start,
+                                chunk.end,
+                                true,
+                            )?));
+                            continue;
+                        }
+                        if !self.backlog.is_empty() {
+                            self.start_chunk()?;
+                            continue;
+                        }
+                        self.checkpoint();
+                        if self.index >= self.size {
+                            if let Err(e) = self.finalize(&response) {
+                                self.audit(Some(e.to_string()));
+                                return Poll::Ready(Err(std::io::Error::other(e)));
                             }
-                            Poll::Ready(Ok(buf.len()))
-                        } else {
-                            this.active = Some(Operation::Error(Box::pin(response.bytes())));
-                            cx.waker().wake_by_ref();
-                            Poll::Pending
+                            self.audit(None);
                         }
                     }
-                    Poll::Ready(Err(e)) => Poll::Ready(Err(std::io::Error::other(e))),
-                    Poll::Pending => {
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                    Err(e) => {
+                        self.active = None;
+                        self.audit(Some(e.to_string()));
+                        return Poll::Ready(Err(std::io::Error::other(e)));
                     }
                 },
-                Operation::Error(poll) => match poll.poll_unpin(cx) {
-                    Poll::Ready(Ok(response)) => {
-                        this.active = None;
-                        Poll::Ready(Err(std::io::Error::other(String::from_utf8_lossy(
-                            response.as_ref(),
-                        ))))
-                    }
-                    Poll::Ready(Err(e)) => Poll::Ready(Err(std::io::Error::other(e))),
-                    Poll::Pending => {
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                Operation::Recover(poll) => {
+                    let result = ready!(poll.poll_unpin(cx));
+                    self.active = None;
+                    match result {
+                        Ok(Some(committed)) => match self.resume(committed) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                self.chunk = None;
+                                self.replay.clear();
+                                self.retries = 0;
+                                if !self.backlog.is_empty() {
+                                    self.start_chunk()?;
+                                    continue;
+                                }
+                                self.checkpoint();
+                            }
+                            Err(e) => {
+                                self.audit(Some(e.to_string()));
+                                return Poll::Ready(Err(std::io::Error::other(e)));
+                            }
+                        },
+                        Ok(None) => {
+                            let e = error::UploadResumeSnafu {
+                                reason: "the registry no longer knows the upload session",
+                            }
+                            .build();
+                            self.audit(Some(e.to_string()));
+                            return Poll::Ready(Err(std::io::Error::other(e)));
+                        }
+                        Err(e) => {
+                            self.audit(Some(e.to_string()));
+                            return Poll::Ready(Err(std::io::Error::other(e)));
+                        }
                     }
-                },
+                }
+                Operation::Error(poll) => {
+                    let result = ready!(poll.poll_unpin(cx));
+                    self.active = None;
+                    let reason = match result {
+                        Ok(response) => String::from_utf8_lossy(response.as_ref()).to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    self.audit(Some(reason.clone()));
+                    return Poll::Ready(Err(std::io::Error::other(reason)));
+                }
+            }
+        }
+    }
+
+    /// Report bytes of the blob as transferred
+    fn advance(&mut self, bytes: usize) {
+        self.reporter.advanced(bytes as u64);
+    }
+
+    /// Digest the registry reported for the finished upload
+    pub fn registry_digest(&self) -> Option<&str> {
+        self.registry_digest.as_deref()
+    }
+
+    /// Validate the response finishing the upload: the registry must respond 201 Created with a
+    /// Location pointing at the blob, and the digest it computed must match the one we sent.
+    fn finalize(&mut self, response: &Response) -> crate::Result<()> {
+        let digest = self.digest.digest();
+        ensure!(
+            response.status() == StatusCode::CREATED,
+            error::FinalizeBlobSnafu {
+                digest: digest.clone(),
+                reason: format!("registry responded {} instead of 201", response.status()),
+            }
+        );
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|x| x.to_str().ok())
+            .context(error::FinalizeBlobSnafu {
+                digest: digest.clone(),
+                reason: "registry did not provide the location of the blob",
+            })?;
+        let location_digest = location
+            .split('?')
+            .next()
+            .and_then(|x| x.rsplit('/').next())
+            .map(|x| x.replace("%3A", ":").replace("%3a", ":"))
+            .filter(|x| x.contains(':'));
+        // Prefer the digest the registry reports, otherwise take it from the blob location
+        let registry_digest = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST)
+            .and_then(|x| x.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| location_digest.clone())
+            .context(error::FinalizeBlobSnafu {
+                digest: digest.clone(),
+                reason: format!("blob location '{location}' does not identify a digest"),
+            })?;
+        for actual in [Some(&registry_digest), location_digest.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            ensure!(
+                *actual == digest,
+                error::UploadDigestMismatchSnafu {
+                    expected: digest.clone(),
+                    actual: actual.clone(),
+                }
+            );
+        }
+        self.registry_digest = Some(registry_digest);
+        Ok(())
+    }
+
+    /// Record the outcome of the blob upload to the registry's audit sink
+    fn audit(&self, error: Option<String>) {
+        let digest = if error.is_none() {
+            Some(self.digest.digest())
+        } else {
+            self.expected.clone()
+        };
+        self.uri.registry().audit(
+            AuditOperation::UploadBlob,
+            self.uri.repository(),
+            None,
+            digest.as_deref(),
+            Some(self.size),
+            error,
+        );
+    }
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.get_mut();
+        let _span = this.span.clone().entered();
+        if this.dry_run {
+            this.digest.update(buf);
+            this.index += buf.len();
+            this.advance(buf.len());
+            return Poll::Ready(Ok(buf.len()));
+        }
+        loop {
+            ready!(this.poll_active(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            if this.index < this.committed {
+                // These bytes were committed by a previous upload session so they only need hashing
+                let skip = min(buf.len(), this.committed - this.index);
+                this.digest.update(&buf[..skip]);
+                this.index += skip;
+                this.advance(skip);
+                return Poll::Ready(Ok(skip));
+            }
+            if this.stream.is_some() {
+                match ready!(this.poll_stream(cx, buf))? {
+                    // The request of the chunk ended early and is handled before writing on
+                    0 => continue,
+                    written => return Poll::Ready(Ok(written)),
+                }
+            }
+            if let Some(monolithic) = this.monolithic.as_mut() {
+                let piece = &buf[..min(buf.len(), this.size - monolithic.len())];
+                monolithic.extend_from_slice(piece);
+                this.digest.update(piece);
+                this.index += piece.len();
+                this.advance(piece.len());
+                this.post_monolithic()?;
+                return Poll::Ready(Ok(piece.len()));
             }
-        } else if let Some(upload_url) = this.upload_url.as_ref() {
-            if this.index + buf.len() >= this.size {
-                // If our position plus the buffer we want to write is the end we should
-                // finish the upload
+            if this.parallel.is_some() && this.upload_url.is_some() {
+                match ready!(this.poll_parallel(cx, buf))? {
+                    // The registry rejected a chunk, the upload carries on one chunk at a time
+                    0 => continue,
+                    written => return Poll::Ready(Ok(written)),
+                }
+            }
+            if this.upload_url.is_some() {
+                this.start_chunk()?;
+            } else if this.index == 0 && buf.len() == this.size {
+                // If we haven't started an upload and the passed buffer is equal to the size of
+                // the layer we are writing, we can send a single post upload
+                this.parallel = None;
                 this.digest.update(buf);
-                let hash = this.digest.clone().finalize();
-                let digest = base16::encode_lower(hash.as_slice());
                 let url = this.uri.registry().url().map_err(std::io::Error::other)?;
                 this.active = Some(Operation::Upload(Box::pin(
-                    this.uri.registry().client.clone().finish_blob_upload(
+                    this.uri.registry().client.clone().post_blob(
                         url,
-                        upload_url.clone(),
-                        Bytes::from_owner(buf.to_vec()),
-                        format!("sha256:{digest}"),
-                        this.index,
-                        this.size,
+                        this.uri.repository().clone(),
+                        Bytes::copy_from_slice(buf),
+                        this.digest.digest(),
                     ),
                 )));
-                this.index += buf.len();
-                cx.waker().wake_by_ref();
-                Poll::Pending
+                this.index = buf.len();
+                this.advance(buf.len());
+                return Poll::Ready(Ok(buf.len()));
             } else {
-                // Otherwise we should send what we have as a patch
+                // If we have not started an upload we should do so now
                 let url = this.uri.registry().url().map_err(std::io::Error::other)?;
-                this.active = Some(Operation::Upload(Box::pin(
-                    this.uri.registry().client.clone().upload_part(
-                        url,
-                        upload_url.clone(),
-                        Bytes::from_owner(buf.to_vec()),
-                        this.index,
-                        this.index + buf.len(),
-                    ),
+                this.active = Some(Operation::Start(Box::pin(
+                    this.uri
+                        .registry()
+                        .client
+                        .clone()
+                        .start_upload(url, this.uri.repository().clone()),
                 )));
-                this.index += buf.len();
-                this.digest.update(buf);
-                cx.waker().wake_by_ref();
-                Poll::Pending
+                this.index = 0;
             }
-        } else if buf.len() == this.size {
-            // If we haven't started an upload and the passed buffer is equal to the size of the layer
-            // we are writing, we can send a single post upload
-            this.digest.update(buf);
-            let hash = this.digest.clone().finalize();
-            let digest = base16::encode_lower(hash.as_slice());
-            let url = this.uri.registry().url().map_err(std::io::Error::other)?;
-            this.active = Some(Operation::Upload(Box::pin(
-                this.uri.registry().client.clone().post_blob(
-                    url,
-                    this.uri.repository().clone(),
-                    Bytes::from_owner(buf.to_vec()),
-                    format!("sha256:{digest}"),
-                ),
-            )));
-            this.index = buf.len();
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        } else {
-            // If we have not started an upload we should do so now
-            let url = this.uri.registry().url().map_err(std::io::Error::other)?;
-            this.active = Some(Operation::Start(Box::pin(
-                this.uri
-                    .registry()
-                    .client
-                    .clone()
-                    .start_upload(url, this.uri.repository().clone()),
-            )));
-            this.index = 0;
-            cx.waker().wake_by_ref();
-            Poll::Pending
-        }
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let this = self.get_mut();
+        let _span = this.span.clone().entered();
+        if this.dry_run {
+            return Poll::Ready(Ok(()));
+        }
+        // An empty blob is uploaded without being written to
+        this.post_monolithic()?;
+        ready!(this.poll_active(cx))?;
+        ready!(this.poll_parallel_flush(cx))?;
+        this.poll_active(cx)
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use reqwest::{Method, StatusCode};
+    use tokio::io::AsyncWriteExt;
+
+    use super::Layer;
+    use crate::models::{Compression, MediaType};
+    use crate::options::ChunkPolicy;
+    use crate::testing::MockRegistryClient;
+    use crate::uri::{Algorithm, Reference, RegistryUri, Uri};
+
+    const CONTENT: &[u8] = b"0123456789abcdefghij";
+
+    fn uri(client: &MockRegistryClient) -> Uri {
+        let mut registry = client.registry(&RegistryUri::from_str("mock.local").unwrap());
+        registry.set_chunk_policy(
+            ChunkPolicy::builder()
+                .min_bytes(4)
+                .max_bytes(4)
+                .monolithic_bytes(0)
+                .connections(3)
+                .build(),
+        );
+        Uri::builder()
+            .registry(registry)
+            .repository("app")
+            .reference(Reference::Tag("latest".to_string()))
+            .build()
+    }
+
+    /// Upload the content a few bytes at a time, as a layer streamed from a file would be
+    async fn upload(uri: &Uri) -> Layer {
+        let digest = Algorithm::Sha256.digest(CONTENT);
+        let media_type = MediaType::Layer(Compression::None);
+        let mut writer = Layer::create(uri, &media_type, CONTENT.len(), Some(digest))
+            .await
+            .unwrap()
+            .unwrap();
+        for piece in CONTENT.chunks(3) {
+            writer.write_all(piece).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        writer.layer().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upload_parallel_chunks() {
+        let client = MockRegistryClient::new();
+        let uri = uri(&client);
+        let layer = upload(&uri).await;
+        assert_eq!(client.blob("app", layer.digest()).unwrap(), CONTENT);
+        assert_eq!(client.requests(&Method::PATCH).len(), 5);
+        assert_eq!(uri.registry().chunk_connections(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upload_parallel_fallback() {
+        let client = MockRegistryClient::new();
+        let uri = uri(&client);
+        // Registries taking chunks one at a time reject the ones sent after the first
+        client.fail(Method::PATCH, StatusCode::RANGE_NOT_SATISFIABLE, 1);
+        let layer = upload(&uri).await;
+        assert_eq!(client.blob("app", layer.digest()).unwrap(), CONTENT);
+        assert_eq!(uri.registry().chunk_connections(), 1);
     }
 }
@@ -1,24 +1,61 @@
 #[macro_use]
 extern crate tracing;
 
-pub(crate) mod client;
+/// Artifacts attached to images, i.e. SBOMs.
+pub mod artifact;
+/// Audit logging of mutating registry operations.
+pub mod audit;
+/// Azure Container Registry token exchange.
+#[cfg(feature = "azure")]
+pub(crate) mod azure;
+/// HTTP clients sending the requests of a registry.
+pub mod client;
 /// Layer decompression utilities.
 #[cfg(feature = "compression")]
 pub mod compression;
+/// Docker credential helper execution.
+pub mod credentials;
+/// Loading images into container daemons.
+#[cfg(feature = "compression")]
+pub mod daemon;
 /// Error types for the crate.
 pub mod error;
+/// Google Cloud application default credentials.
+#[cfg(feature = "gcp")]
+pub(crate) mod gcp;
 /// Image manifest handling.
 pub mod image;
 /// Image index operations.
 pub mod index;
+/// Image summaries combining the manifest, index and configuration.
+pub mod inspect;
 /// Layer read/write operations.
 pub mod layer;
+/// Index or image manifest resolution.
+pub mod manifest;
 /// OCI specification model types.
 pub mod models;
+/// Settings file and per registry options.
+pub mod options;
+/// Building images from local directories.
+#[cfg(feature = "compression")]
+pub mod pack;
+/// Transfer progress events.
+pub mod progress;
 /// Registry client and operations.
 pub mod registry;
 /// Repository operations.
 pub mod repository;
+/// Cosign compatible image signatures.
+pub mod signing;
+/// AWS SigV4 request signing.
+#[cfg(feature = "aws")]
+pub mod sigv4;
+/// Local content addressed store.
+pub mod store;
+/// In-memory registry client for tests of code using this crate.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 /// URI parsing and representation.
 pub mod uri;
 
@@ -1,54 +1,211 @@
 use crate::cmd::export::Export;
 use crate::cmd::pull::Pull;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use cmd::{
-    blob::Blob, catalog::Catalog, config::Config, context::Ctx, copy::Copy, delete::Delete,
-    index::IndexCmd, list::List, manifest::Manifest, push::Push,
+    append::Append,
+    attest::Attest,
+    blob::Blob,
+    cache::Cache,
+    cat::Cat,
+    catalog::Catalog,
+    config::Config,
+    context::{Ctx, parse_size},
+    copy::Copy,
+    delete::Delete,
+    digest::Digest,
+    eq::Eq,
+    extract::Extract,
+    files::Files,
+    flatten::Flatten,
+    gc::Gc,
+    index::IndexCmd,
+    inspect::Inspect,
+    list::List,
+    login::Login,
+    logout::Logout,
+    manifest::Manifest,
+    mutate::Mutate,
+    pack::Pack,
+    prefetch::Prefetch,
+    push::Push,
+    rebase::Rebase,
+    referrers::Referrers,
+    sbom::SbomCmd,
+    sign::Sign,
+    sync::Sync,
+    tag::Tag,
+    verify::Verify,
+    verify_digests::VerifyDigests,
 };
+use std::path::PathBuf;
 
 mod cmd;
 
 /// CLI argument parser.
 #[derive(Parser, Debug)]
 struct Args {
+    #[clap(flatten)]
+    global: Global,
     #[clap(subcommand)]
     command: Commands,
 }
 
+/// Options shared by all subcommands.
+#[derive(Parser, Debug)]
+pub struct Global {
+    /// Append a JSON line for every mutating registry operation to this file
+    #[arg(long, global = true)]
+    audit_log: Option<PathBuf>,
+    /// Write logs to this file instead of the terminal, leaving it to progress and summaries
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// Format of log lines
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// Log a line with the duration, status and bytes of every registry operation as it completes
+    #[arg(long, global = true)]
+    log_spans: bool,
+    /// Format of the results printed by commands
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output: OutputFormat,
+    /// Progress bars to show while transferring blobs
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: ProgressMode,
+    /// Always fetch from the registry, without reading or writing the local blob cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Most blob transfers to run at the same time, across all registries of the command
+    #[arg(long, global = true)]
+    max_concurrent: Option<usize>,
+    /// Registry settings file, i.e. mirrors, instead of ~/.config/ocilot/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Upload blobs in chunks of this size (i.e. 8MiB) instead of the chunk policy of the
+    /// settings file or the preset of the registry
+    #[arg(long, global = true, value_parser = parse_size)]
+    chunk_size: Option<usize>,
+    /// Upload the chunks of a blob over this many connections at once, for registries accepting
+    /// chunks out of order
+    #[arg(long, global = true)]
+    chunk_connections: Option<usize>,
+    /// Resolve everything but only print the plan of blob uploads and manifest pushes or deletes,
+    /// without changing any registry
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+/// Format of log lines.
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Format of the results printed by commands.
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text, documents are pretty printed
+    #[default]
+    Text,
+    /// A single line of JSON for scripting
+    Json,
+}
+
+/// Progress bars shown while transferring blobs.
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy, ValueEnum)]
+pub enum ProgressMode {
+    /// An overall bar for the operation followed by a bar per blob
+    #[default]
+    All,
+    /// Only the overall bar for the operation
+    Aggregate,
+}
+
 /// Available CLI subcommands.
 #[derive(Parser, Debug)]
 enum Commands {
     Index(IndexCmd),
     Manifest(Manifest),
     Config(Config),
+    Inspect(Inspect),
     Blob(Blob),
     List(List),
     Catalog(Catalog),
     Export(Export),
+    Files(Files),
+    Cat(Cat),
+    Extract(Extract),
     Pull(Pull),
+    Prefetch(Prefetch),
     Push(Push),
     Delete(Delete),
+    Gc(Gc),
     Copy(Copy),
+    Sync(Sync),
+    Login(Login),
+    Logout(Logout),
+    Referrers(Referrers),
+    Sbom(SbomCmd),
+    Attest(Attest),
+    Sign(Sign),
+    Verify(Verify),
+    VerifyDigests(VerifyDigests),
+    Cache(Cache),
+    Tag(Tag),
+    Digest(Digest),
+    Eq(Eq),
+    Append(Append),
+    Mutate(Mutate),
+    Flatten(Flatten),
+    Rebase(Rebase),
+    Pack(Pack),
 }
 
 #[snafu::report]
 #[tokio::main]
 async fn main() -> ocilot::Result<()> {
-    let mut ctx = Ctx::init()?;
     let args = Args::parse();
+    let mut ctx = Ctx::init(&args.global).await?;
 
     match args.command {
         Commands::Index(cmd) => cmd.run(&mut ctx).await?,
         Commands::Manifest(cmd) => cmd.run(&ctx).await?,
         Commands::Config(cmd) => cmd.run(&ctx).await?,
+        Commands::Inspect(cmd) => cmd.run(&ctx).await?,
         Commands::Blob(cmd) => cmd.run(&ctx).await?,
         Commands::List(cmd) => cmd.run(&ctx).await?,
         Commands::Catalog(cmd) => cmd.run(&ctx).await?,
         Commands::Export(cmd) => cmd.run(&mut ctx).await?,
+        Commands::Files(cmd) => cmd.run(&ctx).await?,
+        Commands::Cat(cmd) => cmd.run(&ctx).await?,
+        Commands::Extract(cmd) => cmd.run(&ctx).await?,
         Commands::Pull(cmd) => cmd.run(&mut ctx).await?,
+        Commands::Prefetch(cmd) => cmd.run(&ctx).await?,
         Commands::Delete(cmd) => cmd.run(&ctx).await?,
+        Commands::Gc(cmd) => cmd.run(&ctx).await?,
         Commands::Push(cmd) => cmd.run(&mut ctx).await?,
         Commands::Copy(cmd) => cmd.run(&mut ctx).await?,
+        Commands::Sync(cmd) => cmd.run(&ctx).await?,
+        Commands::Login(cmd) => cmd.run(&ctx).await?,
+        Commands::Logout(cmd) => cmd.run(&ctx).await?,
+        Commands::Referrers(cmd) => cmd.run(&ctx).await?,
+        Commands::Sbom(cmd) => cmd.run(&ctx).await?,
+        Commands::Attest(cmd) => cmd.run(&ctx).await?,
+        Commands::Sign(cmd) => cmd.run(&ctx).await?,
+        Commands::Verify(cmd) => cmd.run(&ctx).await?,
+        Commands::VerifyDigests(cmd) => cmd.run(&ctx).await?,
+        Commands::Cache(cmd) => cmd.run(&ctx).await?,
+        Commands::Tag(cmd) => cmd.run(&ctx).await?,
+        Commands::Digest(cmd) => cmd.run(&ctx).await?,
+        Commands::Eq(cmd) => cmd.run(&ctx).await?,
+        Commands::Append(cmd) => cmd.run(&ctx).await?,
+        Commands::Mutate(cmd) => cmd.run(&ctx).await?,
+        Commands::Flatten(cmd) => cmd.run(&ctx).await?,
+        Commands::Rebase(cmd) => cmd.run(&ctx).await?,
+        Commands::Pack(cmd) => cmd.run(&ctx).await?,
     }
     Ok(())
 }
@@ -0,0 +1,70 @@
+use crate::error;
+use crate::image::Image;
+use crate::index::Index;
+use crate::models::{MediaType, Platform};
+use crate::uri::{Reference, Uri};
+use serde::Serialize;
+use snafu::{ResultExt, ensure};
+
+/// Either kind of manifest a reference can point to, an image index listing images per platform
+/// or a single image manifest.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Manifest {
+    Index(Index),
+    Image(Box<Image>),
+}
+
+impl Manifest {
+    /// Fetch the manifest the uri points to, deciding on the kind of manifest by its media type
+    pub async fn fetch(uri: &Uri) -> crate::Result<Self> {
+        let reference = uri.reference().to_string();
+        let (bytes, digest, media_type) = uri
+            .registry()
+            .fetch_manifest_raw(uri.repository(), reference.as_str())
+            .await?;
+        if let Reference::Digest { .. } = uri.reference() {
+            ensure!(
+                digest == reference,
+                error::DigestMismatchSnafu {
+                    expected: reference,
+                    actual: digest,
+                }
+            );
+        }
+        Self::from_slice(&bytes, &media_type)
+    }
+
+    /// Deserialize a manifest of the media type
+    pub fn from_slice(bytes: &[u8], media_type: &MediaType) -> crate::Result<Self> {
+        Ok(match media_type {
+            MediaType::ImageIndex | MediaType::DockerManifestList => {
+                Self::Index(serde_json::from_slice(bytes).context(error::BodyDeserializeSnafu)?)
+            }
+            _ => Self::Image(Box::new(
+                serde_json::from_slice(bytes).context(error::BodyDeserializeSnafu)?,
+            )),
+        })
+    }
+
+    /// Media type of the manifest
+    pub fn media_type(&self) -> &MediaType {
+        match self {
+            Self::Index(index) => index.media_type(),
+            Self::Image(image) => image.media_type(),
+        }
+    }
+
+    /// Resolve the image to use, selecting it by platform from an index as in
+    /// [`Index::fetch_image`]. An image manifest is returned as is, regardless of the platform.
+    pub async fn fetch_image(
+        self,
+        uri: &Uri,
+        platform: Option<Platform>,
+    ) -> crate::Result<Option<Image>> {
+        match self {
+            Self::Index(index) => index.fetch_image(uri, platform).await,
+            Self::Image(image) => Ok(Some(*image)),
+        }
+    }
+}
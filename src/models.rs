@@ -1,14 +1,15 @@
 use base64::Engine;
 use bon::Builder;
 use chrono::{DateTime, Utc};
-use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
 use std::env::consts;
-use std::{collections::HashMap, fmt};
+use std::fmt;
+use std::str::FromStr;
 
 /// Handles all the supported media type enumerations by this tool.
-/// Since OCI specification allows custom types this is rather limited currently
-/// but should be expanded to treat any unrecognized MediaType as a Custom variant
+/// Since OCI specification allows custom types any unrecognized media type is kept
+/// as is in the `Other` variant, i.e. the in-toto layers of attestations
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MediaType {
     ImageIndex,
@@ -19,36 +20,41 @@ pub enum MediaType {
     DockerManifest,
     DockerContainerImage,
     DockerImageRootfs(Compression),
+    /// Empty JSON object `{}`, used as the config of artifact manifests
+    Empty,
+    /// Any other media type, such as `application/vnd.in-toto+json`
+    Other(String),
 }
 
 impl MediaType {
+    /// The oci media type of a docker one, other media types are kept
+    pub fn to_oci(&self) -> Self {
+        match self {
+            Self::DockerManifestList => Self::ImageIndex,
+            Self::DockerManifest => Self::Manifest,
+            Self::DockerContainerImage => Self::Config,
+            Self::DockerImageRootfs(compression) => Self::Layer(compression.clone()),
+            other => other.clone(),
+        }
+    }
+
     pub fn compression(&self) -> Compression {
         match self {
-            Self::DockerImageRootfs(compression) => {
-                if *compression == Compression::None {
-                    Compression::Gzip
-                } else {
-                    compression.clone()
-                }
-            }
-            Self::Layer(compression) => compression.clone(),
+            Self::DockerImageRootfs(compression) | Self::Layer(compression) => compression.clone(),
             _ => Compression::None,
         }
     }
 }
 
-impl Serialize for MediaType {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let string = match self {
             Self::ImageIndex => "application/vnd.oci.image.index.v1+json".into(),
             Self::Manifest => "application/vnd.oci.image.manifest.v1+json".into(),
             Self::Config => "application/vnd.oci.image.config.v1+json".into(),
             Self::Layer(compression) => format!(
                 "application/vnd.oci.image.layer.v1.tar{}",
-                compression.to_ext()
+                compression.to_oci_suffix()
             ),
             Self::DockerManifestList => {
                 "application/vnd.docker.distribution.manifest.list.v2+json".into()
@@ -57,10 +63,21 @@ impl Serialize for MediaType {
             Self::DockerContainerImage => "application/vnd.docker.container.image.v1+json".into(),
             Self::DockerImageRootfs(compression) => format!(
                 "application/vnd.docker.image.rootfs.diff.tar{}",
-                compression.to_ext()
+                compression.to_docker_suffix()
             ),
+            Self::Empty => "application/vnd.oci.empty.v1+json".into(),
+            Self::Other(media_type) => media_type.clone(),
         };
-        serializer.serialize_str(string.as_str())
+        write!(f, "{string}")
+    }
+}
+
+impl Serialize for MediaType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
     }
 }
 
@@ -90,18 +107,8 @@ impl<'de> Deserialize<'de> for MediaType {
                 "application/vnd.oci.image.manifest.v1+json" => Ok(MediaType::Manifest),
                 "application/vnd.oci.image.index.v1+json" => Ok(MediaType::ImageIndex),
                 "application/vnd.oci.image.config.v1+json" => Ok(MediaType::Config),
-                variant => Err(D::Error::unknown_variant(
-                    variant,
-                    &[
-                        "application/vnd.docker.image.rootfs.diff.tar.*",
-                        "application/vnd.docker.container.image.v1+json",
-                        "application/vnd.docker.distribution.manifest.list.v2+json",
-                        "application/vnd.docker.distribution.manifest.v2+json",
-                        "application/vnd.oci.image.index.v1+json",
-                        "application/vnd.oci.image.manifest.v1+json",
-                        "application/vnd.oci.image.config.v1+json",
-                    ],
-                )),
+                "application/vnd.oci.empty.v1+json" => Ok(MediaType::Empty),
+                _ => Ok(MediaType::Other(string)),
             }
         }
     }
@@ -120,14 +127,17 @@ pub enum Compression {
 }
 
 impl Compression {
+    /// Detect the compression from a file extension or the suffix of a layer media type, i.e.
+    /// `+gzip` for oci layers or `.gzip` for docker layers
     pub fn new(string: &str) -> Self {
-        if string.ends_with(".gz") || string.ends_with(".gzip2") {
+        if string.ends_with(".gz") || string.ends_with(".gzip") || string.ends_with("+gzip") {
             Compression::Gzip
         } else if string.ends_with(".xz") {
             Compression::Xz
         } else if string.ends_with(".lz4") {
             Compression::Lz4
-        } else if string.ends_with(".zst") {
+        } else if string.ends_with(".zst") || string.ends_with(".zstd") || string.ends_with("+zstd")
+        {
             Compression::Zstd
         } else if string.ends_with(".bz2") || string.ends_with(".bzip2") {
             Compression::Bzip2
@@ -146,6 +156,28 @@ impl Compression {
             Self::None => "",
         }
     }
+
+    /// Suffix of an oci layer media type with this compression
+    pub fn to_oci_suffix(&self) -> &str {
+        match self {
+            Self::Gzip => "+gzip",
+            Self::Zstd => "+zstd",
+            other => other.to_ext(),
+        }
+    }
+
+    /// Suffix of a docker layer media type with this compression
+    pub fn to_docker_suffix(&self) -> &str {
+        match self {
+            Self::Gzip => ".gzip",
+            other => other.to_ext(),
+        }
+    }
+
+    /// Whether docker defines a layer media type for this compression, only gzip and none
+    pub fn has_docker_media_type(&self) -> bool {
+        matches!(self, Self::Gzip | Self::None)
+    }
 }
 
 /// This defines the format of a manifest.json file in a tarball representation of
@@ -162,13 +194,64 @@ pub struct TarballManifest {
 }
 
 /// Represents the frequently used platform identifiers both in json format and as the
-/// commandline <os>/<architecture> format.
+/// commandline <os>[(<os.version>)]/<architecture>[/<variant>] format.
 #[derive(Builder, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Platform {
     #[builder(into)]
     pub architecture: String,
     #[builder(into)]
     pub os: String,
+    /// Variant of the cpu, i.e. `v7` for `arm`
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Version of the operating system, primarily used by windows images
+    #[builder(into)]
+    #[serde(
+        default,
+        rename = "os.version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_version: Option<String>,
+    /// Features of the operating system the image requires, i.e. `win32k` for windows
+    #[builder(default, into)]
+    #[serde(default, rename = "os.features", skip_serializing_if = "Vec::is_empty")]
+    pub os_features: Vec<String>,
+}
+
+impl Platform {
+    /// Check if an image platform satisfies this platform. The os and architecture must be equal,
+    /// while the variant and os version are only compared when this platform specifies them. Any
+    /// os features of this platform must also be present on the image platform.
+    pub fn matches(&self, other: &Platform) -> bool {
+        if self.os != other.os || self.architecture != other.architecture {
+            return false;
+        }
+        if !self
+            .os_features
+            .iter()
+            .all(|x| other.os_features.contains(x))
+        {
+            return false;
+        }
+        if let Some(variant) = self.variant.as_ref() {
+            // arm64 images rarely specify their variant as v8 is the only one in use
+            let other_variant = other
+                .variant
+                .as_deref()
+                .or((other.architecture == "arm64").then_some("v8"));
+            if other_variant != Some(variant.as_str()) {
+                return false;
+            }
+        }
+        if let Some(os_version) = self.os_version.as_ref() {
+            return other
+                .os_version
+                .as_ref()
+                .is_some_and(|x| x.starts_with(os_version.as_str()));
+        }
+        true
+    }
 }
 
 impl Default for Platform {
@@ -180,51 +263,167 @@ impl Default for Platform {
         Self {
             os: "linux".to_string(),
             architecture: arch.to_string(),
+            variant: None,
+            os_version: None,
+            os_features: Vec::new(),
         }
     }
 }
 
-impl From<String> for Platform {
-    fn from(value: String) -> Self {
-        let (os, architecture) = value.split_once("/").unwrap();
-        Self {
+impl FromStr for Platform {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::error::InvalidPlatformSnafu { platform: s }.build();
+        let mut parts = s.split('/');
+        let os = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?;
+        let architecture = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?;
+        let variant = parts.next().map(str::to_string);
+        if parts.next().is_some() || variant.as_ref().is_some_and(|x| x.is_empty()) {
+            return Err(invalid());
+        }
+        let (os, os_version) = match os.split_once('(') {
+            Some((os, version)) => (
+                os,
+                Some(version.strip_suffix(')').ok_or_else(invalid)?.to_string()),
+            ),
+            None => (os, None),
+        };
+        Ok(Self {
             architecture: architecture.to_string(),
             os: os.to_string(),
-        }
+            variant,
+            os_version,
+            os_features: Vec::new(),
+        })
+    }
+}
+
+impl From<String> for Platform {
+    fn from(value: String) -> Self {
+        value
+            .parse()
+            .expect("platform must be in the form os/architecture[/variant]")
     }
 }
 
 impl fmt::Display for Platform {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("{}/{}", self.os, self.architecture))
+        f.write_str(&self.os)?;
+        if let Some(os_version) = self.os_version.as_ref() {
+            f.write_fmt(format_args!("({os_version})"))?;
+        }
+        f.write_fmt(format_args!("/{}", self.architecture))?;
+        if let Some(variant) = self.variant.as_ref() {
+            f.write_fmt(format_args!("/{variant}"))?;
+        }
+        Ok(())
     }
 }
 
-/// Represents the config block inside of an image config and frequently utilized fields
-#[derive(Builder, Serialize, Deserialize, Debug, Clone)]
+/// Represents the config block inside of an image config. Fields without a dedicated member are
+/// kept in `other`, so configs round trip without losing any of their content.
+#[derive(Builder, Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "PascalCase")]
 pub struct Config {
     #[builder(into)]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+    /// Ports to expose as `<port>/<protocol>`, the values are always empty objects
+    #[builder(into, default)]
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub exposed_ports: BTreeMap<String, serde_json::Value>,
     #[builder(into)]
-    #[serde(default)]
+    #[serde(default, deserialize_with = "nullable")]
     pub env: Vec<String>,
+    #[builder(into, default)]
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub entrypoint: Vec<String>,
     #[builder(into)]
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub cmd: Vec<String>,
+    /// Paths of volumes, the values are always empty objects
+    #[builder(into, default)]
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub volumes: BTreeMap<String, serde_json::Value>,
     #[builder(into)]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub working_dir: Option<String>,
     #[builder(into)]
-    #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub on_build: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub on_build: Vec<String>,
     #[builder(into)]
     #[serde(default)]
     pub args_escaped: bool,
     #[builder(into)]
-    #[serde(default)]
-    pub labels: HashMap<String, String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub labels: BTreeMap<String, String>,
+    /// Signal sent to the container to stop it, i.e. `SIGTERM`
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<Healthcheck>,
+    /// Any other fields of the config
+    #[builder(default)]
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Represents the docker healthcheck of a config, durations are in nanoseconds
+#[derive(Builder, Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Healthcheck {
+    /// Test to run, i.e. `["CMD-SHELL", "curl -f http://localhost/"]` or `["NONE"]`
+    #[builder(into)]
+    #[serde(default, deserialize_with = "nullable")]
+    pub test: Vec<String>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_period: Option<u64>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u64>,
+    /// Any other fields of the healthcheck
+    #[builder(default)]
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Deserialize a field that docker writes as `null` when it is empty
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
 }
 
 /// Represents a history log entry in an image config
@@ -232,30 +431,96 @@ pub struct Config {
 #[serde(rename_all = "snake_case")]
 pub struct History {
     #[builder(into)]
-    pub created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    #[builder(into)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
     #[builder(into)]
-    pub created_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
     #[builder(into)]
-    pub comment: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
     #[builder(into)]
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     pub empty_layer: bool,
 }
 
-/// Represents the shape of an image configuration blob
+/// Represents the shape of an image configuration blob. Fields without a dedicated member are kept
+/// in `other`, so configs round trip without losing any of their content.
 #[derive(Builder, Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ImageConfig {
     #[builder(into)]
     pub architecture: String,
     #[builder(into)]
+    #[serde(default)]
     pub config: Config,
     #[builder(into)]
-    pub created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
     #[builder(into)]
+    #[serde(default, deserialize_with = "nullable")]
     pub history: Vec<History>,
     #[builder(into)]
     pub os: String,
+    #[builder(into, default)]
+    #[serde(default)]
+    pub rootfs: RootFs,
+    /// Any other fields of the config, i.e. `author` or `os.version`
+    #[builder(default)]
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ImageConfig {
+    /// Platform the image was built for, as it would be described in an image index
+    pub fn platform(&self) -> Platform {
+        let other = |key: &str| {
+            self.other
+                .get(key)
+                .and_then(|x| x.as_str())
+                .map(str::to_string)
+        };
+        Platform {
+            os: self.os.clone(),
+            architecture: self.architecture.clone(),
+            variant: other("variant"),
+            os_version: other("os.version"),
+            os_features: self
+                .other
+                .get("os.features")
+                .and_then(|x| x.as_array())
+                .map(|x| {
+                    x.iter()
+                        .filter_map(|x| x.as_str())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Represents the rootfs section of an image config, which lists the digests of the uncompressed
+/// layers so the content of the layers can be verified after decompression.
+#[derive(Builder, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RootFs {
+    /// Type of the rootfs, this is always `layers`
+    #[builder(into, default = "layers")]
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    /// Digests of the uncompressed layers, in the order of the layers of the manifest
+    #[builder(into, default)]
+    #[serde(default)]
+    pub diff_ids: Vec<String>,
+}
+
+impl Default for RootFs {
+    fn default() -> Self {
+        Self::builder().build()
+    }
 }
 
 /// Helper structure that represents the response type of a
@@ -373,13 +638,43 @@ impl fmt::Display for ErrorResponse {
 }
 
 /// Represents an authorization token
-#[derive(Debug, Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Token {
+    /// Token sent as `Authorization: Bearer`, also used to answer bearer challenges
     Bearer(String),
+    /// Username and password sent as `Authorization: Basic`, also used to answer bearer challenges
     Basic { username: String, password: String },
 }
 
+impl fmt::Debug for Token {
+    /// Secrets are redacted so registries can be logged
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bearer(_) => f.write_str("Bearer(<redacted>)"),
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
 impl Token {
+    /// Create a bearer token, i.e. an identity token or one issued by a token service
+    pub fn bearer(token: impl Into<String>) -> Self {
+        Self::Bearer(token.into())
+    }
+
+    /// Create a token of a username and password
+    pub fn basic(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self::Basic {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Token of an entry of the docker config, if it holds one
     pub fn parse(value: DockerAuth) -> Option<Self> {
         if let Some(identitytoken) = value.identitytoken {
             Some(Self::Bearer(identitytoken))
@@ -399,11 +694,28 @@ impl Token {
     }
 }
 
+/// Response of a token service answering a bearer challenge
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenResponse {
+    pub token: Option<String>,
+    pub access_token: Option<String>,
+}
+
 /// View model for the common docker/finch config for finding authorizations
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct DockerConfig {
     #[serde(default)]
     pub auths: HashMap<String, DockerAuth>,
+    /// Credential helper to use per registry, overriding the credential store
+    #[serde(default, rename = "credHelpers")]
+    pub cred_helpers: HashMap<String, String>,
+    /// Credential helper storing the credentials of every registry
+    #[serde(
+        default,
+        rename = "credsStore",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creds_store: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -411,3 +723,172 @@ pub struct DockerAuth {
     pub auth: Option<String>,
     pub identitytoken: Option<String>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, Config, ImageConfig, MediaType, Platform, RootFs, Token};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_layer_media_types() {
+        for (media_type, parsed) in [
+            (
+                "application/vnd.oci.image.layer.v1.tar+gzip",
+                MediaType::Layer(Compression::Gzip),
+            ),
+            (
+                "application/vnd.oci.image.layer.v1.tar+zstd",
+                MediaType::Layer(Compression::Zstd),
+            ),
+            (
+                "application/vnd.oci.image.layer.v1.tar",
+                MediaType::Layer(Compression::None),
+            ),
+            (
+                "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                MediaType::DockerImageRootfs(Compression::Gzip),
+            ),
+            (
+                "application/vnd.in-toto+json",
+                MediaType::Other("application/vnd.in-toto+json".to_string()),
+            ),
+        ] {
+            let json = format!("\"{media_type}\"");
+            assert_eq!(serde_json::from_str::<MediaType>(&json).unwrap(), parsed);
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+            assert_eq!(parsed.to_string(), media_type);
+        }
+    }
+
+    #[test]
+    fn test_image_config_rootfs() {
+        let config: ImageConfig = serde_json::from_str(
+            r#"{"architecture":"amd64","config":{},"created":"2024-01-01T00:00:00Z","history":[],"os":"linux"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.rootfs, RootFs::default());
+        let config = ImageConfig {
+            rootfs: RootFs::builder()
+                .diff_ids(vec!["sha256:abc".to_string()])
+                .build(),
+            ..config
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json["rootfs"],
+            serde_json::json!({ "type": "layers", "diff_ids": ["sha256:abc"] })
+        );
+    }
+
+    #[test]
+    fn test_image_config_round_trip() {
+        let json = serde_json::json!({
+            "architecture": "arm",
+            "variant": "v7",
+            "author": "someone",
+            "created": "2024-01-01T00:00:00Z",
+            "os": "linux",
+            "config": {
+                "User": "app",
+                "ExposedPorts": { "8080/tcp": {} },
+                "Env": ["PATH=/usr/bin"],
+                "Entrypoint": ["/app"],
+                "Volumes": { "/data": {} },
+                "WorkingDir": "/srv",
+                "ArgsEscaped": true,
+                "Labels": { "a": "b" },
+                "StopSignal": "SIGINT",
+                "Healthcheck": { "Test": ["CMD", "/health"], "Interval": 30000000000u64 },
+                "Domainname": ""
+            },
+            "history": [{ "created_by": "/bin/sh", "empty_layer": true }],
+            "rootfs": { "type": "layers", "diff_ids": ["sha256:abc"] }
+        });
+        let config: ImageConfig = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(config.config.entrypoint, vec!["/app"]);
+        assert_eq!(config.config.stop_signal.as_deref(), Some("SIGINT"));
+        assert_eq!(serde_json::to_value(&config).unwrap(), json);
+        assert_eq!(config.platform().to_string(), "linux/arm/v7");
+        // Docker writes empty fields as null
+        let config: Config =
+            serde_json::from_str(r#"{"Env":null,"Cmd":null,"Labels":null,"OnBuild":null}"#)
+                .unwrap();
+        assert!(config.cmd.is_empty() && config.labels.is_empty());
+        // Maps serialize in key order, so the same config always has the same digest
+        let config: Config = serde_json::from_str(
+            r#"{"ExposedPorts":{"80/tcp":{},"443/tcp":{}},"Labels":{"b":"1","a":"2","c":"3"}}"#,
+        )
+        .unwrap();
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert!(serialized.contains(r#""ExposedPorts":{"443/tcp":{},"80/tcp":{}}"#));
+        assert!(serialized.contains(r#""Labels":{"a":"2","b":"1","c":"3"}"#));
+    }
+
+    #[test]
+    fn test_platform_from_str() {
+        let platform = Platform::from_str("linux/arm/v7").unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm");
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+        let platform = Platform::from_str("windows(10.0.17763)/amd64").unwrap();
+        assert_eq!(platform.os, "windows");
+        assert_eq!(platform.os_version.as_deref(), Some("10.0.17763"));
+        assert_eq!(platform.to_string(), "windows(10.0.17763)/amd64");
+        assert!(Platform::from_str("linux").is_err());
+        assert!(Platform::from_str("linux/arm/v7/extra").is_err());
+        assert!(Platform::from_str("windows(10/amd64").is_err());
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let image = Platform::from_str("linux/arm/v7").unwrap();
+        assert!(Platform::from_str("linux/arm").unwrap().matches(&image));
+        assert!(Platform::from_str("linux/arm/v7").unwrap().matches(&image));
+        assert!(!Platform::from_str("linux/arm/v6").unwrap().matches(&image));
+        let image = Platform::from_str("linux/arm64").unwrap();
+        assert!(
+            Platform::from_str("linux/arm64/v8")
+                .unwrap()
+                .matches(&image)
+        );
+        let image = Platform::from_str("windows(10.0.17763.1234)/amd64").unwrap();
+        assert!(
+            Platform::from_str("windows(10.0.17763)/amd64")
+                .unwrap()
+                .matches(&image)
+        );
+        assert!(
+            !Platform::from_str("windows(10.0.20348)/amd64")
+                .unwrap()
+                .matches(&image)
+        );
+        let image: Platform = serde_json::from_str(
+            r#"{"architecture":"amd64","os":"windows","os.version":"10.0.17763","os.features":["win32k"]}"#,
+        )
+        .unwrap();
+        assert_eq!(image.os_features, vec!["win32k"]);
+        let mut platform = Platform::from_str("windows/amd64").unwrap();
+        assert!(platform.matches(&image));
+        platform.os_features.push("win32k".to_string());
+        assert!(platform.matches(&image));
+        platform.os_features.push("other".to_string());
+        assert!(!platform.matches(&image));
+    }
+
+    #[test]
+    fn test_token_debug_redacted() {
+        let basic = Token::basic("ci", "hunter2");
+        assert_eq!(
+            basic,
+            Token::Basic {
+                username: "ci".to_string(),
+                password: "hunter2".to_string()
+            }
+        );
+        let debug = format!("{basic:?} {:?}", Token::bearer("secret-token"));
+        assert!(debug.contains("ci"));
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("secret-token"));
+    }
+}
@@ -0,0 +1,420 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::compression::compress_file;
+use crate::error;
+use crate::image::{GZIP_MAGIC, Image, ZSTD_MAGIC, hash, upload_file};
+use crate::index::Index;
+use crate::layer::Layer;
+use crate::models::{
+    Compression, Config, History, ImageConfig, MediaType, Platform, RootFs, TarballManifest,
+};
+use crate::progress::ProgressSink;
+use crate::registry::Registry;
+use crate::store::Store;
+use crate::uri::{Algorithm, Reference, RegistryUri, Transport, Uri};
+use cfg_if::cfg_if;
+use snafu::ResultExt;
+use tempfile::tempdir;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tar::{Archive, Builder as ArchiveBuilder, EntryType, Header};
+
+/// Version file skopeo writes into the directories of its dir transport.
+const DIR_VERSION: &str = "Directory Transport Version: 1.1\n";
+
+/// Archive the content of a directory as a reproducible tar written to the output. Entries are
+/// sorted by path and their owners and modification times are reset, so the same content always
+/// produces the same archive. Returns the output once the archive is finished.
+pub async fn archive_dir<W>(path: &Path, output: W) -> crate::Result<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut entries = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut read = tokio::fs::read_dir(path.join(&dir))
+            .await
+            .context(error::DirectorySnafu)?;
+        while let Some(entry) = read.next_entry().await.context(error::DirectorySnafu)? {
+            let name = dir.join(entry.file_name());
+            if entry
+                .file_type()
+                .await
+                .context(error::DirectorySnafu)?
+                .is_dir()
+            {
+                dirs.push(name.clone());
+            }
+            entries.push(name);
+        }
+    }
+    entries.sort();
+
+    let mut archive = ArchiveBuilder::new(output);
+    for name in entries.iter() {
+        let full = path.join(name);
+        let metadata = tokio::fs::symlink_metadata(&full)
+            .await
+            .context(error::FileSnafu)?;
+        let mut header = Header::new_gnu();
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(mode(&metadata));
+        if metadata.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+            header.set_size(0);
+            let name = format!("{}/", name.display());
+            archive
+                .append_data(&mut header, name, tokio::io::empty())
+                .await
+                .context(error::ArchiveSnafu)?;
+        } else if metadata.is_symlink() {
+            let target = tokio::fs::read_link(&full)
+                .await
+                .context(error::FileSnafu)?;
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            header.set_link_name(&target).context(error::ArchiveSnafu)?;
+            archive
+                .append_data(&mut header, name, tokio::io::empty())
+                .await
+                .context(error::ArchiveSnafu)?;
+        } else if metadata.is_file() {
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(metadata.len());
+            let file = File::open(&full).await.context(error::FileSnafu)?;
+            archive
+                .append_data(&mut header, name, file)
+                .await
+                .context(error::ArchiveSnafu)?;
+        } else {
+            debug!(target: "pack", "skipping special file {}", full.display());
+        }
+    }
+    archive.into_inner().await.context(error::ArchiveSnafu)
+}
+
+/// Permission bits of an archive entry
+fn mode(metadata: &Metadata) -> u32 {
+    cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode() & 0o7777
+        } else {
+            if metadata.is_dir() { 0o755 } else { 0o644 }
+        }
+    }
+}
+
+/// Build a single layer image from the content of a local directory and push it to the uri. The
+/// layer is a reproducible archive of the directory with the compression, which has to be gzip,
+/// zstd or none. The config is a minimal config for the platform running the container config.
+pub async fn pack(
+    uri: &Uri,
+    path: &Path,
+    compression: Compression,
+    platform: Platform,
+    config: Config,
+) -> crate::Result<Layer> {
+    let tmp_dir = tempdir().context(error::TempSnafu)?;
+    let tar_path = tmp_dir.path().join("layer.tar");
+    archive_dir(
+        path,
+        File::create(&tar_path).await.context(error::FileSnafu)?,
+    )
+    .await?
+    .flush()
+    .await
+    .context(error::FileSnafu)?;
+    let (diff_id, _) = hash(File::open(&tar_path).await.context(error::FileSnafu)?).await?;
+
+    let blob_path = match compression {
+        Compression::None => tar_path,
+        _ => {
+            let blob_path = tmp_dir.path().join("layer");
+            compress_file(&tar_path, &blob_path, &compression).await?;
+            blob_path
+        }
+    };
+    let (layer, _) = upload_file(uri, &blob_path, &MediaType::Layer(compression)).await?;
+    debug!(target: "pack", "packed {} as {}", path.display(), layer.digest());
+
+    let mut image_config = ImageConfig::builder()
+        .architecture(platform.architecture.clone())
+        .os(platform.os.clone())
+        .config(config)
+        .history(vec![
+            History::builder()
+                .created_by("ocilot pack")
+                .empty_layer(false)
+                .build(),
+        ])
+        .rootfs(RootFs::builder().diff_ids(vec![diff_id]).build())
+        .build();
+    if let Some(variant) = platform.variant.as_ref() {
+        image_config
+            .other
+            .insert("variant".to_string(), variant.clone().into());
+    }
+    let config_layer = Image::create_config(uri, &image_config).await?;
+    Image::create(&config_layer, &[layer], Some(platform))
+        .await
+        .push(uri)
+        .await
+}
+
+/// Convert an image saved by `docker save`, an archive or its extracted directory holding a
+/// `manifest.json` along with the config and layer tarballs it lists, into an OCI layout written
+/// to a directory. Uncompressed layers are gzipped, compressed ones are kept as they are. Only the
+/// first image of the manifest is converted when the archive holds several.
+pub async fn docker_to_oci_layout(source: &Path, layout: &Path) -> crate::Result<()> {
+    let extracted = tempdir().context(error::TempSnafu)?;
+    let source = if source.is_dir() {
+        source.to_path_buf()
+    } else {
+        let file = File::open(source).await.context(error::FileSnafu)?;
+        Archive::new(file)
+            .unpack(extracted.path())
+            .await
+            .context(error::ArchiveSnafu)?;
+        extracted.path().to_path_buf()
+    };
+    let invalid = |reason: String| error::DockerArchiveSnafu { reason }.build();
+    let manifest = tokio::fs::read(source.join("manifest.json"))
+        .await
+        .map_err(|e| invalid(format!("manifest.json can not be read: {e}")))?;
+    let manifests: Vec<TarballManifest> = serde_json::from_slice(&manifest)
+        .map_err(|e| invalid(format!("manifest.json is malformed: {e}")))?;
+    let manifest = manifests
+        .first()
+        .ok_or_else(|| invalid("manifest.json lists no images".to_string()))?;
+    if manifests.len() > 1 {
+        warn!(target: "pack", "{} images in {}, converting only the first", manifests.len(), source.display());
+    }
+
+    let blobs = layout.join("blobs").join("sha256");
+    tokio::fs::create_dir_all(&blobs)
+        .await
+        .context(error::FileSnafu)?;
+    let config_path = source.join(&manifest.config);
+    let config_bytes = tokio::fs::read(&config_path)
+        .await
+        .context(error::FileSnafu)?;
+    let platform = serde_json::from_slice::<ImageConfig>(&config_bytes)
+        .ok()
+        .map(|x| x.platform());
+    let config = write_blob(&blobs, &config_path, MediaType::Config).await?;
+
+    let mut layers = Vec::new();
+    for path in manifest.layers.iter().map(|x| source.join(x)) {
+        let mut file = File::open(&path).await.context(error::FileSnafu)?;
+        let mut magic = [0u8; 4];
+        let compression = match file.read_exact(&mut magic).await {
+            Ok(_) if magic[..2] == GZIP_MAGIC => Compression::Gzip,
+            Ok(_) if magic == ZSTD_MAGIC => Compression::Zstd,
+            _ => Compression::None,
+        };
+        let layer = match compression {
+            Compression::None => {
+                let compressed = extracted.path().join("layer.tar.gz");
+                compress_file(&path, &compressed, &Compression::Gzip).await?;
+                write_blob(&blobs, &compressed, MediaType::Layer(Compression::Gzip)).await?
+            }
+            compression => write_blob(&blobs, &path, MediaType::Layer(compression)).await?,
+        };
+        debug!(target: "pack", "converted {} to {}", path.display(), layer.digest());
+        layers.push(layer);
+    }
+
+    let image = Image::create(&config, &layers, platform.clone()).await;
+    let bytes = serde_json::to_vec(&image).context(error::SerializeSnafu)?;
+    let (digest, size) = hash(bytes.as_slice()).await?;
+    tokio::fs::write(blobs.join(digest.trim_start_matches("sha256:")), &bytes)
+        .await
+        .context(error::FileSnafu)?;
+    let descriptor = Layer::builder()
+        .media_type(MediaType::Manifest)
+        .size(size)
+        .digest(digest)
+        .maybe_platform(platform)
+        .build();
+    let index =
+        serde_json::to_vec(&Index::new(&[descriptor]).await).context(error::SerializeSnafu)?;
+    tokio::fs::write(layout.join("index.json"), index)
+        .await
+        .context(error::FileSnafu)?;
+    tokio::fs::write(
+        layout.join("oci-layout"),
+        r#"{"imageLayoutVersion":"1.0.0"}"#,
+    )
+    .await
+    .context(error::FileSnafu)
+}
+
+/// Copy a file into the blobs directory of an OCI layout, named by its digest
+async fn write_blob(blobs: &Path, path: &Path, media_type: MediaType) -> crate::Result<Layer> {
+    let (digest, size) = hash(File::open(path).await.context(error::FileSnafu)?).await?;
+    tokio::fs::copy(path, blobs.join(digest.trim_start_matches("sha256:")))
+        .await
+        .context(error::FileSnafu)?;
+    Ok(Layer::builder()
+        .media_type(media_type)
+        .size(size)
+        .digest(digest)
+        .build())
+}
+
+/// Serve the image of a local transport from a store rooted at the directory, staging its OCI
+/// layout there. Blobs of layout and dir transport directories are hard linked into the store
+/// when possible instead of copied. Returns an offline uri of the image tagged `latest` in a
+/// repository named after the file, it is read like any image in a registry.
+pub async fn open_local(transport: &Transport, root: &Path) -> crate::Result<Uri> {
+    let store = Store::new(root);
+    let (path, digest) = match transport {
+        Transport::Registry(_) => {
+            return error::MalformedUriSnafu {
+                reason: format!("{transport} is not on the local filesystem"),
+            }
+            .fail();
+        }
+        Transport::Oci(path) => {
+            link_blobs(path, &store).await?;
+            (path, layout_root(path, &store).await?)
+        }
+        Transport::OciArchive(path) => {
+            let file = File::open(path).await.context(error::FileSnafu)?;
+            Archive::new(file)
+                .unpack(root)
+                .await
+                .context(error::ArchiveSnafu)?;
+            (path, layout_root(root, &store).await?)
+        }
+        Transport::DockerArchive(path) => {
+            docker_to_oci_layout(path, root).await?;
+            (path, layout_root(root, &store).await?)
+        }
+        Transport::Dir(path) => (path, stage_dir(path, &store).await?),
+    };
+    let registry = RegistryUri::from_str("localhost")?;
+    let repository = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "image".to_string());
+    store
+        .tag(registry.base(), &repository, "latest", &digest)
+        .await?;
+    Ok(Uri::builder()
+        .registry(Registry::offline(&registry, &store))
+        .repository(repository)
+        .reference(Reference::Tag("latest".to_string()))
+        .build())
+}
+
+/// Digest of the root manifest of an OCI layout whose blobs are in the store. A single manifest
+/// listed by `index.json` is the root so it keeps its digest, otherwise `index.json` is stored as
+/// the root index.
+async fn layout_root(layout: &Path, store: &Store) -> crate::Result<String> {
+    let bytes = tokio::fs::read(layout.join("index.json"))
+        .await
+        .context(error::FileSnafu)?;
+    let index: serde_json::Value =
+        serde_json::from_slice(&bytes).context(error::ImageInvalidIndexSnafu)?;
+    if let Some([manifest]) = index["manifests"].as_array().map(Vec::as_slice)
+        && let Some(digest) = manifest["digest"].as_str()
+    {
+        return Ok(digest.to_string());
+    }
+    let digest = Algorithm::Sha256.digest(&bytes);
+    store.write_blob(&digest, &bytes).await?;
+    Ok(digest)
+}
+
+/// Link the blobs of an OCI layout directory into the store
+async fn link_blobs(layout: &Path, store: &Store) -> crate::Result<()> {
+    let mut algorithms = tokio::fs::read_dir(layout.join("blobs"))
+        .await
+        .context(error::FileSnafu)?;
+    while let Some(algorithm) = algorithms.next_entry().await.context(error::FileSnafu)? {
+        let mut blobs = tokio::fs::read_dir(algorithm.path())
+            .await
+            .context(error::FileSnafu)?;
+        while let Some(blob) = blobs.next_entry().await.context(error::FileSnafu)? {
+            let digest = format!(
+                "{}:{}",
+                algorithm.file_name().to_string_lossy(),
+                blob.file_name().to_string_lossy()
+            );
+            link_blob(&blob.path(), &store.blob_path(&digest)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Stage the image of a dir transport into the store, returning the digest of its manifest
+async fn stage_dir(path: &Path, store: &Store) -> crate::Result<String> {
+    let mut entries = tokio::fs::read_dir(path).await.context(error::FileSnafu)?;
+    while let Some(entry) = entries.next_entry().await.context(error::FileSnafu)? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Manifests of the images of an index are stored as `<hex>.manifest.json`
+        let hex = name.strip_suffix(".manifest.json").unwrap_or(&name);
+        if hex.len() == 64 && hex.chars().all(|x| x.is_ascii_hexdigit()) {
+            link_blob(&entry.path(), &store.blob_path(&format!("sha256:{hex}"))).await?;
+        }
+    }
+    let manifest = tokio::fs::read(path.join("manifest.json"))
+        .await
+        .context(error::FileSnafu)?;
+    let digest = Algorithm::Sha256.digest(&manifest);
+    store.write_blob(&digest, &manifest).await?;
+    Ok(digest)
+}
+
+/// Hard link a file to the target path, copying it when it can not be linked, i.e. across
+/// filesystems
+async fn link_blob(path: &Path, target: &Path) -> crate::Result<()> {
+    tokio::fs::create_dir_all(target.parent().unwrap())
+        .await
+        .context(error::FileSnafu)?;
+    if tokio::fs::hard_link(path, target).await.is_err() {
+        tokio::fs::copy(path, target)
+            .await
+            .context(error::FileSnafu)?;
+    }
+    Ok(())
+}
+
+/// Write an image as a dir transport directory: its manifest as `manifest.json`, its config and
+/// layers named by the hex of their digest and the `version` of the layout as skopeo writes it.
+/// The uri must refer to an image manifest, it is written as fetched to keep its digest.
+pub async fn write_dir(
+    uri: &Uri,
+    path: &Path,
+    progress: &Arc<dyn ProgressSink>,
+) -> crate::Result<()> {
+    let (bytes, _, _) = uri
+        .registry()
+        .fetch_manifest_raw(uri.repository(), uri.reference().to_string().as_str())
+        .await?;
+    let image: Image = serde_json::from_slice(&bytes).context(error::ImageInvalidManifestSnafu)?;
+    tokio::fs::create_dir_all(path)
+        .await
+        .context(error::DirectorySnafu)?;
+    for blob in std::iter::once(image.config()).chain(image.layers()) {
+        let (_, hex) = blob.digest().split_once(':').unwrap_or(("", blob.digest()));
+        let mut reader = blob.open_progress(uri, progress.clone()).await?;
+        let mut file = File::create(path.join(hex))
+            .await
+            .context(error::FileSnafu)?;
+        Layer::copy(&mut reader, &mut file, blob.size()).await?;
+    }
+    tokio::fs::write(path.join("manifest.json"), &bytes)
+        .await
+        .context(error::FileSnafu)?;
+    tokio::fs::write(path.join("version"), DIR_VERSION)
+        .await
+        .context(error::FileSnafu)
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "progress")]
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+#[cfg(feature = "progress")]
+use std::collections::HashMap;
+#[cfg(feature = "progress")]
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Transfer progress of a single blob, emitted by layer readers and writers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Transfer of a blob began
+    Started {
+        /// Digest of the blob when it is known up front
+        digest: Option<String>,
+        size: u64,
+    },
+    /// Bytes of a blob were transferred
+    Advanced { bytes: u64 },
+    /// Transfer of a blob completed
+    Finished,
+    /// Transfer of a blob was skipped as the target already has it
+    Skipped { digest: String, size: u64 },
+}
+
+/// Sending half of a progress event stream.
+pub type ProgressSender = UnboundedSender<ProgressEvent>;
+
+/// Direction of a blob transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The blob is written to a registry
+    Upload,
+    /// The blob is read from a registry
+    Download,
+}
+
+/// Blob transfer reported to a progress sink.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    /// Identifies the transfer among the ones in flight, unique within the process
+    pub id: u64,
+    pub direction: Direction,
+    /// Digest of the blob when it is known up front
+    pub digest: Option<String>,
+    /// Size of the blob in bytes
+    pub size: u64,
+}
+
+impl Transfer {
+    /// Start tracking a new transfer of a blob
+    pub fn new(direction: Direction, digest: Option<String>, size: u64) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            direction,
+            digest,
+            size,
+        }
+    }
+}
+
+/// Destination for blob transfer progress.
+///
+/// Sinks are called inline with the reads and writes of the blob so they should not block for
+/// long.
+pub trait ProgressSink: Send + Sync + fmt::Debug {
+    /// Transfer of a blob began
+    fn started(&self, transfer: &Transfer);
+    /// Bytes of the blob were transferred
+    fn advanced(&self, transfer: &Transfer, bytes: u64);
+    /// Transfer of the blob completed
+    fn finished(&self, transfer: &Transfer);
+    /// Transfer of the blob was skipped as the target already has it
+    fn skipped(&self, transfer: &Transfer);
+}
+
+impl ProgressSink for ProgressSender {
+    fn started(&self, transfer: &Transfer) {
+        // The receiver going away only means nobody is watching anymore
+        let _ = self.send(ProgressEvent::Started {
+            digest: transfer.digest.clone(),
+            size: transfer.size,
+        });
+    }
+
+    fn advanced(&self, _: &Transfer, bytes: u64) {
+        let _ = self.send(ProgressEvent::Advanced { bytes });
+    }
+
+    fn finished(&self, _: &Transfer) {
+        let _ = self.send(ProgressEvent::Finished);
+    }
+
+    fn skipped(&self, transfer: &Transfer) {
+        let _ = self.send(ProgressEvent::Skipped {
+            digest: transfer.digest.clone().unwrap_or_default(),
+            size: transfer.size,
+        });
+    }
+}
+
+/// Progress sink drawing an indicatif progress bar per blob transfer.
+#[cfg(feature = "progress")]
+#[derive(Debug)]
+pub struct IndicatifProgress {
+    multi: MultiProgress,
+    bars: Mutex<HashMap<u64, ProgressBar>>,
+}
+
+#[cfg(feature = "progress")]
+impl IndicatifProgress {
+    /// Add the bars of transfers to the multi progress
+    pub fn new(multi: MultiProgress) -> Self {
+        Self {
+            multi,
+            bars: Mutex::default(),
+        }
+    }
+
+    /// Add a bar for the transfer, labelled with the short digest of the blob
+    fn add(&self, transfer: &Transfer) -> ProgressBar {
+        let arrow = match transfer.direction {
+            Direction::Upload => "->",
+            Direction::Download => "<-",
+        };
+        let bar = self.multi.add(ProgressBar::new(transfer.size));
+        bar.set_style(
+            ProgressStyle::with_template(&format!(
+                "{arrow} {{prefix}}: [{{elapsed_precise}}] {{bar:40.cyan/blue}} {{msg}} ({{binary_bytes:>7}}/{{binary_total_bytes:7}})"
+            ))
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        let prefix = match transfer.digest.as_deref() {
+            Some(digest) => {
+                let hex = digest.split_once(':').map_or(digest, |(_, x)| x);
+                format!("blob {}", &hex[..hex.len().min(9)])
+            }
+            None => "blob".to_string(),
+        };
+        bar.set_prefix(prefix);
+        bar
+    }
+}
+
+#[cfg(feature = "progress")]
+impl ProgressSink for IndicatifProgress {
+    fn started(&self, transfer: &Transfer) {
+        let bar = self.add(transfer);
+        self.bars.lock().unwrap().insert(transfer.id, bar);
+    }
+
+    fn advanced(&self, transfer: &Transfer, bytes: u64) {
+        if let Some(bar) = self.bars.lock().unwrap().get(&transfer.id) {
+            bar.inc(bytes);
+        }
+    }
+
+    fn finished(&self, transfer: &Transfer) {
+        if let Some(bar) = self.bars.lock().unwrap().remove(&transfer.id) {
+            bar.finish_with_message("done");
+        }
+    }
+
+    fn skipped(&self, transfer: &Transfer) {
+        self.add(transfer).finish_with_message("already exists");
+    }
+}
+
+/// Reports a single blob transfer to every sink watching it.
+pub(crate) struct Reporter {
+    transfer: Transfer,
+    sinks: Vec<Arc<dyn ProgressSink>>,
+}
+
+impl Reporter {
+    pub(crate) fn new(
+        transfer: Transfer,
+        sinks: impl IntoIterator<Item = Arc<dyn ProgressSink>>,
+    ) -> Self {
+        Self {
+            transfer,
+            sinks: sinks.into_iter().collect(),
+        }
+    }
+
+    pub(crate) fn started(&self) {
+        self.sinks.iter().for_each(|x| x.started(&self.transfer));
+    }
+
+    pub(crate) fn advanced(&self, bytes: u64) {
+        self.sinks
+            .iter()
+            .for_each(|x| x.advanced(&self.transfer, bytes));
+    }
+
+    pub(crate) fn finished(&self) {
+        self.sinks.iter().for_each(|x| x.finished(&self.transfer));
+    }
+
+    pub(crate) fn skipped(&self) {
+        self.sinks.iter().for_each(|x| x.skipped(&self.transfer));
+    }
+}
@@ -1,26 +1,90 @@
-use crate::client::RegistryClient;
-use crate::layer::Layer;
+use crate::audit::{AuditEvent, AuditOperation, AuditOutcome, AuditSink};
+use crate::client::{self, RegistryClient, http_client};
+use crate::credentials;
+use crate::image::Image;
+use crate::index::Index;
+use crate::layer::{DOCKER_CONTENT_DIGEST, Layer};
 use crate::models::{
     DockerConfig, ErrorResponse, MediaType, Platform, RepositoryList, TagList, Token,
 };
-use crate::uri::RegistryUri;
+use crate::options::{ChunkPolicy, ClientConfig, RegistryOptions, RetryPolicy, Settings};
+use crate::progress::ProgressSink;
+use crate::store::Store;
+use crate::uri::{Algorithm, Reference, RegistryUri};
 use crate::{Result, error};
 #[cfg(feature = "aws")]
 use aws_config::BehaviorVersion;
 use base64::Engine;
 use bytes::Bytes;
 use cfg_if::cfg_if;
+use chrono::Utc;
 use futures::stream::{Stream, TryStreamExt};
 use home::home_dir;
 use keyring::Entry;
-use reqwest::Response;
-use serde::Serialize;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, LINK};
+use reqwest::{Response, StatusCode};
 use serde::de::DeserializeOwned;
-use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, ensure};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::field::Empty;
+use tracing::{Span, instrument};
 use url::Url;
 
 const COMMON_AUTH_FILES: &[&str] = &[".finch/config.json", ".docker/config.json"];
+/// GitHub Container Registry, authorized with the github token of the environment
+#[cfg(feature = "github")]
+const GHCR: &str = "ghcr.io";
+/// Header registries list the filters applied to a referrers response in
+const FILTERS_APPLIED: &str = "OCI-Filters-Applied";
+/// Header registries with the referrers api answer a manifest push with a subject with
+const OCI_SUBJECT: &str = "OCI-Subject";
+/// Most manifests kept for conditional fetches
+const MAX_CACHED_MANIFESTS: usize = 256;
+
+/// Content of a blob streamed from the registry or the local store, `Sync` so a [`Reader`]
+/// wrapping it can be shared between threads.
+///
+/// [`Reader`]: crate::layer::Reader
+pub(crate) type BlobStream =
+    Pin<Box<dyn Stream<Item = std::result::Result<Bytes, std::io::Error>> + Send + Sync>>;
+
+/// A single page of a paginated list.
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// Entries in this page
+    pub entries: Vec<String>,
+    /// Whether the registry has more entries after this page
+    pub more: bool,
+}
+
+/// Size and digest of a blob the registry reported without sending it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlobStat {
+    /// Digest of the blob
+    pub digest: String,
+    /// Size of the blob in bytes
+    pub size: u64,
+}
+
+/// Manifest fetched by tag or digest, kept to make later fetches conditional on its etag.
+#[derive(Debug, Clone)]
+struct CachedManifest {
+    etag: String,
+    content_type: Option<String>,
+    body: Bytes,
+}
 
 /// Represents a client to a specific OCI registry.
 ///
@@ -31,17 +95,203 @@ pub struct Registry {
     uri: RegistryUri,
     /// Registry client to use
     pub(crate) client: RegistryClient,
+    /// Mirrors to attempt before this registry when pulling
+    mirrors: Arc<Vec<Registry>>,
+    /// Mirrors to attempt when this registry fails or throttles a pull
+    fallbacks: Arc<Vec<Registry>>,
+    /// Record of the endpoint that served each pulled digest
+    served: Arc<Mutex<HashMap<String, String>>>,
+    /// Fetched manifests keyed by repository and reference, shared between clones of this registry
+    manifests: Arc<Mutex<HashMap<(String, String), CachedManifest>>>,
+    /// Blob uploads in flight keyed by digest, shared between clones of this registry
+    uploads: Arc<Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>>,
+    /// Local store that pulled content is written through to and blobs are served from
+    store: Option<Store>,
+    /// Only serve content from the local store without any network calls
+    offline: bool,
+    /// Resolve everything but skip mutating operations, recording them as planned to the audit
+    /// sink instead
+    dry_run: bool,
+    /// Sink that mutating operations are recorded to
+    audit: Option<Arc<dyn AuditSink>>,
+    /// Sink that blob transfer progress is reported to
+    progress: Option<Arc<dyn ProgressSink>>,
+    /// Limits the number of concurrent blob transfers, possibly shared with other registries
+    limiter: Option<Arc<Semaphore>>,
+    /// Overall time limit for list operations, including time spent waiting on rate limits
+    list_deadline: Option<Duration>,
+    /// Number of entries to request per page of list operations
+    page_size: Option<usize>,
+    /// Policy for retrying transient failures, also applied when resuming chunked uploads
+    retry: RetryPolicy,
+    /// Size of the chunks blobs are uploaded in
+    chunk: ChunkPolicy,
+    /// Whether the registry rejected a chunk uploaded concurrently, later blobs are then uploaded
+    /// one chunk at a time
+    sequential_chunks: Arc<AtomicBool>,
     #[cfg(feature = "aws")]
     is_ecr: bool,
 }
 
-unsafe impl Send for Registry {}
-unsafe impl Sync for Registry {}
-
 impl Registry {
     /// Given a uri to a registry create a new registry client and gather
-    /// the appropriate authorization.
+    /// the appropriate authorization. Options for the registry are read from the settings file.
     pub async fn new(uri: &RegistryUri) -> Result<Self> {
+        let settings = Settings::load().await?;
+        Self::new_with_options(uri, &settings.registry(uri.base())).await
+    }
+
+    /// Given a uri to a registry create a new registry client whose HTTP client uses the
+    /// provided configuration. Other options for the registry are read from the settings file.
+    pub async fn new_with_config(uri: &RegistryUri, config: &ClientConfig) -> Result<Self> {
+        let settings = Settings::load().await?;
+        let options = RegistryOptions {
+            client: config.clone(),
+            ..settings.registry(uri.base())
+        };
+        Self::new_with_options(uri, &options).await
+    }
+
+    /// Given a uri to a registry create a new registry client with the provided options and
+    /// gather the appropriate authorization for it and any of its mirrors.
+    pub async fn new_with_options(uri: &RegistryUri, options: &RegistryOptions) -> Result<Self> {
+        Self::discover(uri, options).await?.configure(options).await
+    }
+
+    /// Given a uri to a registry create a new registry client that authorizes with the provided
+    /// token instead of discovering credentials, i.e. one obtained from the ECR SDK or a secret
+    /// store. Options for the registry are read from the settings file.
+    pub async fn with_auth(uri: &RegistryUri, token: Token) -> Result<Self> {
+        let settings = Settings::load().await?;
+        Self::with_token(uri, &settings.registry(uri.base()), token).await
+    }
+
+    /// Given a uri to a registry create a new registry client that sends no credentials, bearer
+    /// challenges are answered anonymously. Options for the registry are read from the settings
+    /// file.
+    pub async fn anonymous(uri: &RegistryUri) -> Result<Self> {
+        let settings = Settings::load().await?;
+        Self::explicit(uri, &settings.registry(uri.base()), None).await
+    }
+
+    /// Given a uri to a registry create a new registry client with the provided options that
+    /// authorizes with the provided token instead of discovering credentials, i.e. to copy between
+    /// registries with different credentials. Mirrors of the registry still discover their own.
+    pub async fn with_token(
+        uri: &RegistryUri,
+        options: &RegistryOptions,
+        token: Token,
+    ) -> Result<Self> {
+        Self::explicit(uri, options, Some(token)).await
+    }
+
+    /// Create a registry client with the provided options and authorization, if any
+    async fn explicit(
+        uri: &RegistryUri,
+        options: &RegistryOptions,
+        token: Option<Token>,
+    ) -> Result<Self> {
+        #[allow(unused_mut)]
+        let mut registry = Self::with_client(
+            uri,
+            RegistryClient::new(http_client(uri, options)?, token, options.retry.clone()),
+        );
+        #[cfg(feature = "aws")]
+        {
+            registry.is_ecr = uri.base().contains("ecr");
+        }
+        registry.configure(options).await
+    }
+
+    /// Apply the retry and chunk policies and mirrors of the options to a newly created registry
+    /// client
+    async fn configure(mut self, options: &RegistryOptions) -> Result<Self> {
+        self.retry = options.retry.clone();
+        if let Some(chunk) = options.chunk.as_ref() {
+            chunk.validate()?;
+            self.chunk = chunk.clone();
+        }
+        self.mirrors =
+            Arc::new(Self::discover_mirrors(&self.uri, options, &options.mirrors).await?);
+        self.fallbacks =
+            Arc::new(Self::discover_mirrors(&self.uri, options, &options.fallbacks).await?);
+        Ok(self)
+    }
+
+    /// Create registry clients for the mirror endpoints of the registry at the uri, sharing the
+    /// HTTP client configuration of the registry
+    async fn discover_mirrors(
+        uri: &RegistryUri,
+        options: &RegistryOptions,
+        endpoints: &[String],
+    ) -> Result<Vec<Self>> {
+        let mirror_options = RegistryOptions {
+            client: options.client.clone(),
+            ..Default::default()
+        };
+        let mut mirrors = Vec::new();
+        for mirror in endpoints.iter() {
+            let mirror_uri = RegistryUri::from_str(mirror)?;
+            debug!(target: "registry", "using mirror {} for {}", mirror_uri.base(), uri.base());
+            mirrors.push(Self::discover(&mirror_uri, &mirror_options).await?);
+        }
+        Ok(mirrors)
+    }
+
+    /// Create a registry client that serves content purely from the local store, no authorization
+    /// is discovered and no network calls are ever made.
+    pub fn offline(uri: &RegistryUri, store: &Store) -> Self {
+        let mut registry = Self::with_client(
+            uri,
+            RegistryClient::new(reqwest::Client::new(), None, RetryPolicy::none()),
+        );
+        registry.store = Some(store.clone());
+        registry.offline = true;
+        registry
+    }
+
+    /// Create a registry for the uri that sends requests with the given client, i.e. one of a
+    /// custom [`RegistryClientImpl`](crate::client::RegistryClientImpl) or a `MockRegistryClient`
+    /// in tests. No authorization is discovered and the default options apply.
+    pub fn with_client(uri: &RegistryUri, client: RegistryClient) -> Self {
+        Self {
+            client,
+            uri: uri.clone(),
+            mirrors: Arc::default(),
+            fallbacks: Arc::default(),
+            served: Arc::default(),
+            manifests: Arc::default(),
+            uploads: Arc::default(),
+            store: None,
+            offline: false,
+            dry_run: false,
+            audit: None,
+            progress: None,
+            limiter: None,
+            list_deadline: None,
+            page_size: None,
+            retry: RetryPolicy::default(),
+            chunk: ChunkPolicy::for_registry(uri.base()),
+            sequential_chunks: Arc::default(),
+            #[cfg(feature = "aws")]
+            is_ecr: false,
+        }
+    }
+
+    /// Create a registry client for the uri using the discovered authorization
+    async fn discover(uri: &RegistryUri, options: &RegistryOptions) -> Result<Self> {
+        if let Some(sigv4) = options.sigv4.as_ref() {
+            cfg_if! {
+                if #[cfg(feature = "aws")] {
+                    debug!(target: "registry", "signing requests to {} with sigv4", uri.base());
+                    let signer = crate::sigv4::SigV4Signer::new(sigv4).await?;
+                    return Ok(Self::with_client(uri, RegistryClient::sigv4(http_client(uri, options)?, signer, options.retry.clone())));
+                } else {
+                    let _ = sigv4;
+                    return error::SigningSnafu { reason: format!("sigv4 is configured for {} but the aws feature is disabled", uri.base()) }.fail();
+                }
+            }
+        }
         // First check our common auth files for an entry
         let mut token = None;
         #[cfg(feature = "aws")]
@@ -94,16 +344,34 @@ impl Registry {
                             .context(error::FileSnafu)?;
                         let config: DockerConfig =
                             serde_json::from_str(&auth).context(error::ConfigDeserializeSnafu)?;
-                        if let Some(entry) = config.auths.get(uri.base()) {
+                        // Credential helpers take precedence over the stored auths
+                        let server = credentials::server(uri);
+                        if let Some(helper) = config
+                            .cred_helpers
+                            .get(uri.base())
+                            .or(config.creds_store.as_ref())
+                            && let Some(helped) = credentials::helper_get(helper, server).await?
+                        {
+                            debug!(target: "registry", "using credentials from helper {helper}");
+                            token = Some(helped);
+                            continue;
+                        }
+                        // Docker Hub credentials are stored under the url of its index
+                        let entry = config.auths.get(uri.base()).or_else(|| {
+                            uri.is_docker_hub()
+                                .then(|| config.auths.get(credentials::DOCKER_HUB_AUTH))
+                                .flatten()
+                        });
+                        if let Some(entry) = entry {
                             // If both the auth and identity token are null then the password is probably stored in the system keychai
                             if entry.auth.is_none() && entry.identitytoken.is_none() {
-                                if let Ok(entry) =
-                                    Entry::new("docker-credential-helpers", uri.base())
+                                // Logins store them under the same server as the helpers
+                                if let Ok(entry) = Entry::new(credentials::KEYRING_SERVICE, server)
                                 {
                                     if let Ok(password) = entry.get_password() {
                                         let decoded = base64::engine::general_purpose::STANDARD
                                             .decode(password)
-                                            .unwrap();
+                                            .context(error::KeychainCredentialsSnafu { server })?;
                                         let decoded = String::from_utf8_lossy(decoded.as_slice());
                                         if decoded.contains(':') {
                                             let (username, password) =
@@ -127,12 +395,48 @@ impl Registry {
                 }
             }
         }
-        Ok(Self {
-            client: RegistryClient::new(token),
-            uri: uri.clone(),
-            #[cfg(feature = "aws")]
-            is_ecr,
-        })
+        // Cloud credentials of the environment are only used without credentials in the config
+        #[cfg(feature = "github")]
+        if token.is_none() && uri.base() == GHCR {
+            let password = ["GITHUB_TOKEN", "GH_TOKEN"]
+                .into_iter()
+                .find_map(|x| std::env::var(x).ok().filter(|x| !x.is_empty()));
+            if let Some(password) = password {
+                debug!(target: "registry", "using github token of the environment");
+                let username =
+                    std::env::var("GITHUB_ACTOR").unwrap_or_else(|_| "token".to_string());
+                token = Some(Token::basic(username, password));
+            }
+        }
+        #[cfg(feature = "gcp")]
+        if token.is_none() && crate::gcp::is_google(uri.base()) {
+            debug!(target: "registry", "looking up gcp application default credentials");
+            token = crate::gcp::access_token()
+                .await?
+                .map(|x| Token::basic(crate::gcp::USERNAME, x));
+        }
+        #[cfg(feature = "azure")]
+        if token.is_none() && crate::azure::is_azure(uri.base()) {
+            debug!(target: "registry", "exchanging azure credentials for an acr token");
+            // Public repositories can still be pulled anonymously without a token
+            token = match crate::azure::refresh_token(uri.base()).await {
+                Ok(token) => token.map(|x| Token::basic(crate::azure::USERNAME, x)),
+                Err(e) => {
+                    warn!(target: "registry", "could not get an acr token, continuing anonymously: {e}");
+                    None
+                }
+            };
+        }
+        #[allow(unused_mut)]
+        let mut registry = Self::with_client(
+            uri,
+            RegistryClient::new(http_client(uri, options)?, token, options.retry.clone()),
+        );
+        #[cfg(feature = "aws")]
+        {
+            registry.is_ecr = is_ecr;
+        }
+        Ok(registry)
     }
 
     /// Change the security of the registry connection
@@ -150,8 +454,316 @@ impl Registry {
         self.uri.clone().try_into()
     }
 
+    /// Set the local store that pulled manifests and blobs are written through to, blobs already
+    /// in the store are served from it instead of the registry
+    pub fn set_store(&mut self, store: Option<Store>) {
+        self.store = store;
+    }
+
+    /// Local store backing this registry, if any
+    pub fn store(&self) -> Option<&Store> {
+        self.store.as_ref()
+    }
+
+    /// Set the sink that mutating operations against this registry are recorded to
+    pub fn set_audit(&mut self, audit: Option<Arc<dyn AuditSink>>) {
+        self.audit = audit;
+    }
+
+    /// Skip mutating operations against this registry, recording them as planned to the audit sink
+    /// instead. Reads, authorization and existence checks still go to the registry.
+    pub fn set_dry_run(&mut self, flag: bool) {
+        self.dry_run = flag;
+    }
+
+    /// Whether mutating operations against this registry are skipped
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Set the sink that blob transfer progress against this registry is reported to
+    pub fn set_progress(&mut self, progress: Option<Arc<dyn ProgressSink>>) {
+        self.progress = progress;
+    }
+
+    /// Limit the number of blob transfers against this registry that run at the same time
+    pub fn set_max_concurrent(&mut self, limit: Option<usize>) {
+        self.limiter = limit.map(|x| Arc::new(Semaphore::new(x.max(1))));
+    }
+
+    /// Share a limit on concurrent blob transfers with other registries, i.e. both sides of a copy
+    pub fn set_limiter(&mut self, limiter: Option<Arc<Semaphore>>) {
+        self.limiter = limiter;
+    }
+
+    /// Wait for a slot to transfer a blob in, the slot is held until the permit is dropped.
+    /// Without a limit this returns immediately.
+    pub async fn transfer_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match self.limiter.as_ref() {
+            // The semaphore is never closed so acquiring can only fail if it was
+            Some(limiter) => limiter.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Sink blob transfer progress is reported to, if one is set
+    pub(crate) fn progress(&self) -> Option<Arc<dyn ProgressSink>> {
+        self.progress.clone()
+    }
+
+    /// Policy for retrying transient failures of this registry
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Policy for the size of the chunks blobs are uploaded to this registry in
+    pub fn chunk_policy(&self) -> &ChunkPolicy {
+        &self.chunk
+    }
+
+    /// Change the size of the chunks blobs are uploaded to this registry in
+    pub fn set_chunk_policy(&mut self, chunk: ChunkPolicy) {
+        self.chunk = chunk;
+    }
+
+    /// Chunks of a blob to upload at once, one after the registry rejected concurrent chunks
+    pub(crate) fn chunk_connections(&self) -> usize {
+        if self.sequential_chunks.load(Ordering::Relaxed) {
+            1
+        } else {
+            self.chunk.connections.max(1)
+        }
+    }
+
+    /// Upload blobs one chunk at a time from now on, as the registry rejected concurrent chunks
+    pub(crate) fn reject_concurrent_chunks(&self) {
+        self.sequential_chunks.store(true, Ordering::Relaxed);
+    }
+
+    /// Set the overall time limit for list operations, including time spent waiting on rate limits
+    pub fn set_list_deadline(&mut self, deadline: Option<Duration>) {
+        self.list_deadline = deadline;
+    }
+
+    /// Set the number of entries to request per page of list operations, registries use their own
+    /// page size without one
+    pub fn set_page_size(&mut self, page_size: Option<usize>) {
+        self.page_size = page_size.filter(|x| *x > 0);
+    }
+
+    /// Whether this registry only serves content from the local store
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Mirrors that are attempted before this registry when pulling
+    pub fn mirrors(&self) -> &[Registry] {
+        &self.mirrors
+    }
+
+    /// Mirrors that are attempted when this registry fails or throttles a pull
+    pub fn fallbacks(&self) -> &[Registry] {
+        &self.fallbacks
+    }
+
+    /// The endpoint (registry base) that served the blob digest or manifest reference, if it was pulled
+    pub fn served_by(&self, digest: &str) -> Option<String> {
+        self.served.lock().unwrap().get(digest).cloned()
+    }
+
+    /// Perform a pull operation against each mirror in order, falling back to this registry when
+    /// a mirror does not have the content (404), errors (5xx) or cannot be reached at all. When
+    /// this registry then fails or throttles the operation, each fallback mirror is attempted in
+    /// order before giving up with the response of this registry.
+    async fn mirrored<F, Fut>(&self, reference: &str, operation: F) -> Result<Response>
+    where
+        F: Fn(Registry) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        if let Some(response) = self
+            .first_served(&self.mirrors, reference, &operation)
+            .await
+        {
+            return Ok(response);
+        }
+        let result = operation(self.clone()).await;
+        let failed = match result.as_ref() {
+            Ok(response) => client::transient(response.status()),
+            Err(_) => true,
+        };
+        if failed
+            && let Some(response) = self
+                .first_served(&self.fallbacks, reference, &operation)
+                .await
+        {
+            return Ok(response);
+        }
+        let response = result?;
+        if response.status().is_success() {
+            self.record(reference, self.uri());
+        }
+        Ok(response)
+    }
+
+    /// Perform a pull operation against each of the mirrors in order, returning the response of
+    /// the first that serves the content
+    async fn first_served<F, Fut>(
+        &self,
+        mirrors: &[Registry],
+        reference: &str,
+        operation: &F,
+    ) -> Option<Response>
+    where
+        F: Fn(Registry) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        for mirror in mirrors.iter() {
+            match operation(mirror.clone()).await {
+                Ok(response)
+                    if response.status().is_success()
+                        || response.status() == StatusCode::NOT_MODIFIED =>
+                {
+                    debug!(target: "registry", "{reference} served by mirror {}", mirror.uri().base());
+                    self.record(reference, mirror.uri());
+                    return Some(response);
+                }
+                Ok(response) => {
+                    debug!(target: "registry", "mirror {} responded {} for {reference}, falling back", mirror.uri().base(), response.status());
+                }
+                Err(e) => {
+                    debug!(target: "registry", "mirror {} failed for {reference}, falling back: {e}", mirror.uri().base());
+                }
+            }
+        }
+        None
+    }
+
+    /// Claim the upload of a blob so that concurrent uploads of the same digest to this registry
+    /// wait for it rather than sending the blob again. The claim is held until the guard is
+    /// dropped, the returned flag is true when another upload had to be waited on.
+    pub(crate) async fn claim_upload(&self, digest: &str) -> (OwnedMutexGuard<()>, bool) {
+        let flight = {
+            let mut uploads = self.uploads.lock().unwrap();
+            uploads.retain(|_, x| x.strong_count() > 0);
+            match uploads.get(digest).and_then(Weak::upgrade) {
+                Some(flight) => flight,
+                None => {
+                    let flight = Arc::new(tokio::sync::Mutex::new(()));
+                    uploads.insert(digest.to_string(), Arc::downgrade(&flight));
+                    flight
+                }
+            }
+        };
+        match flight.clone().try_lock_owned() {
+            Ok(guard) => (guard, false),
+            Err(_) => {
+                debug!(target: "registry", "waiting on an upload of {digest} already in flight");
+                (flight.lock_owned().await, true)
+            }
+        }
+    }
+
+    /// Record the endpoint that served a digest or reference
+    fn record(&self, reference: &str, uri: &RegistryUri) {
+        self.served
+            .lock()
+            .unwrap()
+            .insert(reference.to_string(), uri.base().clone());
+    }
+
+    /// Create the repository in ECR, which unlike other registries does not create repositories
+    /// on the first push. Returns whether the repository was created, it is not when it already
+    /// exists or the registry is not ECR.
+    #[cfg(feature = "aws")]
+    pub async fn create_repository(&self, repository: &str) -> Result<bool> {
+        if !self.is_ecr {
+            return Ok(false);
+        }
+        if self.dry_run {
+            info!(target: "registry", "dry run, not creating repository {repository} in {}", self.uri.base());
+            return Ok(false);
+        }
+        let created = if self.uri.base().starts_with("public.ecr.aws") {
+            let sdk_config = aws_config::defaults(BehaviorVersion::latest())
+                .region("us-east-1")
+                .load()
+                .await;
+            let client = aws_sdk_ecrpublic::Client::new(&sdk_config);
+            match client
+                .create_repository()
+                .repository_name(repository)
+                .send()
+                .await
+            {
+                Ok(_) => true,
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|x| x.is_repository_already_exists_exception()) =>
+                {
+                    false
+                }
+                Err(e) => {
+                    return error::CreateRepositorySnafu {
+                        repository,
+                        reason: e.into_service_error().to_string(),
+                    }
+                    .fail();
+                }
+            }
+        } else {
+            // Private hosts are named <account>.dkr.ecr.<region>.amazonaws.com
+            let host = self.uri.base().split('.').collect::<Vec<_>>();
+            let (account, region) = match host.as_slice() {
+                [account, "dkr", "ecr", region, ..] => (Some(*account), Some(region.to_string())),
+                _ => (None, None),
+            };
+            let mut loader = aws_config::defaults(BehaviorVersion::latest());
+            if let Some(region) = region {
+                loader = loader.region(aws_config::Region::new(region));
+            }
+            let client = aws_sdk_ecr::Client::new(&loader.load().await);
+            match client
+                .create_repository()
+                .set_registry_id(account.map(str::to_string))
+                .repository_name(repository)
+                .send()
+                .await
+            {
+                Ok(_) => true,
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|x| x.is_repository_already_exists_exception()) =>
+                {
+                    false
+                }
+                Err(e) => {
+                    return error::CreateRepositorySnafu {
+                        repository,
+                        reason: e.into_service_error().to_string(),
+                    }
+                    .fail();
+                }
+            }
+        };
+        if created {
+            info!(target: "registry", "created repository {repository} in {}", self.uri.base());
+        }
+        Ok(created)
+    }
+
+    /// Name of a repository of Docker Hub, where official images live under the library
+    /// namespace. Mirrors of Docker Hub serve them under that name as well.
+    fn docker_hub_name(&self, repository: &str) -> String {
+        if self.uri.is_docker_hub() && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository.to_string()
+        }
+    }
+
     /// Get a ecr correct repository name
     fn repository_name(&self, repository: &str) -> String {
+        let repository = &self.docker_hub_name(repository);
         cfg_if! {
             if #[cfg(feature = "aws")] {
                 if self.is_ecr {
@@ -171,49 +783,245 @@ impl Registry {
 
     // Fetch the catalog of repositories in the registry
     pub async fn catalog(&self) -> crate::Result<Vec<String>> {
-        let response = self.client.clone().catalog(self.url()?).await?;
-        trace!(target: "registry", "catalog: {:?}", response);
-        ensure!(
-            response.status().is_success(),
-            error::ListReposSnafu {
-                reason: response
-                    .json::<ErrorResponse>()
-                    .await
-                    .context(error::ErrorDeserializeSnafu)?
+        Ok(self.catalog_page(None, None).await?.entries)
+    }
+
+    /// Fetch the catalog of repositories that sort after `last`. When a limit is provided only a
+    /// single page of at most that many repositories is fetched, otherwise all pages are.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(registry = %self.uri.base(), status = Empty)
+    )]
+    pub async fn catalog_page(&self, limit: Option<usize>, last: Option<&str>) -> Result<Page> {
+        let url = self.url()?;
+        self.list_pages::<RepositoryList, _, _>(
+            "/v2/_catalog",
+            limit,
+            last,
+            |reason| error::Error::ListRepos { reason },
+            |x| x.repositories,
+            |deadline| {
+                let client = self.client.clone();
+                let url = url.clone();
+                async move { client.catalog(url, deadline).await }
+            },
+        )
+        .await
+    }
+
+    /// Url of the first page of a list when it needs the n or last query parameters
+    fn page_url(
+        url: &Url,
+        path: &str,
+        limit: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<Option<Url>> {
+        if limit.is_none() && last.is_none() {
+            return Ok(None);
+        }
+        let mut url = url.join(path).context(error::UrlSnafu)?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(limit) = limit {
+                query.append_pair("n", &limit.to_string());
             }
-        );
-        let list: RepositoryList = Self::body(response).await?;
-        Ok(list.repositories)
+            if let Some(last) = last {
+                query.append_pair("last", last);
+            }
+        }
+        Ok(Some(url))
+    }
+
+    /// Fetch the pages of a list at the path, following the links to the next page unless only a
+    /// single page of at most `limit` entries is requested. Pages are requested in the page size
+    /// of the registry when one is set, and registries that truncate a full page without linking
+    /// to the next one are asked for the entries after the last one. A registry rejecting the
+    /// request fails with the error built from its error response.
+    async fn list_pages<T, F, Fut>(
+        &self,
+        path: &str,
+        limit: Option<usize>,
+        last: Option<&str>,
+        fail: fn(ErrorResponse) -> error::Error,
+        entries: fn(T) -> Vec<String>,
+        first: F,
+    ) -> Result<Page>
+    where
+        T: DeserializeOwned,
+        F: Fn(Option<Instant>) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let deadline = self.list_deadline.map(|x| Instant::now() + x);
+        let url = self.url()?;
+        let size = limit.or(self.page_size);
+        let mut next = Self::page_url(&url, path, size, last)?.map(|x| x.to_string());
+        let mut all = Vec::new();
+        loop {
+            // The client retries throttled pages until the deadline
+            let response = match next.as_deref() {
+                Some(next) => self.client.list_next(&url, next, deadline).await?,
+                None => first(deadline).await?,
+            };
+            traced("list", &response);
+            if !response.status().is_success() {
+                return Err(fail(
+                    response
+                        .json::<ErrorResponse>()
+                        .await
+                        .context(error::ErrorDeserializeSnafu)?,
+                ));
+            }
+            let link = Self::next_link(&response);
+            let page = entries(Self::body(response).await?);
+            if limit.is_some() {
+                return Ok(Page {
+                    entries: page,
+                    more: link.is_some(),
+                });
+            }
+            next = match (link, size, page.last()) {
+                (Some(link), _, _) => Some(link),
+                (None, Some(size), Some(last))
+                    if page.len() >= size && all.last() != Some(last) =>
+                {
+                    debug!(target: "registry", "full page without a link, continuing after {last}");
+                    Self::page_url(&url, path, Some(size), Some(last))?.map(|x| x.to_string())
+                }
+                _ => None,
+            };
+            all.extend(page);
+            if next.is_none() {
+                return Ok(Page {
+                    entries: all,
+                    more: false,
+                });
+            }
+        }
     }
 
-    /// Check for the existence of a blob in the registry
-    pub(crate) async fn check_blob(&self, repository: &str, digest: &str) -> Result<bool> {
+    /// Link to the next page of a paginated list, if there is one
+    fn next_link(response: &Response) -> Option<String> {
+        response
+            .headers()
+            .get(LINK)?
+            .to_str()
+            .ok()?
+            .split(',')
+            .find(|x| x.contains("rel=\"next\""))
+            .and_then(|x| {
+                let (_, rest) = x.split_once('<')?;
+                let (link, _) = rest.split_once('>')?;
+                Some(link.to_string())
+            })
+    }
+
+    /// Check for the existence of a blob in the registry without downloading it
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, status = Empty,
+        )
+    )]
+    pub async fn blob_exists(&self, repository: &str, digest: &str) -> Result<bool> {
         let repository = self.repository_name(repository);
         let response = self
             .client
             .clone()
             .head_blob(self.url()?, repository, digest.into())
             .await?;
-        trace!(target: "registry", "head_blob: {:?}", response);
+        traced("head_blob", &response);
         Ok(response.status().is_success())
     }
 
+    /// Size and digest of a blob in the registry without downloading it, none when the registry
+    /// does not have it
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, bytes = Empty,
+            status = Empty,
+        )
+    )]
+    pub async fn stat_blob(&self, repository: &str, digest: &str) -> Result<Option<BlobStat>> {
+        let response = self
+            .client
+            .clone()
+            .head_blob(self.url()?, self.repository_name(repository), digest.into())
+            .await?;
+        traced("head_blob", &response);
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        ensure!(
+            response.status().is_success(),
+            error::StatBlobSnafu {
+                digest,
+                reason: format!("registry responded {}", response.status()),
+            }
+        );
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .context(error::ContentLengthMissingSnafu)?
+            .to_str()
+            .context(error::ImproperHeaderSnafu)?
+            .parse()
+            .context(error::ContentLengthNotNumberSnafu)?;
+        Span::current().record("bytes", size);
+        let digest = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or(digest)
+            .to_string();
+        Ok(Some(BlobStat { digest, size }))
+    }
+
     /// Fetch a blob from the registry
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, bytes = Empty,
+            status = Empty,
+        )
+    )]
     pub(crate) async fn fetch_blob(
         &self,
         repository: &str,
         digest: &str,
-    ) -> Result<(
-        impl Stream<Item = std::result::Result<Bytes, std::io::Error>> + use<>,
-        u64,
-    )> {
-        let repository = self.repository_name(repository);
+    ) -> Result<(BlobStream, u64)> {
+        if self.offline {
+            let (file, size) = self
+                .offline_store()?
+                .open_blob(digest)
+                .await?
+                .context(error::OfflineMissingSnafu { reference: digest })?;
+            return Ok((Box::pin(ReaderStream::new(file)), size));
+        }
+        if let Some(store) = self.store.as_ref()
+            && let Some((file, size)) = store.open_blob(digest).await?
+        {
+            debug!(target: "registry", "serving blob {digest} from the local store");
+            return Ok((Box::pin(ReaderStream::new(file)), size));
+        }
+        let name = self.docker_hub_name(repository);
         let response = self
-            .client
-            .clone()
-            .get_blob(self.url()?, repository, digest.into())
+            .mirrored(digest, |registry| {
+                let repository = registry.repository_name(&name);
+                let digest = digest.to_string();
+                async move {
+                    registry
+                        .client
+                        .get_blob(registry.url()?, repository, digest, None)
+                        .await
+                }
+            })
             .await?;
-        trace!(target: "registry", "get_blob: {:?}", response);
+        traced("get_blob", &response);
         ensure!(
             response.status().is_success(),
             error::FetchBlobSnafu {
@@ -232,52 +1040,344 @@ impl Registry {
             .context(error::ImproperHeaderSnafu)?
             .parse()
             .context(error::ContentLengthNotNumberSnafu)?;
-        Ok((response.bytes_stream().map_err(std::io::Error::other), size))
+        Span::current().record("bytes", size);
+        let stream = response.bytes_stream().map_err(std::io::Error::other);
+        match self.store.as_ref() {
+            Some(store) => Ok((Box::pin(store.tee(digest, Some(size), stream)), size)),
+            None => Ok((Box::pin(stream), size)),
+        }
     }
 
-    /// Delete a blob from the registry
-    pub(crate) async fn delete_blob(&self, repository: &str, digest: &str) -> Result<()> {
-        let repository = self.repository_name(repository);
+    /// Fetch the bytes of a blob from the start up to the end, or up to the end of the blob without
+    /// one. Registries ignoring the range request are served by skipping over the rest of the blob.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, start,
+            end = ?end, status = Empty,
+        )
+    )]
+    pub(crate) async fn fetch_blob_range(
+        &self,
+        repository: &str,
+        digest: &str,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<BlobStream> {
+        ensure!(
+            end.is_none_or(|end| start < end),
+            error::InvalidRangeSnafu {
+                start,
+                end,
+                reason: "the range is empty",
+            }
+        );
+        let local = if self.offline {
+            Some(
+                self.offline_store()?
+                    .open_blob(digest)
+                    .await?
+                    .context(error::OfflineMissingSnafu { reference: digest })?,
+            )
+        } else {
+            match self.store.as_ref() {
+                Some(store) => store.open_blob(digest).await?,
+                None => None,
+            }
+        };
+        if let Some((mut file, size)) = local {
+            ensure!(
+                start < size,
+                error::InvalidRangeSnafu {
+                    start,
+                    end,
+                    reason: format!("the blob is {size} bytes"),
+                }
+            );
+            file.seek(SeekFrom::Start(start))
+                .await
+                .context(error::FileSnafu)?;
+            let reader = file.take(end.unwrap_or(size) - start);
+            return Ok(Box::pin(ReaderStream::new(reader)));
+        }
+        // The range header is inclusive of the last byte
+        let range = match end {
+            Some(end) => format!("bytes={start}-{}", end - 1),
+            None => format!("bytes={start}-"),
+        };
+        let name = self.docker_hub_name(repository);
         let response = self
-            .client
-            .del_blob(self.url()?, repository, digest.into())
+            .mirrored(digest, |registry| {
+                let repository = registry.repository_name(&name);
+                let digest = digest.to_string();
+                let range = range.clone();
+                async move {
+                    registry
+                        .client
+                        .get_blob(registry.url()?, repository, digest, Some(range))
+                        .await
+                }
+            })
             .await?;
-        trace!(target: "registry", "del_blob: {:?}", response);
+        traced("get_blob", &response);
+        ensure!(
+            response.status() != StatusCode::RANGE_NOT_SATISFIABLE,
+            error::InvalidRangeSnafu {
+                start,
+                end,
+                reason: "the registry can not satisfy the range",
+            }
+        );
         ensure!(
             response.status().is_success(),
-            error::DeleteBlobSnafu {
-                digest,
+            error::FetchBlobSnafu {
                 reason: response
                     .json::<ErrorResponse>()
                     .await
                     .context(error::ErrorDeserializeSnafu)?
             }
         );
-        Ok(())
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let stream = response.bytes_stream().map_err(std::io::Error::other);
+        if partial {
+            return Ok(Box::pin(stream));
+        }
+        debug!(target: "registry", "registry ignored the range of {digest}, skipping to byte {start}");
+        let mut reader = Box::pin(StreamReader::new(stream));
+        tokio::io::copy(&mut (&mut reader).take(start), &mut tokio::io::sink())
+            .await
+            .context(error::LayerReadSnafu)?;
+        Ok(match end {
+            Some(end) => Box::pin(ReaderStream::new(reader.take(end - start))),
+            None => Box::pin(ReaderStream::new(reader)),
+        })
     }
 
-    /// Check for the existence of a manifest in the registry
-    pub(crate) async fn check_manifest(&self, repository: &str, reference: &str) -> Result<bool> {
+    /// Query the state of an upload session, returning the number of bytes committed by the
+    /// registry or `None` if the session is no longer active
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(registry = %self.uri.base(), status = Empty)
+    )]
+    pub(crate) async fn upload_status(&self, upload: &str) -> Result<Option<usize>> {
+        let response = self
+            .client
+            .upload_status(self.url()?, upload.to_string())
+            .await?;
+        traced("upload_status", &response);
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        // The range is inclusive, i.e. `0-1023` means 1024 bytes have been committed
+        let committed = response
+            .headers()
+            .get("Range")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.split_once('-'))
+            .and_then(|(_, end)| end.parse::<usize>().ok())
+            .map(|end| end + 1)
+            .unwrap_or(0);
+        Ok(Some(committed))
+    }
+
+    /// Delete a blob from the registry
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, status = Empty,
+        )
+    )]
+    pub(crate) async fn delete_blob(&self, repository: &str, digest: &str) -> Result<()> {
+        if self.dry_run {
+            self.plan(
+                AuditOperation::DeleteBlob,
+                repository,
+                Some(digest),
+                Some(digest),
+                None,
+            );
+            return Ok(());
+        }
+        let result: Result<()> = async {
+            let response = self
+                .client
+                .del_blob(self.url()?, self.repository_name(repository), digest.into())
+                .await?;
+            traced("del_blob", &response);
+            ensure!(
+                response.status().is_success(),
+                error::DeleteBlobSnafu {
+                    digest,
+                    reason: response
+                        .json::<ErrorResponse>()
+                        .await
+                        .context(error::ErrorDeserializeSnafu)?
+                }
+            );
+            Ok(())
+        }
+        .await;
+        self.audit(
+            AuditOperation::DeleteBlob,
+            repository,
+            Some(digest),
+            Some(digest),
+            None,
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+        result
+    }
+
+    /// Check for the existence of a manifest in the registry without downloading it
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, reference = %reference,
+            status = Empty,
+        )
+    )]
+    pub async fn manifest_exists(&self, repository: &str, reference: &str) -> Result<bool> {
         let repository = self.repository_name(repository);
         let response = self
             .client
             .head_manifest(self.url()?, repository, reference.into())
             .await?;
-        trace!(target: "registry", "head_manifest: {:?}", response);
+        traced("head_manifest", &response);
         Ok(response.status().is_success())
     }
 
+    /// Resolve a reference to the digest of the manifest it points to. The digest the registry
+    /// reports for a HEAD request is used, falling back to hashing the manifest when a registry
+    /// does not report one.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, reference = %reference,
+            digest = Empty, status = Empty,
+        )
+    )]
+    pub async fn resolve_digest(&self, repository: &str, reference: &str) -> Result<String> {
+        if self.offline {
+            return self
+                .offline_store()?
+                .resolve(self.uri.base(), repository, reference)
+                .await?
+                .context(error::OfflineMissingSnafu { reference });
+        }
+        let response = self
+            .client
+            .head_manifest(
+                self.url()?,
+                self.repository_name(repository),
+                reference.into(),
+            )
+            .await?;
+        traced("head_manifest", &response);
+        if response.status().is_success()
+            && let Some(digest) = response
+                .headers()
+                .get(DOCKER_CONTENT_DIGEST)
+                .and_then(|x| x.to_str().ok())
+        {
+            Span::current().record("digest", digest);
+            return Ok(digest.to_string());
+        }
+        // Failed requests fall back as well, so the error comes from a request that has a body
+        debug!(target: "registry", "no digest reported for {repository}:{reference}, hashing the manifest");
+        let (_, digest, _) = self.fetch_manifest_raw(repository, reference).await?;
+        Span::current().record("digest", digest.as_str());
+        Ok(digest)
+    }
+
     /// Fetch a manifest from the registry, this could be an Image Index or an Image manifest
     pub(crate) async fn fetch_manifest<T>(&self, repository: &str, reference: &str) -> Result<T>
     where
         T: DeserializeOwned,
     {
-        let repository = self.repository_name(repository);
+        let (bytes, _, _) = self.fetch_manifest_raw(repository, reference).await?;
+        serde_json::from_slice(&bytes).context(error::BodyDeserializeSnafu)
+    }
+
+    /// Fetch the canonical bytes of a manifest along with its digest and media type.
+    ///
+    /// The digest is computed from the bytes with the algorithm of the reference, or sha256 for
+    /// tags, and must match the `Docker-Content-Digest` reported by the registry.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, reference = %reference,
+            digest = Empty, bytes = Empty, status = Empty,
+        )
+    )]
+    pub async fn fetch_manifest_raw(
+        &self,
+        repository: &str,
+        reference: &str,
+    ) -> Result<(Bytes, String, MediaType)> {
+        let algorithm = match reference.split_once(':') {
+            Some((algorithm, _)) => Algorithm::from_str(algorithm)?,
+            None => Algorithm::default(),
+        };
+        if self.offline {
+            let store = self.offline_store()?;
+            let bytes = match store
+                .resolve(self.uri.base(), repository, reference)
+                .await?
+            {
+                Some(digest) => store.read_blob(&digest).await?,
+                None => None,
+            }
+            .context(error::OfflineMissingSnafu { reference })?;
+            let media_type = manifest_media_type(&bytes, None)?;
+            return Ok((bytes.clone(), algorithm.digest(&bytes), media_type));
+        }
+        let key = (repository.to_string(), reference.to_string());
+        let cached = self.manifests.lock().unwrap().get(&key).cloned();
+        if let Some(cached) = cached.as_ref()
+            && reference.contains(':')
+        {
+            // Manifests fetched by digest can never change
+            trace!(target: "registry", "serving {repository}@{reference} from the manifest cache");
+            let media_type = manifest_media_type(&cached.body, cached.content_type.as_deref())?;
+            return Ok((
+                cached.body.clone(),
+                algorithm.digest(&cached.body),
+                media_type,
+            ));
+        }
+        let etag = cached.as_ref().map(|x| x.etag.clone());
+        let name = self.docker_hub_name(repository);
         let response = self
-            .client
-            .get_manifest(self.url()?, repository, reference.into())
+            .mirrored(reference, |registry| {
+                let repository = registry.repository_name(&name);
+                let reference = reference.to_string();
+                let etag = etag.clone();
+                async move {
+                    registry
+                        .client
+                        .get_manifest(registry.url()?, repository, reference, etag)
+                        .await
+                }
+            })
             .await?;
-        trace!(target: "registry", "get_manifest: {:?}", response);
+        traced("get_manifest", &response);
+        if response.status() == StatusCode::NOT_MODIFIED
+            && let Some(cached) = cached
+        {
+            debug!(target: "registry", "manifest {repository}:{reference} not modified");
+            let media_type = manifest_media_type(&cached.body, cached.content_type.as_deref())?;
+            return Ok((
+                cached.body.clone(),
+                algorithm.digest(&cached.body),
+                media_type,
+            ));
+        }
         ensure!(
             response.status().is_success(),
             error::FetchManifestSnafu {
@@ -287,7 +1387,103 @@ impl Registry {
                     .context(error::ErrorDeserializeSnafu)?
             }
         );
-        Self::body(response).await
+        let reported = response
+            .headers()
+            .get(DOCKER_CONTENT_DIGEST)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string());
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string());
+        // Registries without an etag still report the digest, which identifies the content as well
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .or_else(|| response.headers().get(DOCKER_CONTENT_DIGEST))
+            .and_then(|x| x.to_str().ok())
+            .map(|x| {
+                if x.starts_with('"') || x.starts_with("W/") {
+                    x.to_string()
+                } else {
+                    format!("\"{x}\"")
+                }
+            });
+        let bytes = response
+            .bytes()
+            .await
+            .context(error::ResponseDeserializeSnafu)?;
+        trace!(target: "registry", "RESPONSE BODY: {}", String::from_utf8_lossy(&bytes));
+        Span::current().record("bytes", bytes.len());
+        let digest = match reported.as_deref().and_then(|x| x.split_once(':')) {
+            // The registry may report the digest with another algorithm than the reference
+            Some((reported, _)) if !reference.contains(':') => Algorithm::from_str(reported)
+                .unwrap_or_default()
+                .digest(&bytes),
+            _ => algorithm.digest(&bytes),
+        };
+        if let Some(reported) = reported {
+            ensure!(
+                reported == digest,
+                error::DigestMismatchSnafu {
+                    expected: reported,
+                    actual: digest,
+                }
+            );
+        }
+        Span::current().record("digest", digest.as_str());
+        let media_type = manifest_media_type(&bytes, content_type.as_deref())?;
+        if let Some(etag) = etag {
+            self.cache_manifest(key, etag, content_type, bytes.clone());
+        }
+        if let Some(store) = self.store.as_ref() {
+            if !reference.contains(':') {
+                store
+                    .tag(self.uri.base(), repository, reference, &digest)
+                    .await?;
+            }
+            store.write_blob(&digest, &bytes).await?;
+        }
+        Ok((bytes, digest, media_type))
+    }
+
+    /// Drop a cached manifest after the reference was changed through this registry
+    fn forget_manifest(&self, repository: &str, reference: &str) {
+        self.manifests
+            .lock()
+            .unwrap()
+            .remove(&(repository.to_string(), reference.to_string()));
+    }
+
+    /// Keep a fetched manifest so later fetches of the same reference can be conditional
+    fn cache_manifest(
+        &self,
+        key: (String, String),
+        etag: String,
+        content_type: Option<String>,
+        body: Bytes,
+    ) {
+        let mut manifests = self.manifests.lock().unwrap();
+        if manifests.len() >= MAX_CACHED_MANIFESTS && !manifests.contains_key(&key) {
+            // The cache is only an optimization, so any entry can make room
+            if let Some(evict) = manifests.keys().next().cloned() {
+                manifests.remove(&evict);
+            }
+        }
+        manifests.insert(
+            key,
+            CachedManifest {
+                etag,
+                content_type,
+                body,
+            },
+        );
+    }
+
+    /// The store to serve content from while offline
+    fn offline_store(&self) -> Result<&Store> {
+        self.store.as_ref().context(error::StoreUnavailableSnafu)
     }
 
     /// Push a manifest to the oci registtry
@@ -302,82 +1498,468 @@ impl Registry {
     where
         T: Serialize,
     {
-        let repository = self.repository_name(repository);
         let bytes = serde_json::to_vec(manifest).context(error::SerializeSnafu)?;
-        let size = bytes.len();
-        let hash = Sha256::digest(bytes.as_slice());
-        let digest = format!("sha256:{}", base16::encode_lower(hash.as_slice()));
-        let response = self
-            .client
-            .put_manifest(
-                self.url()?,
+        self.push_manifest_raw(
+            media_type,
+            repository,
+            reference,
+            Bytes::from_owner(bytes),
+            platform,
+        )
+        .await
+    }
+
+    /// Push the exact bytes of a manifest, so a manifest read from an archive or layout keeps the
+    /// digest it is referred to by
+    pub async fn push_manifest_raw(
+        &self,
+        media_type: &MediaType,
+        repository: &str,
+        reference: &str,
+        bytes: Bytes,
+        platform: Option<Platform>,
+    ) -> Result<Layer> {
+        self.put_manifest_raw(media_type, repository, reference, bytes, platform)
+            .await
+            .map(|(layer, _)| layer)
+    }
+
+    /// Push an artifact manifest by its digest. Registries that do not report processing the
+    /// subject of the manifest have no referrers api, the descriptor of the artifact is added to
+    /// the index tagged with the subject digest instead, as the tag schema fallback describes.
+    pub async fn push_referrer(&self, repository: &str, artifact: &Image) -> Result<Layer> {
+        let bytes = serde_json::to_vec(artifact).context(error::SerializeSnafu)?;
+        let digest = Algorithm::default().digest(&bytes);
+        let (layer, applied) = self
+            .put_manifest_raw(
+                artifact.media_type(),
                 repository,
-                reference.into(),
+                &digest,
                 Bytes::from_owner(bytes),
+                None,
             )
             .await?;
-        trace!(target: "registry", "put_manifest: {:?}", response);
-        ensure!(
-            response.status().is_success(),
-            error::PushImageSnafu {
-                uri: self.url()?.clone(),
-                reason: response
-                    .json::<ErrorResponse>()
-                    .await
-                    .context(error::ErrorDeserializeSnafu)?
+        let descriptor = Layer::builder()
+            .media_type(layer.media_type().clone())
+            .digest(layer.digest())
+            .size(layer.size())
+            .maybe_artifact_type(artifact.artifact_type().map(str::to_string))
+            .annotations(artifact.annotations().clone())
+            .build();
+        let Some(subject) = artifact.subject().filter(|_| !applied) else {
+            return Ok(descriptor);
+        };
+        let index = self.tagged_referrers(repository, subject.digest()).await?;
+        if index
+            .manifests()
+            .iter()
+            .any(|x| x.digest() == layer.digest())
+        {
+            return Ok(descriptor);
+        }
+        let mut manifests = index.manifests().to_vec();
+        manifests.push(descriptor.clone());
+        let index = Index::builder()
+            .schema_version(2_usize)
+            .media_type(MediaType::ImageIndex)
+            .manifests(manifests)
+            .annotations(index.annotations().clone())
+            .build();
+        let tag = subject.digest().replacen(':', "-", 1);
+        self.push_manifest(&MediaType::ImageIndex, repository, &tag, &index, None)
+            .await?;
+        Ok(descriptor)
+    }
+
+    /// Push the bytes of a manifest, returning its descriptor and whether the registry reported
+    /// processing the subject of the manifest. The descriptor has the digest the registry reports
+    /// for the manifest, or else the one computed from the bytes.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, reference = %reference,
+            media_type = %media_type, digest = Empty, bytes = Empty, status = Empty,
+        )
+    )]
+    async fn put_manifest_raw(
+        &self,
+        media_type: &MediaType,
+        repository: &str,
+        reference: &str,
+        bytes: Bytes,
+        platform: Option<Platform>,
+    ) -> Result<(Layer, bool)> {
+        let size = bytes.len();
+        // Pushing by digest hashes with the algorithm of the reference, sha256 for tags
+        let digest = match reference.split_once(':') {
+            Some((algorithm, _)) => Algorithm::from_str(algorithm)?,
+            None => Algorithm::default(),
+        }
+        .digest(&bytes);
+        Span::current()
+            .record("digest", digest.as_str())
+            .record("bytes", size);
+        if self.dry_run {
+            self.plan(
+                AuditOperation::PutManifest,
+                repository,
+                Some(reference),
+                Some(&digest),
+                Some(size),
+            );
+            return Ok((
+                Layer::builder()
+                    .digest(digest)
+                    .media_type(media_type.clone())
+                    .size(size)
+                    .maybe_platform(platform)
+                    .build(),
+                false,
+            ));
+        }
+        let result: Result<(bool, Option<String>)> = async {
+            let response = self
+                .client
+                .put_manifest(
+                    self.url()?,
+                    self.repository_name(repository),
+                    reference.into(),
+                    media_type.to_string(),
+                    bytes,
+                )
+                .await?;
+            traced("put_manifest", &response);
+            ensure!(
+                response.status().is_success(),
+                error::PushImageSnafu {
+                    uri: self.url()?.clone(),
+                    reason: response
+                        .json::<ErrorResponse>()
+                        .await
+                        .context(error::ErrorDeserializeSnafu)?
+                }
+            );
+            let reported = response
+                .headers()
+                .get(DOCKER_CONTENT_DIGEST)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_string);
+            Ok((response.headers().contains_key(OCI_SUBJECT), reported))
+        }
+        .await;
+        let digest = match result.as_ref() {
+            Ok((_, Some(reported))) if *reported != digest => {
+                warn!(target: "registry", "registry assigned digest {reported} to manifest {repository}:{reference}, its content hashes to {digest}");
+                reported.clone()
             }
+            _ => digest,
+        };
+        self.audit(
+            AuditOperation::PutManifest,
+            repository,
+            Some(reference),
+            Some(&digest),
+            Some(size),
+            result.as_ref().err().map(|e| e.to_string()),
         );
-        Ok(Layer::builder()
-            .digest(digest.clone())
-            .media_type(media_type.clone())
-            .size(size)
-            .maybe_platform(platform)
-            .build())
+        let (applied, _) = result?;
+        self.forget_manifest(repository, reference);
+        Ok((
+            Layer::builder()
+                .digest(digest.clone())
+                .media_type(media_type.clone())
+                .size(size)
+                .maybe_platform(platform)
+                .build(),
+            applied,
+        ))
     }
 
     /// Get the list of tags in a repository on this registry
     pub(crate) async fn get_tags(&self, repository: &str) -> Result<Vec<String>> {
+        let mut tags = self.get_tags_page(repository, None, None).await?.entries;
+        tags.sort();
+        Ok(tags)
+    }
+
+    /// Fetch the tags of a repository that sort after `last`, a single page of at most `limit` tags
+    /// when a limit is provided
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(registry = %self.uri.base(), repository = %repository, status = Empty)
+    )]
+    pub(crate) async fn get_tags_page(
+        &self,
+        repository: &str,
+        limit: Option<usize>,
+        last: Option<&str>,
+    ) -> Result<Page> {
         let repository_name = self.repository_name(repository);
-        let response = self
-            .client
-            .get_tags(&self.url()?, repository_name.as_str())
-            .await?;
-        trace!(target: "registry", "get_tags: {:?}", response);
-        ensure!(
-            response.status().is_success(),
-            error::ListTagsSnafu {
-                reason: response
-                    .json::<ErrorResponse>()
-                    .await
-                    .context(error::ErrorDeserializeSnafu)?
+        let url = self.url()?;
+        self.list_pages::<TagList, _, _>(
+            &format!("/v2/{repository_name}/tags/list"),
+            limit,
+            last,
+            |reason| error::Error::ListTags { reason },
+            |x| x.tags,
+            |deadline| {
+                self.client
+                    .get_tags(&url, repository_name.as_str(), deadline)
+            },
+        )
+        .await
+    }
+
+    /// List the manifests referring to a digest through their subject, optionally only those of
+    /// an artifact type. Registries without the referrers api are queried through the tag schema
+    /// fallback, where the referrers are kept in an index tagged with the digest.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest,
+            artifact_type = ?artifact_type, status = Empty,
+        )
+    )]
+    pub async fn referrers(
+        &self,
+        repository: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Index> {
+        let (index, filtered) = if self.offline {
+            (self.tagged_referrers(repository, digest).await?, false)
+        } else {
+            let response = self
+                .client
+                .get_referrers(
+                    self.url()?,
+                    self.repository_name(repository),
+                    digest.into(),
+                    artifact_type.map(str::to_string),
+                )
+                .await?;
+            traced("get_referrers", &response);
+            if response.status() == StatusCode::NOT_FOUND {
+                debug!(target: "registry", "{} has no referrers api, falling back to the tag schema", self.uri.base());
+                (self.tagged_referrers(repository, digest).await?, false)
+            } else {
+                ensure!(
+                    response.status().is_success(),
+                    error::ReferrersSnafu {
+                        reason: response
+                            .json::<ErrorResponse>()
+                            .await
+                            .context(error::ErrorDeserializeSnafu)?
+                    }
+                );
+                // Registries report when they applied the artifact type filter themselves
+                let filtered = response
+                    .headers()
+                    .get(FILTERS_APPLIED)
+                    .and_then(|x| x.to_str().ok())
+                    .is_some_and(|x| x.split(',').any(|x| x.trim() == "artifactType"));
+                (Self::body(response).await?, filtered)
             }
+        };
+        let Some(artifact_type) = artifact_type.filter(|_| !filtered) else {
+            return Ok(index);
+        };
+        Ok(Index::builder()
+            .schema_version(index.schema_version())
+            .media_type(index.media_type().clone())
+            .manifests(
+                index
+                    .manifests()
+                    .iter()
+                    .filter(|x| x.artifact_type() == Some(artifact_type))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            )
+            .annotations(index.annotations().clone())
+            .build())
+    }
+
+    /// Referrers of a digest kept in an index tagged with the digest, i.e. `sha256-<hex>`
+    async fn tagged_referrers(&self, repository: &str, digest: &str) -> Result<Index> {
+        let tag = digest.replacen(':', "-", 1);
+        if !self.offline && !self.manifest_exists(repository, &tag).await? {
+            return Ok(Index::new(&[]).await);
+        }
+        match self.fetch_manifest(repository, &tag).await {
+            Err(error::Error::OfflineMissing { .. }) => Ok(Index::new(&[]).await),
+            result => result,
+        }
+    }
+
+    /// Delete a manifest by its digest, which removes every tag pointing at it. Registries only
+    /// garbage collect the blobs of untagged manifests once they are deleted this way.
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(
+            registry = %self.uri.base(), repository = %repository, digest = %digest, status = Empty,
+        )
+    )]
+    pub async fn delete_manifest_by_digest(&self, repository: &str, digest: &str) -> Result<()> {
+        ensure!(
+            Reference::from_str(digest).is_ok_and(|x| matches!(x, Reference::Digest { .. })),
+            error::DeleteManifestNoDigestSnafu
         );
-        let taglist: TagList = Self::body(response).await?;
-        let mut tags = taglist.tags.clone();
-        tags.sort();
-        Ok(tags)
+        if self.dry_run {
+            self.plan(
+                AuditOperation::DeleteManifest,
+                repository,
+                Some(digest),
+                Some(digest),
+                None,
+            );
+            return Ok(());
+        }
+        let result: Result<()> = async {
+            let response = self
+                .client
+                .del_manifest(self.url()?, self.repository_name(repository), digest.into())
+                .await?;
+            traced("del_manifest", &response);
+            ensure!(
+                response.status().is_success(),
+                error::DeleteManifestSnafu {
+                    digest,
+                    reason: response
+                        .json::<ErrorResponse>()
+                        .await
+                        .context(error::ErrorDeserializeSnafu)?
+                }
+            );
+            Ok(())
+        }
+        .await;
+        self.audit(
+            AuditOperation::DeleteManifest,
+            repository,
+            Some(digest),
+            Some(digest),
+            None,
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+        // Tags of the repository may have pointed at the manifest, so none of them can be trusted
+        self.manifests
+            .lock()
+            .unwrap()
+            .retain(|(x, _), _| x != repository);
+        result
     }
 
     /// Delete a tag in the registry in the given repository
+    #[instrument(
+        target = "registry",
+        skip_all,
+        fields(registry = %self.uri.base(), repository = %repository, tag = %tag, status = Empty)
+    )]
     pub(crate) async fn delete_tag(&self, repository: &str, tag: &str) -> Result<()> {
-        let repository = self.repository_name(repository);
-        let response = self
-            .client
-            .del_manifest(self.url()?, repository, tag.into())
-            .await?;
-        trace!(target: "registry", "del_tag: {:?}", response);
-        ensure!(
-            response.status().is_success(),
-            error::DeleteTagSnafu {
-                tag: tag.to_string(),
-                reason: response
-                    .json::<ErrorResponse>()
-                    .await
-                    .context(error::ErrorDeserializeSnafu)?
-            }
+        if self.dry_run {
+            self.plan(
+                AuditOperation::DeleteManifest,
+                repository,
+                Some(tag),
+                None,
+                None,
+            );
+            return Ok(());
+        }
+        let result: Result<()> = async {
+            let response = self
+                .client
+                .del_manifest(self.url()?, self.repository_name(repository), tag.into())
+                .await?;
+            traced("del_tag", &response);
+            ensure!(
+                response.status().is_success(),
+                error::DeleteTagSnafu {
+                    tag: tag.to_string(),
+                    reason: response
+                        .json::<ErrorResponse>()
+                        .await
+                        .context(error::ErrorDeserializeSnafu)?
+                }
+            );
+            Ok(())
+        }
+        .await;
+        self.audit(
+            AuditOperation::DeleteManifest,
+            repository,
+            Some(tag),
+            None,
+            None,
+            result.as_ref().err().map(|e| e.to_string()),
         );
+        self.forget_manifest(repository, tag);
+        result
+    }
 
-        Ok(())
+    /// Record a mutating operation to the audit sink, if one is set
+    pub(crate) fn audit(
+        &self,
+        operation: AuditOperation,
+        repository: &str,
+        reference: Option<&str>,
+        digest: Option<&str>,
+        size: Option<usize>,
+        error: Option<String>,
+    ) {
+        let Some(audit) = self.audit.as_ref() else {
+            return;
+        };
+        let mut event = self.audit_event(operation, repository, reference, digest, size);
+        if error.is_some() {
+            event.outcome = AuditOutcome::Failure;
+            event.error = error;
+        }
+        audit.record(&event);
+    }
+
+    /// Record a mutating operation a dry run skipped to the audit sink, if one is set
+    pub(crate) fn plan(
+        &self,
+        operation: AuditOperation,
+        repository: &str,
+        reference: Option<&str>,
+        digest: Option<&str>,
+        size: Option<usize>,
+    ) {
+        debug!(target: "registry", "dry run, skipping {operation:?} in {repository}");
+        let Some(audit) = self.audit.as_ref() else {
+            return;
+        };
+        let mut event = self.audit_event(operation, repository, reference, digest, size);
+        event.outcome = AuditOutcome::Planned;
+        audit.record(&event);
+    }
+
+    /// Successful audit event of an operation against this registry
+    fn audit_event(
+        &self,
+        operation: AuditOperation,
+        repository: &str,
+        reference: Option<&str>,
+        digest: Option<&str>,
+        size: Option<usize>,
+    ) -> AuditEvent {
+        AuditEvent {
+            timestamp: Utc::now(),
+            operation,
+            registry: self.uri.base().clone(),
+            repository: repository.to_string(),
+            reference: reference.map(str::to_string),
+            digest: digest.map(str::to_string),
+            size,
+            outcome: AuditOutcome::Success,
+            error: None,
+        }
     }
 
     /// Handles deserialization of responses with proper logging
@@ -393,3 +1975,130 @@ impl Registry {
         serde_json::from_value(value).context(error::BodyDeserializeSnafu)
     }
 }
+
+/// Record the status of a response on the span of the registry operation it answers, and trace the
+/// response
+fn traced(request: &str, response: &Response) {
+    Span::current().record("status", response.status().as_u16());
+    trace!(target: "registry", "{request}: {:?}", response);
+}
+
+/// Media type of a manifest, from its `mediaType` field or else the `Content-Type` it was served with.
+/// Manifests with neither, or only a generic content type, are told apart by their list of
+/// manifests.
+fn manifest_media_type(bytes: &[u8], content_type: Option<&str>) -> Result<MediaType> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Kind {
+        media_type: Option<String>,
+        manifests: Option<serde_json::Value>,
+    }
+    let kind: Kind = serde_json::from_slice(bytes).context(error::BodyDeserializeSnafu)?;
+    let media_type = kind
+        .media_type
+        .as_deref()
+        .or(content_type)
+        .map(|x| serde_json::from_value(serde_json::Value::String(x.to_string())))
+        .transpose()
+        .context(error::BodyDeserializeSnafu)?;
+    match media_type {
+        // A generic content type such as `application/json` does not tell the kind of manifest
+        Some(MediaType::Other(_)) if kind.media_type.is_none() => {}
+        Some(media_type) => return Ok(media_type),
+        None => {}
+    }
+    if kind.manifests.is_some() {
+        Ok(MediaType::ImageIndex)
+    } else {
+        Ok(MediaType::Manifest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use futures::TryStreamExt;
+    use reqwest::Method;
+
+    use super::Registry;
+    use crate::client::RegistryClient;
+    use crate::models::MediaType;
+    use crate::options::RetryPolicy;
+    use crate::testing::MockRegistryClient;
+    use crate::uri::RegistryUri;
+
+    fn uri(uri: &str) -> RegistryUri {
+        RegistryUri::from_str(uri).unwrap()
+    }
+
+    fn manifest(client: &MockRegistryClient, content: &str) -> String {
+        client.insert_manifest("app", "v1", &MediaType::ImageIndex, content.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_mirror_serves_pull() {
+        let primary = MockRegistryClient::new();
+        let mirror = MockRegistryClient::new();
+        manifest(&primary, r#"{"schemaVersion":2,"manifests":[]}"#);
+        let digest = manifest(&mirror, r#"{"schemaVersion":2,"manifests":[],"a":1}"#);
+        let blob = mirror.insert_blob("app", "layer");
+        let mut registry = primary.registry(&uri("primary.local"));
+        registry.mirrors = Arc::new(vec![mirror.registry(&uri("mirror.local"))]);
+
+        let (_, served, _) = registry.fetch_manifest_raw("app", "v1").await.unwrap();
+        assert_eq!(served, digest);
+        assert_eq!(registry.served_by("v1").unwrap(), "mirror.local");
+        let (stream, size) = registry.fetch_blob("app", &blob).await.unwrap();
+        let content: Vec<_> = stream.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"layer");
+        assert_eq!(size, 5);
+        assert_eq!(registry.served_by(&blob).unwrap(), "mirror.local");
+        assert!(primary.requests(&Method::GET).is_empty());
+
+        // Mirrors of Docker Hub serve official images under the library namespace too
+        let digest = mirror.insert_manifest(
+            "library/alpine",
+            "latest",
+            &MediaType::ImageIndex,
+            r#"{"schemaVersion":2,"manifests":[]}"#,
+        );
+        let mut registry = primary.registry(&uri("docker.io"));
+        registry.mirrors = Arc::new(vec![mirror.registry(&uri("mirror.local"))]);
+        let (_, served, _) = registry
+            .fetch_manifest_raw("alpine", "latest")
+            .await
+            .unwrap();
+        assert_eq!(served, digest);
+        assert_eq!(registry.served_by("latest").unwrap(), "mirror.local");
+        assert!(primary.requests(&Method::GET).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_missing_content() {
+        let primary = MockRegistryClient::new();
+        let mirror = MockRegistryClient::new();
+        let digest = manifest(&primary, r#"{"schemaVersion":2,"manifests":[]}"#);
+        let mut registry = primary.registry(&uri("primary.local"));
+        registry.mirrors = Arc::new(vec![mirror.registry(&uri("mirror.local"))]);
+
+        let (_, served, _) = registry.fetch_manifest_raw("app", "v1").await.unwrap();
+        assert_eq!(served, digest);
+        assert_eq!(registry.served_by("v1").unwrap(), "primary.local");
+        assert_eq!(mirror.requests(&Method::GET).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_serves_unreachable() {
+        let fallback = MockRegistryClient::new();
+        let digest = manifest(&fallback, r#"{"schemaVersion":2,"manifests":[]}"#);
+        let client = RegistryClient::new(reqwest::Client::new(), None, RetryPolicy::none());
+        let mut registry = Registry::with_client(&uri("http://127.0.0.1:1"), client);
+        registry.fallbacks = Arc::new(vec![fallback.registry(&uri("fallback.local"))]);
+
+        let (_, served, _) = registry.fetch_manifest_raw("app", "v1").await.unwrap();
+        assert_eq!(served, digest);
+        assert_eq!(registry.served_by("v1").unwrap(), "fallback.local");
+    }
+}
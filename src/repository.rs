@@ -1,4 +1,113 @@
-use crate::registry::Registry;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
+
+use bon::Builder;
+use futures::{StreamExt, TryStreamExt};
+use regex_lite::Regex;
+use semver::Version;
+use snafu::ResultExt;
+use tracing::debug;
+
+use crate::error;
+use crate::layer::Layer;
+use crate::manifest::Manifest;
+use crate::models::MediaType;
+use crate::registry::{Page, Registry};
+
+/// Order to list tags in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TagSort {
+    /// Lexical order of the tag names
+    #[default]
+    Lexical,
+    /// Semantic version order, an optional leading `v` is ignored and missing minor or patch
+    /// versions are zero. Tags that are not versions follow in lexical order, even when reversed.
+    Semver,
+}
+
+/// Options for listing the tags of a repository.
+#[derive(Builder, Debug, Clone, Default)]
+pub struct TagOptions {
+    /// Only list tags matching the glob, where `*` matches any characters and `?` a single one
+    #[builder(into)]
+    pub filter: Option<String>,
+    /// Only list tags matching the regular expression
+    #[builder(into)]
+    pub regex: Option<String>,
+    #[builder(default)]
+    pub sort: TagSort,
+    /// List the tags in descending order, i.e. the newest versions first
+    #[builder(default)]
+    pub reverse: bool,
+    /// List at most this many tags, after filtering and sorting
+    pub limit: Option<usize>,
+}
+
+impl TagOptions {
+    /// Filter, sort and limit the tags
+    pub fn apply(&self, tags: Vec<String>) -> crate::Result<Vec<String>> {
+        let glob = self.filter.as_deref().map(glob_regex).transpose()?;
+        let regex = self
+            .regex
+            .as_deref()
+            .map(|x| Regex::new(x).context(error::InvalidTagPatternSnafu { pattern: x }))
+            .transpose()?;
+        let mut tags: Vec<String> = tags
+            .into_iter()
+            .filter(|x| glob.as_ref().is_none_or(|glob| glob.is_match(x)))
+            .filter(|x| regex.as_ref().is_none_or(|regex| regex.is_match(x)))
+            .collect();
+        match (self.sort, self.reverse) {
+            (TagSort::Lexical, false) => tags.sort(),
+            (TagSort::Lexical, true) => tags.sort_by(|a, b| b.cmp(a)),
+            (TagSort::Semver, reverse) => tags.sort_by(|a, b| compare_versions(a, b, reverse)),
+        }
+        if let Some(limit) = self.limit {
+            tags.truncate(limit);
+        }
+        Ok(tags)
+    }
+}
+
+/// Regular expression matching the whole tag against a glob
+fn glob_regex(glob: &str) -> crate::Result<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex_lite::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).context(error::InvalidTagPatternSnafu { pattern: glob })
+}
+
+/// Parse a tag as a semantic version, allowing a leading `v` and missing minor or patch versions
+fn parse_version(tag: &str) -> Option<Version> {
+    let version = tag.strip_prefix('v').unwrap_or(tag);
+    // Only the core of the version may be shortened, pre-release and build metadata follow it
+    let end = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(end);
+    let padding = match core.matches('.').count() {
+        0 => ".0.0",
+        1 => ".0",
+        _ => "",
+    };
+    Version::parse(&format!("{core}{padding}{rest}")).ok()
+}
+
+/// Order tags by version, descending when reversed, with the tags that are not versions after
+/// them in lexical order
+fn compare_versions(a: &str, b: &str, reverse: bool) -> Ordering {
+    match (parse_version(a), parse_version(b)) {
+        (Some(x), Some(y)) if reverse => y.cmp(&x).then_with(|| b.cmp(a)),
+        (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
 
 /// Represents a single repository in a registry.
 ///
@@ -27,13 +136,285 @@ impl Repository {
         &self.name
     }
 
-    /// List all the tags in this repository.
-    pub async fn tags(&self) -> crate::Result<Vec<String>> {
-        self.registry.get_tags(self.name.as_str()).await
+    /// List all the tags in this repository, filtered, sorted and limited by the options.
+    pub async fn tags(&self, options: &TagOptions) -> crate::Result<Vec<String>> {
+        options.apply(self.registry.get_tags(self.name.as_str()).await?)
+    }
+
+    /// List the tags in this repository that sort after `last`, only a single page of at most
+    /// `limit` tags is fetched when a limit is provided.
+    pub async fn tags_page(&self, limit: Option<usize>, last: Option<&str>) -> crate::Result<Page> {
+        self.registry
+            .get_tags_page(self.name.as_str(), limit, last)
+            .await
+    }
+
+    /// Point a tag at the manifest of another reference in this repository. The manifest is
+    /// pushed as fetched so it keeps its digest, and no blobs are transferred.
+    pub async fn tag(&self, reference: &str, tag: &str) -> crate::Result<Layer> {
+        let (bytes, _, media_type) = self
+            .registry
+            .fetch_manifest_raw(&self.name, reference)
+            .await?;
+        self.registry
+            .push_manifest_raw(&media_type, &self.name, tag, bytes, None)
+            .await
     }
 
     /// Delete a tag in this repository.
     pub async fn delete_tag(&self, tag: &str) -> crate::Result<()> {
         self.registry.delete_tag(&self.name, tag).await
     }
+
+    /// Delete a manifest in this repository by its digest, along with every tag pointing at it.
+    pub async fn delete_manifest(&self, digest: &str) -> crate::Result<()> {
+        self.registry
+            .delete_manifest_by_digest(&self.name, digest)
+            .await
+    }
+
+    /// Manifests of this repository that no tag references, directly or through an index.
+    ///
+    /// The distribution api can not list untagged manifests, so the only ones found are those
+    /// known through the referrers tag schema: tags named after the digest of a subject (i.e.
+    /// `sha256-<hex>`) pointing at an index of the artifacts referring to it. Once no other tag
+    /// references the subject, the subject, its artifacts and the index of them are orphaned.
+    pub async fn orphaned_manifests(&self) -> crate::Result<Vec<String>> {
+        let (referrers, roots): (Vec<String>, Vec<String>) = self
+            .tags(&TagOptions::default())
+            .await?
+            .into_iter()
+            .partition(|x| Self::referrers_subject(x).is_some());
+        let mut reachable = HashSet::new();
+        for tag in roots.iter() {
+            self.reachable(tag, &mut reachable).await?;
+        }
+        // Subject, digest and artifacts of every referrers index
+        let mut indexes = Vec::new();
+        for tag in referrers.iter() {
+            let subject = Self::referrers_subject(tag).unwrap();
+            let (bytes, digest, media_type) =
+                self.registry.fetch_manifest_raw(&self.name, tag).await?;
+            let Manifest::Index(index) = Manifest::from_slice(&bytes, &media_type)? else {
+                continue;
+            };
+            let artifacts: Vec<String> = index
+                .manifests()
+                .iter()
+                .map(|x| x.digest().to_string())
+                .collect();
+            indexes.push((subject, digest, artifacts));
+        }
+        // The artifacts of a reachable subject are reachable, and so are the artifacts referring
+        // to them in turn, i.e. the signature of an SBOM of a tagged image
+        loop {
+            let mut changed = false;
+            for (subject, digest, _) in indexes.iter() {
+                if reachable.contains(subject) && !reachable.contains(digest) {
+                    self.reachable(digest, &mut reachable).await?;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let mut orphaned = BTreeSet::new();
+        for (subject, digest, artifacts) in indexes {
+            if reachable.contains(&subject) {
+                continue;
+            }
+            debug!(target: "repository", "{subject} is not referenced by any tag, orphaning its referrers");
+            if self.registry.manifest_exists(&self.name, &subject).await? {
+                orphaned.insert(subject);
+            }
+            orphaned.insert(digest);
+            orphaned.extend(artifacts);
+        }
+        Ok(orphaned
+            .into_iter()
+            .filter(|x| !reachable.contains(x))
+            .collect())
+    }
+
+    /// Digest of the subject a referrers tag schema tag (i.e. `sha256-<hex>`) is named after
+    fn referrers_subject(tag: &str) -> Option<String> {
+        let (algorithm, hex) = tag.split_once('-')?;
+        (matches!(algorithm, "sha256" | "sha512")
+            && !hex.is_empty()
+            && hex.chars().all(|x| x.is_ascii_hexdigit()))
+        .then(|| format!("{algorithm}:{hex}"))
+    }
+
+    /// Add the digest of the manifest the reference points to to the reachable manifests, along
+    /// with the manifests of an index, recursively
+    async fn reachable(
+        &self,
+        reference: &str,
+        reachable: &mut HashSet<String>,
+    ) -> crate::Result<()> {
+        let mut pending = vec![reference.to_string()];
+        while let Some(reference) = pending.pop() {
+            let (bytes, digest, media_type) = self
+                .registry
+                .fetch_manifest_raw(&self.name, &reference)
+                .await?;
+            reachable.insert(digest);
+            if let Manifest::Index(index) = Manifest::from_slice(&bytes, &media_type)? {
+                for manifest in index.manifests() {
+                    if reachable.insert(manifest.digest().to_string())
+                        && matches!(
+                            manifest.media_type(),
+                            MediaType::ImageIndex | MediaType::DockerManifestList
+                        )
+                    {
+                        pending.push(manifest.digest().to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete every tag in this repository, at most `jobs` at a time, returning the deleted tags.
+    /// Deletion stops at the first tag that fails to delete.
+    pub async fn delete_all_tags(&self, jobs: usize) -> crate::Result<Vec<String>> {
+        let tags = self.tags(&TagOptions::default()).await?;
+        let mut deleted: Vec<String> = futures::stream::iter(tags.into_iter().map(|tag| async {
+            self.delete_tag(&tag).await?;
+            Ok::<_, error::Error>(tag)
+        }))
+        .buffer_unordered(jobs.max(1))
+        .try_collect()
+        .await?;
+        deleted.sort();
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use serde_json::json;
+
+    use super::{Repository, TagOptions, TagSort};
+    use crate::models::MediaType;
+    use crate::testing::MockRegistryClient;
+    use crate::uri::{Algorithm, RegistryUri};
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let all = tags(&["v1.0", "v1.10.2", "v10.0", "v2.0", "latest"]);
+        let options = TagOptions::builder().filter("v1.*").build();
+        assert_eq!(
+            options.apply(all.clone()).unwrap(),
+            tags(&["v1.0", "v1.10.2"])
+        );
+        let options = TagOptions::builder().regex("^v[0-9]+\\.0$").build();
+        assert_eq!(
+            options.apply(all.clone()).unwrap(),
+            tags(&["v1.0", "v10.0", "v2.0"])
+        );
+        let options = TagOptions::builder().regex("(").build();
+        assert!(options.apply(all).is_err());
+    }
+
+    #[test]
+    fn test_tag_semver_sort() {
+        let all = tags(&["latest", "v1.10.0", "1.2", "v1.9.1", "v2", "v1.10.0-rc.1"]);
+        let options = TagOptions::builder().sort(TagSort::Semver).build();
+        assert_eq!(
+            options.apply(all.clone()).unwrap(),
+            tags(&["1.2", "v1.9.1", "v1.10.0-rc.1", "v1.10.0", "v2", "latest"])
+        );
+        let options = TagOptions::builder()
+            .sort(TagSort::Semver)
+            .reverse(true)
+            .limit(2)
+            .build();
+        assert_eq!(options.apply(all).unwrap(), tags(&["v2", "v1.10.0"]));
+    }
+
+    /// Insert an artifact manifest, referring to the subject when given, returning its digest
+    fn artifact(client: &MockRegistryClient, name: &str, subject: Option<&str>) -> String {
+        let mut manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/example",
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2,
+            },
+            "layers": [],
+            "annotations": { "name": name },
+        });
+        if let Some(subject) = subject {
+            manifest["subject"] = json!({
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": subject,
+                "size": 0,
+            });
+        }
+        let content = serde_json::to_vec(&manifest).unwrap();
+        let digest = Algorithm::Sha256.digest(&content);
+        client.insert_manifest("app", &digest, &MediaType::Manifest, content)
+    }
+
+    /// Tag an index of the artifacts with the referrers tag schema tag of the subject
+    fn referrers(client: &MockRegistryClient, subject: &str, artifacts: &[&str]) -> String {
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": artifacts
+                .iter()
+                .map(|x| json!({
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": x,
+                    "size": 0,
+                }))
+                .collect::<Vec<_>>(),
+        });
+        client.insert_manifest(
+            "app",
+            &subject.replace(':', "-"),
+            &MediaType::ImageIndex,
+            serde_json::to_vec(&index).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_referrer_of_referrer() {
+        let client = MockRegistryClient::new();
+        let registry = client.registry(&RegistryUri::from_str("mock.local").unwrap());
+        let image = artifact(&client, "image", None);
+        client.insert_manifest(
+            "app",
+            "v1",
+            &MediaType::Manifest,
+            client.manifest("app", &image).unwrap(),
+        );
+        // A signature of an SBOM of the tagged image stays reachable
+        let sbom = artifact(&client, "sbom", Some(&image));
+        let signature = artifact(&client, "signature", Some(&sbom));
+        referrers(&client, &image, &[&sbom]);
+        referrers(&client, &sbom, &[&signature]);
+        // The referrers of an untagged image are orphaned with it
+        let untagged = artifact(&client, "untagged", None);
+        let untagged_sbom = artifact(&client, "untagged sbom", Some(&untagged));
+        let index = referrers(&client, &untagged, &[&untagged_sbom]);
+
+        let orphaned = Repository::new(&registry, "app")
+            .orphaned_manifests()
+            .await
+            .unwrap();
+        let mut expected = vec![untagged, untagged_sbom, index];
+        expected.sort();
+        assert_eq!(orphaned, expected);
+    }
 }
@@ -0,0 +1,106 @@
+use std::time::SystemTime;
+
+use aws_config::{BehaviorVersion, Region};
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{SignableBody, SignableRequest, SigningSettings, sign};
+use aws_sigv4::sign::v4;
+use reqwest::Request;
+use reqwest::header::{HeaderName, HeaderValue};
+use snafu::OptionExt;
+
+use crate::options::SigV4Options;
+use crate::{Result, error};
+
+/// Signs registry requests with AWS SigV4 using credentials from the AWS environment.
+#[derive(Debug, Clone)]
+pub struct SigV4Signer {
+    credentials: SharedCredentialsProvider,
+    region: String,
+    service: String,
+}
+
+impl SigV4Signer {
+    /// Resolve the credentials and region from the AWS environment
+    pub async fn new(options: &SigV4Options) -> Result<Self> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = options.region.clone() {
+            loader = loader.region(Region::new(region));
+        }
+        let sdk_config = loader.load().await;
+        let credentials = sdk_config
+            .credentials_provider()
+            .context(error::SigningSnafu {
+                reason: "no aws credentials are available",
+            })?;
+        let region = sdk_config.region().context(error::SigningSnafu {
+            reason: "no aws region is configured",
+        })?;
+        Ok(Self {
+            credentials,
+            region: region.to_string(),
+            service: options.service.clone(),
+        })
+    }
+
+    /// Add the SigV4 authorization headers to the request
+    pub async fn sign(&self, request: &mut Request) -> Result<()> {
+        let credentials =
+            self.credentials
+                .provide_credentials()
+                .await
+                .map_err(|e| error::Error::Signing {
+                    reason: e.to_string(),
+                })?;
+        let identity = credentials.into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(&self.service)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|e| error::Error::Signing {
+                reason: e.to_string(),
+            })?
+            .into();
+        let headers: Vec<(&str, &str)> = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| value.to_str().ok().map(|x| (name.as_str(), x)))
+            .collect();
+        // Streamed bodies can not be hashed up front so their payload is left unsigned
+        let body = match request.body() {
+            Some(body) => body
+                .as_bytes()
+                .map(SignableBody::Bytes)
+                .unwrap_or(SignableBody::UnsignedPayload),
+            None => SignableBody::Bytes(&[]),
+        };
+        let signable = SignableRequest::new(
+            request.method().as_str(),
+            request.url().as_str(),
+            headers.into_iter(),
+            body,
+        )
+        .map_err(|e| error::Error::Signing {
+            reason: e.to_string(),
+        })?;
+        let (instructions, _) = sign(signable, &params)
+            .map_err(|e| error::Error::Signing {
+                reason: e.to_string(),
+            })?
+            .into_parts();
+        for (name, value) in instructions.headers() {
+            let name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|e| error::Error::Signing {
+                    reason: e.to_string(),
+                })?;
+            let value = HeaderValue::from_str(value).map_err(|e| error::Error::Signing {
+                reason: e.to_string(),
+            })?;
+            request.headers_mut().insert(name, value);
+        }
+        trace!(target: "registry", "signed {} {} for {}", request.method(), request.url(), self.service);
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use home::home_dir;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt};
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+
+use crate::error;
+use crate::image::Image;
+use crate::layer::Layer;
+use crate::manifest::Manifest;
+use crate::models::Platform;
+use crate::uri::{Reference, Uri};
+
+/// Location of the default store relative to the home directory.
+const STORE_DIR: &str = ".cache/ocilot";
+
+/// A content addressed store of blobs and manifests on the local filesystem.
+///
+/// Content is laid out similar to an OCI image layout, blobs are stored under
+/// `blobs/<algorithm>/<hex>` and tags are stored under `refs/<registry>/<repository>/<tag>`
+/// with the digest of the manifest they point to as the file contents.
+#[derive(Debug, Clone)]
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Store {
+    /// Create a store rooted at the provided directory
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Open the default store located at `~/.cache/ocilot`
+    pub fn open_default() -> crate::Result<Self> {
+        let home = home_dir().context(error::StoreUnavailableSnafu)?;
+        Ok(Self::new(home.join(STORE_DIR)))
+    }
+
+    /// Root directory of the store
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path a blob with the given digest is stored at
+    pub fn blob_path(&self, digest: &str) -> PathBuf {
+        let (algorithm, value) = digest.split_once(':').unwrap_or(("sha256", digest));
+        self.root.join("blobs").join(algorithm).join(value)
+    }
+
+    /// Path a tag reference is stored at
+    fn ref_path(&self, registry: &str, repository: &str, tag: &str) -> PathBuf {
+        self.root
+            .join("refs")
+            .join(registry.replace(':', "_"))
+            .join(repository)
+            .join(tag)
+    }
+
+    /// Check if the store has a blob with the given digest
+    pub fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest).is_file()
+    }
+
+    /// Read the full contents of a blob
+    pub async fn read_blob(&self, digest: &str) -> crate::Result<Option<Bytes>> {
+        if !self.has_blob(digest) {
+            return Ok(None);
+        }
+        let bytes = tokio::fs::read(self.blob_path(digest))
+            .await
+            .context(error::StoreSnafu)?;
+        Ok(Some(Bytes::from(bytes)))
+    }
+
+    /// Open a blob for reading, returning the file and its size. Opening a blob marks it as
+    /// recently used so pruning by age keeps it.
+    pub async fn open_blob(&self, digest: &str) -> crate::Result<Option<(File, u64)>> {
+        if !self.has_blob(digest) {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(self.blob_path(digest)).context(error::StoreSnafu)?;
+        // Failing to refresh the time only makes the blob a candidate for pruning sooner
+        let _ = file.set_modified(SystemTime::now());
+        let file = File::from_std(file);
+        let size = file.metadata().await.context(error::StoreSnafu)?.len();
+        Ok(Some((file, size)))
+    }
+
+    /// Write the full contents of a blob into the store
+    pub async fn write_blob(&self, digest: &str, bytes: &[u8]) -> crate::Result<()> {
+        let path = self.blob_path(digest);
+        let parent = path.parent().unwrap();
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context(error::StoreSnafu)?;
+        let mut file = NamedTempFile::new_in(parent).context(error::StoreSnafu)?;
+        file.write_all(bytes).context(error::StoreSnafu)?;
+        file.persist(path)
+            .map_err(|e| e.error)
+            .context(error::StoreSnafu)?;
+        Ok(())
+    }
+
+    /// Record that a tag in a repository points to the manifest with the given digest
+    pub async fn tag(
+        &self,
+        registry: &str,
+        repository: &str,
+        tag: &str,
+        digest: &str,
+    ) -> crate::Result<()> {
+        let path = self.ref_path(registry, repository, tag);
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .context(error::StoreSnafu)?;
+        tokio::fs::write(path, digest)
+            .await
+            .context(error::StoreSnafu)
+    }
+
+    /// Resolve a reference (tag or digest) to the digest of the manifest stored for it
+    pub async fn resolve(
+        &self,
+        registry: &str,
+        repository: &str,
+        reference: &str,
+    ) -> crate::Result<Option<String>> {
+        if reference.contains(':') {
+            return Ok(self.has_blob(reference).then(|| reference.to_string()));
+        }
+        let path = self.ref_path(registry, repository, reference);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let digest = tokio::fs::read_to_string(path)
+            .await
+            .context(error::StoreSnafu)?;
+        Ok(Some(digest.trim().to_string()))
+    }
+
+    /// Path the upload checkpoint of a blob is stored at
+    fn checkpoint_path(&self, registry: &str, repository: &str, digest: &str) -> PathBuf {
+        self.root
+            .join("uploads")
+            .join(registry.replace(':', "_"))
+            .join(repository)
+            .join(format!("{}.json", digest.replace(':', "-")))
+    }
+
+    /// Load the checkpoint of an interrupted upload of a blob, if there is one
+    pub fn checkpoint(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+    ) -> Option<UploadCheckpoint> {
+        let contents =
+            std::fs::read_to_string(self.checkpoint_path(registry, repository, digest)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Persist the checkpoint of an in progress upload of a blob.
+    ///
+    /// This uses blocking io as it is called while polling a blob upload.
+    pub fn save_checkpoint(
+        &self,
+        registry: &str,
+        repository: &str,
+        digest: &str,
+        checkpoint: &UploadCheckpoint,
+    ) -> crate::Result<()> {
+        let path = self.checkpoint_path(registry, repository, digest);
+        let parent = path.parent().unwrap();
+        std::fs::create_dir_all(parent).context(error::StoreSnafu)?;
+        let mut file = NamedTempFile::new_in(parent).context(error::StoreSnafu)?;
+        serde_json::to_writer(&mut file, checkpoint).context(error::SerializeSnafu)?;
+        file.persist(path)
+            .map_err(|e| e.error)
+            .context(error::StoreSnafu)?;
+        Ok(())
+    }
+
+    /// Remove the checkpoint of a blob upload once it is finished or can no longer be resumed
+    pub fn clear_checkpoint(&self, registry: &str, repository: &str, digest: &str) {
+        let _ = std::fs::remove_file(self.checkpoint_path(registry, repository, digest));
+    }
+
+    /// Populate the store with the manifests and blobs of the images referenced by each uri.
+    ///
+    /// Indexes are expanded into their images, limited to the provided platforms if any are given.
+    /// Blobs shared between images are only fetched once and at most `jobs` blobs are fetched
+    /// concurrently. Returns the number of blobs that were fetched.
+    pub async fn prefetch(
+        &self,
+        uris: &[Uri],
+        platforms: &[Platform],
+        jobs: usize,
+    ) -> crate::Result<usize> {
+        let mut blobs: HashMap<String, (Uri, Layer)> = HashMap::new();
+        for uri in uris {
+            let mut uri = uri.clone();
+            uri.set_store(Some(self.clone()));
+            let images = match Manifest::fetch(&uri).await? {
+                // A single image has no platform to filter by
+                Manifest::Image(image) => vec![*image],
+                Manifest::Index(index) => {
+                    let mut images = Vec::new();
+                    for manifest in index.manifests().iter() {
+                        if !platforms.is_empty()
+                            && !manifest.platform().is_some_and(|platform| {
+                                platforms.iter().any(|x| x.matches(&platform))
+                            })
+                        {
+                            continue;
+                        }
+                        let image_uri = Uri::builder()
+                            .registry(uri.registry().clone())
+                            .repository(uri.repository())
+                            .reference(Reference::from_str(manifest.digest())?)
+                            .build();
+                        images.push(Image::fetch(&image_uri, manifest.platform()).await?);
+                    }
+                    images
+                }
+            };
+            for image in images.iter() {
+                for layer in std::iter::once(image.config()).chain(image.layers().iter()) {
+                    if !self.has_blob(layer.digest()) {
+                        blobs
+                            .entry(layer.digest().to_string())
+                            .or_insert_with(|| (uri.clone(), layer.clone()));
+                    }
+                }
+            }
+        }
+        let count = blobs.len();
+        debug!(target: "store", "prefetching {count} blobs");
+        futures::stream::iter(blobs.into_values())
+            .map(|(uri, layer)| async move {
+                let mut reader = layer.open(&uri).await?;
+                tokio::io::copy(&mut reader, &mut tokio::io::sink())
+                    .await
+                    .context(error::LayerCopySnafu)?;
+                trace!(target: "store", "prefetched {}", layer.digest());
+                Ok(())
+            })
+            .buffer_unordered(jobs.max(1))
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok(count)
+    }
+
+    /// Remove blobs that were not used within `older_than`, or every blob if no age is given,
+    /// along with the tags and upload checkpoints that can no longer be used.
+    ///
+    /// This uses blocking io as it walks the whole store.
+    pub fn prune(&self, older_than: Option<Duration>) -> crate::Result<Pruned> {
+        let mut pruned = Pruned::default();
+        let now = SystemTime::now();
+        for path in files(&self.root.join("blobs"))? {
+            let metadata = std::fs::metadata(&path).context(error::StoreSnafu)?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|x| now.duration_since(x).ok())
+                .unwrap_or_default();
+            if older_than.is_some_and(|x| age < x) {
+                continue;
+            }
+            std::fs::remove_file(&path).context(error::StoreSnafu)?;
+            trace!(target: "store", "pruned {}", path.display());
+            pruned.blobs += 1;
+            pruned.bytes += metadata.len();
+        }
+        // Tags are only useful while the manifest they point to is stored
+        for path in files(&self.root.join("refs"))? {
+            let digest = std::fs::read_to_string(&path).context(error::StoreSnafu)?;
+            if !self.has_blob(digest.trim()) {
+                std::fs::remove_file(&path).context(error::StoreSnafu)?;
+            }
+        }
+        if older_than.is_none() {
+            for path in files(&self.root.join("uploads"))? {
+                std::fs::remove_file(&path).context(error::StoreSnafu)?;
+            }
+        }
+        debug!(target: "store", "pruned {} blobs ({} bytes)", pruned.blobs, pruned.bytes);
+        Ok(pruned)
+    }
+
+    /// Wrap a blob stream so that its contents are written into the store as it is read.
+    ///
+    /// The blob is only committed to the store once the stream has completed, or the expected
+    /// size was read, and the content matches the expected digest. Any failure to write only
+    /// disables caching for the stream.
+    pub(crate) fn tee<S>(&self, digest: &str, size: Option<u64>, inner: S) -> Tee<S> {
+        let path = self.blob_path(digest);
+        let file = path
+            .parent()
+            .and_then(|parent| std::fs::create_dir_all(parent).ok().map(|_| parent))
+            .and_then(|parent| NamedTempFile::new_in(parent).ok());
+        Tee {
+            inner,
+            file: digest.starts_with("sha256:").then_some(file).flatten(),
+            hasher: Sha256::new(),
+            digest: digest.to_string(),
+            size,
+            written: 0,
+            path,
+        }
+    }
+}
+
+/// Content removed from the store by [`Store::prune`].
+#[derive(Debug, Clone, Default)]
+pub struct Pruned {
+    /// Number of blobs removed
+    pub blobs: usize,
+    /// Total size of the blobs removed
+    pub bytes: u64,
+}
+
+/// All files below a directory, a missing directory has none
+fn files(dir: &Path) -> crate::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    if !dir.is_dir() {
+        return Ok(found);
+    }
+    for entry in std::fs::read_dir(dir).context(error::StoreSnafu)? {
+        let path = entry.context(error::StoreSnafu)?.path();
+        if path.is_dir() {
+            found.extend(files(&path)?);
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Progress of a blob upload session persisted so it can be resumed by a later invocation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadCheckpoint {
+    /// Upload url provided by the registry for the session
+    pub upload_url: String,
+    /// Number of bytes the registry has committed for the session
+    pub offset: usize,
+}
+
+/// Stream adapter that copies blob content into the store.
+pub(crate) struct Tee<S> {
+    inner: S,
+    file: Option<NamedTempFile>,
+    hasher: Sha256,
+    digest: String,
+    /// Size of the blob, readers often stop once they read it instead of reading to the end
+    size: Option<u64>,
+    written: u64,
+    path: PathBuf,
+}
+
+impl<S> Tee<S> {
+    /// Commit the blob to the store if its content matches the digest
+    fn commit(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        let digest = format!(
+            "sha256:{}",
+            base16::encode_lower(self.hasher.clone().finalize().as_slice())
+        );
+        if digest != self.digest {
+            debug!(target: "store", "not storing blob, expected {} but got {digest}", self.digest);
+        } else if let Err(e) = file.persist(&self.path) {
+            debug!(target: "store", "failed to store blob {}: {}", self.digest, e.error);
+        } else {
+            trace!(target: "store", "stored blob {}", self.digest);
+        }
+    }
+}
+
+impl<S> Stream for Tee<S>
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin,
+{
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(bytes))) => {
+                // Blobs are written with blocking io since writes to the local disk are small
+                // relative to network reads.
+                if let Some(file) = this.file.as_mut() {
+                    this.hasher.update(bytes);
+                    this.written += bytes.len() as u64;
+                    if let Err(e) = file.write_all(bytes) {
+                        debug!(target: "store", "disabling store write for {}: {e}", this.digest);
+                        this.file = None;
+                    } else if this.size.is_some_and(|x| this.written >= x) {
+                        this.commit();
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.file = None,
+            Poll::Ready(None) => this.commit(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{StreamExt, TryStreamExt};
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test]
+    async fn test_store_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = super::Store::new(dir.path());
+        let content = b"hello world";
+        let digest = format!(
+            "sha256:{}",
+            base16::encode_lower(Sha256::digest(content).as_slice())
+        );
+        assert!(!store.has_blob(&digest));
+        store.write_blob(&digest, content).await.unwrap();
+        assert_eq!(
+            store.read_blob(&digest).await.unwrap().unwrap().as_ref(),
+            content
+        );
+        store
+            .tag("localhost:5000", "ocilot/test", "latest", &digest)
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .resolve("localhost:5000", "ocilot/test", "latest")
+                .await
+                .unwrap(),
+            Some(digest.clone())
+        );
+        assert_eq!(
+            store
+                .resolve("localhost:5000", "ocilot/test", "missing")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = super::Store::new(dir.path());
+        let content = b"some blob content";
+        let digest = format!(
+            "sha256:{}",
+            base16::encode_lower(Sha256::digest(content).as_slice())
+        );
+        let chunks = vec![
+            Ok(bytes::Bytes::from_static(&content[..4])),
+            Ok(bytes::Bytes::from_static(&content[4..])),
+        ];
+        let tee = store.tee(&digest, None, futures::stream::iter(chunks));
+        let read: Vec<_> = tee.try_collect().await.unwrap();
+        assert_eq!(read.concat(), content);
+        assert!(store.has_blob(&digest));
+
+        // Readers that stop once the size was read still store the blob
+        let store = super::Store::new(dir.path().join("sized"));
+        let chunks = vec![
+            Ok(bytes::Bytes::from_static(&content[..4])),
+            Ok(bytes::Bytes::from_static(&content[4..])),
+        ];
+        let stream = futures::stream::iter(chunks).chain(futures::stream::pending());
+        let tee = store.tee(&digest, Some(content.len() as u64), stream);
+        let read: Vec<_> = tee.take(2).try_collect().await.unwrap();
+        assert_eq!(read.concat(), content);
+        assert!(store.has_blob(&digest));
+
+        // Content that does not match the digest is never stored
+        let bad = "sha256:0000";
+        let tee = store.tee(
+            bad,
+            None,
+            futures::stream::iter(vec![Ok(bytes::Bytes::from_static(content))]),
+        );
+        let _: Vec<_> = tee.try_collect().await.unwrap();
+        assert!(!store.has_blob(bad));
+    }
+
+    #[tokio::test]
+    async fn test_store_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = super::Store::new(dir.path());
+        let content = b"cached blob";
+        let digest = format!(
+            "sha256:{}",
+            base16::encode_lower(Sha256::digest(content).as_slice())
+        );
+        store.write_blob(&digest, content).await.unwrap();
+        store
+            .tag("localhost:5000", "ocilot/test", "latest", &digest)
+            .await
+            .unwrap();
+
+        // Recently used blobs are kept when pruning by age
+        let pruned = store
+            .prune(Some(std::time::Duration::from_secs(3600)))
+            .unwrap();
+        assert_eq!(pruned.blobs, 0);
+        assert!(store.has_blob(&digest));
+
+        let pruned = store.prune(None).unwrap();
+        assert_eq!(pruned.blobs, 1);
+        assert_eq!(pruned.bytes, content.len() as u64);
+        assert!(!store.has_blob(&digest));
+        assert_eq!(
+            store
+                .resolve("localhost:5000", "ocilot/test", "latest")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,784 @@
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use reqwest::header::{
+    CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderName, LINK, LOCATION, RANGE,
+};
+use reqwest::{Body, Method, Response, ResponseBuilderExt, StatusCode};
+use serde_json::{Value, json};
+use snafu::ResultExt;
+use tokio::time::Instant;
+use url::Url;
+
+use crate::client::{RegistryClient, RegistryClientImpl, upload_url};
+use crate::models::MediaType;
+use crate::registry::Registry;
+use crate::uri::{Algorithm, RegistryUri};
+use crate::{Result, error};
+
+const DOCKER_CONTENT_DIGEST: HeaderName = HeaderName::from_static("docker-content-digest");
+const OCI_SUBJECT: HeaderName = HeaderName::from_static("oci-subject");
+const FILTERS_APPLIED: HeaderName = HeaderName::from_static("oci-filters-applied");
+const IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Registry client keeping blobs and manifests in memory, to test code built on this crate without
+/// a registry. Clones share their content, so a test can inspect what went through a registry
+/// created with [`MockRegistryClient::registry`]. Like a registry following the distribution
+/// spec, it rejects chunks out of order and manifests referring to content it does not have.
+#[derive(Debug, Clone, Default)]
+pub struct MockRegistryClient {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Blobs keyed by repository and digest
+    blobs: HashMap<(String, String), Bytes>,
+    /// Manifests and their media type keyed by repository and tag or digest
+    manifests: HashMap<(String, String), (Bytes, String)>,
+    /// Content received so far by upload session
+    uploads: HashMap<String, (String, Vec<u8>)>,
+    sessions: usize,
+    /// Method and url of every request in the order they were sent
+    requests: Vec<(Method, String)>,
+    /// Status to respond with to a request with the method after as many others were handled
+    failures: Vec<(Method, StatusCode, usize)>,
+}
+
+impl MockRegistryClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry for the uri that sends every request to this client
+    pub fn registry(&self, uri: &RegistryUri) -> Registry {
+        Registry::with_client(uri, self.clone().into())
+    }
+
+    /// Add a blob to a repository, returning its digest
+    pub fn insert_blob(&self, repository: &str, content: impl Into<Bytes>) -> String {
+        let content = content.into();
+        let digest = Algorithm::Sha256.digest(&content);
+        self.state
+            .lock()
+            .unwrap()
+            .blobs
+            .insert((repository.to_string(), digest.clone()), content);
+        digest
+    }
+
+    /// Add a manifest to a repository, tagged when the reference is a tag, returning its digest
+    pub fn insert_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        media_type: &MediaType,
+        content: impl Into<Bytes>,
+    ) -> String {
+        self.state.lock().unwrap().insert_manifest(
+            repository,
+            reference,
+            media_type.to_string(),
+            content.into(),
+        )
+    }
+
+    /// Content of a blob in a repository
+    pub fn blob(&self, repository: &str, digest: &str) -> Option<Bytes> {
+        let key = (repository.to_string(), digest.to_string());
+        self.state.lock().unwrap().blobs.get(&key).cloned()
+    }
+
+    /// Content of the manifest a tag or digest points to in a repository
+    pub fn manifest(&self, repository: &str, reference: &str) -> Option<Bytes> {
+        let key = (repository.to_string(), reference.to_string());
+        let state = self.state.lock().unwrap();
+        state
+            .manifests
+            .get(&key)
+            .map(|(content, _)| content.clone())
+    }
+
+    /// Tags of a repository in lexical order
+    pub fn tags(&self, repository: &str) -> Vec<String> {
+        self.state.lock().unwrap().tags(repository)
+    }
+
+    /// Urls of the requests sent with the method, in the order they were sent
+    pub fn requests(&self, method: &Method) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .requests
+            .iter()
+            .filter(|(x, _)| x == method)
+            .map(|(_, url)| url.clone())
+            .collect()
+    }
+
+    /// Respond to a request with the method with the error status instead of handling it, once
+    /// the given number of them were handled, i.e. to test how failures of a registry are
+    /// recovered from
+    pub fn fail(&self, method: Method, status: StatusCode, after: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.push((method, status, after));
+    }
+
+    /// Record a request, returning the status to fail it with if one was set up
+    fn record(&self, method: Method, url: &Url) -> Option<StatusCode> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push((method.clone(), url.to_string()));
+        let position = state.failures.iter().position(|(x, ..)| *x == method)?;
+        match &mut state.failures[position] {
+            (_, _, 0) => Some(state.failures.remove(position).1),
+            (_, _, after) => {
+                *after -= 1;
+                None
+            }
+        }
+    }
+
+    /// Response to a GET or HEAD request of a manifest, not modified when the etag matches
+    fn manifest_response(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+        etag: Option<&str>,
+        head: bool,
+    ) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/manifests/{reference}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(if head { Method::HEAD } else { Method::GET }, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let key = (repository.to_string(), reference.to_string());
+        let Some((content, media_type)) = self.state.lock().unwrap().manifests.get(&key).cloned()
+        else {
+            return failure(&url, StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN");
+        };
+        let digest = Algorithm::Sha256.digest(&content);
+        let tag = format!("\"{digest}\"");
+        let headers = [
+            (CONTENT_LENGTH, content.len().to_string()),
+            (DOCKER_CONTENT_DIGEST, digest),
+            (CONTENT_TYPE, media_type),
+            (ETAG, tag.clone()),
+        ];
+        if etag == Some(tag.as_str()) {
+            return respond(&url, StatusCode::NOT_MODIFIED, &headers[1..], Bytes::new());
+        }
+        let body = if head { Bytes::new() } else { content };
+        respond(&url, StatusCode::OK, &headers, body)
+    }
+}
+
+impl From<MockRegistryClient> for RegistryClient {
+    fn from(client: MockRegistryClient) -> Self {
+        Self::from_impl(Arc::new(client))
+    }
+}
+
+impl State {
+    /// Store a manifest by its digest, and by the reference when it is a tag
+    fn insert_manifest(
+        &mut self,
+        repository: &str,
+        reference: &str,
+        media_type: String,
+        content: Bytes,
+    ) -> String {
+        let digest = Algorithm::Sha256.digest(&content);
+        let entry = (content, media_type);
+        if !reference.contains(':') {
+            self.manifests.insert(
+                (repository.to_string(), reference.to_string()),
+                entry.clone(),
+            );
+        }
+        self.manifests
+            .insert((repository.to_string(), digest.clone()), entry);
+        digest
+    }
+
+    fn tags(&self, repository: &str) -> Vec<String> {
+        self.manifests
+            .keys()
+            .filter(|(name, reference)| name == repository && !reference.contains(':'))
+            .map(|(_, reference)| reference.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn repositories(&self) -> Vec<String> {
+        self.blobs
+            .keys()
+            .chain(self.manifests.keys())
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Store the content of a finished upload if it matches the digest
+    fn commit(
+        &mut self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        content: Bytes,
+    ) -> Result<Response> {
+        let algorithm = digest.split_once(':').map(|(x, _)| x).unwrap_or_default();
+        if Algorithm::from_str(algorithm)
+            .map(|x| x.digest(&content))
+            .ok()
+            .as_deref()
+            != Some(digest)
+        {
+            return failure(uri, StatusCode::BAD_REQUEST, "DIGEST_INVALID");
+        }
+        self.blobs
+            .insert((repository.to_string(), digest.to_string()), content);
+        respond(
+            uri,
+            StatusCode::CREATED,
+            &[
+                (LOCATION, format!("/v2/{repository}/blobs/{digest}")),
+                (DOCKER_CONTENT_DIGEST, digest.to_string()),
+            ],
+            Bytes::new(),
+        )
+    }
+
+    /// Response with the progress of an upload session
+    fn progress(&self, uri: &Url, status: StatusCode, session: &str) -> Result<Response> {
+        let Some((repository, content)) = self.uploads.get(session) else {
+            return failure(uri, StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN");
+        };
+        respond(
+            uri,
+            status,
+            &[
+                // Registries keep the state of the upload in the query of its location
+                (
+                    LOCATION,
+                    format!(
+                        "/v2/{repository}/blobs/uploads/{session}?_state={}",
+                        content.len()
+                    ),
+                ),
+                (RANGE, format!("0-{}", content.len().saturating_sub(1))),
+            ],
+            Bytes::new(),
+        )
+    }
+}
+
+/// Build a response to a request for the url
+fn respond(
+    url: &Url,
+    status: StatusCode,
+    headers: &[(HeaderName, String)],
+    body: Bytes,
+) -> Result<Response> {
+    let mut builder = http::Response::builder().status(status).url(url.clone());
+    // Responses to HEAD requests have the length of the content they leave out
+    if !headers.iter().any(|(name, _)| *name == CONTENT_LENGTH) {
+        builder = builder.header(CONTENT_LENGTH, body.len());
+    }
+    for (name, value) in headers {
+        builder = builder.header(name, value.as_str());
+    }
+    Ok(builder
+        .body(body)
+        .expect("the headers of mock responses are valid")
+        .into())
+}
+
+/// Error response with the body the distribution spec describes
+fn failure(url: &Url, status: StatusCode, code: &str) -> Result<Response> {
+    let body = json!({ "errors": [{ "code": code, "message": code.to_lowercase() }] });
+    respond(
+        url,
+        status,
+        &[(CONTENT_TYPE, "application/json".to_string())],
+        body.to_string().into(),
+    )
+}
+
+/// Read all of a request body, streamed or not
+async fn read_body(body: Body) -> Result<Bytes> {
+    Ok(body
+        .collect()
+        .await
+        .context(error::RequestSnafu)?
+        .to_bytes())
+}
+
+/// Id of an upload session from its url, the last segment of the path
+fn session(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut x| x.next_back())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[async_trait]
+impl RegistryClientImpl for MockRegistryClient {
+    async fn ping(&self, uri: &Url) -> Result<Response> {
+        let url = uri.join("/v2/").context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::GET, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        respond(&url, StatusCode::OK, &[], Bytes::from_static(b"{}"))
+    }
+
+    async fn catalog(&self, uri: &Url, deadline: Option<Instant>) -> Result<Response> {
+        self.list_next(uri, "/v2/_catalog", deadline).await
+    }
+
+    async fn get_tags(
+        &self,
+        uri: &Url,
+        repository: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Response> {
+        self.list_next(uri, &format!("/v2/{repository}/tags/list"), deadline)
+            .await
+    }
+
+    async fn list_next(
+        &self,
+        uri: &Url,
+        next: &str,
+        _deadline: Option<Instant>,
+    ) -> Result<Response> {
+        let url = uri.join(next).context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::GET, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let query = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        let state = self.state.lock().unwrap();
+        let (key, name, entries) = match url
+            .path()
+            .strip_prefix("/v2/")
+            .and_then(|x| x.strip_suffix("/tags/list"))
+        {
+            Some(repository) => ("tags", Some(repository), state.tags(repository)),
+            None => ("repositories", None, state.repositories()),
+        };
+        if name.is_some() && entries.is_empty() {
+            return failure(&url, StatusCode::NOT_FOUND, "NAME_UNKNOWN");
+        }
+        let after = entries
+            .into_iter()
+            .filter(|x| query.get("last").is_none_or(|last| x > last))
+            .collect::<Vec<_>>();
+        let size = query
+            .get("n")
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(after.len());
+        let page = &after[..size.min(after.len())];
+        let mut headers = Vec::new();
+        if let Some(last) = page.last().filter(|_| after.len() > size) {
+            headers.push((
+                LINK,
+                format!("<{}?n={size}&last={last}>; rel=\"next\"", url.path()),
+            ));
+        }
+        let mut body = json!({ key: page });
+        if let Some(name) = name {
+            body["name"] = json!(name);
+        }
+        respond(&url, StatusCode::OK, &headers, body.to_string().into())
+    }
+
+    async fn head_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/blobs/{digest}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::HEAD, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        match self.blob(repository, digest) {
+            Some(content) => respond(
+                &url,
+                StatusCode::OK,
+                &[
+                    (CONTENT_LENGTH, content.len().to_string()),
+                    (DOCKER_CONTENT_DIGEST, digest.to_string()),
+                ],
+                Bytes::new(),
+            ),
+            None => failure(&url, StatusCode::NOT_FOUND, "BLOB_UNKNOWN"),
+        }
+    }
+
+    async fn get_blob(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        range: Option<&str>,
+    ) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/blobs/{digest}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::GET, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let Some(content) = self.blob(repository, digest) else {
+            return failure(&url, StatusCode::NOT_FOUND, "BLOB_UNKNOWN");
+        };
+        let digest_header = (DOCKER_CONTENT_DIGEST, digest.to_string());
+        // Only the `bytes=start-` and `bytes=start-end` forms are served partially
+        let bounds = range
+            .and_then(|x| x.strip_prefix("bytes="))
+            .and_then(|x| x.split_once('-'))
+            .and_then(|(start, end)| {
+                let start = start.parse::<usize>().ok()?;
+                let end = match end {
+                    "" => content.len().checked_sub(1)?,
+                    end => end
+                        .parse::<usize>()
+                        .ok()?
+                        .min(content.len().checked_sub(1)?),
+                };
+                (start <= end).then_some((start, end))
+            });
+        match bounds {
+            Some((start, end)) => respond(
+                &url,
+                StatusCode::PARTIAL_CONTENT,
+                &[
+                    digest_header,
+                    (
+                        CONTENT_RANGE,
+                        format!("bytes {start}-{end}/{}", content.len()),
+                    ),
+                ],
+                content.slice(start..=end),
+            ),
+            None => respond(&url, StatusCode::OK, &[digest_header], content),
+        }
+    }
+
+    async fn del_blob(&self, uri: &Url, repository: &str, digest: &str) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/blobs/{digest}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::DELETE, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let key = (repository.to_string(), digest.to_string());
+        match self.state.lock().unwrap().blobs.remove(&key) {
+            Some(_) => respond(&url, StatusCode::ACCEPTED, &[], Bytes::new()),
+            None => failure(&url, StatusCode::NOT_FOUND, "BLOB_UNKNOWN"),
+        }
+    }
+
+    async fn post_blob(
+        &self,
+        uri: &Url,
+        repository: &str,
+        data: Bytes,
+        digest: &str,
+    ) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/blobs/uploads/"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::POST, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .commit(&url, repository, digest, data)
+    }
+
+    async fn start_upload(&self, uri: &Url, repository: &str) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/blobs/uploads/"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::POST, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let mut state = self.state.lock().unwrap();
+        state.sessions += 1;
+        let session = format!("session-{}", state.sessions);
+        state
+            .uploads
+            .insert(session.clone(), (repository.to_string(), Vec::new()));
+        state.progress(&url, StatusCode::ACCEPTED, &session)
+    }
+
+    async fn upload_status(&self, uri: &Url, upload: &str) -> Result<Response> {
+        let url = upload_url(uri, upload)?;
+        if let Some(status) = self.record(Method::GET, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .progress(&url, StatusCode::NO_CONTENT, &session(&url))
+    }
+
+    async fn upload_part(
+        &self,
+        uri: &Url,
+        upload: &str,
+        body: Body,
+        start: usize,
+        _end: usize,
+    ) -> Result<Response> {
+        let url = upload_url(uri, upload)?;
+        if let Some(status) = self.record(Method::PATCH, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let session = session(&url);
+        let content = read_body(body).await?;
+        let mut state = self.state.lock().unwrap();
+        match state.uploads.get_mut(&session) {
+            Some((_, received)) if received.len() == start => {
+                received.extend_from_slice(&content);
+                state.progress(&url, StatusCode::ACCEPTED, &session)
+            }
+            // Chunks must arrive in order
+            Some(_) => state.progress(&url, StatusCode::RANGE_NOT_SATISFIABLE, &session),
+            None => failure(&url, StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN"),
+        }
+    }
+
+    async fn finish_blob_upload(
+        &self,
+        uri: &Url,
+        upload: &str,
+        body: Body,
+        digest: &str,
+        start: usize,
+        _end: usize,
+    ) -> Result<Response> {
+        let url = upload_url(uri, upload)?;
+        if let Some(status) = self.record(Method::PUT, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let session = session(&url);
+        let content = read_body(body).await?;
+        let mut state = self.state.lock().unwrap();
+        match state.uploads.get(&session) {
+            Some((_, received)) if content.is_empty() || received.len() == start => {
+                let (repository, mut received) = state.uploads.remove(&session).unwrap();
+                received.extend_from_slice(&content);
+                state.commit(&url, &repository, digest, received.into())
+            }
+            Some(_) => state.progress(&url, StatusCode::RANGE_NOT_SATISFIABLE, &session),
+            None => failure(&url, StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN"),
+        }
+    }
+
+    async fn head_manifest(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+    ) -> Result<Response> {
+        self.manifest_response(uri, repository, reference, None, true)
+    }
+
+    async fn get_manifest(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+        etag: Option<&str>,
+    ) -> Result<Response> {
+        self.manifest_response(uri, repository, reference, etag, false)
+    }
+
+    async fn put_manifest(
+        &self,
+        uri: &Url,
+        repository: &str,
+        reference: &str,
+        media_type: &str,
+        body: Bytes,
+    ) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/manifests/{reference}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::PUT, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let Ok(manifest) = serde_json::from_slice::<Value>(&body) else {
+            return failure(&url, StatusCode::BAD_REQUEST, "MANIFEST_INVALID");
+        };
+        // Manifests are pushed with the media type they declare
+        if manifest["mediaType"]
+            .as_str()
+            .is_some_and(|x| x != media_type)
+        {
+            return failure(&url, StatusCode::BAD_REQUEST, "MANIFEST_INVALID");
+        }
+        let digest = Algorithm::Sha256.digest(&body);
+        if reference.contains(':') && reference != digest {
+            return failure(&url, StatusCode::BAD_REQUEST, "DIGEST_INVALID");
+        }
+        let mut state = self.state.lock().unwrap();
+        // Everything the manifest refers to must be in the repository
+        let key = |x: &Value| Some((repository.to_string(), x["digest"].as_str()?.to_string()));
+        let layers = manifest["layers"].as_array().into_iter().flatten();
+        if std::iter::once(&manifest["config"])
+            .chain(layers)
+            .filter_map(key)
+            .any(|x| !state.blobs.contains_key(&x))
+        {
+            return failure(&url, StatusCode::BAD_REQUEST, "MANIFEST_BLOB_UNKNOWN");
+        }
+        let manifests = manifest["manifests"].as_array().into_iter().flatten();
+        if manifests
+            .filter_map(key)
+            .any(|x| !state.manifests.contains_key(&x))
+        {
+            return failure(&url, StatusCode::BAD_REQUEST, "MANIFEST_UNKNOWN");
+        }
+        state.insert_manifest(repository, reference, media_type.to_string(), body);
+        let mut headers = vec![
+            (LOCATION, format!("/v2/{repository}/manifests/{digest}")),
+            (DOCKER_CONTENT_DIGEST, digest),
+        ];
+        if let Some(subject) = manifest["subject"]["digest"].as_str() {
+            headers.push((OCI_SUBJECT, subject.to_string()));
+        }
+        respond(&url, StatusCode::CREATED, &headers, Bytes::new())
+    }
+
+    async fn del_manifest(&self, uri: &Url, repository: &str, reference: &str) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/manifests/{reference}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::DELETE, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let mut state = self.state.lock().unwrap();
+        let Some((content, _)) = state
+            .manifests
+            .remove(&(repository.to_string(), reference.to_string()))
+        else {
+            return failure(&url, StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN");
+        };
+        // Deleting a manifest by its digest deletes the tags pointing to it too
+        if reference.contains(':') {
+            state
+                .manifests
+                .retain(|(name, _), (x, _)| name != repository || *x != content);
+        }
+        respond(&url, StatusCode::ACCEPTED, &[], Bytes::new())
+    }
+
+    async fn get_referrers(
+        &self,
+        uri: &Url,
+        repository: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Response> {
+        let url = uri
+            .join(&format!("/v2/{repository}/referrers/{digest}"))
+            .context(error::UrlSnafu)?;
+        if let Some(status) = self.record(Method::GET, &url) {
+            return failure(&url, status, "UNKNOWN");
+        }
+        let state = self.state.lock().unwrap();
+        let manifests = state
+            .manifests
+            .iter()
+            .filter(|((name, reference), _)| name == repository && reference.contains(':'))
+            .filter_map(|((_, reference), (content, media_type))| {
+                let manifest = serde_json::from_slice::<Value>(content).ok()?;
+                let kind = manifest
+                    .get("artifactType")
+                    .unwrap_or(&manifest["config"]["mediaType"])
+                    .clone();
+                (manifest["subject"]["digest"].as_str() == Some(digest)
+                    && artifact_type.is_none_or(|x| kind.as_str() == Some(x)))
+                .then(|| {
+                    json!({
+                        "mediaType": media_type,
+                        "digest": reference,
+                        "size": content.len(),
+                        "artifactType": kind,
+                        "annotations": manifest.get("annotations").cloned().unwrap_or(json!({})),
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut headers = vec![(CONTENT_TYPE, IMAGE_INDEX.to_string())];
+        if artifact_type.is_some() {
+            headers.push((FILTERS_APPLIED, "artifactType".to_string()));
+        }
+        let body = json!({ "schemaVersion": 2, "mediaType": IMAGE_INDEX, "manifests": manifests });
+        respond(&url, StatusCode::OK, &headers, body.to_string().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::MockRegistryClient;
+    use crate::image::Image;
+    use crate::layer::Layer;
+    use crate::models::{Compression, MediaType};
+    use crate::repository::{Repository, TagOptions};
+    use crate::uri::{Reference, RegistryUri, Uri};
+
+    #[tokio::test]
+    async fn test_mock_push_pull() {
+        let client = MockRegistryClient::new();
+        let mut registry = client.registry(&RegistryUri::from_str("mock.local").unwrap());
+        registry.set_page_size(Some(1));
+        let uri = Uri::builder()
+            .registry(registry.clone())
+            .repository("app")
+            .reference(Reference::Tag("v1".to_string()))
+            .build();
+        let config = Layer::upload(&uri, &MediaType::Config, b"{}")
+            .await
+            .unwrap();
+        let layer = Layer::upload(&uri, &MediaType::Layer(Compression::None), b"layer")
+            .await
+            .unwrap();
+        let manifest = Image::create(&config, std::slice::from_ref(&layer), None)
+            .await
+            .push(&uri)
+            .await
+            .unwrap();
+        assert_eq!(
+            client.blob("app", layer.digest()).unwrap().as_ref(),
+            b"layer"
+        );
+        assert_eq!(
+            registry.resolve_digest("app", "v1").await.unwrap(),
+            manifest.digest()
+        );
+        client.insert_manifest(
+            "app",
+            "v2",
+            &MediaType::Manifest,
+            client.manifest("app", "v1").unwrap(),
+        );
+        let repository = Repository::new(&registry, "app");
+        assert_eq!(
+            repository.tags(&TagOptions::default()).await.unwrap(),
+            ["v1", "v2"]
+        );
+        repository.delete_manifest(manifest.digest()).await.unwrap();
+        assert!(client.tags("app").is_empty());
+        assert!(!registry.manifest_exists("app", "v1").await.unwrap());
+    }
+}
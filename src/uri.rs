@@ -1,12 +1,19 @@
 use bon::Builder;
-use snafu::{OptionExt, ResultExt};
+use sha2::{Digest, Sha256, Sha512};
+use snafu::{OptionExt, ResultExt, ensure};
 use std::fmt;
 use std::fmt::Formatter;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 use url::Url;
 
+use crate::audit::AuditSink;
 use crate::error;
+use crate::progress::ProgressSink;
 use crate::registry::Registry;
+use crate::store::Store;
 
 /// Represents a reference to an object in an OCI container.
 #[derive(Debug, Clone, Builder)]
@@ -22,6 +29,81 @@ pub struct Uri {
     reference: Reference,
 }
 
+/// Names Docker Hub is referred to by.
+const DOCKER_HUB: &[&str] = &["docker.io", "index.docker.io", "registry-1.docker.io"];
+/// Host serving the Docker Hub registry api.
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// Names of the transports that read and write images on the local filesystem.
+const LOCAL_TRANSPORTS: &[&str] = &["oci", "oci-archive", "docker-archive", "dir"];
+
+/// Where an image is read from or written to, named with the transport prefixes of skopeo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// Object uri of a registry, optionally prefixed with `docker://`
+    Registry(String),
+    /// OCI layout directory, `oci:/path`
+    Oci(PathBuf),
+    /// Tar archive of an OCI layout, `oci-archive:/path.tar`
+    OciArchive(PathBuf),
+    /// Tarball written by `docker save`, `docker-archive:/path.tar`
+    DockerArchive(PathBuf),
+    /// Directory of a single image with its manifest and blobs named by their digest, `dir:/path`
+    Dir(PathBuf),
+}
+
+impl Transport {
+    /// Whether the image is on the local filesystem rather than in a registry
+    pub fn is_local(&self) -> bool {
+        !matches!(self, Self::Registry(_))
+    }
+}
+
+impl FromStr for Transport {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(object) = s.strip_prefix("docker://") {
+            return Ok(Self::Registry(object.to_string()));
+        }
+        // A registry named like a transport is followed by its port instead of a path
+        let (name, path) = match s.split_once(':') {
+            Some((name, path))
+                if LOCAL_TRANSPORTS.contains(&name)
+                    && !path.starts_with(|x: char| x.is_ascii_digit()) =>
+            {
+                (name, path)
+            }
+            _ => return Ok(Self::Registry(s.to_string())),
+        };
+        ensure!(
+            !path.is_empty(),
+            error::MalformedUriSnafu {
+                reason: format!("no path was provided for the {name} transport"),
+            }
+        );
+        let path = PathBuf::from(path);
+        Ok(match name {
+            "oci" => Self::Oci(path),
+            "oci-archive" => Self::OciArchive(path),
+            "docker-archive" => Self::DockerArchive(path),
+            _ => Self::Dir(path),
+        })
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registry(object) => f.write_str(object),
+            Self::Oci(path) => write!(f, "oci:{}", path.display()),
+            Self::OciArchive(path) => write!(f, "oci-archive:{}", path.display()),
+            Self::DockerArchive(path) => write!(f, "docker-archive:{}", path.display()),
+            Self::Dir(path) => write!(f, "dir:{}", path.display()),
+        }
+    }
+}
+
 /// URI to a specific registry.
 #[derive(Debug, Clone, Builder)]
 pub struct RegistryUri {
@@ -31,6 +113,9 @@ pub struct RegistryUri {
     /// Whether to connect with https or not
     #[builder(into)]
     is_secure: bool,
+    /// Unix domain socket the registry is listening on
+    #[builder(into)]
+    socket: Option<PathBuf>,
 }
 
 impl RegistryUri {
@@ -45,22 +130,49 @@ impl RegistryUri {
     pub fn set_secure(&mut self, flag: bool) {
         self.is_secure = flag;
     }
+
+    /// Unix domain socket to connect through instead of TCP, if any
+    pub fn socket(&self) -> Option<&PathBuf> {
+        self.socket.as_ref()
+    }
+
+    /// Whether this is Docker Hub, whose api is served from a different host than its name
+    pub fn is_docker_hub(&self) -> bool {
+        DOCKER_HUB.contains(&self.base.as_str())
+    }
 }
 
 impl FromStr for RegistryUri {
     type Err = crate::error::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            ensure!(
+                !path.is_empty(),
+                error::MalformedUriSnafu {
+                    reason: "no path was provided for the unix socket",
+                }
+            );
+            return Ok(Self {
+                base: s.to_string(),
+                is_secure: false,
+                socket: Some(PathBuf::from(path)),
+            });
+        }
         let (registry_base, is_secure) = if s.starts_with("http://") {
             (s.strip_prefix("http://").unwrap(), false)
         } else if s.starts_with("https://") {
             (s.strip_prefix("https://").unwrap(), true)
         } else {
-            (s, !(s.contains("localhost") || s.contains("127.0.0.1")))
+            (
+                s,
+                !(s.contains("localhost") || s.contains("127.0.0.1") || s.contains("[::1]")),
+            )
         };
         Ok(Self {
             base: registry_base.to_string(),
             is_secure,
+            socket: None,
         })
     }
 }
@@ -69,10 +181,18 @@ impl TryInto<Url> for RegistryUri {
     type Error = crate::error::Error;
 
     fn try_into(self) -> Result<Url, Self::Error> {
+        // Requests over a unix socket still need a host for the url, it is never resolved
+        let host = if self.socket.is_some() {
+            "localhost"
+        } else if self.is_docker_hub() {
+            DOCKER_HUB_API
+        } else {
+            self.base.as_str()
+        };
         Url::parse(&format!(
             "{}://{}",
             if self.is_secure { "https" } else { "http" },
-            self.base
+            host
         ))
         .context(crate::error::UrlSnafu)
     }
@@ -81,9 +201,34 @@ impl TryInto<Url> for RegistryUri {
 impl Uri {
     /// Parse an object uri from a string and initialize a registry client
     pub async fn new(input: &str) -> crate::Result<Self> {
-        let (registry, object) = input.split_once("/").context(error::MalformedUriSnafu {
-            reason: "only a registry was provided in the uri",
-        })?;
+        let (registry, repository, reference) = Self::split(input)?;
+        Ok(Self {
+            registry: Registry::new(&registry).await?,
+            repository,
+            reference,
+        })
+    }
+
+    /// Parse an object uri from a string with a registry client that only serves content
+    /// from the local store
+    pub fn offline(input: &str, store: &Store) -> crate::Result<Self> {
+        let (registry, repository, reference) = Self::split(input)?;
+        Ok(Self {
+            registry: Registry::offline(&registry, store),
+            repository,
+            reference,
+        })
+    }
+
+    /// Split an object uri into the registry, repository and reference without creating a
+    /// registry client.
+    ///
+    /// The registry may be prefixed with `http://`, `https://` or `unix://`. A unix socket path is
+    /// separated from the repository with `//`, i.e. `unix:///run/registry.sock//repo:tag`. The
+    /// `docker://` transport prefix is accepted and dropped, local transports such as `oci:` are
+    /// rejected as they are not in a registry.
+    pub fn split(input: &str) -> crate::Result<(RegistryUri, String, Reference)> {
+        let (registry, object) = Self::split_registry(input)?;
         let (repository, tag) = if object.contains('@') {
             let (repository, digest) = object.split_once('@').unwrap();
             let (algorithm, value) = digest.split_once(':').context(error::MalformedUriSnafu {
@@ -102,17 +247,74 @@ impl Uri {
             })?;
             (repository, Reference::Tag(tag.to_string()))
         };
-        Ok(Self {
-            registry: Registry::new(&RegistryUri::from_str(registry)?).await?,
-            repository: repository.into(),
-            reference: tag,
-        })
+        Ok((registry, repository.into(), tag))
+    }
+
+    /// Split a repository uri, an object uri without a reference, into the registry and the
+    /// repository.
+    pub fn split_repository(input: &str) -> crate::Result<(RegistryUri, String)> {
+        let (registry, repository) = Self::split_registry(input)?;
+        ensure!(
+            !repository.is_empty() && !repository.contains(['@', ':']),
+            error::MalformedUriSnafu {
+                reason: "a repository can not have a tag or digest",
+            }
+        );
+        Ok((registry, repository.to_string()))
+    }
+
+    /// Split the registry from the rest of a uri
+    fn split_registry(input: &str) -> crate::Result<(RegistryUri, &str)> {
+        let transport = Transport::from_str(input)?;
+        ensure!(
+            !transport.is_local(),
+            error::MalformedUriSnafu {
+                reason: format!("{transport} is not in a registry, only copy reads and writes it"),
+            }
+        );
+        let input = input.strip_prefix("docker://").unwrap_or(input);
+        let (scheme, rest) = match input.split_once("://") {
+            Some((scheme, rest)) if matches!(scheme, "http" | "https" | "unix") => {
+                (Some(scheme), rest)
+            }
+            _ => (None, input),
+        };
+        let (registry, object) = if scheme == Some("unix") {
+            rest.split_once("//").context(error::MalformedUriSnafu {
+                reason: "the unix socket path must be separated from the repository with '//'",
+            })?
+        } else {
+            rest.split_once("/").context(error::MalformedUriSnafu {
+                reason: "only a registry was provided in the uri",
+            })?
+        };
+        let registry = match scheme {
+            Some(scheme) => format!("{scheme}://{registry}"),
+            None => registry.to_string(),
+        };
+        Ok((RegistryUri::from_str(&registry)?, object))
     }
 
     pub fn set_secure(&mut self, flag: bool) {
         self.registry.set_secure(flag);
     }
 
+    pub fn set_store(&mut self, store: Option<Store>) {
+        self.registry.set_store(store);
+    }
+
+    pub fn set_audit(&mut self, audit: Option<Arc<dyn AuditSink>>) {
+        self.registry.set_audit(audit);
+    }
+
+    pub fn set_progress(&mut self, progress: Option<Arc<dyn ProgressSink>>) {
+        self.registry.set_progress(progress);
+    }
+
+    pub fn set_limiter(&mut self, limiter: Option<Arc<Semaphore>>) {
+        self.registry.set_limiter(limiter);
+    }
+
     pub fn registry(&self) -> &Registry {
         &self.registry
     }
@@ -128,9 +330,11 @@ impl Uri {
 
 impl fmt::Display for Uri {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let uri = self.registry.uri();
         f.write_fmt(format_args!(
-            "{}/{}{}",
-            self.registry.uri().base,
+            "{}{}{}{}",
+            uri.base,
+            if uri.socket.is_some() { "//" } else { "/" },
             self.repository,
             match &self.reference {
                 Reference::Tag(tag) => format!(":{tag}"),
@@ -186,6 +390,17 @@ impl FromStr for Algorithm {
     }
 }
 
+impl Algorithm {
+    /// Digest of some content with this algorithm, in the `algorithm:hex` form
+    pub fn digest(&self, data: &[u8]) -> String {
+        let hash = match self {
+            Self::Sha256 => base16::encode_lower(Sha256::digest(data).as_slice()),
+            Self::Sha512 => base16::encode_lower(Sha512::digest(data).as_slice()),
+        };
+        format!("{self}:{hash}")
+    }
+}
+
 impl fmt::Display for Algorithm {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -210,6 +425,16 @@ impl fmt::Display for Reference {
 mod test {
     use std::str::FromStr;
 
+    #[test]
+    fn test_docker_hub_url() {
+        let registry = super::RegistryUri::from_str("docker.io").unwrap();
+        assert!(registry.is_docker_hub());
+        let url: url::Url = registry.try_into().unwrap();
+        assert_eq!(url.as_str(), "https://registry-1.docker.io/");
+        let registry = super::RegistryUri::from_str("ghcr.io").unwrap();
+        assert!(!registry.is_docker_hub());
+    }
+
     #[test]
     fn test_registry_from_str() {
         let registry = super::RegistryUri::from_str("localhost:5000").unwrap();
@@ -227,6 +452,17 @@ mod test {
         let registry = super::RegistryUri::from_str("https://XXXXXXXXXXXXXXXXXXXXXX").unwrap();
         assert_eq!(registry.base, "XXXXXXXXXXXXXXXXXXXXXX");
         assert!(registry.is_secure);
+        let registry = super::RegistryUri::from_str("[::1]:5000").unwrap();
+        assert_eq!(registry.base, "[::1]:5000");
+        assert!(!registry.is_secure);
+        let registry = super::RegistryUri::from_str("unix:///run/registry.sock").unwrap();
+        assert_eq!(registry.base, "unix:///run/registry.sock");
+        assert_eq!(
+            registry.socket,
+            Some(std::path::PathBuf::from("/run/registry.sock"))
+        );
+        assert!(!registry.is_secure);
+        assert!(super::RegistryUri::from_str("unix://").is_err());
     }
 
     #[test]
@@ -237,6 +473,12 @@ mod test {
         let registry = super::RegistryUri::from_str("public.ecr.aws/bottlerocket").unwrap();
         let url: super::Url = registry.try_into().unwrap();
         assert_eq!(url.as_str(), "https://public.ecr.aws/bottlerocket");
+        let registry = super::RegistryUri::from_str("[::1]:5000").unwrap();
+        let url: super::Url = registry.try_into().unwrap();
+        assert_eq!(url.as_str(), "http://[::1]:5000/");
+        let registry = super::RegistryUri::from_str("unix:///run/registry.sock").unwrap();
+        let url: super::Url = registry.try_into().unwrap();
+        assert_eq!(url.as_str(), "http://localhost/");
     }
 
     #[test]
@@ -247,6 +489,19 @@ mod test {
         assert_eq!(algorithm, super::Algorithm::Sha512);
     }
 
+    #[test]
+    fn test_algorithm_digest() {
+        assert_eq!(
+            super::Algorithm::Sha256.digest(b"{}"),
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert!(
+            super::Algorithm::Sha512
+                .digest(b"{}")
+                .starts_with("sha512:")
+        );
+    }
+
     #[test]
     fn test_reference_from_str() {
         let reference = super::Reference::from_str("latest").unwrap();
@@ -307,5 +562,71 @@ mod test {
             uri.to_string(),
             "fake.io/bottlerocket/bottlerocket-test@sha256:1234567890abcdef"
         );
+        let uri = super::Uri::new("http://[::1]:5000/bottlerocket-test:latest")
+            .await
+            .unwrap();
+        assert_eq!(uri.registry.uri().base, "[::1]:5000");
+        assert_eq!(uri.repository, "bottlerocket-test");
+        assert_eq!(uri.to_string(), "[::1]:5000/bottlerocket-test:latest");
+        let uri = super::Uri::new("unix:///run/registry.sock//bottlerocket/test:latest")
+            .await
+            .unwrap();
+        assert_eq!(uri.registry.uri().base, "unix:///run/registry.sock");
+        assert_eq!(uri.repository, "bottlerocket/test");
+        assert_eq!(
+            uri.to_string(),
+            "unix:///run/registry.sock//bottlerocket/test:latest"
+        );
+        assert!(
+            super::Uri::new("unix:///run/registry.sock/test:latest")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_transport_from_str() {
+        use super::Transport;
+        use std::path::PathBuf;
+        assert_eq!(
+            Transport::from_str("docker://fake.io/test:latest").unwrap(),
+            Transport::Registry("fake.io/test:latest".to_string())
+        );
+        assert_eq!(
+            Transport::from_str("oci:5000/test:latest").unwrap(),
+            Transport::Registry("oci:5000/test:latest".to_string())
+        );
+        assert_eq!(
+            Transport::from_str("oci:/tmp/layout").unwrap(),
+            Transport::Oci(PathBuf::from("/tmp/layout"))
+        );
+        assert_eq!(
+            Transport::from_str("oci-archive:image.tar").unwrap(),
+            Transport::OciArchive(PathBuf::from("image.tar"))
+        );
+        assert_eq!(
+            Transport::from_str("docker-archive:/tmp/image.tar").unwrap(),
+            Transport::DockerArchive(PathBuf::from("/tmp/image.tar"))
+        );
+        assert_eq!(
+            Transport::from_str("dir:/tmp/image").unwrap(),
+            Transport::Dir(PathBuf::from("/tmp/image"))
+        );
+        assert!(Transport::from_str("dir:").is_err());
+        let (registry, repository, _) = super::Uri::split("docker://fake.io/test:latest").unwrap();
+        assert_eq!(registry.base, "fake.io");
+        assert_eq!(repository, "test");
+        assert!(super::Uri::split("oci:/tmp/layout").is_err());
+    }
+
+    #[test]
+    fn test_split_repository() {
+        let (registry, repository) =
+            super::Uri::split_repository("localhost:5000/bottlerocket/test").unwrap();
+        assert_eq!(registry.base, "localhost:5000");
+        assert_eq!(repository, "bottlerocket/test");
+        assert!(super::Uri::split_repository("localhost:5000/test:latest").is_err());
+        assert!(super::Uri::split_repository("localhost:5000/test@sha256:abc").is_err());
+        assert!(super::Uri::split_repository("localhost:5000").is_err());
     }
 }
@@ -0,0 +1,329 @@
+//! End to end push, pull, copy and delete flows against a registry.
+//!
+//! The flows run against the in-memory registry of the `testing` feature by default. Set
+//! `OCILOT_CONFORMANCE_REGISTRY` to the base url of a running registry to run them against it
+//! instead, i.e. `http://localhost:5000` for `docker run -p 5000:5000 registry:2`.
+
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use ocilot::image::Image;
+use ocilot::index::Index;
+use ocilot::layer::Layer;
+use ocilot::manifest::Manifest;
+use ocilot::models::{Compression, MediaType};
+use ocilot::options::ChunkPolicy;
+use ocilot::registry::Registry;
+use ocilot::repository::{Repository, TagOptions};
+use ocilot::testing::MockRegistryClient;
+use ocilot::uri::{Reference, RegistryUri, Uri};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Registry the flows run against, an in-memory one unless a registry is configured
+struct Target {
+    base: String,
+    local: Option<MockRegistryClient>,
+}
+
+impl Target {
+    async fn new() -> Self {
+        match std::env::var("OCILOT_CONFORMANCE_REGISTRY") {
+            Ok(base) => Self { base, local: None },
+            Err(_) => Self {
+                base: "mock.local".to_string(),
+                local: Some(MockRegistryClient::new()),
+            },
+        }
+    }
+
+    /// Repository unique to this run, so runs against a shared registry do not collide
+    fn repository(&self, name: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!("conformance/{name}-{nanos}")
+    }
+
+    async fn registry(&self) -> Registry {
+        let uri = RegistryUri::from_str(&self.base).unwrap();
+        match &self.local {
+            Some(local) => local.registry(&uri),
+            None => Registry::new(&uri).await.unwrap(),
+        }
+    }
+
+    async fn uri(&self, repository: &str, reference: &str) -> Uri {
+        self.uri_with(self.registry().await, repository, reference)
+    }
+
+    fn uri_with(&self, registry: Registry, repository: &str, reference: &str) -> Uri {
+        Uri::builder()
+            .registry(registry)
+            .repository(repository)
+            .reference(Reference::from_str(reference).unwrap())
+            .build()
+    }
+}
+
+fn digest(content: &[u8]) -> String {
+    format!("sha256:{}", base16::encode_lower(&Sha256::digest(content)))
+}
+
+fn content(size: usize, seed: u8) -> Vec<u8> {
+    (0..size)
+        .map(|x| (x as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect()
+}
+
+/// Upload a config and layer and push an image of them
+async fn push_image(uri: &Uri, layer: &[u8]) -> (Image, Layer) {
+    let config = serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "rootfs": { "type": "layers", "diff_ids": [digest(layer)] },
+    });
+    let config = Layer::upload(
+        uri,
+        &MediaType::Config,
+        &serde_json::to_vec(&config).unwrap(),
+    )
+    .await
+    .unwrap();
+    let layer = Layer::upload(uri, &MediaType::Layer(Compression::None), layer)
+        .await
+        .unwrap();
+    let image = Image::create(&config, &[layer], None).await;
+    let manifest = image.push(uri).await.unwrap();
+    (image, manifest)
+}
+
+async fn read_blob(uri: &Uri, layer: &Layer) -> Vec<u8> {
+    let mut content = Vec::new();
+    layer
+        .open(uri)
+        .await
+        .unwrap()
+        .read_to_end(&mut content)
+        .await
+        .unwrap();
+    content
+}
+
+#[tokio::test]
+async fn test_push_pull() {
+    let target = Target::new().await;
+    let repository = target.repository("push-pull");
+    let uri = target.uri(&repository, "v1").await;
+    let layer = content(4096, 1);
+    let (image, manifest) = push_image(&uri, &layer).await;
+
+    let digest = uri
+        .registry()
+        .resolve_digest(&repository, "v1")
+        .await
+        .unwrap();
+    assert_eq!(digest, manifest.digest());
+    let Manifest::Image(pulled) = Manifest::fetch(&uri).await.unwrap() else {
+        panic!("expected an image manifest");
+    };
+    assert_eq!(pulled.layers().len(), 1);
+    assert_eq!(pulled.config().digest(), image.config().digest());
+    let by_digest = target.uri(&repository, &digest).await;
+    let pulled = Image::fetch(&by_digest, None).await.unwrap();
+    assert_eq!(read_blob(&uri, &pulled.layers()[0]).await, layer);
+
+    // An index of the image
+    let index_uri = target.uri(&repository, "index").await;
+    Index::new(std::slice::from_ref(&manifest))
+        .await
+        .push(&index_uri)
+        .await
+        .unwrap();
+    let Manifest::Index(index) = Manifest::fetch(&index_uri).await.unwrap() else {
+        panic!("expected an image index");
+    };
+    assert_eq!(
+        index.images().map(|x| x.digest()).collect::<Vec<_>>(),
+        [manifest.digest()]
+    );
+    let (_, _, media_type) = uri
+        .registry()
+        .fetch_manifest_raw(&repository, "index")
+        .await
+        .unwrap();
+    assert_eq!(media_type, MediaType::ImageIndex);
+}
+
+#[tokio::test]
+async fn test_chunked_upload() {
+    let target = Target::new().await;
+    let repository = target.repository("chunked");
+    let mut registry = target.registry().await;
+    registry.set_chunk_policy(
+        ChunkPolicy::builder()
+            .min_bytes(1024 * 1024)
+            .max_bytes(2 * 1024 * 1024)
+            .monolithic_bytes(1024 * 1024)
+            .build(),
+    );
+    let uri = target.uri_with(registry, &repository, "v1");
+    // Larger than the copy buffer, so the blob is streamed to the writer
+    let blob = content(6 * 1024 * 1024 + 17, 2);
+    let media_type = MediaType::Layer(Compression::None);
+    let mut writer = Layer::create(&uri, &media_type, blob.len(), Some(digest(&blob)))
+        .await
+        .unwrap()
+        .expect("the blob is not in the registry yet");
+    Layer::copy(&mut blob.as_slice(), &mut writer, blob.len())
+        .await
+        .unwrap();
+    writer.flush().await.unwrap();
+    let layer = writer.layer().await.unwrap();
+
+    assert_eq!(layer.digest(), digest(&blob));
+    assert!(
+        uri.registry()
+            .blob_exists(&repository, layer.digest())
+            .await
+            .unwrap()
+    );
+    assert_eq!(read_blob(&uri, &layer).await, blob);
+    if let Some(local) = &target.local {
+        // Every chunk went to the location of the previous response
+        let patches = local.requests(&Method::PATCH);
+        assert!(patches.len() > 1, "{patches:?}");
+        assert!(patches.iter().all(|x| x.contains("_state=")), "{patches:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_copy() {
+    let target = Target::new().await;
+    let source_repository = target.repository("copy-source");
+    let target_repository = target.repository("copy-target");
+    let source = target.uri(&source_repository, "v1").await;
+    let destination = target.uri(&target_repository, "v1").await;
+    let (image, manifest) = push_image(&source, &content(2048, 3)).await;
+
+    for layer in std::iter::once(image.config()).chain(image.layers()) {
+        let writer = Layer::create(
+            &destination,
+            layer.media_type(),
+            layer.size(),
+            Some(layer.digest().to_string()),
+        )
+        .await
+        .unwrap();
+        if let Some(mut writer) = writer {
+            let mut reader = layer.open(&source).await.unwrap();
+            Layer::copy(&mut reader, &mut writer, layer.size())
+                .await
+                .unwrap();
+            writer.flush().await.unwrap();
+            writer.layer().await.unwrap();
+        }
+    }
+    let (bytes, _, media_type) = source
+        .registry()
+        .fetch_manifest_raw(&source_repository, "v1")
+        .await
+        .unwrap();
+    let copied = destination
+        .registry()
+        .push_manifest_raw(&media_type, &target_repository, "v1", bytes, None)
+        .await
+        .unwrap();
+
+    // The copy keeps the digest of the source manifest
+    assert_eq!(copied.digest(), manifest.digest());
+    assert_eq!(
+        destination
+            .registry()
+            .resolve_digest(&target_repository, "v1")
+            .await
+            .unwrap(),
+        manifest.digest()
+    );
+    for layer in std::iter::once(image.config()).chain(image.layers()) {
+        assert_eq!(
+            read_blob(&destination, layer).await,
+            read_blob(&source, layer).await
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_delete() {
+    let target = Target::new().await;
+    let repository = target.repository("delete");
+    let uri = target.uri(&repository, "v1").await;
+    let (image, manifest) = push_image(&uri, &content(1024, 4)).await;
+    let registry = uri.registry();
+    let repo = Repository::new(registry, &repository);
+
+    repo.delete_tag("v1").await.unwrap();
+    assert!(!registry.manifest_exists(&repository, "v1").await.unwrap());
+    assert!(
+        registry
+            .manifest_exists(&repository, manifest.digest())
+            .await
+            .unwrap()
+    );
+    repo.delete_manifest(manifest.digest()).await.unwrap();
+    assert!(
+        !registry
+            .manifest_exists(&repository, manifest.digest())
+            .await
+            .unwrap()
+    );
+    for layer in std::iter::once(image.config()).chain(image.layers()) {
+        layer.delete(&uri).await.unwrap();
+        assert!(
+            !registry
+                .blob_exists(&repository, layer.digest())
+                .await
+                .unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_tags_pagination() {
+    let target = Target::new().await;
+    let repository = target.repository("tags");
+    let uri = target.uri(&repository, "base").await;
+    let (_, manifest) = push_image(&uri, &content(512, 5)).await;
+    let (bytes, _, media_type) = uri
+        .registry()
+        .fetch_manifest_raw(&repository, manifest.digest())
+        .await
+        .unwrap();
+    let tags = ["v1", "v2", "v3", "v4"];
+    for tag in tags {
+        uri.registry()
+            .push_manifest_raw(&media_type, &repository, tag, Bytes::clone(&bytes), None)
+            .await
+            .unwrap();
+    }
+
+    let mut registry = target.registry().await;
+    registry.set_page_size(Some(2));
+    let listed = Repository::new(&registry, &repository)
+        .tags(&TagOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(listed, ["base", "v1", "v2", "v3", "v4"]);
+    if let Some(local) = &target.local {
+        // Five tags take three pages of two
+        let pages = local
+            .requests(&Method::GET)
+            .into_iter()
+            .filter(|x| x.contains("/tags/list"))
+            .count();
+        assert_eq!(pages, 3);
+    }
+}